@@ -195,7 +195,9 @@ fn setup_page_tables(mem: &GuestMemoryMmap, sregs: &mut kvm_sregs) -> Result<()>
     Ok(())
 }
 
-fn create_msr_entries() -> Msrs {
+/// Build the list of MSRs configured at boot time. This list also defines
+/// which MSRs are saved and restored when snapshotting a vCPU.
+pub fn create_msr_entries() -> Msrs {
     let mut entries = Vec::<kvm_msr_entry>::new();
 
     entries.push(kvm_msr_entry {
@@ -10,6 +10,7 @@
 // See https://pdos.csail.mit.edu/6.828/2016/readings/ia32/ioapic.pdf for a specification.
 
 use crate::BusDevice;
+use anyhow::anyhow;
 use byteorder::{ByteOrder, LittleEndian};
 use std::io;
 use std::result;
@@ -18,6 +19,7 @@ use vm_device::interrupt::{
     InterruptIndex, InterruptManager, InterruptSourceConfig, InterruptSourceGroup,
     MsiIrqGroupConfig, MsiIrqSourceConfig,
 };
+use vm_device::{Migratable, MigratableError, Pausable, Snapshot, Snapshotable, VersionedState};
 use vm_memory::GuestAddress;
 
 #[derive(Debug)]
@@ -119,6 +121,7 @@ pub struct MsiMessage {
 }
 
 pub const NUM_IOAPIC_PINS: usize = 24;
+const IOAPIC_SNAPSHOT_ID: &str = "ioapic";
 const IOAPIC_VERSION_ID: u32 = 0x0017_0011;
 
 // Constants for IOAPIC direct register offset
@@ -165,6 +168,17 @@ fn decode_irq_from_selector(selector: u8) -> (usize, bool) {
     )
 }
 
+#[derive(Deserialize, Serialize)]
+pub struct IoapicState {
+    id: u32,
+    reg_sel: u32,
+    reg_entries: [RedirectionTableEntry; NUM_IOAPIC_PINS],
+}
+
+impl VersionedState for IoapicState {
+    const VERSION: u16 = 1;
+}
+
 pub struct Ioapic {
     id: u32,
     reg_sel: u32,
@@ -365,6 +379,33 @@ impl Ioapic {
         }
     }
 
+    fn state(&self) -> IoapicState {
+        IoapicState {
+            id: self.id,
+            reg_sel: self.reg_sel,
+            reg_entries: self.reg_entries,
+        }
+    }
+
+    // The interrupt routes are programmed from the redirection table, so
+    // they must be updated for each entry the guest had set up.
+    fn set_state(&mut self, state: &IoapicState) -> result::Result<(), MigratableError> {
+        self.id = state.id;
+        self.reg_sel = state.reg_sel;
+        self.reg_entries = state.reg_entries;
+
+        for irq in 0..NUM_IOAPIC_PINS {
+            if self.reg_entries[irq] == 0 {
+                continue;
+            }
+            self.update_entry(irq).map_err(|e| {
+                MigratableError::Restore(anyhow!("Could not restore IOAPIC entry {}: {:?}", irq, e))
+            })?;
+        }
+
+        Ok(())
+    }
+
     fn ioapic_read(&self) -> u32 {
         debug!("IOAPIC_R reg 0x{:x}", self.reg_sel);
 
@@ -386,3 +427,29 @@ impl Ioapic {
         }
     }
 }
+
+impl Pausable for Ioapic {
+    fn pause(&mut self) -> result::Result<(), MigratableError> {
+        Ok(())
+    }
+
+    fn resume(&mut self) -> result::Result<(), MigratableError> {
+        Ok(())
+    }
+}
+
+impl Snapshotable for Ioapic {
+    fn id(&self) -> String {
+        IOAPIC_SNAPSHOT_ID.to_string()
+    }
+
+    fn snapshot(&self) -> result::Result<Snapshot, MigratableError> {
+        Snapshot::new_from_state(&self.id(), &self.state())
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> result::Result<(), MigratableError> {
+        self.set_state(&snapshot.to_state(&self.id())?)
+    }
+}
+
+impl Migratable for Ioapic {}
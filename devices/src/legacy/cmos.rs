@@ -2,9 +2,12 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use anyhow::anyhow;
 use libc::{gmtime_r, time, time_t, tm};
 use std::cmp::min;
 use std::mem;
+use std::result;
+use vm_device::{Migratable, MigratableError, Pausable, Snapshot, Snapshotable, VersionedState};
 
 use crate::BusDevice;
 
@@ -12,6 +15,7 @@ const INDEX_MASK: u8 = 0x7f;
 const INDEX_OFFSET: u64 = 0x0;
 const DATA_OFFSET: u64 = 0x1;
 const DATA_LEN: usize = 128;
+const CMOS_SNAPSHOT_ID: &str = "cmos";
 
 /// A CMOS/RTC device commonly seen on x86 I/O port 0x70/0x71.
 pub struct Cmos {
@@ -42,6 +46,37 @@ impl Cmos {
 
         Cmos { index: 0, data }
     }
+
+    fn state(&self) -> CmosState {
+        CmosState {
+            index: self.index,
+            data: self.data.to_vec(),
+        }
+    }
+
+    fn set_state(&mut self, state: &CmosState) -> result::Result<(), MigratableError> {
+        if state.data.len() != DATA_LEN {
+            return Err(MigratableError::Restore(anyhow!(
+                "Invalid CMOS data length {}",
+                state.data.len()
+            )));
+        }
+
+        self.index = state.index & INDEX_MASK;
+        self.data.copy_from_slice(&state.data);
+
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct CmosState {
+    index: u8,
+    data: Vec<u8>,
+}
+
+impl VersionedState for CmosState {
+    const VERSION: u16 = 1;
 }
 
 impl BusDevice for Cmos {
@@ -113,3 +148,29 @@ impl BusDevice for Cmos {
         }
     }
 }
+
+impl Pausable for Cmos {
+    fn pause(&mut self) -> result::Result<(), MigratableError> {
+        Ok(())
+    }
+
+    fn resume(&mut self) -> result::Result<(), MigratableError> {
+        Ok(())
+    }
+}
+
+impl Snapshotable for Cmos {
+    fn id(&self) -> String {
+        CMOS_SNAPSHOT_ID.to_string()
+    }
+
+    fn snapshot(&self) -> result::Result<Snapshot, MigratableError> {
+        Snapshot::new_from_state(&self.id(), &self.state())
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> result::Result<(), MigratableError> {
+        self.set_state(&snapshot.to_state(&self.id())?)
+    }
+}
+
+impl Migratable for Cmos {}
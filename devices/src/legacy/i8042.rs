@@ -2,10 +2,14 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE-BSD-3-Clause file.
 
+use std::result;
+use vm_device::{Migratable, MigratableError, Pausable, Snapshotable};
 use vmm_sys_util::eventfd::EventFd;
 
 use BusDevice;
 
+const I8042_SNAPSHOT_ID: &str = "i8042";
+
 /// A i8042 PS/2 controller that emulates just enough to shutdown the machine.
 pub struct I8042Device {
     reset_evt: EventFd,
@@ -41,3 +45,22 @@ impl BusDevice for I8042Device {
         }
     }
 }
+
+impl Pausable for I8042Device {
+    fn pause(&mut self) -> result::Result<(), MigratableError> {
+        Ok(())
+    }
+
+    fn resume(&mut self) -> result::Result<(), MigratableError> {
+        Ok(())
+    }
+}
+
+// The device has no state of its own, its snapshot is always empty.
+impl Snapshotable for I8042Device {
+    fn id(&self) -> String {
+        I8042_SNAPSHOT_ID.to_string()
+    }
+}
+
+impl Migratable for I8042Device {}
@@ -8,9 +8,11 @@
 #[cfg(feature = "cmos")]
 mod cmos;
 mod i8042;
+mod pvpanic;
 mod serial;
 
 #[cfg(feature = "cmos")]
 pub use self::cmos::Cmos;
 pub use self::i8042::I8042Device;
+pub use self::pvpanic::PvPanicDevice;
 pub use self::serial::Serial;
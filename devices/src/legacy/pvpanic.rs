@@ -0,0 +1,47 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use BusDevice;
+
+/// The guest kernel panicked.
+const PVPANIC_PANICKED: u8 = 1 << 0;
+/// The guest kernel is about to boot into a crash kernel.
+const PVPANIC_CRASH_LOADED: u8 = 1 << 1;
+
+/// A pvpanic ISA device, through which the guest reports its kernel panics.
+/// Panics are reported as "guest" events on the event monitor.
+#[derive(Default)]
+pub struct PvPanicDevice {}
+
+impl PvPanicDevice {
+    pub fn new() -> PvPanicDevice {
+        PvPanicDevice {}
+    }
+}
+
+// The pvpanic device is a single 8-bit I/O port. Reading it returns the
+// supported events, writing it reports an event.
+impl BusDevice for PvPanicDevice {
+    fn read(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
+        if data.len() == 1 && offset == 0 {
+            data[0] = PVPANIC_PANICKED | PVPANIC_CRASH_LOADED;
+        }
+    }
+
+    fn write(&mut self, _base: u64, offset: u64, data: &[u8]) {
+        if data.len() != 1 || offset != 0 {
+            return;
+        }
+
+        if data[0] & PVPANIC_PANICKED != 0 {
+            warn!("Guest kernel panicked");
+            event!("guest", "panic");
+        }
+        if data[0] & PVPANIC_CRASH_LOADED != 0 {
+            warn!("Guest kernel booting into its crash kernel");
+            event!("guest", "crash-loaded");
+        }
+    }
+}
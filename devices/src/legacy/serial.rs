@@ -10,9 +10,11 @@ use std::collections::VecDeque;
 use std::sync::Arc;
 use std::{io, result};
 use vm_device::interrupt::InterruptSourceGroup;
+use vm_device::{Migratable, MigratableError, Pausable, Snapshot, Snapshotable, VersionedState};
 use vmm_sys_util::errno::Result;
 
 const LOOP_SIZE: usize = 0x40;
+const SERIAL_SNAPSHOT_ID: &str = "serial";
 
 const DATA: u8 = 0;
 const IER: u8 = 1;
@@ -164,6 +166,32 @@ impl Serial {
         self.interrupt_identification = DEFAULT_INTERRUPT_IDENTIFICATION;
     }
 
+    fn state(&self) -> SerialState {
+        SerialState {
+            interrupt_enable: self.interrupt_enable,
+            interrupt_identification: self.interrupt_identification,
+            line_control: self.line_control,
+            line_status: self.line_status,
+            modem_control: self.modem_control,
+            modem_status: self.modem_status,
+            scratch: self.scratch,
+            baud_divisor: self.baud_divisor,
+            in_buffer: self.in_buffer.iter().cloned().collect(),
+        }
+    }
+
+    fn set_state(&mut self, state: &SerialState) {
+        self.interrupt_enable = state.interrupt_enable;
+        self.interrupt_identification = state.interrupt_identification;
+        self.line_control = state.line_control;
+        self.line_status = state.line_status;
+        self.modem_control = state.modem_control;
+        self.modem_status = state.modem_status;
+        self.scratch = state.scratch;
+        self.baud_divisor = state.baud_divisor;
+        self.in_buffer = state.in_buffer.iter().cloned().collect();
+    }
+
     fn handle_write(&mut self, offset: u8, v: u8) -> Result<()> {
         match offset as u8 {
             DLAB_LOW if self.is_dlab_set() => {
@@ -196,6 +224,23 @@ impl Serial {
     }
 }
 
+#[derive(Deserialize, Serialize)]
+pub struct SerialState {
+    interrupt_enable: u8,
+    interrupt_identification: u8,
+    line_control: u8,
+    line_status: u8,
+    modem_control: u8,
+    modem_status: u8,
+    scratch: u8,
+    baud_divisor: u16,
+    in_buffer: Vec<u8>,
+}
+
+impl VersionedState for SerialState {
+    const VERSION: u16 = 1;
+}
+
 impl BusDevice for Serial {
     fn read(&mut self, _base: u64, offset: u64, data: &mut [u8]) {
         if data.len() != 1 {
@@ -236,6 +281,33 @@ impl BusDevice for Serial {
     }
 }
 
+impl Pausable for Serial {
+    fn pause(&mut self) -> result::Result<(), MigratableError> {
+        Ok(())
+    }
+
+    fn resume(&mut self) -> result::Result<(), MigratableError> {
+        Ok(())
+    }
+}
+
+impl Snapshotable for Serial {
+    fn id(&self) -> String {
+        SERIAL_SNAPSHOT_ID.to_string()
+    }
+
+    fn snapshot(&self) -> result::Result<Snapshot, MigratableError> {
+        Snapshot::new_from_state(&self.id(), &self.state())
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> result::Result<(), MigratableError> {
+        self.set_state(&snapshot.to_state(&self.id())?);
+        Ok(())
+    }
+}
+
+impl Migratable for Serial {}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -426,4 +498,37 @@ mod tests {
         serial.read(0, SCR as u64, &mut data[..]);
         assert_eq!(data[0], 0x12 as u8);
     }
+
+    #[test]
+    fn serial_snapshot() {
+        let intr_evt = EventFd::new(0).unwrap();
+        let mut serial = Serial::new_sink(Arc::new(Box::new(TestInterrupt::new(
+            intr_evt.try_clone().unwrap(),
+        ))));
+
+        serial.write(0, LCR as u64, &[LCR_DLAB_BIT as u8]);
+        serial.write(0, DLAB_LOW as u64, &[0x12 as u8]);
+        serial.write(0, LCR as u64, &[DEFAULT_LINE_CONTROL]);
+        serial.write(0, SCR as u64, &[0x34 as u8]);
+        serial.queue_input_bytes(&['a' as u8, 'b' as u8]).unwrap();
+        let snapshot = serial.snapshot().unwrap();
+
+        let mut restored = Serial::new_sink(Arc::new(Box::new(TestInterrupt::new(
+            intr_evt.try_clone().unwrap(),
+        ))));
+        restored.restore(snapshot).unwrap();
+
+        let mut data = [0u8];
+        restored.read(0, SCR as u64, &mut data[..]);
+        assert_eq!(data[0], 0x34 as u8);
+        restored.read(0, LSR as u64, &mut data[..]);
+        assert_ne!(data[0] & LSR_DATA_BIT, 0);
+        restored.read(0, DATA as u64, &mut data[..]);
+        assert_eq!(data[0], 'a' as u8);
+        restored.read(0, DATA as u64, &mut data[..]);
+        assert_eq!(data[0], 'b' as u8);
+        restored.write(0, LCR as u64, &[LCR_DLAB_BIT as u8]);
+        restored.read(0, DLAB_LOW as u64, &mut data[..]);
+        assert_eq!(data[0], 0x12);
+    }
 }
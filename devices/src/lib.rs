@@ -6,15 +6,21 @@
 // found in the LICENSE-BSD-3-Clause file.
 
 //! Emulates virtual and hardware devices.
+extern crate anyhow;
 #[macro_use]
 extern crate bitflags;
 extern crate byteorder;
 extern crate epoll;
+#[macro_use]
+extern crate event_monitor;
 extern crate libc;
 #[macro_use]
 extern crate log;
 #[cfg(feature = "acpi")]
 extern crate acpi_tables;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
 extern crate vm_device;
 extern crate vm_memory;
 extern crate vmm_sys_util;
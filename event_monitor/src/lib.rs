@@ -0,0 +1,101 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Reports the VMM and guest events, such as VM state changes or device
+//! hotplug, as a stream of JSON objects, one per line, written to the
+//! monitor file.
+
+#[macro_use]
+extern crate lazy_static;
+#[macro_use]
+extern crate serde_derive;
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+lazy_static! {
+    static ref MONITOR: Mutex<Option<(File, Instant)>> = Mutex::new(None);
+}
+
+#[derive(Serialize)]
+struct Event<'a> {
+    timestamp: Duration,
+    source: &'a str,
+    event: &'a str,
+    properties: Option<&'a HashMap<Cow<'a, str>, Cow<'a, str>>>,
+}
+
+/// Set the file events are written to. Event timestamps are relative to
+/// the time the monitor is set.
+pub fn set_monitor(file: File) {
+    *MONITOR.lock().unwrap() = Some((file, Instant::now()));
+}
+
+/// Report an event. This is a no-op if no monitor has been set.
+pub fn event_log(source: &str, event: &str, properties: Option<&HashMap<Cow<str>, Cow<str>>>) {
+    if let Some((file, start)) = MONITOR.lock().unwrap().as_mut() {
+        let e = Event {
+            timestamp: start.elapsed(),
+            source,
+            event,
+            properties,
+        };
+
+        let mut line = serde_json::to_vec(&e).unwrap();
+        line.push(b'\n');
+        // A failure to report an event must not affect the VMM.
+        file.write_all(&line).ok();
+    }
+}
+
+/// Report an event, with an optional list of key/value properties:
+///
+/// event!("vm", "device-added", "id", device_id);
+#[macro_export]
+macro_rules! event {
+    ($source:expr, $event:expr) => {
+        $crate::event_log($source, $event, None)
+    };
+    ($source:expr, $event:expr, $($key:expr, $value:expr),+) => {{
+        let mut properties = ::std::collections::HashMap::new();
+        $(
+            properties.insert($key.into(), $value.into());
+        )+
+        $crate::event_log($source, $event, Some(&properties))
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+
+    #[test]
+    fn test_event_log() {
+        let path = std::env::temp_dir().join(format!("event-monitor-{}", std::process::id()));
+        set_monitor(File::create(&path).unwrap());
+
+        event!("vm", "booted");
+        event!("vm", "device-added", "id", "disk0");
+        *MONITOR.lock().unwrap() = None;
+
+        let events: Vec<serde_json::Value> = BufReader::new(File::open(&path).unwrap())
+            .lines()
+            .map(|l| serde_json::from_str(&l.unwrap()).unwrap())
+            .collect();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["source"], "vm");
+        assert_eq!(events[0]["event"], "booted");
+        assert!(events[0]["properties"].is_null());
+        assert_eq!(events[1]["event"], "device-added");
+        assert_eq!(events[1]["properties"]["id"], "disk0");
+    }
+}
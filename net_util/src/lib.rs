@@ -11,6 +11,8 @@
 #[macro_use]
 extern crate lazy_static;
 extern crate libc;
+#[macro_use]
+extern crate log;
 extern crate rand;
 extern crate serde;
 
@@ -19,6 +21,7 @@ extern crate vmm_sys_util;
 
 mod mac;
 mod tap;
+mod user;
 
 use std::io::Error as IoError;
 use std::mem;
@@ -27,6 +30,9 @@ use std::os::unix::io::FromRawFd;
 
 pub use mac::{MacAddr, MAC_ADDR_LEN};
 pub use tap::{Error as TapError, Tap};
+pub use user::{
+    start_user_net, Error as UserNetError, PortForward, PortForwardProtocol, UserNetConfig,
+};
 
 #[derive(Debug)]
 pub enum Error {
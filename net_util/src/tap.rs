@@ -152,6 +152,15 @@ impl Tap {
         Self::open_named("vmtap%d", num_queue_pairs)
     }
 
+    /// Wraps the socket a userspace network stack exchanges frames through,
+    /// so that it can stand in for a tap interface without any name.
+    pub(crate) fn from_socket(socket: File) -> Tap {
+        Tap {
+            tap_file: socket,
+            if_name: Vec::new(),
+        }
+    }
+
     /// Set the host-side IP address for the tap interface.
     pub fn set_ip_addr(&self, ip_addr: net::Ipv4Addr) -> Result<()> {
         let sock = create_socket().map_err(Error::NetUtil)?;
@@ -265,6 +274,44 @@ impl Tap {
     pub fn get_if_name(&self) -> Vec<u8> {
         self.if_name.clone()
     }
+
+    /// Reads a single frame, scattering it across the buffers described by
+    /// `iovecs`.
+    ///
+    /// # Safety
+    ///
+    /// Every iovec must point to memory valid for writes of its length.
+    pub unsafe fn readv(&self, iovecs: &[libc::iovec]) -> IoResult<usize> {
+        let ret = libc::readv(
+            self.tap_file.as_raw_fd(),
+            iovecs.as_ptr(),
+            iovecs.len() as c_int,
+        );
+        if ret < 0 {
+            return Err(IoError::last_os_error());
+        }
+
+        Ok(ret as usize)
+    }
+
+    /// Writes a single frame, gathered from the buffers described by
+    /// `iovecs`.
+    ///
+    /// # Safety
+    ///
+    /// Every iovec must point to memory valid for reads of its length.
+    pub unsafe fn writev(&self, iovecs: &[libc::iovec]) -> IoResult<usize> {
+        let ret = libc::writev(
+            self.tap_file.as_raw_fd(),
+            iovecs.as_ptr(),
+            iovecs.len() as c_int,
+        );
+        if ret < 0 {
+            return Err(IoError::last_os_error());
+        }
+
+        Ok(ret as usize)
+    }
 }
 
 impl Read for Tap {
@@ -289,6 +336,14 @@ impl AsRawFd for Tap {
     }
 }
 
+/// Any file exchanging frames preceded by a virtio-net header, such as one
+/// end of a socket pair, can stand for a tap interface.
+impl FromRawFd for Tap {
+    unsafe fn from_raw_fd(fd: RawFd) -> Tap {
+        Tap::from_socket(File::from_raw_fd(fd))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     extern crate pnet;
@@ -0,0 +1,244 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Minimal DHCP server, always leasing the same address to the guest.
+
+use crate::MacAddr;
+use std::net::Ipv4Addr;
+
+pub const DHCP_SERVER_PORT: u16 = 67;
+pub const DHCP_CLIENT_PORT: u16 = 68;
+
+const BOOTREQUEST: u8 = 1;
+const BOOTREPLY: u8 = 2;
+const BOOTP_HDR_LEN: usize = 236;
+const BOOTP_FLAG_BROADCAST: u16 = 0x8000;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+
+const OPT_PAD: u8 = 0;
+const OPT_SUBNET_MASK: u8 = 1;
+const OPT_ROUTER: u8 = 3;
+const OPT_DNS_SERVER: u8 = 6;
+const OPT_REQUESTED_IP: u8 = 50;
+const OPT_LEASE_TIME: u8 = 51;
+const OPT_MESSAGE_TYPE: u8 = 53;
+const OPT_SERVER_ID: u8 = 54;
+const OPT_END: u8 = 255;
+
+const DHCPDISCOVER: u8 = 1;
+const DHCPOFFER: u8 = 2;
+const DHCPREQUEST: u8 = 3;
+const DHCPACK: u8 = 5;
+const DHCPNAK: u8 = 6;
+
+// The lease never changes, so a long lease time only saves renewals.
+const LEASE_TIME_SECS: u32 = 86400;
+
+/// The network configuration handed out to the guest.
+pub struct DhcpLease {
+    /// Address of the DHCP server, which is also the router.
+    pub server: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    /// Address leased to the guest.
+    pub client: Ipv4Addr,
+    pub dns: Ipv4Addr,
+}
+
+/// A reply to a DHCP request.
+#[derive(Debug, PartialEq)]
+pub struct DhcpReply {
+    /// Hardware address of the client the reply is for.
+    pub client_mac: MacAddr,
+    /// Destination address of the reply, the client may not have an address
+    /// yet, in which case the reply is broadcast.
+    pub dst: Ipv4Addr,
+    pub payload: Vec<u8>,
+}
+
+fn parse_options(mut options: &[u8]) -> (Option<u8>, Option<Ipv4Addr>) {
+    let mut message_type = None;
+    let mut requested_ip = None;
+
+    while let Some(&code) = options.first() {
+        match code {
+            OPT_END => break,
+            OPT_PAD => options = &options[1..],
+            _ => {
+                if options.len() < 2 || options.len() < 2 + usize::from(options[1]) {
+                    break;
+                }
+                let (option, rest) = options.split_at(2 + usize::from(options[1]));
+                match (code, &option[2..]) {
+                    (OPT_MESSAGE_TYPE, [t]) => message_type = Some(*t),
+                    (OPT_REQUESTED_IP, [a, b, c, d]) => {
+                        requested_ip = Some(Ipv4Addr::new(*a, *b, *c, *d))
+                    }
+                    _ => {}
+                }
+                options = rest;
+            }
+        }
+    }
+
+    (message_type, requested_ip)
+}
+
+fn push_option(payload: &mut Vec<u8>, code: u8, data: &[u8]) {
+    payload.push(code);
+    payload.push(data.len() as u8);
+    payload.extend_from_slice(data);
+}
+
+impl DhcpLease {
+    /// Handles a DHCP message sent by the guest, returning the reply to
+    /// send back, if any.
+    pub fn handle_request(&self, request: &[u8]) -> Option<DhcpReply> {
+        if request.len() < BOOTP_HDR_LEN + MAGIC_COOKIE.len()
+            || request[0] != BOOTREQUEST
+            || request[1] != 1
+            || request[2] != 6
+            || request[BOOTP_HDR_LEN..BOOTP_HDR_LEN + 4] != MAGIC_COOKIE
+        {
+            return None;
+        }
+
+        let (message_type, requested_ip) = parse_options(&request[BOOTP_HDR_LEN + 4..]);
+        let ciaddr = Ipv4Addr::new(request[12], request[13], request[14], request[15]);
+        let reply_type = match message_type? {
+            DHCPDISCOVER => DHCPOFFER,
+            DHCPREQUEST => match requested_ip.unwrap_or(ciaddr) {
+                ip if ip == self.client => DHCPACK,
+                _ => DHCPNAK,
+            },
+            _ => return None,
+        };
+
+        let mut payload = vec![0u8; BOOTP_HDR_LEN];
+        payload[0] = BOOTREPLY;
+        // Hardware type, hardware address length, transaction ID and flags
+        // are copied from the request.
+        payload[1..4].copy_from_slice(&[request[1], request[2], 0]);
+        payload[4..8].copy_from_slice(&request[4..8]);
+        payload[10..12].copy_from_slice(&request[10..12]);
+        if reply_type != DHCPNAK {
+            payload[16..20].copy_from_slice(&self.client.octets());
+            payload[20..24].copy_from_slice(&self.server.octets());
+        }
+        payload[28..44].copy_from_slice(&request[28..44]);
+        payload.extend_from_slice(&MAGIC_COOKIE);
+
+        push_option(&mut payload, OPT_MESSAGE_TYPE, &[reply_type]);
+        push_option(&mut payload, OPT_SERVER_ID, &self.server.octets());
+        if reply_type != DHCPNAK {
+            push_option(&mut payload, OPT_LEASE_TIME, &LEASE_TIME_SECS.to_be_bytes());
+            push_option(&mut payload, OPT_SUBNET_MASK, &self.netmask.octets());
+            push_option(&mut payload, OPT_ROUTER, &self.server.octets());
+            push_option(&mut payload, OPT_DNS_SERVER, &self.dns.octets());
+        }
+        payload.push(OPT_END);
+
+        let flags = u16::from(request[10]) << 8 | u16::from(request[11]);
+        let dst = if reply_type == DHCPNAK || flags & BOOTP_FLAG_BROADCAST != 0 {
+            Ipv4Addr::BROADCAST
+        } else {
+            self.client
+        };
+
+        Some(DhcpReply {
+            client_mac: MacAddr::from_bytes_unchecked(&request[28..34]),
+            dst,
+            payload,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lease() -> DhcpLease {
+        DhcpLease {
+            server: Ipv4Addr::new(192, 168, 249, 1),
+            netmask: Ipv4Addr::new(255, 255, 255, 0),
+            client: Ipv4Addr::new(192, 168, 249, 2),
+            dns: Ipv4Addr::new(192, 168, 249, 1),
+        }
+    }
+
+    fn request(message_type: u8, requested_ip: Option<Ipv4Addr>, flags: u16) -> Vec<u8> {
+        let mut request = vec![0u8; BOOTP_HDR_LEN];
+        request[0] = BOOTREQUEST;
+        request[1] = 1;
+        request[2] = 6;
+        request[4..8].copy_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+        request[10..12].copy_from_slice(&flags.to_be_bytes());
+        request[28..34].copy_from_slice(&[0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc]);
+        request.extend_from_slice(&MAGIC_COOKIE);
+        push_option(&mut request, OPT_MESSAGE_TYPE, &[message_type]);
+        if let Some(ip) = requested_ip {
+            push_option(&mut request, OPT_REQUESTED_IP, &ip.octets());
+        }
+        request.push(OPT_END);
+        request
+    }
+
+    #[test]
+    fn test_dhcp_discover() {
+        let lease = lease();
+        let reply = lease
+            .handle_request(&request(DHCPDISCOVER, None, BOOTP_FLAG_BROADCAST))
+            .unwrap();
+
+        assert_eq!(
+            reply.client_mac,
+            MacAddr::parse_str("12:34:56:78:9a:bc").unwrap()
+        );
+        assert_eq!(reply.dst, Ipv4Addr::BROADCAST);
+        assert_eq!(reply.payload[0], BOOTREPLY);
+        assert_eq!(&reply.payload[4..8], &[0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(&reply.payload[16..20], &lease.client.octets());
+
+        let (message_type, _) = parse_options(&reply.payload[BOOTP_HDR_LEN + 4..]);
+        assert_eq!(message_type, Some(DHCPOFFER));
+        let options = &reply.payload[BOOTP_HDR_LEN + 4..];
+        assert!(options
+            .windows(6)
+            .any(|o| o == [OPT_ROUTER, 4, 192, 168, 249, 1]));
+        assert!(options
+            .windows(6)
+            .any(|o| o == [OPT_SUBNET_MASK, 4, 255, 255, 255, 0]));
+    }
+
+    #[test]
+    fn test_dhcp_request() {
+        let lease = lease();
+
+        let reply = lease
+            .handle_request(&request(DHCPREQUEST, Some(lease.client), 0))
+            .unwrap();
+        assert_eq!(reply.dst, lease.client);
+        let (message_type, _) = parse_options(&reply.payload[BOOTP_HDR_LEN + 4..]);
+        assert_eq!(message_type, Some(DHCPACK));
+
+        // Any other address is refused.
+        let reply = lease
+            .handle_request(&request(
+                DHCPREQUEST,
+                Some(Ipv4Addr::new(192, 168, 249, 3)),
+                0,
+            ))
+            .unwrap();
+        assert_eq!(reply.dst, Ipv4Addr::BROADCAST);
+        assert_eq!(&reply.payload[16..20], &[0, 0, 0, 0]);
+        let (message_type, _) = parse_options(&reply.payload[BOOTP_HDR_LEN + 4..]);
+        assert_eq!(message_type, Some(DHCPNAK));
+
+        // Releases are ignored, and so are malformed messages.
+        assert!(lease.handle_request(&request(7, None, 0)).is_none());
+        assert!(lease
+            .handle_request(&request(DHCPDISCOVER, None, 0)[..BOOTP_HDR_LEN])
+            .is_none());
+    }
+}
@@ -0,0 +1,927 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Userspace network stack, giving a guest access to the network through
+//! the sockets of the VMM process, without any tap interface nor privilege.
+//!
+//! The guest is leased an address on a private network by a built-in DHCP
+//! server. Its TCP connections and UDP flows are relayed through host
+//! sockets, and its DNS queries to the gateway are forwarded to the first
+//! nameserver of the host, and host ports can be forwarded to the guest.
+//! Only when explicitly enabled does the gateway also stand for the loopback
+//! interface of the host, as it exposes services which are usually meant to
+//! be local to the host.
+
+mod dhcp;
+mod packet;
+mod tcp;
+
+use self::dhcp::{DhcpLease, DHCP_CLIENT_PORT, DHCP_SERVER_PORT};
+use self::packet::{
+    parse_ethernet, udp_datagram, ArpRequest, IcmpEcho, Ipv4Packet, TcpSegment, UdpDatagram,
+    ETHERTYPE_ARP, ETHERTYPE_IPV4, IPPROTO_ICMP, IPPROTO_TCP, IPPROTO_UDP, TCP_ACK, TCP_RST,
+    TCP_SYN,
+};
+use self::tcp::TcpConn;
+use crate::{MacAddr, Tap};
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, UdpSocket};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Size of the virtio_net_hdr_v1 preceding each frame exchanged with the
+// device. No offload is offered to the guest, so it is always empty.
+const VNET_HDR_LEN: usize = 12;
+
+const GATEWAY_MAC: [u8; 6] = [0x52, 0x55, 0x0a, 0x00, 0x02, 0x02];
+const DNS_PORT: u16 = 53;
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+
+// Ports the gateway connects from, as seen by the guest, when forwarding
+// host ports.
+const FIRST_FORWARD_PORT: u16 = 49152;
+const UDP_FLOW_TIMEOUT: Duration = Duration::from_secs(60);
+// Stop reading from the guest and the host sockets when that many frames are
+// waiting for the guest to make room for them.
+const MAX_PENDING_FRAMES: usize = 1024;
+// Maximum number of frames handled at once, before looking at host sockets.
+const MAX_GUEST_FRAMES: usize = 64;
+const MAX_FRAME_SIZE: usize = 65536;
+
+/// Errors for the userspace network stack.
+#[derive(Debug)]
+pub enum Error {
+    /// The network is too small to hold both the gateway and the guest.
+    InvalidNetwork,
+    /// Failed to create the socket pair connecting the device to the stack.
+    CreateSocketPair(io::Error),
+    /// Failed to bind the host socket of a port forwarding rule.
+    BindPortForward(io::Error),
+    /// Failed to spawn the thread running the stack.
+    SpawnThread(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PortForwardProtocol {
+    Tcp,
+    Udp,
+}
+
+/// Forwarding of a host port to a port of the guest.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PortForward {
+    pub protocol: PortForwardProtocol,
+    pub host_addr: SocketAddrV4,
+    pub guest_port: u16,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct UserNetConfig {
+    /// Address of the gateway, DHCP server and DNS forwarder of the guest.
+    pub gateway: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub guest_mac: MacAddr,
+    pub port_forwards: Vec<PortForward>,
+    /// Whether the guest reaches the loopback interface of the host through
+    /// the ports of the gateway other than DNS.
+    pub host_loopback: bool,
+}
+
+/// Ethernet addresses of both ends of the link with the guest.
+pub struct Link {
+    pub gateway_mac: MacAddr,
+    pub guest_mac: MacAddr,
+}
+
+impl Link {
+    /// Builds a frame sent by the gateway to the guest, carrying an IPv4
+    /// packet.
+    pub fn ipv4_frame(
+        &self,
+        src: Ipv4Addr,
+        dst: Ipv4Addr,
+        protocol: u8,
+        payload: &[u8],
+    ) -> Vec<u8> {
+        packet::ipv4_frame(
+            self.guest_mac,
+            self.gateway_mac,
+            src,
+            dst,
+            protocol,
+            payload,
+        )
+    }
+}
+
+/// Returns the first address of the network, other than the gateway, which
+/// is leased to the guest.
+fn guest_address(gateway: Ipv4Addr, netmask: Ipv4Addr) -> Option<Ipv4Addr> {
+    let gateway = u32::from(gateway);
+    let netmask = u32::from(netmask);
+    let network = gateway & netmask;
+    let broadcast = network | !netmask;
+
+    (network.saturating_add(1)..broadcast)
+        .find(|addr| *addr != gateway)
+        .map(Ipv4Addr::from)
+}
+
+fn parse_nameserver(resolv_conf: &str) -> Option<Ipv4Addr> {
+    resolv_conf
+        .lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next()) {
+                (Some("nameserver"), Some(addr)) => addr.parse().ok(),
+                _ => None,
+            }
+        })
+        .next()
+}
+
+fn socket_pair() -> io::Result<(File, File)> {
+    let mut fds = [0; 2];
+    // Safe because we check the return value, and fds has room for both ends.
+    let ret = unsafe {
+        libc::socketpair(
+            libc::AF_UNIX,
+            libc::SOCK_SEQPACKET | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            0,
+            fds.as_mut_ptr(),
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Safe because nothing else holds onto the fds we just created.
+    Ok(unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) })
+}
+
+fn pollfd(fd: RawFd, events: libc::c_short) -> libc::pollfd {
+    libc::pollfd {
+        // Negative fds are ignored, which is the only way to not be woken up
+        // by hang ups on a socket we do not want to hear about yet.
+        fd: if events != 0 { fd } else { -1 },
+        events,
+        revents: 0,
+    }
+}
+
+enum UdpTarget {
+    /// Flow opened by the guest, relayed through a socket of its own.
+    Socket(UdpSocket),
+    /// Flow opened by a host peer through a forwarded port, relayed through
+    /// the socket of the port forwarding rule.
+    Forward { index: usize, peer: SocketAddr },
+}
+
+struct UdpFlow {
+    target: UdpTarget,
+    last_used: Instant,
+}
+
+/// Identifies a flow by the guest port, and the remote address as seen by
+/// the guest.
+type FlowKey = (u16, SocketAddrV4);
+
+struct Stack {
+    socket: File,
+    link: Link,
+    gateway: Ipv4Addr,
+    netmask: Ipv4Addr,
+    guest_ip: Ipv4Addr,
+    nameserver: Ipv4Addr,
+    host_loopback: bool,
+    dhcp: DhcpLease,
+    /// Frames waiting for room in the socket to the device.
+    pending: VecDeque<Vec<u8>>,
+    tcp_conns: HashMap<FlowKey, TcpConn>,
+    udp_flows: HashMap<FlowKey, UdpFlow>,
+    tcp_forwards: Vec<(TcpListener, u16)>,
+    udp_forwards: Vec<(UdpSocket, u16)>,
+    next_forward_port: u16,
+}
+
+impl Stack {
+    /// Returns the host address the guest reaches through `addr`, if any.
+    fn translate(&self, addr: SocketAddrV4) -> Option<SocketAddrV4> {
+        let ip = *addr.ip();
+        if ip == self.gateway {
+            if addr.port() == DNS_PORT {
+                Some(SocketAddrV4::new(self.nameserver, DNS_PORT))
+            } else if self.host_loopback {
+                Some(SocketAddrV4::new(Ipv4Addr::LOCALHOST, addr.port()))
+            } else {
+                None
+            }
+        } else if ip.is_unspecified()
+            || ip.is_loopback()
+            || ip.is_broadcast()
+            || ip.is_multicast()
+            || u32::from(ip) & u32::from(self.netmask)
+                == u32::from(self.gateway) & u32::from(self.netmask)
+        {
+            None
+        } else {
+            Some(addr)
+        }
+    }
+
+    /// Picks the port the gateway uses to reach `guest_port` on behalf of a
+    /// forwarded host peer.
+    fn forward_remote(&mut self, guest_port: u16) -> Option<SocketAddrV4> {
+        for _ in FIRST_FORWARD_PORT..=0xffff {
+            let port = self.next_forward_port;
+            self.next_forward_port = port.checked_add(1).unwrap_or(FIRST_FORWARD_PORT);
+
+            let remote = SocketAddrV4::new(self.gateway, port);
+            let key = (guest_port, remote);
+            if !self.tcp_conns.contains_key(&key) && !self.udp_flows.contains_key(&key) {
+                return Some(remote);
+            }
+        }
+
+        None
+    }
+
+    fn flush_pending(&mut self) {
+        while let Some(frame) = self.pending.front() {
+            match (&self.socket).write(frame) {
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => warn!("Failed to send a frame to the guest: {}", e),
+            }
+            self.pending.pop_front();
+        }
+    }
+
+    fn read_guest(&mut self, buf: &mut [u8], out: &mut Vec<Vec<u8>>) {
+        for _ in 0..MAX_GUEST_FRAMES {
+            match (&self.socket).read(buf) {
+                Ok(len) => self.handle_frame(&buf[..len], out),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    warn!("Failed to receive a frame from the guest: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+
+    fn handle_frame(&mut self, frame: &[u8], out: &mut Vec<Vec<u8>>) {
+        if frame.len() < VNET_HDR_LEN {
+            return;
+        }
+        let (_, src_mac, ethertype, payload) = match parse_ethernet(&frame[VNET_HDR_LEN..]) {
+            Some(eth) => eth,
+            None => return,
+        };
+
+        // Follow the changes of the guest MAC address.
+        if src_mac.get_bytes()[0] & 1 == 0 {
+            self.link.guest_mac = src_mac;
+        }
+
+        match ethertype {
+            ETHERTYPE_ARP => {
+                if let Some(request) = ArpRequest::parse(payload) {
+                    if request.target_ip == self.gateway {
+                        out.push(request.reply(self.link.gateway_mac));
+                    }
+                }
+            }
+            ETHERTYPE_IPV4 => {
+                if let Some(packet) = Ipv4Packet::parse(payload) {
+                    self.handle_ipv4(&packet, out);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn handle_ipv4(&mut self, packet: &Ipv4Packet, out: &mut Vec<Vec<u8>>) {
+        match packet.protocol {
+            IPPROTO_UDP => {
+                if let Some(datagram) = UdpDatagram::parse(packet.payload) {
+                    self.handle_udp(packet, &datagram, out);
+                }
+            }
+            IPPROTO_TCP if packet.src == self.guest_ip => {
+                if let Some(seg) = TcpSegment::parse(packet.payload) {
+                    self.handle_tcp(packet, &seg, out);
+                }
+            }
+            IPPROTO_ICMP if packet.src == self.guest_ip && packet.dst == self.gateway => {
+                if let Some(echo) = IcmpEcho::parse(packet.payload) {
+                    out.push(self.link.ipv4_frame(
+                        self.gateway,
+                        self.guest_ip,
+                        IPPROTO_ICMP,
+                        &echo.reply(),
+                    ));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn handle_udp(&mut self, packet: &Ipv4Packet, datagram: &UdpDatagram, out: &mut Vec<Vec<u8>>) {
+        if datagram.dst_port == DHCP_SERVER_PORT {
+            if let Some(reply) = self.dhcp.handle_request(datagram.payload) {
+                let src = SocketAddrV4::new(self.gateway, DHCP_SERVER_PORT);
+                let dst = SocketAddrV4::new(reply.dst, DHCP_CLIENT_PORT);
+                out.push(packet::ipv4_frame(
+                    reply.client_mac,
+                    self.link.gateway_mac,
+                    self.gateway,
+                    reply.dst,
+                    IPPROTO_UDP,
+                    &udp_datagram(src, dst, &reply.payload),
+                ));
+            }
+            return;
+        }
+        if packet.src != self.guest_ip {
+            return;
+        }
+
+        let remote = SocketAddrV4::new(packet.dst, datagram.dst_port);
+        let key = (datagram.src_port, remote);
+        if !self.udp_flows.contains_key(&key) {
+            let target = match self.translate(remote) {
+                Some(target) => target,
+                None => return,
+            };
+            let socket = match UdpSocket::bind("0.0.0.0:0").and_then(|socket| {
+                socket.connect(target)?;
+                socket.set_nonblocking(true)?;
+                Ok(socket)
+            }) {
+                Ok(socket) => socket,
+                Err(e) => {
+                    debug!("Failed to open a UDP socket to {}: {}", target, e);
+                    return;
+                }
+            };
+            self.udp_flows.insert(
+                key,
+                UdpFlow {
+                    target: UdpTarget::Socket(socket),
+                    last_used: Instant::now(),
+                },
+            );
+        }
+
+        if let Some(flow) = self.udp_flows.get_mut(&key) {
+            flow.last_used = Instant::now();
+            let res = match flow.target {
+                UdpTarget::Socket(ref socket) => socket.send(datagram.payload),
+                UdpTarget::Forward { index, peer } => {
+                    self.udp_forwards[index].0.send_to(datagram.payload, peer)
+                }
+            };
+            if let Err(e) = res {
+                debug!("Failed to send a UDP datagram to {}: {}", remote, e);
+            }
+        }
+    }
+
+    fn handle_tcp(&mut self, packet: &Ipv4Packet, seg: &TcpSegment, out: &mut Vec<Vec<u8>>) {
+        let guest = SocketAddrV4::new(packet.src, seg.src_port);
+        let remote = SocketAddrV4::new(packet.dst, seg.dst_port);
+        let key = (seg.src_port, remote);
+
+        if let Some(conn) = self.tcp_conns.get_mut(&key) {
+            conn.on_segment(seg, &self.link, out);
+            return;
+        }
+        if seg.flags & TCP_RST != 0 {
+            return;
+        }
+        if seg.flags & (TCP_SYN | TCP_ACK) == TCP_SYN {
+            if let Some(target) = self.translate(remote) {
+                match TcpConn::connect(guest, remote, target, seg) {
+                    Ok(conn) => {
+                        self.tcp_conns.insert(key, conn);
+                        return;
+                    }
+                    Err(e) => debug!("Failed to connect to {}: {}", target, e),
+                }
+            }
+        }
+
+        out.push(tcp::reset_frame(&self.link, guest, remote, seg));
+    }
+
+    fn send_udp_to_guest(&self, key: FlowKey, payload: &[u8], out: &mut Vec<Vec<u8>>) {
+        let (guest_port, remote) = key;
+        let guest = SocketAddrV4::new(self.guest_ip, guest_port);
+
+        out.extend(packet::ipv4_fragments(
+            self.link.guest_mac,
+            self.link.gateway_mac,
+            *remote.ip(),
+            self.guest_ip,
+            IPPROTO_UDP,
+            &udp_datagram(remote, guest, payload),
+        ));
+    }
+
+    fn recv_udp_flow(&mut self, key: FlowKey, buf: &mut [u8], out: &mut Vec<Vec<u8>>) {
+        loop {
+            let res = match self.udp_flows.get_mut(&key) {
+                Some(UdpFlow {
+                    target: UdpTarget::Socket(ref socket),
+                    ref mut last_used,
+                }) => {
+                    *last_used = Instant::now();
+                    socket.recv(buf)
+                }
+                _ => return,
+            };
+            match res {
+                Ok(len) => self.send_udp_to_guest(key, &buf[..len], out),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                // Errors such as unreachable ports are reported on the next
+                // receive, and are not worth keeping the flow for.
+                Err(_) => {
+                    self.udp_flows.remove(&key);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn recv_udp_forward(&mut self, index: usize, buf: &mut [u8], out: &mut Vec<Vec<u8>>) {
+        loop {
+            let (len, peer) = match self.udp_forwards[index].0.recv_from(buf) {
+                Ok(res) => res,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    debug!("Failed to receive a forwarded UDP datagram: {}", e);
+                    return;
+                }
+            };
+
+            let existing = self
+                .udp_flows
+                .iter()
+                .find_map(|(key, flow)| match flow.target {
+                    UdpTarget::Forward { index: i, peer: p } if i == index && p == peer => {
+                        Some(*key)
+                    }
+                    _ => None,
+                });
+            let key = match existing {
+                Some(key) => key,
+                None => {
+                    let guest_port = self.udp_forwards[index].1;
+                    let remote = match self.forward_remote(guest_port) {
+                        Some(remote) => remote,
+                        None => continue,
+                    };
+                    let key = (guest_port, remote);
+                    self.udp_flows.insert(
+                        key,
+                        UdpFlow {
+                            target: UdpTarget::Forward { index, peer },
+                            last_used: Instant::now(),
+                        },
+                    );
+                    key
+                }
+            };
+
+            if let Some(flow) = self.udp_flows.get_mut(&key) {
+                flow.last_used = Instant::now();
+            }
+            self.send_udp_to_guest(key, &buf[..len], out);
+        }
+    }
+
+    fn accept_tcp_forward(&mut self, index: usize, out: &mut Vec<Vec<u8>>) {
+        loop {
+            let stream = match self.tcp_forwards[index].0.accept() {
+                Ok((stream, _)) => stream,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    warn!("Failed to accept a forwarded TCP connection: {}", e);
+                    return;
+                }
+            };
+
+            let guest = SocketAddrV4::new(self.guest_ip, self.tcp_forwards[index].1);
+            let remote = match self.forward_remote(guest.port()) {
+                Some(remote) => remote,
+                None => continue,
+            };
+            match TcpConn::accept(stream, guest, remote, &self.link, out) {
+                Ok(conn) => {
+                    self.tcp_conns.insert((guest.port(), remote), conn);
+                }
+                Err(e) => debug!("Failed to forward a TCP connection: {}", e),
+            }
+        }
+    }
+
+    fn run(&mut self) {
+        let mut buf = vec![0u8; MAX_FRAME_SIZE];
+        let mut out = Vec::new();
+
+        loop {
+            // Host sockets are only read when the guest keeps up with the
+            // frames sent its way.
+            let input = if self.pending.len() < MAX_PENDING_FRAMES {
+                libc::POLLIN
+            } else {
+                0
+            };
+            let output = if self.pending.is_empty() {
+                0
+            } else {
+                libc::POLLOUT
+            };
+
+            let mut pollfds = vec![pollfd(self.socket.as_raw_fd(), input | output)];
+            for (listener, _) in self.tcp_forwards.iter() {
+                pollfds.push(pollfd(listener.as_raw_fd(), input));
+            }
+            for (socket, _) in self.udp_forwards.iter() {
+                pollfds.push(pollfd(socket.as_raw_fd(), input));
+            }
+            let mut udp_keys = Vec::new();
+            for (key, flow) in self.udp_flows.iter() {
+                if let UdpTarget::Socket(ref socket) = flow.target {
+                    udp_keys.push(*key);
+                    pollfds.push(pollfd(socket.as_raw_fd(), input));
+                }
+            }
+            let mut tcp_keys = Vec::new();
+            for (key, conn) in self.tcp_conns.iter() {
+                let mut events = 0;
+                if conn.wants_read() {
+                    events |= input;
+                }
+                if conn.wants_write() {
+                    events |= libc::POLLOUT;
+                }
+                tcp_keys.push(*key);
+                pollfds.push(pollfd(conn.as_raw_fd(), events));
+            }
+
+            let now = Instant::now();
+            let mut timeout = self
+                .tcp_conns
+                .values()
+                .filter_map(|conn| conn.retransmit_deadline())
+                .min()
+                .map(|deadline| deadline.saturating_duration_since(now));
+            if !self.udp_flows.is_empty() {
+                let expiry = Duration::from_secs(1);
+                timeout = Some(timeout.map_or(expiry, |t| t.min(expiry)));
+            }
+            let timeout_ms = timeout.map_or(-1, |t| t.as_millis() as libc::c_int + 1);
+
+            // Safe because pollfds is a valid array of pollfd, and we pass
+            // its length along with it.
+            let ret = unsafe {
+                libc::poll(
+                    pollfds.as_mut_ptr(),
+                    pollfds.len() as libc::nfds_t,
+                    timeout_ms,
+                )
+            };
+            if ret < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                error!("Failed to poll the userspace network sockets: {}", e);
+                return;
+            }
+
+            let mut revents = pollfds.iter().map(|pollfd| pollfd.revents);
+            let guest_events = revents.next().unwrap_or(0);
+            if guest_events & libc::POLLHUP != 0 {
+                // The device is gone.
+                return;
+            }
+            if guest_events & libc::POLLOUT != 0 {
+                self.flush_pending();
+            }
+            if guest_events & libc::POLLIN != 0 {
+                self.read_guest(&mut buf, &mut out);
+            }
+
+            for index in 0..self.tcp_forwards.len() {
+                if revents.next().unwrap_or(0) != 0 {
+                    self.accept_tcp_forward(index, &mut out);
+                }
+            }
+            for index in 0..self.udp_forwards.len() {
+                if revents.next().unwrap_or(0) != 0 {
+                    self.recv_udp_forward(index, &mut buf, &mut out);
+                }
+            }
+            for key in udp_keys {
+                if revents.next().unwrap_or(0) != 0 {
+                    self.recv_udp_flow(key, &mut buf, &mut out);
+                }
+            }
+            for key in tcp_keys {
+                let events = revents.next().unwrap_or(0);
+                if let Some(conn) = self.tcp_conns.get_mut(&key) {
+                    if events & (libc::POLLOUT | libc::POLLERR | libc::POLLHUP) != 0 {
+                        conn.on_writable(&self.link, &mut out);
+                    }
+                    if events & (libc::POLLIN | libc::POLLERR | libc::POLLHUP) != 0 {
+                        conn.on_readable(&self.link, &mut out);
+                    }
+                }
+            }
+
+            let now = Instant::now();
+            for conn in self.tcp_conns.values_mut() {
+                conn.on_timer(now, &self.link, &mut out);
+            }
+            self.tcp_conns.retain(|_, conn| !conn.is_closed());
+            self.udp_flows
+                .retain(|_, flow| now.duration_since(flow.last_used) < UDP_FLOW_TIMEOUT);
+
+            self.pending.extend(out.drain(..));
+            self.flush_pending();
+        }
+    }
+}
+
+/// Starts the userspace network stack in a thread of its own, and returns
+/// the endpoint a virtio-net device exchanges frames with, each preceded by
+/// a virtio-net header as with a tap interface. The stack stops once every
+/// handle on that endpoint is closed.
+pub fn start_user_net(config: UserNetConfig) -> Result<Tap> {
+    let guest_ip = guest_address(config.gateway, config.netmask).ok_or(Error::InvalidNetwork)?;
+    let nameserver = fs::read_to_string(RESOLV_CONF)
+        .ok()
+        .and_then(|resolv_conf| parse_nameserver(&resolv_conf))
+        .unwrap_or(Ipv4Addr::LOCALHOST);
+
+    let mut tcp_forwards = Vec::new();
+    let mut udp_forwards = Vec::new();
+    for forward in config.port_forwards.iter() {
+        match forward.protocol {
+            PortForwardProtocol::Tcp => {
+                let listener =
+                    TcpListener::bind(forward.host_addr).map_err(Error::BindPortForward)?;
+                listener
+                    .set_nonblocking(true)
+                    .map_err(Error::BindPortForward)?;
+                tcp_forwards.push((listener, forward.guest_port));
+            }
+            PortForwardProtocol::Udp => {
+                let socket = UdpSocket::bind(forward.host_addr).map_err(Error::BindPortForward)?;
+                socket
+                    .set_nonblocking(true)
+                    .map_err(Error::BindPortForward)?;
+                udp_forwards.push((socket, forward.guest_port));
+            }
+        }
+    }
+
+    let (device_socket, socket) = socket_pair().map_err(Error::CreateSocketPair)?;
+    let mut stack = Stack {
+        socket,
+        link: Link {
+            gateway_mac: MacAddr::from_bytes_unchecked(&GATEWAY_MAC),
+            guest_mac: config.guest_mac,
+        },
+        gateway: config.gateway,
+        netmask: config.netmask,
+        guest_ip,
+        nameserver,
+        host_loopback: config.host_loopback,
+        dhcp: DhcpLease {
+            server: config.gateway,
+            netmask: config.netmask,
+            client: guest_ip,
+            dns: config.gateway,
+        },
+        pending: VecDeque::new(),
+        tcp_conns: HashMap::new(),
+        udp_flows: HashMap::new(),
+        tcp_forwards,
+        udp_forwards,
+        next_forward_port: FIRST_FORWARD_PORT,
+    };
+
+    thread::Builder::new()
+        .name("user_net".to_string())
+        .spawn(move || stack.run())
+        .map_err(Error::SpawnThread)?;
+
+    Ok(Tap::from_socket(device_socket))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::packet::{tcp_segment, TcpSegment};
+    use super::*;
+    use std::net::TcpStream;
+
+    fn config() -> UserNetConfig {
+        UserNetConfig {
+            gateway: Ipv4Addr::new(192, 168, 249, 1),
+            netmask: Ipv4Addr::new(255, 255, 255, 0),
+            guest_mac: MacAddr::parse_str("12:34:56:78:9a:bc").unwrap(),
+            port_forwards: Vec::new(),
+            host_loopback: false,
+        }
+    }
+
+    fn guest_ip() -> Ipv4Addr {
+        Ipv4Addr::new(192, 168, 249, 2)
+    }
+
+    fn local_port() -> u16 {
+        TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    /// Sends a frame carrying an IPv4 packet from the guest to the stack.
+    fn send(tap: &mut Tap, dst: Ipv4Addr, protocol: u8, payload: &[u8]) {
+        let frame = packet::ipv4_frame(
+            MacAddr::from_bytes_unchecked(&GATEWAY_MAC),
+            config().guest_mac,
+            guest_ip(),
+            dst,
+            protocol,
+            payload,
+        );
+        tap.write_all(&frame).unwrap();
+    }
+
+    /// Receives the next IPv4 packet the stack sends to the guest, returning
+    /// its source, protocol and payload.
+    fn recv(tap: &mut Tap) -> (Ipv4Addr, u8, Vec<u8>) {
+        let mut pollfd = pollfd(tap.as_raw_fd(), libc::POLLIN);
+        // Safe because we pass a single valid pollfd.
+        assert_eq!(unsafe { libc::poll(&mut pollfd, 1, 5000) }, 1);
+
+        let mut frame = vec![0u8; MAX_FRAME_SIZE];
+        let len = tap.read(&mut frame).unwrap();
+        let (dst_mac, _, ethertype, payload) = parse_ethernet(&frame[VNET_HDR_LEN..len]).unwrap();
+        assert_eq!(dst_mac, config().guest_mac);
+        assert_eq!(ethertype, ETHERTYPE_IPV4);
+        let packet = Ipv4Packet::parse(payload).unwrap();
+        assert_eq!(packet.dst, guest_ip());
+        (packet.src, packet.protocol, packet.payload.to_vec())
+    }
+
+    #[test]
+    fn test_guest_address() {
+        let mask = Ipv4Addr::new(255, 255, 255, 0);
+        assert_eq!(
+            guest_address(Ipv4Addr::new(10, 0, 2, 1), mask),
+            Some(Ipv4Addr::new(10, 0, 2, 2))
+        );
+        assert_eq!(
+            guest_address(Ipv4Addr::new(10, 0, 2, 2), mask),
+            Some(Ipv4Addr::new(10, 0, 2, 1))
+        );
+        assert_eq!(
+            guest_address(
+                Ipv4Addr::new(10, 0, 2, 2),
+                Ipv4Addr::new(255, 255, 255, 254)
+            ),
+            None
+        );
+        assert_eq!(
+            guest_address(Ipv4Addr::new(10, 0, 2, 2), Ipv4Addr::BROADCAST),
+            None
+        );
+    }
+
+    #[test]
+    fn test_parse_nameserver() {
+        let resolv_conf =
+            "# Generated\nsearch example.com\nnameserver 10.0.0.53\nnameserver 10.0.0.54\n";
+        assert_eq!(
+            parse_nameserver(resolv_conf),
+            Some(Ipv4Addr::new(10, 0, 0, 53))
+        );
+        assert_eq!(parse_nameserver("nameserver ::1\n"), None);
+        assert_eq!(parse_nameserver(""), None);
+    }
+
+    #[test]
+    fn test_user_net_udp() {
+        let mut config = config();
+        config.host_loopback = true;
+        let gateway = config.gateway;
+        let mut tap = start_user_net(config).unwrap();
+        let host = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let host_port = host.local_addr().unwrap().port();
+
+        // The gateway stands for the host loopback interface when enabled.
+        let guest = SocketAddrV4::new(guest_ip(), 5000);
+        let remote = SocketAddrV4::new(gateway, host_port);
+        send(
+            &mut tap,
+            gateway,
+            IPPROTO_UDP,
+            &udp_datagram(guest, remote, b"ping"),
+        );
+        let mut buf = [0u8; 16];
+        let (len, peer) = host.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"ping");
+
+        host.send_to(b"pong", peer).unwrap();
+        let (src, protocol, payload) = recv(&mut tap);
+        assert_eq!((src, protocol), (gateway, IPPROTO_UDP));
+        let datagram = UdpDatagram::parse(&payload).unwrap();
+        assert_eq!(datagram.src_port, host_port);
+        assert_eq!(datagram.dst_port, 5000);
+        assert_eq!(datagram.payload, b"pong");
+
+        // The gateway answers pings.
+        let mut echo = vec![packet::ICMP_ECHO_REQUEST, 0, 0, 0, 0, 1, 0, 2];
+        echo.extend_from_slice(b"data");
+        let csum = packet::checksum(&echo);
+        echo[2..4].copy_from_slice(&csum.to_be_bytes());
+        send(&mut tap, gateway, IPPROTO_ICMP, &echo);
+        let (src, protocol, payload) = recv(&mut tap);
+        assert_eq!((src, protocol), (gateway, IPPROTO_ICMP));
+        assert_eq!(payload[0], packet::ICMP_ECHO_REPLY);
+        assert_eq!(&payload[4..], &echo[4..]);
+    }
+
+    #[test]
+    fn test_user_net_no_host_loopback() {
+        let mut tap = start_user_net(config()).unwrap();
+        let host = TcpListener::bind("127.0.0.1:0").unwrap();
+        host.set_nonblocking(true).unwrap();
+        let host_port = host.local_addr().unwrap().port();
+        let gateway = config().gateway;
+
+        // By default, connections to the gateway do not reach the host
+        // loopback interface.
+        let guest = SocketAddrV4::new(guest_ip(), 5000);
+        let remote = SocketAddrV4::new(gateway, host_port);
+        let syn = tcp_segment(guest, remote, 1, 0, TCP_SYN, 1000, None, &[]);
+        send(&mut tap, gateway, IPPROTO_TCP, &syn);
+        let (src, protocol, payload) = recv(&mut tap);
+        assert_eq!((src, protocol), (gateway, IPPROTO_TCP));
+        let rst = TcpSegment::parse(&payload).unwrap();
+        assert_eq!(rst.src_port, host_port);
+        assert_eq!((rst.ack, rst.flags), (2, TCP_RST | TCP_ACK));
+        assert_eq!(host.accept().unwrap_err().kind(), io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn test_user_net_tcp_forward() {
+        let host_port = local_port();
+        let mut config = config();
+        config.port_forwards.push(PortForward {
+            protocol: PortForwardProtocol::Tcp,
+            host_addr: SocketAddrV4::new(Ipv4Addr::LOCALHOST, host_port),
+            guest_port: 22,
+        });
+        let mut tap = start_user_net(config).unwrap();
+
+        // A connection to the host port opens one to the guest.
+        let _host = TcpStream::connect(("127.0.0.1", host_port)).unwrap();
+        let (src, protocol, payload) = recv(&mut tap);
+        assert_eq!(
+            (src, protocol),
+            (Ipv4Addr::new(192, 168, 249, 1), IPPROTO_TCP)
+        );
+        let syn = TcpSegment::parse(&payload).unwrap();
+        assert_eq!(syn.dst_port, 22);
+        assert_eq!(syn.flags, TCP_SYN);
+
+        // Segments for unknown connections are reset.
+        let guest = SocketAddrV4::new(guest_ip(), 22);
+        let remote = SocketAddrV4::new(src, syn.src_port.wrapping_add(1));
+        let seg = tcp_segment(guest, remote, 1, 2, TCP_ACK, 1000, None, &[]);
+        send(&mut tap, src, IPPROTO_TCP, &seg);
+        let (_, _, payload) = recv(&mut tap);
+        let rst = TcpSegment::parse(&payload).unwrap();
+        assert_eq!((rst.seq, rst.flags), (2, TCP_RST));
+    }
+}
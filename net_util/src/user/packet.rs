@@ -0,0 +1,636 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Parsing and building of the Ethernet, ARP, IPv4, ICMP, UDP and TCP
+//! headers exchanged with the guest.
+
+use super::VNET_HDR_LEN;
+use crate::MacAddr;
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+pub const ETH_HDR_LEN: usize = 14;
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+
+const ARP_PACKET_LEN: usize = 28;
+const ARP_OP_REQUEST: u16 = 1;
+const ARP_OP_REPLY: u16 = 2;
+
+const IPV4_HDR_LEN: usize = 20;
+const IPV4_FLAG_DF: u16 = 0x4000;
+const IPV4_FLAG_MF: u16 = 0x2000;
+const IPV4_FRAG_OFFSET_MASK: u16 = 0x1fff;
+const IPV4_TTL: u8 = 64;
+// Largest IPv4 payload fitting in a 1500 bytes MTU, a multiple of 8 as
+// required for fragments.
+const MAX_IPV4_PAYLOAD: usize = 1480;
+
+pub const IPPROTO_ICMP: u8 = 1;
+pub const IPPROTO_TCP: u8 = 6;
+pub const IPPROTO_UDP: u8 = 17;
+
+pub const ICMP_ECHO_REPLY: u8 = 0;
+pub const ICMP_ECHO_REQUEST: u8 = 8;
+
+const UDP_HDR_LEN: usize = 8;
+const TCP_HDR_LEN: usize = 20;
+const TCP_OPT_END: u8 = 0;
+const TCP_OPT_NOP: u8 = 1;
+const TCP_OPT_MSS: u8 = 2;
+
+pub const TCP_FIN: u8 = 0x01;
+pub const TCP_SYN: u8 = 0x02;
+pub const TCP_RST: u8 = 0x04;
+pub const TCP_PSH: u8 = 0x08;
+pub const TCP_ACK: u8 = 0x10;
+
+fn read_u16(data: &[u8]) -> u16 {
+    u16::from(data[0]) << 8 | u16::from(data[1])
+}
+
+fn read_u32(data: &[u8]) -> u32 {
+    u32::from(read_u16(data)) << 16 | u32::from(read_u16(&data[2..]))
+}
+
+fn read_ipv4(data: &[u8]) -> Ipv4Addr {
+    Ipv4Addr::new(data[0], data[1], data[2], data[3])
+}
+
+/// Adds `data` to a ones' complement sum, as used by the Internet checksum.
+fn checksum_add(mut sum: u32, data: &[u8]) -> u32 {
+    let mut chunks = data.chunks_exact(2);
+    for chunk in &mut chunks {
+        sum += u32::from(read_u16(chunk));
+    }
+    if let [last] = chunks.remainder() {
+        sum += u32::from(*last) << 8;
+    }
+    sum
+}
+
+fn checksum_finish(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+pub fn checksum(data: &[u8]) -> u16 {
+    checksum_finish(checksum_add(0, data))
+}
+
+/// Computes the checksum of a TCP or UDP packet, including the IPv4
+/// pseudo-header.
+fn transport_checksum(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, packet: &[u8]) -> u16 {
+    let mut sum = checksum_add(0, &src.octets());
+    sum = checksum_add(sum, &dst.octets());
+    sum += u32::from(protocol);
+    sum += packet.len() as u32;
+    checksum_finish(checksum_add(sum, packet))
+}
+
+/// Returns the destination, source and EtherType of an Ethernet frame,
+/// along with its payload.
+pub fn parse_ethernet(frame: &[u8]) -> Option<(MacAddr, MacAddr, u16, &[u8])> {
+    if frame.len() < ETH_HDR_LEN {
+        return None;
+    }
+
+    Some((
+        MacAddr::from_bytes_unchecked(&frame[0..6]),
+        MacAddr::from_bytes_unchecked(&frame[6..12]),
+        read_u16(&frame[12..]),
+        &frame[ETH_HDR_LEN..],
+    ))
+}
+
+/// Builds a frame, preceded by an empty virtio-net header, with room for a
+/// payload of `len` bytes.
+fn ethernet_frame(dst: MacAddr, src: MacAddr, ethertype: u16, len: usize) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(VNET_HDR_LEN + ETH_HDR_LEN + len);
+    frame.resize(VNET_HDR_LEN, 0);
+    frame.extend_from_slice(dst.get_bytes());
+    frame.extend_from_slice(src.get_bytes());
+    frame.extend_from_slice(&ethertype.to_be_bytes());
+    frame
+}
+
+/// An ARP request for an IPv4 address.
+#[derive(Debug, PartialEq)]
+pub struct ArpRequest {
+    pub sender_mac: MacAddr,
+    pub sender_ip: Ipv4Addr,
+    pub target_ip: Ipv4Addr,
+}
+
+impl ArpRequest {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        // Only Ethernet (1) and IPv4 (0x0800) addresses are supported.
+        if data.len() < ARP_PACKET_LEN
+            || read_u16(data) != 1
+            || read_u16(&data[2..]) != ETHERTYPE_IPV4
+            || data[4] != 6
+            || data[5] != 4
+            || read_u16(&data[6..]) != ARP_OP_REQUEST
+        {
+            return None;
+        }
+
+        Some(ArpRequest {
+            sender_mac: MacAddr::from_bytes_unchecked(&data[8..14]),
+            sender_ip: read_ipv4(&data[14..]),
+            target_ip: read_ipv4(&data[24..]),
+        })
+    }
+
+    /// Builds the frame answering this request with `mac`.
+    pub fn reply(&self, mac: MacAddr) -> Vec<u8> {
+        let mut frame = ethernet_frame(self.sender_mac, mac, ETHERTYPE_ARP, ARP_PACKET_LEN);
+        frame.extend_from_slice(&1u16.to_be_bytes());
+        frame.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        frame.extend_from_slice(&[6, 4]);
+        frame.extend_from_slice(&ARP_OP_REPLY.to_be_bytes());
+        frame.extend_from_slice(mac.get_bytes());
+        frame.extend_from_slice(&self.target_ip.octets());
+        frame.extend_from_slice(self.sender_mac.get_bytes());
+        frame.extend_from_slice(&self.sender_ip.octets());
+        frame
+    }
+}
+
+/// An unfragmented IPv4 packet.
+#[derive(Debug, PartialEq)]
+pub struct Ipv4Packet<'a> {
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+    pub protocol: u8,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Ipv4Packet<'a> {
+    pub fn parse(data: &'a [u8]) -> Option<Self> {
+        if data.len() < IPV4_HDR_LEN || data[0] >> 4 != 4 {
+            return None;
+        }
+
+        let header_len = usize::from(data[0] & 0xf) * 4;
+        let total_len = usize::from(read_u16(&data[2..]));
+        if header_len < IPV4_HDR_LEN || total_len < header_len || total_len > data.len() {
+            return None;
+        }
+
+        // Fragments are not reassembled, the guest is expected to discover
+        // the MTU of the path instead.
+        let flags = read_u16(&data[6..]);
+        if flags & IPV4_FLAG_MF != 0 || flags & IPV4_FRAG_OFFSET_MASK != 0 {
+            return None;
+        }
+
+        Some(Ipv4Packet {
+            src: read_ipv4(&data[12..]),
+            dst: read_ipv4(&data[16..]),
+            protocol: data[9],
+            payload: &data[header_len..total_len],
+        })
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn ipv4_frame_with_flags(
+    dst_mac: MacAddr,
+    src_mac: MacAddr,
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    protocol: u8,
+    id: u16,
+    flags: u16,
+    payload: &[u8],
+) -> Vec<u8> {
+    let total_len = IPV4_HDR_LEN + payload.len();
+    let mut frame = ethernet_frame(dst_mac, src_mac, ETHERTYPE_IPV4, total_len);
+    let header_start = frame.len();
+
+    frame.push(0x45);
+    frame.push(0);
+    frame.extend_from_slice(&(total_len as u16).to_be_bytes());
+    frame.extend_from_slice(&id.to_be_bytes());
+    frame.extend_from_slice(&flags.to_be_bytes());
+    frame.push(IPV4_TTL);
+    frame.push(protocol);
+    frame.extend_from_slice(&[0, 0]);
+    frame.extend_from_slice(&src.octets());
+    frame.extend_from_slice(&dst.octets());
+
+    let csum = checksum(&frame[header_start..]);
+    frame[header_start + 10..header_start + 12].copy_from_slice(&csum.to_be_bytes());
+
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Builds a frame carrying an IPv4 packet, which must fit in the MTU.
+pub fn ipv4_frame(
+    dst_mac: MacAddr,
+    src_mac: MacAddr,
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    protocol: u8,
+    payload: &[u8],
+) -> Vec<u8> {
+    ipv4_frame_with_flags(
+        dst_mac,
+        src_mac,
+        src,
+        dst,
+        protocol,
+        0,
+        IPV4_FLAG_DF,
+        payload,
+    )
+}
+
+/// Builds the frames carrying an IPv4 packet, fragmenting it if it does not
+/// fit in the MTU.
+pub fn ipv4_fragments(
+    dst_mac: MacAddr,
+    src_mac: MacAddr,
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    protocol: u8,
+    payload: &[u8],
+) -> Vec<Vec<u8>> {
+    if payload.len() <= MAX_IPV4_PAYLOAD {
+        return vec![ipv4_frame(dst_mac, src_mac, src, dst, protocol, payload)];
+    }
+
+    let id = rand::random();
+    payload
+        .chunks(MAX_IPV4_PAYLOAD)
+        .enumerate()
+        .map(|(i, fragment)| {
+            let offset = (i * MAX_IPV4_PAYLOAD / 8) as u16;
+            let more = if (i + 1) * MAX_IPV4_PAYLOAD < payload.len() {
+                IPV4_FLAG_MF
+            } else {
+                0
+            };
+            ipv4_frame_with_flags(
+                dst_mac,
+                src_mac,
+                src,
+                dst,
+                protocol,
+                id,
+                more | offset,
+                fragment,
+            )
+        })
+        .collect()
+}
+
+/// An ICMP echo request.
+#[derive(Debug, PartialEq)]
+pub struct IcmpEcho<'a> {
+    pub ident: u16,
+    pub seq: u16,
+    pub payload: &'a [u8],
+}
+
+impl<'a> IcmpEcho<'a> {
+    pub fn parse(data: &'a [u8]) -> Option<Self> {
+        if data.len() < 8 || data[0] != ICMP_ECHO_REQUEST || data[1] != 0 {
+            return None;
+        }
+
+        Some(IcmpEcho {
+            ident: read_u16(&data[4..]),
+            seq: read_u16(&data[6..]),
+            payload: &data[8..],
+        })
+    }
+
+    /// Builds the ICMP message answering this request.
+    pub fn reply(&self) -> Vec<u8> {
+        let mut packet = vec![ICMP_ECHO_REPLY, 0, 0, 0];
+        packet.extend_from_slice(&self.ident.to_be_bytes());
+        packet.extend_from_slice(&self.seq.to_be_bytes());
+        packet.extend_from_slice(self.payload);
+
+        let csum = checksum(&packet);
+        packet[2..4].copy_from_slice(&csum.to_be_bytes());
+        packet
+    }
+}
+
+/// A UDP datagram.
+#[derive(Debug, PartialEq)]
+pub struct UdpDatagram<'a> {
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub payload: &'a [u8],
+}
+
+impl<'a> UdpDatagram<'a> {
+    pub fn parse(data: &'a [u8]) -> Option<Self> {
+        if data.len() < UDP_HDR_LEN {
+            return None;
+        }
+
+        let len = usize::from(read_u16(&data[4..]));
+        if len < UDP_HDR_LEN || len > data.len() {
+            return None;
+        }
+
+        Some(UdpDatagram {
+            src_port: read_u16(data),
+            dst_port: read_u16(&data[2..]),
+            payload: &data[UDP_HDR_LEN..len],
+        })
+    }
+}
+
+/// Builds a UDP datagram from `src` to `dst`, including its checksum.
+pub fn udp_datagram(src: SocketAddrV4, dst: SocketAddrV4, payload: &[u8]) -> Vec<u8> {
+    let len = UDP_HDR_LEN + payload.len();
+    let mut packet = Vec::with_capacity(len);
+    packet.extend_from_slice(&src.port().to_be_bytes());
+    packet.extend_from_slice(&dst.port().to_be_bytes());
+    packet.extend_from_slice(&(len as u16).to_be_bytes());
+    packet.extend_from_slice(&[0, 0]);
+    packet.extend_from_slice(payload);
+
+    let csum = match transport_checksum(*src.ip(), *dst.ip(), IPPROTO_UDP, &packet) {
+        // A computed checksum of zero is transmitted as all ones.
+        0 => 0xffff,
+        csum => csum,
+    };
+    packet[6..8].copy_from_slice(&csum.to_be_bytes());
+    packet
+}
+
+/// A TCP segment, of which only the MSS option is retained.
+#[derive(Debug, PartialEq)]
+pub struct TcpSegment<'a> {
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub seq: u32,
+    pub ack: u32,
+    pub flags: u8,
+    pub window: u16,
+    pub mss: Option<u16>,
+    pub payload: &'a [u8],
+}
+
+impl<'a> TcpSegment<'a> {
+    pub fn parse(data: &'a [u8]) -> Option<Self> {
+        if data.len() < TCP_HDR_LEN {
+            return None;
+        }
+
+        let header_len = usize::from(data[12] >> 4) * 4;
+        if header_len < TCP_HDR_LEN || header_len > data.len() {
+            return None;
+        }
+
+        let mut mss = None;
+        let mut options = &data[TCP_HDR_LEN..header_len];
+        while let Some(&kind) = options.first() {
+            match kind {
+                TCP_OPT_END => break,
+                TCP_OPT_NOP => options = &options[1..],
+                _ => {
+                    if options.len() < 2 {
+                        break;
+                    }
+                    let len = usize::from(options[1]);
+                    if len < 2 || len > options.len() {
+                        break;
+                    }
+                    if kind == TCP_OPT_MSS && len == 4 {
+                        mss = Some(read_u16(&options[2..]));
+                    }
+                    options = &options[len..];
+                }
+            }
+        }
+
+        Some(TcpSegment {
+            src_port: read_u16(data),
+            dst_port: read_u16(&data[2..]),
+            seq: read_u32(&data[4..]),
+            ack: read_u32(&data[8..]),
+            flags: data[13],
+            window: read_u16(&data[14..]),
+            mss,
+            payload: &data[header_len..],
+        })
+    }
+
+    /// Returns the amount of sequence space used by the segment.
+    pub fn seq_len(&self) -> u32 {
+        let mut len = self.payload.len() as u32;
+        if self.flags & TCP_SYN != 0 {
+            len += 1;
+        }
+        if self.flags & TCP_FIN != 0 {
+            len += 1;
+        }
+        len
+    }
+}
+
+/// Builds a TCP segment from `src` to `dst`, including its checksum.
+#[allow(clippy::too_many_arguments)]
+pub fn tcp_segment(
+    src: SocketAddrV4,
+    dst: SocketAddrV4,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+    mss: Option<u16>,
+    payload: &[u8],
+) -> Vec<u8> {
+    let header_len = if mss.is_some() {
+        TCP_HDR_LEN + 4
+    } else {
+        TCP_HDR_LEN
+    };
+    let mut packet = Vec::with_capacity(header_len + payload.len());
+    packet.extend_from_slice(&src.port().to_be_bytes());
+    packet.extend_from_slice(&dst.port().to_be_bytes());
+    packet.extend_from_slice(&seq.to_be_bytes());
+    packet.extend_from_slice(&ack.to_be_bytes());
+    packet.push((header_len as u8 / 4) << 4);
+    packet.push(flags);
+    packet.extend_from_slice(&window.to_be_bytes());
+    packet.extend_from_slice(&[0, 0, 0, 0]);
+    if let Some(mss) = mss {
+        packet.extend_from_slice(&[TCP_OPT_MSS, 4]);
+        packet.extend_from_slice(&mss.to_be_bytes());
+    }
+    packet.extend_from_slice(payload);
+
+    let csum = transport_checksum(*src.ip(), *dst.ip(), IPPROTO_TCP, &packet);
+    packet[16..18].copy_from_slice(&csum.to_be_bytes());
+    packet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checksum() {
+        // Example from RFC 1071.
+        let data = [0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7];
+        assert_eq!(checksum(&data), !0xddf2);
+        assert_eq!(checksum(&[0xab]), !0xab00);
+    }
+
+    #[test]
+    fn test_ipv4_frame() {
+        let guest_mac = MacAddr::parse_str("12:34:56:78:9a:bc").unwrap();
+        let gateway_mac = MacAddr::parse_str("52:54:00:00:00:01").unwrap();
+        let src = Ipv4Addr::new(192, 168, 249, 1);
+        let dst = Ipv4Addr::new(192, 168, 249, 2);
+
+        let frame = ipv4_frame(guest_mac, gateway_mac, src, dst, IPPROTO_UDP, &[1, 2, 3]);
+        let (eth_dst, eth_src, ethertype, payload) =
+            parse_ethernet(&frame[VNET_HDR_LEN..]).unwrap();
+        assert_eq!(eth_dst, guest_mac);
+        assert_eq!(eth_src, gateway_mac);
+        assert_eq!(ethertype, ETHERTYPE_IPV4);
+        // A valid header sums up to zero.
+        assert_eq!(checksum(&payload[..IPV4_HDR_LEN]), 0);
+
+        let packet = Ipv4Packet::parse(payload).unwrap();
+        assert_eq!(
+            packet,
+            Ipv4Packet {
+                src,
+                dst,
+                protocol: IPPROTO_UDP,
+                payload: &[1, 2, 3],
+            }
+        );
+
+        // Fragments and truncated packets are rejected.
+        let mut fragment = payload.to_vec();
+        fragment[6] |= 0x20;
+        assert!(Ipv4Packet::parse(&fragment).is_none());
+        assert!(Ipv4Packet::parse(&payload[..payload.len() - 1]).is_none());
+    }
+
+    #[test]
+    fn test_ipv4_fragments() {
+        let guest_mac = MacAddr::parse_str("12:34:56:78:9a:bc").unwrap();
+        let gateway_mac = MacAddr::parse_str("52:54:00:00:00:01").unwrap();
+        let src = Ipv4Addr::new(192, 168, 249, 1);
+        let dst = Ipv4Addr::new(192, 168, 249, 2);
+        let payload: Vec<u8> = (0..4000).map(|i| i as u8).collect();
+
+        let frames = ipv4_fragments(guest_mac, gateway_mac, src, dst, IPPROTO_UDP, &payload);
+        assert_eq!(frames.len(), 3);
+
+        let mut reassembled = Vec::new();
+        for (i, frame) in frames.iter().enumerate() {
+            let header = &frame[VNET_HDR_LEN + ETH_HDR_LEN..];
+            assert!(header.len() <= 1500);
+            assert_eq!(checksum(&header[..IPV4_HDR_LEN]), 0);
+            let flags = read_u16(&header[6..]);
+            assert_eq!(flags & IPV4_FLAG_MF != 0, i < 2);
+            assert_eq!(
+                usize::from(flags & IPV4_FRAG_OFFSET_MASK) * 8,
+                reassembled.len()
+            );
+            reassembled.extend_from_slice(&header[IPV4_HDR_LEN..]);
+        }
+        assert_eq!(reassembled, payload);
+
+        // Small packets are not fragmented.
+        let frames = ipv4_fragments(guest_mac, gateway_mac, src, dst, IPPROTO_UDP, &[0; 1480]);
+        assert_eq!(frames.len(), 1);
+    }
+
+    #[test]
+    fn test_udp_datagram() {
+        let src = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 53);
+        let dst = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 4000);
+
+        let packet = udp_datagram(src, dst, b"hello");
+        assert_eq!(
+            transport_checksum(*src.ip(), *dst.ip(), IPPROTO_UDP, &packet),
+            0
+        );
+        assert_eq!(
+            UdpDatagram::parse(&packet).unwrap(),
+            UdpDatagram {
+                src_port: 53,
+                dst_port: 4000,
+                payload: b"hello",
+            }
+        );
+        assert!(UdpDatagram::parse(&packet[..4]).is_none());
+    }
+
+    #[test]
+    fn test_tcp_segment() {
+        let src = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 80);
+        let dst = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 4000);
+
+        let packet = tcp_segment(src, dst, 1, 2, TCP_SYN | TCP_ACK, 1000, Some(1460), b"");
+        assert_eq!(
+            transport_checksum(*src.ip(), *dst.ip(), IPPROTO_TCP, &packet),
+            0
+        );
+        let segment = TcpSegment::parse(&packet).unwrap();
+        assert_eq!(
+            segment,
+            TcpSegment {
+                src_port: 80,
+                dst_port: 4000,
+                seq: 1,
+                ack: 2,
+                flags: TCP_SYN | TCP_ACK,
+                window: 1000,
+                mss: Some(1460),
+                payload: b"",
+            }
+        );
+        assert_eq!(segment.seq_len(), 1);
+
+        let packet = tcp_segment(src, dst, 1, 2, TCP_FIN | TCP_ACK, 1000, None, b"data");
+        let segment = TcpSegment::parse(&packet).unwrap();
+        assert_eq!(segment.mss, None);
+        assert_eq!(segment.payload, b"data");
+        assert_eq!(segment.seq_len(), 5);
+    }
+
+    #[test]
+    fn test_arp_reply() {
+        let guest_mac = MacAddr::parse_str("12:34:56:78:9a:bc").unwrap();
+        let gateway_mac = MacAddr::parse_str("52:54:00:00:00:01").unwrap();
+        let request = ArpRequest {
+            sender_mac: guest_mac,
+            sender_ip: Ipv4Addr::new(192, 168, 249, 2),
+            target_ip: Ipv4Addr::new(192, 168, 249, 1),
+        };
+
+        let frame = request.reply(gateway_mac);
+        let (dst, src, ethertype, payload) = parse_ethernet(&frame[VNET_HDR_LEN..]).unwrap();
+        assert_eq!(
+            (dst, src, ethertype),
+            (guest_mac, gateway_mac, ETHERTYPE_ARP)
+        );
+        // A reply is not a request.
+        assert!(ArpRequest::parse(payload).is_none());
+        assert_eq!(read_u16(&payload[6..]), ARP_OP_REPLY);
+        assert_eq!(&payload[8..14], gateway_mac.get_bytes());
+        assert_eq!(read_ipv4(&payload[14..]), request.target_ip);
+        assert_eq!(&payload[18..24], guest_mac.get_bytes());
+        assert_eq!(read_ipv4(&payload[24..]), request.sender_ip);
+    }
+}
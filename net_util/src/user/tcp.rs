@@ -0,0 +1,694 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Translation between the TCP connections of the guest and host sockets.
+//!
+//! The guest end of each connection is terminated here, so that its payload
+//! can be relayed through a regular host socket. The link with the guest
+//! hardly ever loses segments, which keeps the implementation simple: out of
+//! order segments are dropped, and lost ones are recovered by going back to
+//! the oldest unacknowledged byte after a fixed timeout.
+
+use super::packet::{
+    tcp_segment, TcpSegment, IPPROTO_TCP, TCP_ACK, TCP_FIN, TCP_PSH, TCP_RST, TCP_SYN,
+};
+use super::Link;
+use std::cmp;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{Shutdown, SocketAddrV4, TcpStream};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::time::{Duration, Instant};
+
+// Largest payload sent to the guest, for a 1500 bytes MTU.
+const MSS: usize = 1460;
+// MSS assumed when the guest does not announce one.
+const DEFAULT_MSS: usize = 536;
+// Amount of data received from the guest and not written to the host yet.
+// Window scaling is not negotiated, so this is also the largest window.
+const RECV_BUF_SIZE: usize = 65535;
+// Amount of data read from the host and not acknowledged by the guest yet.
+const SEND_BUF_SIZE: usize = 256 * 1024;
+const RETRANSMIT_TIMEOUT: Duration = Duration::from_millis(500);
+const MAX_RETRANSMITS: u32 = 10;
+
+#[derive(Debug, PartialEq)]
+enum State {
+    /// The guest sent a SYN, the host socket is connecting.
+    Connecting,
+    /// The host socket is connected, a SYN-ACK was sent to the guest.
+    SynAckSent,
+    /// A connection was accepted on the host, a SYN was sent to the guest.
+    SynSent,
+    Established,
+    Closed,
+}
+
+/// Opens a TCP socket connecting to `addr` without waiting for the
+/// connection to be established.
+fn connect_nonblocking(addr: SocketAddrV4) -> io::Result<TcpStream> {
+    // Safe because we check the return value.
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_INET,
+            libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            0,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe because nothing else holds onto the fd we just created.
+    let stream = unsafe { TcpStream::from_raw_fd(fd) };
+
+    let sockaddr = libc::sockaddr_in {
+        sin_family: libc::AF_INET as libc::sa_family_t,
+        sin_port: addr.port().to_be(),
+        sin_addr: libc::in_addr {
+            s_addr: u32::from(*addr.ip()).to_be(),
+        },
+        sin_zero: [0; 8],
+    };
+    // Safe because sockaddr is a valid sockaddr_in, and its size is passed
+    // along with it.
+    let ret = unsafe {
+        libc::connect(
+            fd,
+            &sockaddr as *const libc::sockaddr_in as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EINPROGRESS) {
+            return Err(err);
+        }
+    }
+
+    Ok(stream)
+}
+
+/// Builds the reset answering a segment which does not belong to any
+/// connection, as per RFC 793.
+pub fn reset_frame(
+    link: &Link,
+    guest: SocketAddrV4,
+    remote: SocketAddrV4,
+    seg: &TcpSegment,
+) -> Vec<u8> {
+    let (seq, ack, flags) = if seg.flags & TCP_ACK != 0 {
+        (seg.ack, 0, TCP_RST)
+    } else {
+        (0, seg.seq.wrapping_add(seg.seq_len()), TCP_RST | TCP_ACK)
+    };
+    let packet = tcp_segment(remote, guest, seq, ack, flags, 0, None, &[]);
+
+    link.ipv4_frame(*remote.ip(), *guest.ip(), IPPROTO_TCP, &packet)
+}
+
+/// A TCP connection between the guest and a host socket.
+pub struct TcpConn {
+    stream: TcpStream,
+    state: State,
+    /// Guest end of the connection.
+    guest: SocketAddrV4,
+    /// Remote end of the connection, as seen by the guest.
+    remote: SocketAddrV4,
+    /// Oldest sequence number not acknowledged by the guest.
+    snd_una: u32,
+    /// Next sequence number to send to the guest.
+    snd_nxt: u32,
+    /// Data read from the host, starting at snd_una.
+    send_buf: Vec<u8>,
+    guest_window: usize,
+    guest_mss: usize,
+    /// Next sequence number expected from the guest.
+    rcv_nxt: u32,
+    /// Data received from the guest, not written to the host yet.
+    recv_buf: Vec<u8>,
+    host_eof: bool,
+    fin_sent: bool,
+    fin_acked: bool,
+    guest_fin: bool,
+    host_shutdown: bool,
+    retransmit_deadline: Option<Instant>,
+    retransmits: u32,
+}
+
+impl TcpConn {
+    fn new(stream: TcpStream, state: State, guest: SocketAddrV4, remote: SocketAddrV4) -> Self {
+        let iss = rand::random();
+
+        TcpConn {
+            stream,
+            state,
+            guest,
+            remote,
+            snd_una: iss,
+            snd_nxt: iss,
+            send_buf: Vec::new(),
+            guest_window: 0,
+            guest_mss: DEFAULT_MSS,
+            rcv_nxt: 0,
+            recv_buf: Vec::new(),
+            host_eof: false,
+            fin_sent: false,
+            fin_acked: false,
+            guest_fin: false,
+            host_shutdown: false,
+            retransmit_deadline: None,
+            retransmits: 0,
+        }
+    }
+
+    /// Starts relaying a connection the guest opened with `syn`, by
+    /// connecting to `target` on the host.
+    pub fn connect(
+        guest: SocketAddrV4,
+        remote: SocketAddrV4,
+        target: SocketAddrV4,
+        syn: &TcpSegment,
+    ) -> io::Result<Self> {
+        let stream = connect_nonblocking(target)?;
+        stream.set_nodelay(true)?;
+
+        let mut conn = TcpConn::new(stream, State::Connecting, guest, remote);
+        conn.rcv_nxt = syn.seq.wrapping_add(1);
+        conn.guest_window = usize::from(syn.window);
+        conn.guest_mss = syn.mss.map_or(DEFAULT_MSS, usize::from);
+        Ok(conn)
+    }
+
+    /// Starts relaying a connection accepted on the host, by opening a
+    /// connection from `remote` to `guest`.
+    pub fn accept(
+        stream: TcpStream,
+        guest: SocketAddrV4,
+        remote: SocketAddrV4,
+        link: &Link,
+        out: &mut Vec<Vec<u8>>,
+    ) -> io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        stream.set_nodelay(true)?;
+
+        let mut conn = TcpConn::new(stream, State::SynSent, guest, remote);
+        conn.send_syn(link, out);
+        Ok(conn)
+    }
+
+    /// Returns whether the host socket should be polled for reading.
+    pub fn wants_read(&self) -> bool {
+        self.state == State::Established && !self.host_eof && self.send_buf.len() < SEND_BUF_SIZE
+    }
+
+    /// Returns whether the host socket should be polled for writing.
+    pub fn wants_write(&self) -> bool {
+        self.state == State::Connecting || !self.recv_buf.is_empty()
+    }
+
+    pub fn retransmit_deadline(&self) -> Option<Instant> {
+        self.retransmit_deadline
+    }
+
+    /// Returns whether both ends are done with the connection.
+    pub fn is_closed(&self) -> bool {
+        self.state == State::Closed || (self.fin_acked && self.guest_fin && self.host_shutdown)
+    }
+
+    fn window(&self) -> u16 {
+        (RECV_BUF_SIZE - self.recv_buf.len()) as u16
+    }
+
+    fn frame(&self, link: &Link, seq: u32, flags: u8, payload: &[u8]) -> Vec<u8> {
+        let mss = if flags & TCP_SYN != 0 {
+            Some(MSS as u16)
+        } else {
+            None
+        };
+        let packet = tcp_segment(
+            self.remote,
+            self.guest,
+            seq,
+            self.rcv_nxt,
+            flags,
+            self.window(),
+            mss,
+            payload,
+        );
+
+        link.ipv4_frame(*self.remote.ip(), *self.guest.ip(), IPPROTO_TCP, &packet)
+    }
+
+    fn send_syn(&mut self, link: &Link, out: &mut Vec<Vec<u8>>) {
+        let flags = if self.state == State::SynSent {
+            TCP_SYN
+        } else {
+            TCP_SYN | TCP_ACK
+        };
+        out.push(self.frame(link, self.snd_una, flags, &[]));
+        self.snd_nxt = self.snd_una.wrapping_add(1);
+        self.retransmit_deadline = Some(Instant::now() + RETRANSMIT_TIMEOUT);
+    }
+
+    fn reset(&mut self, link: &Link, out: &mut Vec<Vec<u8>>) {
+        out.push(self.frame(link, self.snd_nxt, TCP_RST | TCP_ACK, &[]));
+        self.state = State::Closed;
+    }
+
+    fn establish(&mut self) {
+        self.snd_una = self.snd_nxt;
+        self.retransmit_deadline = None;
+        self.retransmits = 0;
+        self.state = State::Established;
+    }
+
+    /// Sends as much pending data as the guest window allows, followed by a
+    /// FIN once the host closed its end.
+    fn flush(&mut self, link: &Link, out: &mut Vec<Vec<u8>>) {
+        if self.state != State::Established || self.fin_sent {
+            return;
+        }
+
+        loop {
+            let sent = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+            let len = cmp::min(
+                cmp::min(self.send_buf.len() - sent, self.guest_mss),
+                self.guest_window.saturating_sub(sent),
+            );
+            if len == 0 {
+                break;
+            }
+
+            let frame = self.frame(
+                link,
+                self.snd_nxt,
+                TCP_ACK | TCP_PSH,
+                &self.send_buf[sent..sent + len],
+            );
+            out.push(frame);
+            self.snd_nxt = self.snd_nxt.wrapping_add(len as u32);
+        }
+
+        let sent = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+        if self.host_eof && sent == self.send_buf.len() {
+            out.push(self.frame(link, self.snd_nxt, TCP_FIN | TCP_ACK, &[]));
+            self.snd_nxt = self.snd_nxt.wrapping_add(1);
+            self.fin_sent = true;
+        }
+
+        if self.snd_nxt != self.snd_una && self.retransmit_deadline.is_none() {
+            self.retransmit_deadline = Some(Instant::now() + RETRANSMIT_TIMEOUT);
+        }
+    }
+
+    fn process_ack(&mut self, ack: u32, window: u16) {
+        let in_flight = self.snd_nxt.wrapping_sub(self.snd_una);
+        let acked = ack.wrapping_sub(self.snd_una);
+        // Ignore acknowledgements for data which was not sent.
+        if acked > in_flight {
+            return;
+        }
+
+        self.guest_window = usize::from(window);
+        if acked == 0 {
+            return;
+        }
+
+        let data = cmp::min(acked as usize, self.send_buf.len());
+        self.send_buf.drain(..data);
+        if acked as usize > data {
+            self.fin_acked = true;
+        }
+        self.snd_una = ack;
+        self.retransmits = 0;
+        self.retransmit_deadline = if self.snd_una == self.snd_nxt {
+            None
+        } else {
+            Some(Instant::now() + RETRANSMIT_TIMEOUT)
+        };
+    }
+
+    /// Writes the data received from the guest to the host socket.
+    fn write_host(&mut self, link: &Link, out: &mut Vec<Vec<u8>>) {
+        let window = usize::from(self.window());
+
+        while !self.recv_buf.is_empty() {
+            match self.stream.write(&self.recv_buf) {
+                Ok(0) => break,
+                Ok(n) => {
+                    self.recv_buf.drain(..n);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(_) => {
+                    self.reset(link, out);
+                    return;
+                }
+            }
+        }
+
+        if self.recv_buf.is_empty() && self.guest_fin && !self.host_shutdown {
+            // The connection is still readable if the host already closed it.
+            let _ = self.stream.shutdown(Shutdown::Write);
+            self.host_shutdown = true;
+        }
+
+        // Let the guest know as soon as its window reopens.
+        if window < MSS && usize::from(self.window()) >= MSS {
+            out.push(self.frame(link, self.snd_nxt, TCP_ACK, &[]));
+        }
+    }
+
+    /// Handles a segment the guest sent on this connection.
+    pub fn on_segment(&mut self, seg: &TcpSegment, link: &Link, out: &mut Vec<Vec<u8>>) {
+        if seg.flags & TCP_RST != 0 {
+            self.state = State::Closed;
+            return;
+        }
+
+        match self.state {
+            State::Connecting | State::Closed => return,
+            State::SynSent => {
+                if seg.flags & (TCP_SYN | TCP_ACK) == TCP_SYN | TCP_ACK && seg.ack == self.snd_nxt {
+                    self.rcv_nxt = seg.seq.wrapping_add(1);
+                    self.guest_window = usize::from(seg.window);
+                    self.guest_mss = seg.mss.map_or(DEFAULT_MSS, usize::from);
+                    self.establish();
+                    out.push(self.frame(link, self.snd_nxt, TCP_ACK, &[]));
+                }
+                return;
+            }
+            State::SynAckSent => {
+                if seg.flags & TCP_SYN != 0 {
+                    // The guest did not get the SYN-ACK.
+                    self.send_syn(link, out);
+                    return;
+                }
+                if seg.flags & TCP_ACK == 0 || seg.ack != self.snd_nxt {
+                    return;
+                }
+                self.establish();
+            }
+            State::Established => {}
+        }
+
+        if seg.flags & TCP_ACK != 0 {
+            self.process_ack(seg.ack, seg.window);
+        }
+
+        let mut need_ack = false;
+        if !seg.payload.is_empty() {
+            need_ack = true;
+            if seg.seq == self.rcv_nxt && !self.guest_fin {
+                let len = cmp::min(seg.payload.len(), RECV_BUF_SIZE - self.recv_buf.len());
+                self.recv_buf.extend_from_slice(&seg.payload[..len]);
+                self.rcv_nxt = self.rcv_nxt.wrapping_add(len as u32);
+            }
+        }
+
+        if seg.flags & TCP_FIN != 0 {
+            need_ack = true;
+            let fin_seq = seg.seq.wrapping_add(seg.payload.len() as u32);
+            if !self.guest_fin && fin_seq == self.rcv_nxt {
+                self.guest_fin = true;
+                self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+            }
+        }
+
+        self.write_host(link, out);
+        if self.state == State::Closed {
+            return;
+        }
+        if need_ack {
+            out.push(self.frame(link, self.snd_nxt, TCP_ACK, &[]));
+        }
+        self.flush(link, out);
+    }
+
+    /// Handles the host socket becoming readable.
+    pub fn on_readable(&mut self, link: &Link, out: &mut Vec<Vec<u8>>) {
+        let mut buf = [0u8; 16384];
+
+        while self.wants_read() {
+            let len = cmp::min(buf.len(), SEND_BUF_SIZE - self.send_buf.len());
+            match self.stream.read(&mut buf[..len]) {
+                Ok(0) => self.host_eof = true,
+                Ok(n) => self.send_buf.extend_from_slice(&buf[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(_) => {
+                    self.reset(link, out);
+                    return;
+                }
+            }
+        }
+
+        self.flush(link, out);
+    }
+
+    /// Handles the host socket becoming writable, which also signals the
+    /// completion of a connection attempt.
+    pub fn on_writable(&mut self, link: &Link, out: &mut Vec<Vec<u8>>) {
+        if self.state != State::Connecting {
+            self.write_host(link, out);
+            return;
+        }
+
+        match self.stream.take_error() {
+            Ok(None) if self.stream.peer_addr().is_ok() => {
+                self.state = State::SynAckSent;
+                self.send_syn(link, out);
+            }
+            _ => self.reset(link, out),
+        }
+    }
+
+    /// Retransmits whatever the guest did not acknowledge in time.
+    pub fn on_timer(&mut self, now: Instant, link: &Link, out: &mut Vec<Vec<u8>>) {
+        match self.retransmit_deadline {
+            Some(deadline) if now >= deadline => {}
+            _ => return,
+        }
+
+        self.retransmits += 1;
+        if self.retransmits > MAX_RETRANSMITS {
+            self.reset(link, out);
+            return;
+        }
+
+        match self.state {
+            State::SynSent | State::SynAckSent => self.send_syn(link, out),
+            State::Established => {
+                self.snd_nxt = self.snd_una;
+                self.fin_sent = false;
+                self.retransmit_deadline = None;
+                self.flush(link, out);
+            }
+            State::Connecting | State::Closed => {}
+        }
+    }
+}
+
+impl AsRawFd for TcpConn {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::packet::{parse_ethernet, Ipv4Packet};
+    use super::super::VNET_HDR_LEN;
+    use super::*;
+    use crate::MacAddr;
+    use std::net::{Ipv4Addr, TcpListener};
+    use std::thread;
+
+    fn link() -> Link {
+        Link {
+            gateway_mac: MacAddr::parse_str("52:54:00:00:00:01").unwrap(),
+            guest_mac: MacAddr::parse_str("12:34:56:78:9a:bc").unwrap(),
+        }
+    }
+
+    fn guest() -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::new(192, 168, 249, 2), 40000)
+    }
+
+    fn remote() -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::new(192, 168, 249, 1), 8080)
+    }
+
+    /// Extracts the TCP segment sent to the guest in `frame`, returning its
+    /// sequence number, acknowledgement number, flags and payload.
+    fn parse_frame(frame: &[u8]) -> (u32, u32, u8, Vec<u8>) {
+        let (_, _, _, payload) = parse_ethernet(&frame[VNET_HDR_LEN..]).unwrap();
+        let packet = Ipv4Packet::parse(payload).unwrap();
+        assert_eq!(packet.src, *remote().ip());
+        assert_eq!(packet.dst, *guest().ip());
+        let seg = TcpSegment::parse(packet.payload).unwrap();
+        assert_eq!(seg.src_port, remote().port());
+        assert_eq!(seg.dst_port, guest().port());
+        (seg.seq, seg.ack, seg.flags, seg.payload.to_vec())
+    }
+
+    fn guest_segment(seq: u32, ack: u32, flags: u8, payload: &[u8]) -> TcpSegment<'_> {
+        TcpSegment {
+            src_port: guest().port(),
+            dst_port: remote().port(),
+            seq,
+            ack,
+            flags,
+            window: 65535,
+            mss: Some(1460),
+            payload,
+        }
+    }
+
+    fn wait_writable(conn: &TcpConn) {
+        let mut pollfd = libc::pollfd {
+            fd: conn.as_raw_fd(),
+            events: libc::POLLOUT,
+            revents: 0,
+        };
+        // Safe because we pass a single valid pollfd.
+        assert_eq!(unsafe { libc::poll(&mut pollfd, 1, 5000) }, 1);
+    }
+
+    fn wait_until<F: FnMut() -> bool>(mut f: F) {
+        for _ in 0..100 {
+            if f() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("timed out");
+    }
+
+    #[test]
+    fn test_tcp_outbound() {
+        let link = link();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let target = match listener.local_addr().unwrap() {
+            std::net::SocketAddr::V4(addr) => addr,
+            _ => unreachable!(),
+        };
+        let mut out = Vec::new();
+
+        let syn = guest_segment(1000, 0, TCP_SYN, &[]);
+        let mut conn = TcpConn::connect(guest(), remote(), target, &syn).unwrap();
+        let (mut host, _) = listener.accept().unwrap();
+        wait_writable(&conn);
+        conn.on_writable(&link, &mut out);
+        let (iss, ack, flags, _) = parse_frame(&out.pop().unwrap());
+        assert_eq!((ack, flags), (1001, TCP_SYN | TCP_ACK));
+
+        // Handshake completion, carrying data for the host.
+        let seg = guest_segment(1001, iss.wrapping_add(1), TCP_ACK | TCP_PSH, b"ping");
+        conn.on_segment(&seg, &link, &mut out);
+        let (_, ack, flags, _) = parse_frame(&out.pop().unwrap());
+        assert_eq!((ack, flags), (1005, TCP_ACK));
+        let mut buf = [0u8; 4];
+        host.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+
+        // Data from the host, followed by its end of the connection.
+        host.write_all(b"pong").unwrap();
+        host.shutdown(Shutdown::Write).unwrap();
+        wait_until(|| {
+            conn.on_readable(&link, &mut out);
+            out.len() == 2
+        });
+        let (seq, _, flags, payload) = parse_frame(&out[0]);
+        assert_eq!((seq, flags), (iss.wrapping_add(1), TCP_ACK | TCP_PSH));
+        assert_eq!(payload, b"pong");
+        let (seq, _, flags, _) = parse_frame(&out[1]);
+        assert_eq!((seq, flags), (iss.wrapping_add(5), TCP_FIN | TCP_ACK));
+        out.clear();
+
+        // The guest acknowledges only the data, so the FIN is resent along
+        // with nothing else once the timer expires.
+        let seg = guest_segment(1005, iss.wrapping_add(5), TCP_ACK, &[]);
+        conn.on_segment(&seg, &link, &mut out);
+        assert!(out.is_empty());
+        conn.on_timer(Instant::now() + RETRANSMIT_TIMEOUT, &link, &mut out);
+        let (seq, _, flags, _) = parse_frame(&out.pop().unwrap());
+        assert_eq!((seq, flags), (iss.wrapping_add(5), TCP_FIN | TCP_ACK));
+
+        // The guest closes its end too.
+        let seg = guest_segment(1005, iss.wrapping_add(6), TCP_FIN | TCP_ACK, &[]);
+        conn.on_segment(&seg, &link, &mut out);
+        let (_, ack, flags, _) = parse_frame(&out.pop().unwrap());
+        assert_eq!((ack, flags), (1006, TCP_ACK));
+        assert!(conn.is_closed());
+        assert_eq!(host.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_tcp_inbound() {
+        let link = link();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut host = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let mut out = Vec::new();
+
+        let mut conn = TcpConn::accept(stream, guest(), remote(), &link, &mut out).unwrap();
+        let (iss, _, flags, _) = parse_frame(&out.pop().unwrap());
+        assert_eq!(flags, TCP_SYN);
+
+        // The SYN is retransmitted until the guest answers.
+        conn.on_timer(Instant::now() + RETRANSMIT_TIMEOUT, &link, &mut out);
+        let (seq, _, flags, _) = parse_frame(&out.pop().unwrap());
+        assert_eq!((seq, flags), (iss, TCP_SYN));
+
+        let seg = guest_segment(5000, iss.wrapping_add(1), TCP_SYN | TCP_ACK, &[]);
+        conn.on_segment(&seg, &link, &mut out);
+        let (_, ack, flags, _) = parse_frame(&out.pop().unwrap());
+        assert_eq!((ack, flags), (5001, TCP_ACK));
+        assert!(conn.wants_read());
+
+        // Out of order data is dropped and acknowledged again.
+        let seg = guest_segment(5002, iss.wrapping_add(1), TCP_ACK, b"late");
+        conn.on_segment(&seg, &link, &mut out);
+        let (_, ack, _, _) = parse_frame(&out.pop().unwrap());
+        assert_eq!(ack, 5001);
+
+        // A reset from the guest closes the connection.
+        let seg = guest_segment(5001, iss.wrapping_add(1), TCP_RST, &[]);
+        conn.on_segment(&seg, &link, &mut out);
+        assert!(out.is_empty());
+        assert!(conn.is_closed());
+        drop(conn);
+        let mut buf = [0u8; 4];
+        assert_eq!(host.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_tcp_refused() {
+        let link = link();
+        // Find a port nothing listens on.
+        let target = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            match listener.local_addr().unwrap() {
+                std::net::SocketAddr::V4(addr) => addr,
+                _ => unreachable!(),
+            }
+        };
+        let mut out = Vec::new();
+
+        let syn = guest_segment(1000, 0, TCP_SYN, &[]);
+        let mut conn = TcpConn::connect(guest(), remote(), target, &syn).unwrap();
+        wait_writable(&conn);
+        conn.on_writable(&link, &mut out);
+        let (_, ack, flags, _) = parse_frame(&out.pop().unwrap());
+        assert_eq!((ack, flags), (1001, TCP_RST | TCP_ACK));
+        assert!(conn.is_closed());
+
+        // Segments for unknown connections are reset too.
+        let seg = guest_segment(1000, 77, TCP_ACK, b"data");
+        let (seq, _, flags, _) = parse_frame(&reset_frame(&link, guest(), remote(), &seg));
+        assert_eq!((seq, flags), (77, TCP_RST));
+    }
+}
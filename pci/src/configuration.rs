@@ -263,8 +263,24 @@ pub struct PciConfiguration {
     msix_config: Option<Arc<Mutex<MsixConfig>>>,
 }
 
+/// The configuration space state, as saved in a device snapshot.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct PciConfigurationState {
+    registers: Vec<u32>,
+    writable_bits: Vec<u32>,
+    bar_addr: Vec<u32>,
+    bar_size: Vec<u32>,
+    bar_used: Vec<bool>,
+    bar_type: Vec<Option<PciBarRegionType>>,
+    rom_bar_addr: u32,
+    rom_bar_size: u32,
+    rom_bar_used: bool,
+    last_capability: Option<(usize, usize)>,
+    msix_cap_reg_idx: Option<usize>,
+}
+
 /// See pci_regs.h in kernel
-#[derive(Copy, Clone, PartialEq)]
+#[derive(Copy, Clone, Deserialize, PartialEq, Serialize)]
 pub enum PciBarRegionType {
     Memory32BitRegion = 0,
     IORegion = 0x01,
@@ -297,6 +313,7 @@ pub enum Error {
     CapabilityEmpty,
     CapabilityLengthInvalid(usize),
     CapabilitySpaceFull(usize),
+    InvalidState,
     RomBarAddressInvalid(u64, u64),
     RomBarInUse(usize),
     RomBarInvalid(usize),
@@ -324,6 +341,7 @@ impl Display for Error {
             CapabilityEmpty => write!(f, "empty capabilities are invalid"),
             CapabilityLengthInvalid(l) => write!(f, "Invalid capability length {}", l),
             CapabilitySpaceFull(s) => write!(f, "capability of size {} doesn't fit", s),
+            InvalidState => write!(f, "invalid configuration space state"),
             RomBarAddressInvalid(a, s) => write!(f, "address {} size {} too big", a, s),
             RomBarInUse(b) => write!(f, "rom bar {} already used", b),
             RomBarInvalid(b) => write!(f, "rom bar {} invalid, max {}", b, NUM_BAR_REGS - 1),
@@ -390,6 +408,50 @@ impl PciConfiguration {
         }
     }
 
+    pub fn state(&self) -> PciConfigurationState {
+        PciConfigurationState {
+            registers: self.registers.to_vec(),
+            writable_bits: self.writable_bits.to_vec(),
+            bar_addr: self.bar_addr.to_vec(),
+            bar_size: self.bar_size.to_vec(),
+            bar_used: self.bar_used.to_vec(),
+            bar_type: self.bar_type.to_vec(),
+            rom_bar_addr: self.rom_bar_addr,
+            rom_bar_size: self.rom_bar_size,
+            rom_bar_used: self.rom_bar_used,
+            last_capability: self.last_capability,
+            msix_cap_reg_idx: self.msix_cap_reg_idx,
+        }
+    }
+
+    /// Restore the configuration space. The state must come from a device
+    /// with the same layout, the number of registers and BARs being fixed.
+    pub fn set_state(&mut self, state: &PciConfigurationState) -> Result<()> {
+        if state.registers.len() != NUM_CONFIGURATION_REGISTERS
+            || state.writable_bits.len() != NUM_CONFIGURATION_REGISTERS
+            || state.bar_addr.len() != NUM_BAR_REGS
+            || state.bar_size.len() != NUM_BAR_REGS
+            || state.bar_used.len() != NUM_BAR_REGS
+            || state.bar_type.len() != NUM_BAR_REGS
+        {
+            return Err(Error::InvalidState);
+        }
+
+        self.registers.copy_from_slice(&state.registers);
+        self.writable_bits.copy_from_slice(&state.writable_bits);
+        self.bar_addr.copy_from_slice(&state.bar_addr);
+        self.bar_size.copy_from_slice(&state.bar_size);
+        self.bar_used.copy_from_slice(&state.bar_used);
+        self.bar_type.copy_from_slice(&state.bar_type);
+        self.rom_bar_addr = state.rom_bar_addr;
+        self.rom_bar_size = state.rom_bar_size;
+        self.rom_bar_used = state.rom_bar_used;
+        self.last_capability = state.last_capability;
+        self.msix_cap_reg_idx = state.msix_cap_reg_idx;
+
+        Ok(())
+    }
+
     /// Reads a 32bit register from `reg_idx` in the register map.
     pub fn read_reg(&self, reg_idx: usize) -> u32 {
         *(self.registers.get(reg_idx).unwrap_or(&0xffff_ffff))
@@ -10,9 +10,10 @@ mod refcount;
 mod vec_cache;
 
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
-use libc::{EINVAL, ENOSPC, ENOTSUP};
+use flate2::{Decompress, FlushDecompress};
+use libc::{EINVAL, ENOSPC};
 use remain::sorted;
-use vm_virtio::RawFile;
+use vm_virtio::{read_iovecs_bounced, write_iovecs_bounced, DiskIovecs, DiskResize, RawFile};
 use vmm_sys_util::{
     file_traits::FileSetLen, file_traits::FileSync, seek_hole::SeekHole, write_zeroes::PunchHole,
     write_zeroes::WriteZeroes,
@@ -20,8 +21,13 @@ use vmm_sys_util::{
 
 use std::cmp::{max, min};
 use std::fmt::{self, Display};
+use std::fs::{self, OpenOptions};
 use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::mem::size_of;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::str;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use crate::qcow_raw_file::QcowRawFile;
 use crate::refcount::RefCount;
@@ -30,12 +36,15 @@ use crate::vec_cache::{CacheMap, Cacheable, VecCache};
 #[sorted]
 #[derive(Debug)]
 pub enum Error {
-    BackingFilesNotSupported,
-    CompressedBlocksNotSupported,
+    BackingFileIo(io::Error),
+    BackingFileOpen(Box<Error>),
+    BackingFileTooLong(usize),
+    CheckingImage(io::Error),
     EvictingCache(io::Error),
     FileTooBig(u64),
     GettingFileSize(io::Error),
     GettingRefcount(refcount::Error),
+    InvalidBackingFileName(str::Utf8Error),
     InvalidClusterIndex,
     InvalidClusterSize,
     InvalidIndex,
@@ -45,6 +54,7 @@ pub enum Error {
     InvalidOffset(u64),
     InvalidRefcountTableOffset,
     InvalidRefcountTableSize(u64),
+    MaxNestingDepthExceeded,
     NoFreeClusters,
     NoRefcountClusters,
     NotEnoughSpaceForRefcounts,
@@ -54,19 +64,28 @@ pub enum Error {
     ReadingPointers(io::Error),
     ReadingRefCountBlock(refcount::Error),
     ReadingRefCounts(io::Error),
+    ReadingSnapshots(io::Error),
     RebuildingRefCounts(io::Error),
+    RefcountRebuildWithSnapshots,
     RefcountTableOffEnd,
     RefcountTableTooLarge,
+    ResizingImage(io::Error),
     SeekingFile(io::Error),
     SettingFileSize(io::Error),
     SettingRefcountRefcount(io::Error),
+    ShrinkingNotSupported,
     SizeTooSmallForNumberOfClusters,
+    SnapshotExists(String),
+    SnapshotNameTooLong(usize),
+    SnapshotNotFound(String),
     TooManyL1Entries(u64),
     TooManyRefcounts(u64),
+    TooManySnapshots,
     UnsupportedRefcountOrder,
     UnsupportedVersion(u32),
     WritingData(io::Error),
     WritingHeader(io::Error),
+    WritingSnapshots(io::Error),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -78,8 +97,10 @@ impl Display for Error {
 
         #[sorted]
         match self {
-            BackingFilesNotSupported => write!(f, "backing files not supported"),
-            CompressedBlocksNotSupported => write!(f, "compressed blocks not supported"),
+            BackingFileIo(e) => write!(f, "backing file io error: {}", e),
+            BackingFileOpen(e) => write!(f, "backing file open error: {}", *e),
+            BackingFileTooLong(len) => write!(f, "backing file name is too long: {}", len),
+            CheckingImage(e) => write!(f, "failed to check image: {}", e),
             EvictingCache(e) => write!(f, "failed to evict cache: {}", e),
             FileTooBig(size) => write!(
                 f,
@@ -88,6 +109,7 @@ impl Display for Error {
             ),
             GettingFileSize(e) => write!(f, "failed to get file size: {}", e),
             GettingRefcount(e) => write!(f, "failed to get refcount: {}", e),
+            InvalidBackingFileName(e) => write!(f, "backing file name is invalid: {}", e),
             InvalidClusterIndex => write!(f, "invalid cluster index"),
             InvalidClusterSize => write!(f, "invalid cluster size"),
             InvalidIndex => write!(f, "invalid index"),
@@ -97,6 +119,7 @@ impl Display for Error {
             InvalidOffset(_) => write!(f, "invalid offset"),
             InvalidRefcountTableOffset => write!(f, "invalid refcount table offset"),
             InvalidRefcountTableSize(size) => write!(f, "invalid refcount table size: {}", size),
+            MaxNestingDepthExceeded => write!(f, "too many nested backing files"),
             NoFreeClusters => write!(f, "no free clusters"),
             NoRefcountClusters => write!(f, "no refcount clusters"),
             NotEnoughSpaceForRefcounts => write!(f, "not enough space for refcounts"),
@@ -106,19 +129,30 @@ impl Display for Error {
             ReadingPointers(e) => write!(f, "failed to read pointers: {}", e),
             ReadingRefCountBlock(e) => write!(f, "failed to read ref count block: {}", e),
             ReadingRefCounts(e) => write!(f, "failed to read ref counts: {}", e),
+            ReadingSnapshots(e) => write!(f, "failed to read snapshots: {}", e),
             RebuildingRefCounts(e) => write!(f, "failed to rebuild ref counts: {}", e),
+            RefcountRebuildWithSnapshots => {
+                write!(f, "can't rebuild ref counts of an image with snapshots")
+            }
             RefcountTableOffEnd => write!(f, "refcount table offset past file end"),
             RefcountTableTooLarge => write!(f, "too many clusters specified for refcount table"),
+            ResizingImage(e) => write!(f, "failed to resize image: {}", e),
             SeekingFile(e) => write!(f, "failed to seek file: {}", e),
             SettingFileSize(e) => write!(f, "failed to set file size: {}", e),
             SettingRefcountRefcount(e) => write!(f, "failed to set refcount refcount: {}", e),
+            ShrinkingNotSupported => write!(f, "shrinking the image is not supported"),
             SizeTooSmallForNumberOfClusters => write!(f, "size too small for number of clusters"),
+            SnapshotExists(name) => write!(f, "snapshot {} already exists", name),
+            SnapshotNameTooLong(len) => write!(f, "snapshot name is too long: {}", len),
+            SnapshotNotFound(name) => write!(f, "snapshot {} not found", name),
             TooManyL1Entries(count) => write!(f, "l1 entry table too large: {}", count),
             TooManyRefcounts(count) => write!(f, "ref count table too large: {}", count),
+            TooManySnapshots => write!(f, "too many snapshots, max is {}", MAX_SNAPSHOTS),
             UnsupportedRefcountOrder => write!(f, "unsupported refcount order"),
             UnsupportedVersion(v) => write!(f, "unsupported version: {}", v),
             WritingData(e) => write!(f, "failed to write data: {}", e),
             WritingHeader(e) => write!(f, "failed to write header: {}", e),
+            WritingSnapshots(e) => write!(f, "failed to write snapshots: {}", e),
         }
     }
 }
@@ -149,6 +183,25 @@ const DEFAULT_REFCOUNT_ORDER: u32 = 4;
 const V2_BARE_HEADER_SIZE: u32 = 72;
 const V3_BARE_HEADER_SIZE: u32 = 104;
 
+// Same limit as qemu for the length of the backing file name.
+const MAX_BACKING_FILE_SIZE: u32 = 1023;
+// Maximum length of a chain of backing files, which also protects against loops.
+const MAX_NESTING_DEPTH: u32 = 10;
+
+// Same limits as qemu for the number of snapshots and the extra data of a snapshot table entry.
+const MAX_SNAPSHOTS: usize = 65536;
+const MAX_SNAPSHOT_EXTRA_DATA_SIZE: u32 = 1024;
+// Size of a snapshot table entry, without its extra data, ID and name.
+const SNAPSHOT_ENTRY_HEADER_SIZE: usize = 40;
+
+// Offsets of the header fields updated by snapshot operations.
+const HEADER_SIZE_OFFSET: u64 = 24;
+const HEADER_L1_SIZE_OFFSET: u64 = 36;
+const HEADER_NB_SNAPSHOTS_OFFSET: u64 = 60;
+
+// Number of L2 tables kept in RAM.
+const L2_CACHE_SIZE: usize = 100;
+
 // bits 0-8 and 56-63 are reserved.
 const L1_TABLE_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
 const L2_TABLE_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
@@ -360,6 +413,128 @@ fn max_refcount_clusters(refcount_order: u32, cluster_size: u32, num_clusters: u
     for_data + for_refcounts
 }
 
+/// Problems found by the consistency check of a qcow2 image.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CheckResult {
+    /// Clusters whose refcount is lower than the number of references to them. They could be
+    /// reallocated while still in use.
+    pub corruptions: u64,
+    /// Clusters whose refcount is higher than the number of references to them, wasting space.
+    pub leaks: u64,
+    /// L1 and L2 entries pointing to unaligned clusters or past the end of the file.
+    pub invalid_entries: u64,
+    /// L2 entries whose COPIED flag doesn't match the refcount of their data cluster.
+    pub copied_flag_mismatches: u64,
+}
+
+impl CheckResult {
+    /// Returns true if the image is consistent.
+    pub fn is_clean(&self) -> bool {
+        *self == CheckResult::default()
+    }
+}
+
+/// Internal snapshot of a qcow2 image, as listed in its snapshot table.
+#[derive(Clone, Debug)]
+pub struct QcowSnapshot {
+    pub id: String,
+    pub name: String,
+    pub date_sec: u32,
+    pub date_nsec: u32,
+    pub vm_clock_nsec: u64,
+    /// Virtual size of the image when the snapshot was taken.
+    pub disk_size: u64,
+
+    l1_table_offset: u64,
+    l1_size: u32,
+    vm_state_size: u32,
+    extra_data: Vec<u8>,
+}
+
+impl QcowSnapshot {
+    // Reads the snapshot table entry found at the current position of `f`, padding included.
+    // `image_size` is the disk size of snapshots that don't record it.
+    fn read_from(f: &mut RawFile, image_size: u64) -> io::Result<QcowSnapshot> {
+        let l1_table_offset = f.read_u64::<BigEndian>()?;
+        let l1_size = f.read_u32::<BigEndian>()?;
+        let id_size = f.read_u16::<BigEndian>()?;
+        let name_size = f.read_u16::<BigEndian>()?;
+        let date_sec = f.read_u32::<BigEndian>()?;
+        let date_nsec = f.read_u32::<BigEndian>()?;
+        let vm_clock_nsec = f.read_u64::<BigEndian>()?;
+        let vm_state_size = f.read_u32::<BigEndian>()?;
+        let extra_data_size = f.read_u32::<BigEndian>()?;
+
+        if u64::from(l1_size) > MAX_RAM_POINTER_TABLE_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "snapshot L1 table too large",
+            ));
+        }
+        if extra_data_size > MAX_SNAPSHOT_EXTRA_DATA_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "snapshot extra data too large",
+            ));
+        }
+
+        let mut extra_data = vec![0u8; extra_data_size as usize];
+        f.read_exact(&mut extra_data)?;
+        let mut id = vec![0u8; id_size as usize];
+        f.read_exact(&mut id)?;
+        let mut name = vec![0u8; name_size as usize];
+        f.read_exact(&mut name)?;
+
+        let entry_size = SNAPSHOT_ENTRY_HEADER_SIZE + extra_data.len() + id.len() + name.len();
+        f.seek(SeekFrom::Current(snapshot_entry_padding(entry_size) as i64))?;
+
+        // The second field of the extra data is the disk size, when present.
+        let disk_size = if extra_data.len() >= 16 {
+            (&extra_data[8..16]).read_u64::<BigEndian>()?
+        } else {
+            image_size
+        };
+
+        let to_string = |bytes: Vec<u8>| {
+            String::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        };
+
+        Ok(QcowSnapshot {
+            id: to_string(id)?,
+            name: to_string(name)?,
+            date_sec,
+            date_nsec,
+            vm_clock_nsec,
+            disk_size,
+            l1_table_offset,
+            l1_size,
+            vm_state_size,
+            extra_data,
+        })
+    }
+
+    // Appends the snapshot table entry, padding included, to `table`.
+    fn write_to(&self, table: &mut Vec<u8>) -> io::Result<()> {
+        table.write_u64::<BigEndian>(self.l1_table_offset)?;
+        table.write_u32::<BigEndian>(self.l1_size)?;
+        table.write_u16::<BigEndian>(self.id.len() as u16)?;
+        table.write_u16::<BigEndian>(self.name.len() as u16)?;
+        table.write_u32::<BigEndian>(self.date_sec)?;
+        table.write_u32::<BigEndian>(self.date_nsec)?;
+        table.write_u64::<BigEndian>(self.vm_clock_nsec)?;
+        table.write_u32::<BigEndian>(self.vm_state_size)?;
+        table.write_u32::<BigEndian>(self.extra_data.len() as u32)?;
+        table.extend_from_slice(&self.extra_data);
+        table.extend_from_slice(self.id.as_bytes());
+        table.extend_from_slice(self.name.as_bytes());
+
+        let entry_size =
+            SNAPSHOT_ENTRY_HEADER_SIZE + self.extra_data.len() + self.id.len() + self.name.len();
+        table.resize(table.len() + snapshot_entry_padding(entry_size), 0);
+        Ok(())
+    }
+}
+
 /// Represents a qcow2 file. This is a sparse file format maintained by the qemu project.
 /// Full documentation of the format can be found in the qemu repository.
 ///
@@ -391,12 +566,26 @@ pub struct QcowFile {
     // List of unreferenced clusters available to be used. unref clusters become available once the
     // removal of references to them have been synced to disk.
     avail_clusters: Vec<u64>,
-    //TODO(dgreid) Add support for backing files. - backing_file: Option<Box<QcowFile<T>>>,
+    backing_file: Option<BackingFile>,
+    // Last compressed cluster read, along with its L2 table entry, so that consecutive reads
+    // within the same cluster don't decompress it again.
+    decompressed_cluster: Option<(u64, Vec<u8>)>,
+    snapshots: Vec<QcowSnapshot>,
 }
 
 impl QcowFile {
     /// Creates a QcowFile from `file`. File must be a valid qcow2 image.
-    pub fn from(mut file: RawFile) -> Result<QcowFile> {
+    ///
+    /// A relative backing file path is looked up from the directory of the image.
+    pub fn from(file: RawFile) -> Result<QcowFile> {
+        Self::from_with_nesting_depth(file, 0)
+    }
+
+    fn from_with_nesting_depth(mut file: RawFile, nesting_depth: u32) -> Result<QcowFile> {
+        if nesting_depth > MAX_NESTING_DEPTH {
+            return Err(Error::MaxNestingDepthExceeded);
+        }
+
         let header = QcowHeader::new(&mut file)?;
 
         // Only v2 and v3 files are supported.
@@ -420,10 +609,16 @@ impl QcowFile {
             return Err(Error::FileTooBig(header.size));
         }
 
-        // No current support for backing files.
-        if header.backing_file_offset != 0 {
-            return Err(Error::BackingFilesNotSupported);
-        }
+        let backing_file = if header.backing_file_offset != 0 {
+            let backing_file_name = read_backing_file_name(&mut file, &header)?;
+            let backing_file_path = backing_file_path(&file, &backing_file_name);
+            Some(
+                BackingFile::open(&backing_file_path, nesting_depth + 1)
+                    .map_err(|e| Error::BackingFileOpen(Box::new(e)))?,
+            )
+        } else {
+            None
+        };
 
         // Only support two byte refcounts.
         let refcount_bits: u64 = 0x01u64
@@ -438,7 +633,6 @@ impl QcowFile {
         if header.refcount_table_clusters == 0 {
             return Err(Error::NoRefcountClusters);
         }
-        offset_is_cluster_boundary(header.backing_file_offset, header.cluster_bits)?;
         offset_is_cluster_boundary(header.l1_table_offset, header.cluster_bits)?;
         offset_is_cluster_boundary(header.snapshots_offset, header.cluster_bits)?;
         // refcount table must be a cluster boundary, and within the file's virtual or actual size.
@@ -468,9 +662,19 @@ impl QcowFile {
             refcount_rebuild_required = true;
         }
 
+        if header.nb_snapshots as usize > MAX_SNAPSHOTS {
+            return Err(Error::TooManySnapshots);
+        }
+        let snapshots = read_snapshot_table(&mut file, &header).map_err(Error::ReadingSnapshots)?;
+
         let mut raw_file =
             QcowRawFile::from(file, cluster_size).ok_or(Error::InvalidClusterSize)?;
         if refcount_rebuild_required {
+            // The rebuild only follows the active L1 table, it would drop the references held by
+            // the snapshots.
+            if !snapshots.is_empty() {
+                return Err(Error::RefcountRebuildWithSnapshots);
+            }
             QcowFile::rebuild_refcounts(&mut raw_file, header)?;
         }
 
@@ -522,11 +726,14 @@ impl QcowFile {
             header,
             l1_table,
             l2_entries,
-            l2_cache: CacheMap::new(100),
+            l2_cache: CacheMap::new(L2_CACHE_SIZE),
             refcounts,
             current_offset: 0,
             unref_clusters: Vec::new(),
             avail_clusters: Vec::new(),
+            backing_file,
+            decompressed_cluster: None,
+            snapshots,
         };
 
         // Check that the L1 and refcount tables fit in a 64bit address space.
@@ -545,10 +752,48 @@ impl QcowFile {
     }
 
     /// Creates a new QcowFile at the given path.
-    pub fn new(mut file: RawFile, version: u32, virtual_size: u64) -> Result<QcowFile> {
+    pub fn new(file: RawFile, version: u32, virtual_size: u64) -> Result<QcowFile> {
         let header = QcowHeader::create_for_size(version, virtual_size);
+        Self::create(file, header, None)
+    }
+
+    /// Creates a new QcowFile at the given path, as a copy-on-write overlay of the image found
+    /// at `backing_file_name`. The overlay has the same virtual size as its backing file.
+    pub fn new_from_backing(
+        file: RawFile,
+        version: u32,
+        backing_file_name: &str,
+    ) -> Result<QcowFile> {
+        if backing_file_name.len() > MAX_BACKING_FILE_SIZE as usize {
+            return Err(Error::BackingFileTooLong(backing_file_name.len()));
+        }
+
+        let backing_file_path = backing_file_path(&file, backing_file_name);
+        let mut backing_file = BackingFile::open(&backing_file_path, 1)
+            .map_err(|e| Error::BackingFileOpen(Box::new(e)))?;
+        let virtual_size = backing_file.size().map_err(Error::BackingFileIo)?;
+
+        let mut header = QcowHeader::create_for_size(version, virtual_size);
+        // The backing file name is stored right after the header, in the first cluster.
+        header.backing_file_offset = u64::from(header.header_size);
+        header.backing_file_size = backing_file_name.len() as u32;
+
+        Self::create(file, header, Some(backing_file_name))
+    }
+
+    fn create(
+        mut file: RawFile,
+        header: QcowHeader,
+        backing_file_name: Option<&str>,
+    ) -> Result<QcowFile> {
         file.seek(SeekFrom::Start(0)).map_err(Error::SeekingFile)?;
         header.write_to(&mut file)?;
+        if let Some(backing_file_name) = backing_file_name {
+            file.seek(SeekFrom::Start(header.backing_file_offset))
+                .map_err(Error::WritingHeader)?;
+            file.write_all(backing_file_name.as_bytes())
+                .map_err(Error::WritingHeader)?;
+        }
 
         let mut qcow = Self::from(file)?;
 
@@ -599,11 +844,7 @@ impl QcowFile {
             let raw_file = &mut self.raw_file;
             self.l2_cache
                 .insert(l1_index, table, |index, evicted| {
-                    raw_file.write_pointer_table(
-                        l1_table[index],
-                        evicted.get_values(),
-                        CLUSTER_USED_FLAG,
-                    )
+                    raw_file.write_pointer_table(l1_table[index], evicted.get_values(), 0)
                 })
                 .map_err(Error::EvictingCache)?;
         }
@@ -648,6 +889,624 @@ impl QcowFile {
         Ok(None)
     }
 
+    /// Returns the internal snapshots of the image.
+    pub fn snapshots(&self) -> &[QcowSnapshot] {
+        &self.snapshots
+    }
+
+    /// Returns the snapshot whose ID or name is `id_or_name`.
+    pub fn snapshot(&self, id_or_name: &str) -> Option<&QcowSnapshot> {
+        self.find_snapshot(id_or_name)
+            .ok()
+            .map(|index| &self.snapshots[index])
+    }
+
+    /// Creates an internal snapshot named `name`, holding the current content of the image.
+    pub fn create_snapshot(&mut self, name: &str) -> Result<()> {
+        if name.len() > std::u16::MAX as usize {
+            return Err(Error::SnapshotNameTooLong(name.len()));
+        }
+        if self.snapshots.iter().any(|s| s.name == name) {
+            return Err(Error::SnapshotExists(name.to_string()));
+        }
+        if self.snapshots.len() >= MAX_SNAPSHOTS {
+            return Err(Error::TooManySnapshots);
+        }
+
+        self.add_snapshot(name).map_err(Error::WritingSnapshots)
+    }
+
+    /// Reverts the content of the image to the snapshot whose ID or name is `id_or_name`. The
+    /// snapshot itself is kept.
+    pub fn apply_snapshot(&mut self, id_or_name: &str) -> Result<()> {
+        let index = self.find_snapshot(id_or_name)?;
+        self.revert_to_snapshot(index)
+            .map_err(Error::WritingSnapshots)
+    }
+
+    /// Deletes the snapshot whose ID or name is `id_or_name`.
+    pub fn delete_snapshot(&mut self, id_or_name: &str) -> Result<()> {
+        let index = self.find_snapshot(id_or_name)?;
+        self.remove_snapshot(index).map_err(Error::WritingSnapshots)
+    }
+
+    /// Returns the name of the backing file of the image, as recorded in its header.
+    pub fn backing_file_name(&mut self) -> Result<Option<String>> {
+        if self.header.backing_file_offset == 0 {
+            return Ok(None);
+        }
+        read_backing_file_name(self.raw_file.file_mut(), &self.header).map(Some)
+    }
+
+    /// Checks that the refcount of every cluster matches the references the image and its
+    /// snapshots hold on it, and that the L1 and L2 tables only point to clusters of the file.
+    ///
+    /// When `repair` is set, invalid table entries are dropped and the refcounts and COPIED flags
+    /// are fixed. The returned result always describes the image as it was before the repair.
+    pub fn check(&mut self, repair: bool) -> Result<CheckResult> {
+        self.flush().map_err(Error::CheckingImage)?;
+
+        let (result, refcounts) = self
+            .find_cluster_references(repair)
+            .map_err(Error::CheckingImage)?;
+        if repair && !result.is_clean() {
+            self.repair_refcounts(&refcounts)
+                .map_err(Error::CheckingImage)?;
+            // Clusters that were leaked can now be reused.
+            self.avail_clusters.clear();
+            self.find_avail_clusters()?;
+        }
+
+        Ok(result)
+    }
+
+    /// Grows the virtual size of the image to `size` bytes. The new area reads as zeros, or from
+    /// the backing file if it is large enough. Shrinking the image isn't supported.
+    pub fn resize(&mut self, size: u64) -> Result<()> {
+        if size > MAX_QCOW_FILE_SIZE {
+            return Err(Error::FileTooBig(size));
+        }
+        if size < self.virtual_size() {
+            return Err(Error::ShrinkingNotSupported);
+        }
+        if size == self.virtual_size() {
+            return Ok(());
+        }
+
+        let cluster_size = self.raw_file.cluster_size();
+        let num_clusters = div_round_up_u64(size, cluster_size);
+        let num_l2_clusters = div_round_up_u64(num_clusters, self.l2_entries);
+        let l1_clusters = div_round_up_u64(num_l2_clusters, cluster_size);
+        let header_clusters = div_round_up_u64(size_of::<QcowHeader>() as u64, cluster_size);
+        if num_l2_clusters > MAX_RAM_POINTER_TABLE_SIZE {
+            return Err(Error::TooManyL1Entries(num_l2_clusters));
+        }
+
+        let refcount_clusters = max_refcount_clusters(
+            self.header.refcount_order,
+            cluster_size as u32,
+            (num_clusters + l1_clusters + num_l2_clusters + header_clusters) as u32,
+        );
+        if l1_clusters + refcount_clusters > MAX_RAM_POINTER_TABLE_SIZE {
+            return Err(Error::TooManyRefcounts(refcount_clusters));
+        }
+        // The refcount table is never moved, it must already have room for the refcount blocks
+        // of the grown image.
+        if refcount_clusters * size_of::<u64>() as u64
+            > u64::from(self.header.refcount_table_clusters) * cluster_size
+        {
+            return Err(Error::NotEnoughSpaceForRefcounts);
+        }
+
+        self.grow(size, num_l2_clusters, refcount_clusters)
+            .map_err(Error::ResizingImage)
+    }
+
+    fn grow(&mut self, size: u64, num_l2_clusters: u64, refcount_clusters: u64) -> io::Result<()> {
+        // The refcounts and the L1 table are reloaded from the disk, which must be up to date.
+        self.sync_caches()?;
+
+        let cluster_size = self.raw_file.cluster_size();
+        let refcount_block_entries = self.refcounts.refcounts_per_block();
+        self.refcounts = RefCount::new(
+            &mut self.raw_file,
+            self.header.refcount_table_offset,
+            refcount_clusters,
+            refcount_block_entries,
+            cluster_size,
+        )?;
+
+        if num_l2_clusters > u64::from(self.header.l1_size) {
+            // The L1 table must be contiguous, the larger one is written to new clusters.
+            let mut l1_table = self.l1_table.get_values().to_vec();
+            l1_table.resize(num_l2_clusters as usize, 0);
+            let l1_table_offset = self.write_l1_table_copy(&l1_table)?;
+            self.sync_caches()?;
+
+            let old_l1_table_offset = self.header.l1_table_offset;
+            let old_l1_size = self.header.l1_size;
+
+            self.header.size = size;
+            self.header.l1_size = l1_table.len() as u32;
+            self.header.l1_table_offset = l1_table_offset;
+            self.write_header_tables()?;
+            self.l1_table = VecCache::from_vec(l1_table);
+
+            self.free_table_clusters(old_l1_table_offset, u64::from(old_l1_size) * 8)?;
+        } else {
+            // The current L1 table is already large enough, only the entries in use were loaded.
+            self.l1_table = VecCache::from_vec(self.raw_file.read_pointer_table(
+                self.header.l1_table_offset,
+                num_l2_clusters,
+                Some(L1_TABLE_OFFSET_MASK),
+            )?);
+            self.header.size = size;
+            self.write_header_tables()?;
+        }
+
+        self.flush()
+    }
+
+    // Returns the index of the snapshot matching `id_or_name`. Like in qemu, IDs take precedence
+    // over names.
+    fn find_snapshot(&self, id_or_name: &str) -> Result<usize> {
+        self.snapshots
+            .iter()
+            .position(|s| s.id == id_or_name)
+            .or_else(|| self.snapshots.iter().position(|s| s.name == id_or_name))
+            .ok_or_else(|| Error::SnapshotNotFound(id_or_name.to_string()))
+    }
+
+    fn add_snapshot(&mut self, name: &str) -> std::io::Result<()> {
+        // The snapshot shares the L2 tables of the image, which must all be on disk first.
+        self.sync_caches()?;
+
+        let l1_table = self.l1_table.get_values().to_vec();
+        self.update_l1_table_refcounts(&l1_table, true)?;
+        self.update_copied_flags()?;
+        let l1_table_offset = self.write_l1_table_copy(&l1_table)?;
+
+        let id = self
+            .snapshots
+            .iter()
+            .filter_map(|s| s.id.parse::<u64>().ok())
+            .max()
+            .unwrap_or(0)
+            + 1;
+        let date = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        // The extra data holds the 64 bit VM state size and the disk size.
+        let mut extra_data = Vec::new();
+        extra_data.write_u64::<BigEndian>(0)?;
+        extra_data.write_u64::<BigEndian>(self.virtual_size())?;
+
+        let mut snapshots = self.snapshots.clone();
+        snapshots.push(QcowSnapshot {
+            id: id.to_string(),
+            name: name.to_string(),
+            date_sec: date.as_secs() as u32,
+            date_nsec: date.subsec_nanos(),
+            vm_clock_nsec: 0,
+            disk_size: self.virtual_size(),
+            l1_table_offset,
+            l1_size: l1_table.len() as u32,
+            vm_state_size: 0,
+            extra_data,
+        });
+        self.write_snapshot_table(snapshots)?;
+
+        self.reset_l2_cache();
+        self.flush()
+    }
+
+    fn revert_to_snapshot(&mut self, index: usize) -> std::io::Result<()> {
+        self.sync_caches()?;
+
+        let snapshot = self.snapshots[index].clone();
+        let cluster_size = self.raw_file.cluster_size();
+        let num_clusters = div_round_up_u64(snapshot.disk_size, cluster_size);
+        let num_l2_clusters = div_round_up_u64(num_clusters, self.l2_entries);
+        if num_l2_clusters > MAX_RAM_POINTER_TABLE_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "snapshot disk size too large",
+            ));
+        }
+        let mut l1_table = self.raw_file.read_pointer_table(
+            snapshot.l1_table_offset,
+            min(u64::from(snapshot.l1_size), num_l2_clusters),
+            Some(L1_TABLE_OFFSET_MASK),
+        )?;
+        l1_table.resize(num_l2_clusters as usize, 0);
+
+        // References to the snapshot clusters are taken before the ones to the current clusters
+        // are dropped, so that the clusters they share are never freed.
+        self.update_l1_table_refcounts(&l1_table, true)?;
+        let l1_table_offset = self.write_l1_table_copy(&l1_table)?;
+        self.sync_caches()?;
+
+        let old_l1_table = self.l1_table.get_values().to_vec();
+        let old_l1_table_offset = self.header.l1_table_offset;
+        let old_l1_size = self.header.l1_size;
+
+        self.header.size = snapshot.disk_size;
+        self.header.l1_size = l1_table.len() as u32;
+        self.header.l1_table_offset = l1_table_offset;
+        self.write_header_tables()?;
+        self.l1_table = VecCache::from_vec(l1_table);
+
+        self.update_l1_table_refcounts(&old_l1_table, false)?;
+        self.free_table_clusters(old_l1_table_offset, u64::from(old_l1_size) * 8)?;
+        self.update_copied_flags()?;
+
+        self.reset_l2_cache();
+        self.flush()
+    }
+
+    fn remove_snapshot(&mut self, index: usize) -> std::io::Result<()> {
+        self.sync_caches()?;
+
+        let snapshot = self.snapshots[index].clone();
+        let mut snapshots = self.snapshots.clone();
+        snapshots.remove(index);
+        self.write_snapshot_table(snapshots)?;
+
+        // The snapshot is gone from the table, its clusters can now be released.
+        let l1_table = self.raw_file.read_pointer_table(
+            snapshot.l1_table_offset,
+            u64::from(snapshot.l1_size),
+            Some(L1_TABLE_OFFSET_MASK),
+        )?;
+        self.update_l1_table_refcounts(&l1_table, false)?;
+        self.free_table_clusters(snapshot.l1_table_offset, u64::from(snapshot.l1_size) * 8)?;
+        self.update_copied_flags()?;
+
+        self.reset_l2_cache();
+        self.flush()
+    }
+
+    // Adds or drops a reference to every cluster reachable from `l1_table`, L2 tables included.
+    fn update_l1_table_refcounts(
+        &mut self,
+        l1_table: &[u64],
+        increment: bool,
+    ) -> std::io::Result<()> {
+        let cluster_size = self.raw_file.cluster_size();
+        for l2_addr in l1_table.iter().copied().filter(|addr| *addr != 0) {
+            let l2_table = self.raw_file.read_pointer_cluster(l2_addr, None)?;
+            for l2_entry in l2_table {
+                if l2_entry & COMPRESSED_FLAG != 0 {
+                    let (offset, size) =
+                        compressed_cluster_range(l2_entry, self.header.cluster_bits);
+                    let mut cluster_addr = offset & !(cluster_size - 1);
+                    while cluster_addr < offset + size {
+                        self.update_cluster_refcount(cluster_addr, increment)?;
+                        cluster_addr += cluster_size;
+                    }
+                } else if l2_entry & L2_TABLE_OFFSET_MASK != 0 {
+                    self.update_cluster_refcount(l2_entry & L2_TABLE_OFFSET_MASK, increment)?;
+                }
+            }
+            self.update_cluster_refcount(l2_addr, increment)?;
+        }
+        Ok(())
+    }
+
+    // Sets the COPIED flag on the L2 entries of the data clusters only used by the image, and
+    // clears it from the ones shared with snapshots. Writes to clusters without the flag are
+    // checked for sharing.
+    fn update_copied_flags(&mut self) -> std::io::Result<()> {
+        let l1_table = self.l1_table.get_values().to_vec();
+        for l2_addr in l1_table.into_iter().filter(|addr| *addr != 0) {
+            let mut l2_table = self.raw_file.read_pointer_cluster(l2_addr, None)?;
+            let mut modified = false;
+            for l2_entry in l2_table.iter_mut() {
+                let cluster_addr = *l2_entry & L2_TABLE_OFFSET_MASK;
+                if *l2_entry & COMPRESSED_FLAG != 0 || cluster_addr == 0 {
+                    continue;
+                }
+                let copied = if self.cluster_refcount(cluster_addr)? == 1 {
+                    CLUSTER_USED_FLAG
+                } else {
+                    0
+                };
+                if *l2_entry & CLUSTER_USED_FLAG != copied {
+                    *l2_entry = (*l2_entry & !CLUSTER_USED_FLAG) | copied;
+                    modified = true;
+                }
+            }
+            if modified {
+                self.raw_file.write_pointer_table(l2_addr, &l2_table, 0)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Counts the references to every cluster of the file, returning the problems found along with
+    // the expected refcount of each cluster whose refcount is wrong.
+    fn find_cluster_references(
+        &mut self,
+        repair: bool,
+    ) -> io::Result<(CheckResult, Vec<(u64, u16)>)> {
+        let cluster_size = self.raw_file.cluster_size();
+        let file_size = self.raw_file.file_mut().metadata()?.len();
+        let mut refs = ClusterReferences::new(cluster_size, file_size);
+        let mut result = CheckResult::default();
+
+        // The header, the refcount table and the refcount blocks.
+        refs.add_range(0, cluster_size);
+        refs.add_range(
+            self.header.refcount_table_offset,
+            u64::from(self.header.refcount_table_clusters) * cluster_size,
+        );
+        for refblock_addr in self.refcounts.ref_table().to_vec() {
+            if refblock_addr == 0 {
+                continue;
+            }
+            if refs.is_valid(refblock_addr) {
+                refs.add(refblock_addr);
+            } else {
+                result.invalid_entries += 1;
+            }
+        }
+
+        // The snapshot table, and the tables of the snapshots.
+        if self.header.snapshots_offset != 0 {
+            refs.add_range(
+                self.header.snapshots_offset,
+                snapshot_table(&self.snapshots)?.len() as u64,
+            );
+        }
+        for snapshot in self.snapshots.clone() {
+            result.invalid_entries += self.check_l1_table(
+                &mut refs,
+                snapshot.l1_table_offset,
+                u64::from(snapshot.l1_size),
+                repair,
+            )?;
+        }
+
+        // The active L1 table. The cached tables are reloaded once repaired, as the L2 tables can
+        // be shared with the snapshots.
+        result.invalid_entries += self.check_l1_table(
+            &mut refs,
+            self.header.l1_table_offset,
+            u64::from(self.header.l1_size),
+            repair,
+        )?;
+        if repair && result.invalid_entries > 0 {
+            let l1_size = self.l1_table.len() as u64;
+            self.l1_table = VecCache::from_vec(self.raw_file.read_pointer_table(
+                self.header.l1_table_offset,
+                l1_size,
+                Some(L1_TABLE_OFFSET_MASK),
+            )?);
+            self.reset_l2_cache();
+        }
+
+        // Only the data clusters referenced once can be written in place.
+        let l1_table = self.l1_table.get_values().to_vec();
+        for l2_addr in l1_table.into_iter().filter(|addr| refs.is_valid(*addr)) {
+            for l2_entry in self.raw_file.read_pointer_cluster(l2_addr, None)? {
+                let cluster_addr = l2_entry & L2_TABLE_OFFSET_MASK;
+                if l2_entry & COMPRESSED_FLAG != 0 || !refs.is_valid(cluster_addr) {
+                    continue;
+                }
+                let copied = refs.get(cluster_addr) == 1;
+                if (l2_entry & CLUSTER_USED_FLAG != 0) != copied {
+                    result.copied_flag_mismatches += 1;
+                }
+            }
+        }
+
+        let mut mismatches = Vec::new();
+        let max_valid_cluster_offset = self.refcounts.max_valid_cluster_offset();
+        for (index, expected) in refs.refcounts.iter().copied().enumerate() {
+            let cluster_addr = index as u64 * cluster_size;
+            let refcount = if cluster_addr <= max_valid_cluster_offset {
+                self.cluster_refcount(cluster_addr)?
+            } else {
+                0
+            };
+            if refcount < expected {
+                result.corruptions += 1;
+            } else if refcount > expected {
+                result.leaks += 1;
+            }
+            if refcount != expected {
+                mismatches.push((cluster_addr, expected));
+            }
+        }
+
+        Ok((result, mismatches))
+    }
+
+    // Adds the references held by the L1 table found at `l1_table_offset` and by its L2 tables to
+    // `refs`. Returns the number of invalid entries, which are dropped from the tables if `repair`
+    // is set.
+    fn check_l1_table(
+        &mut self,
+        refs: &mut ClusterReferences,
+        l1_table_offset: u64,
+        l1_size: u64,
+        repair: bool,
+    ) -> io::Result<u64> {
+        if l1_size == 0 {
+            return Ok(0);
+        }
+        refs.add_range(l1_table_offset, l1_size * size_of::<u64>() as u64);
+
+        let mut l1_table = self.raw_file.read_pointer_table(
+            l1_table_offset,
+            l1_size,
+            Some(L1_TABLE_OFFSET_MASK),
+        )?;
+        let mut invalid_entries = 0;
+        let mut l1_modified = false;
+        for l2_addr in l1_table.iter_mut().filter(|addr| **addr != 0) {
+            if !refs.is_valid(*l2_addr) {
+                invalid_entries += 1;
+                *l2_addr = 0;
+                l1_modified = true;
+                continue;
+            }
+            refs.add(*l2_addr);
+
+            let mut l2_table = self.raw_file.read_pointer_cluster(*l2_addr, None)?;
+            let mut l2_modified = false;
+            for l2_entry in l2_table.iter_mut() {
+                if *l2_entry & COMPRESSED_FLAG != 0 {
+                    let (offset, size) =
+                        compressed_cluster_range(*l2_entry, self.header.cluster_bits);
+                    if refs.contains(offset, size) {
+                        refs.add_range(offset, size);
+                        continue;
+                    }
+                } else {
+                    let cluster_addr = *l2_entry & L2_TABLE_OFFSET_MASK;
+                    if cluster_addr == 0 {
+                        continue;
+                    }
+                    if refs.is_valid(cluster_addr) {
+                        refs.add(cluster_addr);
+                        continue;
+                    }
+                }
+                invalid_entries += 1;
+                *l2_entry = 0;
+                l2_modified = true;
+            }
+            if repair && l2_modified {
+                self.raw_file.write_pointer_table(*l2_addr, &l2_table, 0)?;
+            }
+        }
+        if repair && l1_modified {
+            self.raw_file
+                .write_pointer_table(l1_table_offset, &l1_table, 0)?;
+        }
+
+        Ok(invalid_entries)
+    }
+
+    // Sets the refcount of each cluster in `refcounts`, then the COPIED flags accordingly.
+    fn repair_refcounts(&mut self, refcounts: &[(u64, u16)]) -> io::Result<()> {
+        // Refcount blocks allocated along the way are appended to the file, the clusters found
+        // free so far could still be in use.
+        self.avail_clusters.clear();
+        self.unref_clusters.clear();
+        for (cluster_addr, refcount) in refcounts.iter().copied() {
+            let mut newly_unref = self.set_cluster_refcount(cluster_addr, refcount)?;
+            self.unref_clusters.append(&mut newly_unref);
+        }
+        self.sync_caches()?;
+
+        self.update_copied_flags()?;
+        self.reset_l2_cache();
+        self.raw_file.file_mut().sync_all()
+    }
+
+    // Writes a copy of `l1_table` to new clusters, returning its offset.
+    fn write_l1_table_copy(&mut self, l1_table: &[u64]) -> std::io::Result<u64> {
+        if l1_table.is_empty() {
+            return Ok(0);
+        }
+        let cluster_size = self.raw_file.cluster_size();
+        let clusters = div_round_up_u64(l1_table.len() as u64 * 8, cluster_size);
+        let offset = self.append_clusters(clusters)?;
+        self.raw_file.write_pointer_table(offset, l1_table, 0)?;
+        Ok(offset)
+    }
+
+    // Replaces the snapshot table with one listing `snapshots`.
+    fn write_snapshot_table(&mut self, snapshots: Vec<QcowSnapshot>) -> std::io::Result<()> {
+        let cluster_size = self.raw_file.cluster_size();
+        let old_table_offset = self.header.snapshots_offset;
+        let old_table_size = snapshot_table(&self.snapshots)?.len() as u64;
+
+        let table = snapshot_table(&snapshots)?;
+        let table_offset = if table.is_empty() {
+            0
+        } else {
+            let offset =
+                self.append_clusters(div_round_up_u64(table.len() as u64, cluster_size))?;
+            let file = self.raw_file.file_mut();
+            file.seek(SeekFrom::Start(offset))?;
+            file.write_all(&table)?;
+            offset
+        };
+        // The new table and its refcounts must be on disk before the header points to it.
+        self.sync_caches()?;
+
+        self.header.nb_snapshots = snapshots.len() as u32;
+        self.header.snapshots_offset = table_offset;
+        self.write_header_tables()?;
+        self.snapshots = snapshots;
+
+        self.free_table_clusters(old_table_offset, old_table_size)
+    }
+
+    // Drops the reference to the clusters of the `size` bytes long table found at `offset`.
+    fn free_table_clusters(&mut self, offset: u64, size: u64) -> std::io::Result<()> {
+        if offset == 0 {
+            return Ok(());
+        }
+        let cluster_size = self.raw_file.cluster_size();
+        for i in 0..div_round_up_u64(size, cluster_size) {
+            self.update_cluster_refcount(offset + i * cluster_size, false)?;
+        }
+        Ok(())
+    }
+
+    // Allocates `count` contiguous clusters at the end of the file, for tables that can't be
+    // split across the free clusters of the file. Returns the offset of the first one.
+    fn append_clusters(&mut self, count: u64) -> std::io::Result<u64> {
+        let cluster_size = self.raw_file.cluster_size();
+        let max_valid_cluster_offset = self.refcounts.max_valid_cluster_offset();
+        let mut first_cluster = None;
+        for _ in 0..count {
+            match self.raw_file.add_cluster_end(max_valid_cluster_offset)? {
+                Some(addr) => {
+                    first_cluster.get_or_insert(addr);
+                }
+                None => {
+                    error!("No free clusters in append_clusters()");
+                    return Err(std::io::Error::from_raw_os_error(ENOSPC));
+                }
+            }
+        }
+        let first_cluster =
+            first_cluster.ok_or_else(|| std::io::Error::from_raw_os_error(EINVAL))?;
+
+        // Refcounts are only set once all the clusters are allocated, as new refcount blocks are
+        // appended to the file as well.
+        for i in 0..count {
+            self.update_cluster_refcount(first_cluster + i * cluster_size, true)?;
+        }
+        Ok(first_cluster)
+    }
+
+    // Updates the header fields describing the active L1 table and the snapshot table.
+    fn write_header_tables(&mut self) -> std::io::Result<()> {
+        let header = self.header;
+        let file = self.raw_file.file_mut();
+        file.seek(SeekFrom::Start(HEADER_SIZE_OFFSET))?;
+        file.write_u64::<BigEndian>(header.size)?;
+        file.seek(SeekFrom::Start(HEADER_L1_SIZE_OFFSET))?;
+        file.write_u32::<BigEndian>(header.l1_size)?;
+        file.write_u64::<BigEndian>(header.l1_table_offset)?;
+        file.seek(SeekFrom::Start(HEADER_NB_SNAPSHOTS_OFFSET))?;
+        file.write_u32::<BigEndian>(header.nb_snapshots)?;
+        file.write_u64::<BigEndian>(header.snapshots_offset)?;
+        file.sync_data()
+    }
+
+    // Drops the cached L2 tables, once their content changed on disk. They must be clean.
+    fn reset_l2_cache(&mut self) {
+        self.l2_cache = CacheMap::new(L2_CACHE_SIZE);
+        self.decompressed_cluster = None;
+    }
+
     fn find_avail_clusters(&mut self) -> Result<()> {
         let cluster_size = self.raw_file.cluster_size();
 
@@ -726,12 +1585,21 @@ impl QcowFile {
                         .read_pointer_table(
                             l2_addr_disk,
                             cluster_size / size_of::<u64>() as u64,
-                            Some(L2_TABLE_OFFSET_MASK),
+                            None,
                         )
                         .map_err(Error::ReadingPointers)?;
-                    for data_cluster_addr in l2_table {
-                        if data_cluster_addr != 0 {
-                            add_ref(refcounts, cluster_size, data_cluster_addr)?;
+                    for l2_entry in l2_table {
+                        if l2_entry & COMPRESSED_FLAG != 0 {
+                            // Compressed data holds a reference on every cluster it spans.
+                            let (offset, size) =
+                                compressed_cluster_range(l2_entry, header.cluster_bits);
+                            let mut data_cluster_addr = offset & !(cluster_size - 1);
+                            while data_cluster_addr < offset + size {
+                                add_ref(refcounts, cluster_size, data_cluster_addr)?;
+                                data_cluster_addr += cluster_size;
+                            }
+                        } else if l2_entry & L2_TABLE_OFFSET_MASK != 0 {
+                            add_ref(refcounts, cluster_size, l2_entry & L2_TABLE_OFFSET_MASK)?;
                         }
                     }
                 }
@@ -944,9 +1812,9 @@ impl QcowFile {
         (address / self.raw_file.cluster_size()) % self.l2_entries
     }
 
-    // Gets the offset of the given guest address in the host file. If L1, L2, or data clusters have
-    // yet to be allocated, return None.
-    fn file_offset_read(&mut self, address: u64) -> std::io::Result<Option<u64>> {
+    // Gets the L2 table entry of the cluster containing the given guest address. If L1, L2, or data
+    // clusters have yet to be allocated, return 0.
+    fn l2_entry(&mut self, address: u64) -> std::io::Result<u64> {
         if address >= self.virtual_size() as u64 {
             return Err(std::io::Error::from_raw_os_error(EINVAL));
         }
@@ -959,7 +1827,7 @@ impl QcowFile {
 
         if l2_addr_disk == 0 {
             // Reading from an unallocated cluster will return zeros.
-            return Ok(None);
+            return Ok(0);
         }
 
         let l2_index = self.l2_table_index(address) as usize;
@@ -972,19 +1840,11 @@ impl QcowFile {
             let l1_table = &self.l1_table;
             let raw_file = &mut self.raw_file;
             self.l2_cache.insert(l1_index, table, |index, evicted| {
-                raw_file.write_pointer_table(
-                    l1_table[index],
-                    evicted.get_values(),
-                    CLUSTER_USED_FLAG,
-                )
+                raw_file.write_pointer_table(l1_table[index], evicted.get_values(), 0)
             })?;
         };
 
-        let cluster_addr = self.l2_cache.get(l1_index).unwrap()[l2_index];
-        if cluster_addr == 0 {
-            return Ok(None);
-        }
-        Ok(Some(cluster_addr + self.raw_file.cluster_offset(address)))
+        Ok(self.l2_cache.get(l1_index).unwrap()[l2_index] & !CLUSTER_USED_FLAG)
     }
 
     // Gets the offset of the given guest address in the host file. If L1, L2, or data clusters need
@@ -1019,22 +1879,72 @@ impl QcowFile {
             let l1_table = &self.l1_table;
             let raw_file = &mut self.raw_file;
             self.l2_cache.insert(l1_index, l2_table, |index, evicted| {
-                raw_file.write_pointer_table(
-                    l1_table[index],
-                    evicted.get_values(),
-                    CLUSTER_USED_FLAG,
-                )
+                raw_file.write_pointer_table(l1_table[index], evicted.get_values(), 0)
             })?;
         }
 
         let cluster_addr = match self.l2_cache.get(l1_index).unwrap()[l2_index] {
             0 => {
+                // The new cluster starts with the data of the backing file, if any, so that
+                // a partial write doesn't hide the rest of the cluster.
+                let initial_data = if let Some(backing_file) = self.backing_file.as_mut() {
+                    let cluster_size = self.raw_file.cluster_size();
+                    let cluster_begin = address - (address % cluster_size);
+                    let mut cluster_data = vec![0u8; cluster_size as usize];
+                    backing_file.read_exact_at(cluster_begin, &mut cluster_data)?;
+                    Some(cluster_data)
+                } else {
+                    None
+                };
                 // Need to allocate a data cluster
-                let cluster_addr = self.append_data_cluster()?;
-                self.update_cluster_addr(l1_index, l2_index, cluster_addr, &mut set_refcounts)?;
+                let cluster_addr = self.append_data_cluster(initial_data)?;
+                self.update_cluster_addr(
+                    l1_index,
+                    l2_index,
+                    cluster_addr | CLUSTER_USED_FLAG,
+                    &mut set_refcounts,
+                )?;
                 cluster_addr
             }
-            a => a,
+            l2_entry if l2_entry & COMPRESSED_FLAG != 0 => {
+                // Compressed clusters are never written in place, their data moves to a new
+                // uncompressed cluster.
+                let initial_data = self.decompress_cluster(l2_entry)?.to_vec();
+                let cluster_addr = self.append_data_cluster(Some(initial_data))?;
+                self.update_cluster_addr(
+                    l1_index,
+                    l2_index,
+                    cluster_addr | CLUSTER_USED_FLAG,
+                    &mut set_refcounts,
+                )?;
+                self.unref_compressed_cluster(l2_entry)?;
+                cluster_addr
+            }
+            l2_entry if l2_entry & CLUSTER_USED_FLAG == 0 => {
+                // Without the COPIED flag, the cluster may be shared with a snapshot, in which
+                // case the write goes to a copy of it.
+                let cluster_addr = l2_entry & L2_TABLE_OFFSET_MASK;
+                let new_cluster_addr = if self.cluster_refcount(cluster_addr)? > 1 {
+                    let mut cluster_data = vec![0u8; self.raw_file.cluster_size() as usize];
+                    self.raw_file
+                        .file_mut()
+                        .seek(SeekFrom::Start(cluster_addr))?;
+                    self.raw_file.file_mut().read_exact(&mut cluster_data)?;
+                    let new_cluster_addr = self.append_data_cluster(Some(cluster_data))?;
+                    self.update_cluster_refcount(cluster_addr, false)?;
+                    new_cluster_addr
+                } else {
+                    cluster_addr
+                };
+                self.update_cluster_addr(
+                    l1_index,
+                    l2_index,
+                    new_cluster_addr | CLUSTER_USED_FLAG,
+                    &mut set_refcounts,
+                )?;
+                new_cluster_addr
+            }
+            l2_entry => l2_entry & L2_TABLE_OFFSET_MASK,
         };
 
         for (addr, count) in set_refcounts {
@@ -1060,8 +1970,9 @@ impl QcowFile {
             // The index must be valid from when it was insterted.
             let addr = self.l1_table[l1_index];
             if addr != 0 {
-                self.unref_clusters.push(addr);
-                set_refcounts.push((addr, 0));
+                // The previous table may still be used by a snapshot, so only the reference
+                // held by the image goes away.
+                self.update_cluster_refcount(addr, false)?;
             }
 
             // Allocate a new cluster to store the L2 table and update the L1 table to point
@@ -1097,8 +2008,11 @@ impl QcowFile {
 
     // Allocate and initialize a new data cluster. Returns the offset of the
     // cluster in to the file on success.
-    fn append_data_cluster(&mut self) -> std::io::Result<u64> {
+    fn append_data_cluster(&mut self, initial_data: Option<Vec<u8>>) -> std::io::Result<u64> {
         let new_addr: u64 = self.get_new_cluster()?;
+        if let Some(initial_data) = initial_data {
+            self.raw_file.write_cluster(new_addr, initial_data)?;
+        }
         // The cluster refcount starts at one indicating it is used but doesn't need COW.
         let mut newly_unref = self.set_cluster_refcount(new_addr, 1)?;
         self.unref_clusters.append(&mut newly_unref);
@@ -1131,11 +2045,7 @@ impl QcowFile {
             let l1_table = &self.l1_table;
             let raw_file = &mut self.raw_file;
             self.l2_cache.insert(l1_index, table, |index, evicted| {
-                raw_file.write_pointer_table(
-                    l1_table[index],
-                    evicted.get_values(),
-                    CLUSTER_USED_FLAG,
-                )
+                raw_file.write_pointer_table(l1_table[index], evicted.get_values(), 0)
             })?;
         }
 
@@ -1144,20 +2054,27 @@ impl QcowFile {
         Ok(cluster_addr != 0)
     }
 
-    // Find the first guest address greater than or equal to `address` whose allocation state
-    // matches `allocated`.
-    fn find_allocated_cluster(
-        &mut self,
-        address: u64,
-        allocated: bool,
-    ) -> std::io::Result<Option<u64>> {
+    // Returns true if the cluster containing `address` reads as data. Without a backing file,
+    // unallocated clusters read as zeroes and are holes. With one, they hold the backing file
+    // data, so every cluster is data.
+    fn cluster_has_data(&mut self, address: u64) -> std::io::Result<bool> {
+        if self.backing_file.is_some() {
+            return Ok(true);
+        }
+
+        self.cluster_allocated(address)
+    }
+
+    // Find the first guest address greater than or equal to `address` which is data if `data`
+    // is true, or a hole otherwise.
+    fn find_allocated_cluster(&mut self, address: u64, data: bool) -> std::io::Result<Option<u64>> {
         let size = self.virtual_size();
         if address >= size {
             return Ok(None);
         }
 
-        // If offset is already within a hole, return it.
-        if self.cluster_allocated(address)? == allocated {
+        // If offset is already within the desired kind of range, return it.
+        if self.cluster_has_data(address)? == data {
             return Ok(Some(address));
         }
 
@@ -1165,9 +2082,9 @@ impl QcowFile {
         let cluster_size = self.raw_file.cluster_size();
         let mut cluster_addr = (address / cluster_size + 1) * cluster_size;
 
-        // Search for clusters with the desired allocation state.
+        // Search for clusters of the desired kind.
         while cluster_addr < size {
-            if self.cluster_allocated(cluster_addr)? == allocated {
+            if self.cluster_has_data(cluster_addr)? == data {
                 return Ok(Some(cluster_addr));
             }
             cluster_addr += cluster_size;
@@ -1203,43 +2120,32 @@ impl QcowFile {
             let l1_table = &self.l1_table;
             let raw_file = &mut self.raw_file;
             self.l2_cache.insert(l1_index, table, |index, evicted| {
-                raw_file.write_pointer_table(
-                    l1_table[index],
-                    evicted.get_values(),
-                    CLUSTER_USED_FLAG,
-                )
+                raw_file.write_pointer_table(l1_table[index], evicted.get_values(), 0)
             })?;
         }
 
-        let cluster_addr = self.l2_cache.get(l1_index).unwrap()[l2_index];
-        if cluster_addr == 0 {
+        let l2_entry = self.l2_cache.get(l1_index).unwrap()[l2_index];
+        if l2_entry == 0 {
             // This cluster is already unallocated; nothing to do.
             return Ok(());
         }
 
-        // Decrement the refcount.
-        let refcount = self
-            .refcounts
-            .get_cluster_refcount(&mut self.raw_file, cluster_addr)
-            .map_err(|e| {
-                io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    format!("failed to get cluster refcount: {}", e),
-                )
-            })?;
-        if refcount == 0 {
-            return Err(std::io::Error::from_raw_os_error(EINVAL));
+        // Rewrite the L2 entry to remove the cluster mapping. The L2 table may be shared with a
+        // snapshot, so it is updated the same way as on a write.
+        let mut set_refcounts = Vec::new();
+        self.update_cluster_addr(l1_index, l2_index, 0, &mut set_refcounts)?;
+        for (addr, count) in set_refcounts {
+            let mut newly_unref = self.set_cluster_refcount(addr, count)?;
+            self.unref_clusters.append(&mut newly_unref);
         }
 
-        let new_refcount = refcount - 1;
-        let mut newly_unref = self.set_cluster_refcount(cluster_addr, new_refcount)?;
-        self.unref_clusters.append(&mut newly_unref);
-
-        // Rewrite the L2 entry to remove the cluster mapping.
-        // unwrap is safe as we just checked/inserted this entry.
-        self.l2_cache.get_mut(l1_index).unwrap()[l2_index] = 0;
+        if l2_entry & COMPRESSED_FLAG != 0 {
+            return self.unref_compressed_cluster(l2_entry);
+        }
 
-        if new_refcount == 0 {
+        // Decrement the refcount.
+        let cluster_addr = l2_entry & L2_TABLE_OFFSET_MASK;
+        if self.update_cluster_refcount(cluster_addr, false)? == 0 {
             let cluster_size = self.raw_file.cluster_size();
             // This cluster is no longer in use; deallocate the storage.
             // The underlying FS may not support FALLOC_FL_PUNCH_HOLE,
@@ -1248,7 +2154,6 @@ impl QcowFile {
                 .raw_file
                 .file_mut()
                 .punch_hole(cluster_addr, cluster_size);
-            self.unref_clusters.push(cluster_addr);
         }
         Ok(())
     }
@@ -1263,15 +2168,23 @@ impl QcowFile {
             let curr_addr = address + nwritten as u64;
             let count = self.limit_range_cluster(curr_addr, write_count - nwritten);
 
-            if count == self.raw_file.cluster_size() as usize {
+            if self.backing_file.is_some() {
+                // Unallocated clusters read from the backing file, so the range has to be
+                // allocated and zeroed for it to read back as zeroes.
+                let offset = self.file_offset_write(curr_addr)?;
+                self.raw_file.file_mut().seek(SeekFrom::Start(offset))?;
+                self.raw_file.file_mut().write_zeroes(count)?;
+            } else if count == self.raw_file.cluster_size() as usize {
                 // Full cluster - deallocate the storage.
                 self.deallocate_cluster(curr_addr)?;
             } else {
                 // Partial cluster - zero out the relevant bytes if it was allocated.
                 // Any space in unallocated clusters can be left alone, since
                 // unallocated clusters already read back as zeroes.
-                if let Some(offset) = self.file_offset_read(curr_addr)? {
-                    // Partial cluster - zero it out.
+                if self.l2_entry(curr_addr)? != 0 {
+                    // Partial cluster - zero it out. A compressed cluster is moved to a new
+                    // cluster first.
+                    let offset = self.file_offset_write(curr_addr)?;
                     self.raw_file.file_mut().seek(SeekFrom::Start(offset))?;
                     self.raw_file.file_mut().write_zeroes(count)?;
                 }
@@ -1282,19 +2195,113 @@ impl QcowFile {
         Ok(())
     }
 
-    // Reads an L2 cluster from the disk, returning an error if the file can't be read or if any
-    // cluster is compressed.
+    // Returns the data of the compressed cluster described by `l2_entry`.
+    fn decompress_cluster(&mut self, l2_entry: u64) -> std::io::Result<&[u8]> {
+        let cached = match &self.decompressed_cluster {
+            Some((entry, _)) => *entry == l2_entry,
+            None => false,
+        };
+
+        if !cached {
+            let (offset, size) = compressed_cluster_range(l2_entry, self.header.cluster_bits);
+            let mut compressed = vec![0u8; size as usize];
+            let file = self.raw_file.file_mut();
+            file.seek(SeekFrom::Start(offset))?;
+            // The compressed size is rounded up to a sector, which can go past the end of the
+            // file for the last cluster of the image.
+            let mut len = 0;
+            while len < compressed.len() {
+                match file.read(&mut compressed[len..])? {
+                    0 => break,
+                    n => len += n,
+                }
+            }
+
+            let mut cluster = vec![0u8; self.raw_file.cluster_size() as usize];
+            // Clusters are compressed with raw deflate, without any zlib header.
+            let mut decompress = Decompress::new(false);
+            decompress
+                .decompress(&compressed[..len], &mut cluster, FlushDecompress::Finish)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            if decompress.total_out() != cluster.len() as u64 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "truncated compressed cluster",
+                ));
+            }
+
+            self.decompressed_cluster = Some((l2_entry, cluster));
+        }
+
+        // unwrap is safe as the cache was just filled.
+        Ok(&self.decompressed_cluster.as_ref().unwrap().1)
+    }
+
+    // Drops the references a compressed cluster holds on the clusters storing its data.
+    fn unref_compressed_cluster(&mut self, l2_entry: u64) -> std::io::Result<()> {
+        let (offset, size) = compressed_cluster_range(l2_entry, self.header.cluster_bits);
+        let cluster_size = self.raw_file.cluster_size();
+
+        let mut cluster_addr = offset & !(cluster_size - 1);
+        while cluster_addr < offset + size {
+            // Other compressed clusters may share it, so the cluster is only freed once the last
+            // of them is gone.
+            self.update_cluster_refcount(cluster_addr, false)?;
+            cluster_addr += cluster_size;
+        }
+
+        Ok(())
+    }
+
+    // Reads an L2 cluster from the disk, returning an error if the file can't be read. Compressed
+    // cluster entries are kept as is, as their layout differs from the other entries. The other
+    // entries keep the COPIED flag, telling the cluster isn't shared with a snapshot.
     fn read_l2_cluster(raw_file: &mut QcowRawFile, cluster_addr: u64) -> std::io::Result<Vec<u64>> {
         let file_values = raw_file.read_pointer_cluster(cluster_addr, None)?;
-        if file_values.iter().any(|entry| entry & COMPRESSED_FLAG != 0) {
-            return Err(std::io::Error::from_raw_os_error(ENOTSUP));
-        }
         Ok(file_values
             .iter()
-            .map(|entry| *entry & L2_TABLE_OFFSET_MASK)
+            .map(|entry| {
+                if entry & COMPRESSED_FLAG != 0 {
+                    *entry & !CLUSTER_USED_FLAG
+                } else {
+                    *entry & (L2_TABLE_OFFSET_MASK | CLUSTER_USED_FLAG)
+                }
+            })
             .collect())
     }
 
+    // Returns the refcount of the cluster at `address`.
+    fn cluster_refcount(&mut self, address: u64) -> std::io::Result<u16> {
+        self.refcounts
+            .get_cluster_refcount(&mut self.raw_file, address)
+            .map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("failed to get cluster refcount: {}", e),
+                )
+            })
+    }
+
+    // Adds or drops a reference to the cluster at `address`, returning its new refcount. Clusters
+    // can be shared between the image and its snapshots, a cluster is only freed once its last
+    // reference is gone.
+    fn update_cluster_refcount(&mut self, address: u64, increment: bool) -> std::io::Result<u16> {
+        let refcount = self.cluster_refcount(address)?;
+        let new_refcount = if increment {
+            refcount.checked_add(1)
+        } else {
+            refcount.checked_sub(1)
+        }
+        .ok_or_else(|| std::io::Error::from_raw_os_error(EINVAL))?;
+
+        let mut newly_unref = self.set_cluster_refcount(address, new_refcount)?;
+        self.unref_clusters.append(&mut newly_unref);
+        if new_refcount == 0 {
+            self.unref_clusters.push(address);
+        }
+        Ok(new_refcount)
+    }
+
     // Set the refcount for a cluster with the given address.
     // Returns a list of any refblocks that can be reused, this happens when a refblock is moved,
     // the old location can be reused.
@@ -1353,16 +2360,13 @@ impl QcowFile {
     }
 
     fn sync_caches(&mut self) -> std::io::Result<()> {
-        // Write out all dirty L2 tables.
+        // Write out all dirty L2 tables. Their entries already carry the COPIED flag when needed.
         for (l1_index, l2_table) in self.l2_cache.iter_mut().filter(|(_k, v)| v.dirty()) {
             // The index must be valid from when we insterted it.
             let addr = self.l1_table[*l1_index];
             if addr != 0 {
-                self.raw_file.write_pointer_table(
-                    addr,
-                    l2_table.get_values(),
-                    CLUSTER_USED_FLAG,
-                )?;
+                self.raw_file
+                    .write_pointer_table(addr, l2_table.get_values(), 0)?;
             } else {
                 return Err(std::io::Error::from_raw_os_error(EINVAL));
             }
@@ -1408,14 +2412,23 @@ impl Read for QcowFile {
         let mut nread: usize = 0;
         while nread < read_count {
             let curr_addr = address + nread as u64;
-            let file_offset = self.file_offset_read(curr_addr)?;
+            let l2_entry = self.l2_entry(curr_addr)?;
             let count = self.limit_range_cluster(curr_addr, read_count - nread);
 
-            if let Some(offset) = file_offset {
+            if l2_entry & COMPRESSED_FLAG != 0 {
+                let cluster_offset = self.raw_file.cluster_offset(curr_addr) as usize;
+                let cluster_data = self.decompress_cluster(l2_entry)?;
+                buf[nread..(nread + count)]
+                    .copy_from_slice(&cluster_data[cluster_offset..(cluster_offset + count)]);
+            } else if l2_entry != 0 {
+                let offset = l2_entry + self.raw_file.cluster_offset(curr_addr);
                 self.raw_file.file_mut().seek(SeekFrom::Start(offset))?;
                 self.raw_file
                     .file_mut()
                     .read_exact(&mut buf[nread..(nread + count)])?;
+            } else if let Some(backing_file) = self.backing_file.as_mut() {
+                // Previously unwritten region, read it from the backing file
+                backing_file.read_exact_at(curr_addr, &mut buf[nread..(nread + count)])?;
             } else {
                 // Previously unwritten region, return zeros
                 for b in &mut buf[nread..(nread + count)] {
@@ -1512,6 +2525,23 @@ impl FileSetLen for QcowFile {
     }
 }
 
+impl DiskResize for QcowFile {
+    fn resize(&mut self, size: u64) -> std::io::Result<()> {
+        QcowFile::resize(self, size)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))
+    }
+}
+
+impl DiskIovecs for QcowFile {
+    unsafe fn read_iovecs(&mut self, iovecs: &[libc::iovec]) -> io::Result<usize> {
+        read_iovecs_bounced(self, iovecs)
+    }
+
+    unsafe fn write_iovecs(&mut self, iovecs: &[libc::iovec]) -> io::Result<usize> {
+        write_iovecs_bounced(self, iovecs)
+    }
+}
+
 impl PunchHole for QcowFile {
     fn punch_hole(&mut self, offset: u64, length: u64) -> std::io::Result<()> {
         let mut remaining = length;
@@ -1556,6 +2586,180 @@ impl SeekHole for QcowFile {
     }
 }
 
+/// Image holding the data of the clusters a qcow2 overlay hasn't allocated. Backing files are
+/// only ever read from.
+#[derive(Clone, Debug)]
+enum BackingFile {
+    Raw(RawFile),
+    Qcow(Box<QcowFile>),
+}
+
+impl BackingFile {
+    fn open(path: &Path, nesting_depth: u32) -> Result<BackingFile> {
+        let file = OpenOptions::new()
+            .read(true)
+            .open(path)
+            .map_err(Error::BackingFileIo)?;
+        let mut raw_file = RawFile::new(file, false);
+
+        match detect_image_type(&mut raw_file)? {
+            ImageType::Raw => Ok(BackingFile::Raw(raw_file)),
+            ImageType::Qcow2 => Ok(BackingFile::Qcow(Box::new(
+                QcowFile::from_with_nesting_depth(raw_file, nesting_depth)?,
+            ))),
+        }
+    }
+
+    fn size(&mut self) -> io::Result<u64> {
+        match self {
+            BackingFile::Raw(file) => file.seek(SeekFrom::End(0)),
+            BackingFile::Qcow(file) => Ok(file.virtual_size()),
+        }
+    }
+
+    // Fills `buf` with the data found at `address`. The part of `buf` beyond the end of the
+    // backing file reads as zeroes, as the overlay can be larger than its backing file.
+    fn read_exact_at(&mut self, address: u64, buf: &mut [u8]) -> io::Result<()> {
+        let size = self.size()?;
+        let count = if address < size {
+            min(buf.len() as u64, size - address) as usize
+        } else {
+            0
+        };
+
+        if count > 0 {
+            let (data, _) = buf.split_at_mut(count);
+            match self {
+                BackingFile::Raw(file) => {
+                    file.seek(SeekFrom::Start(address))?;
+                    file.read_exact(data)?;
+                }
+                BackingFile::Qcow(file) => {
+                    file.seek(SeekFrom::Start(address))?;
+                    file.read_exact(data)?;
+                }
+            }
+        }
+        for b in &mut buf[count..] {
+            *b = 0;
+        }
+
+        Ok(())
+    }
+}
+
+// Reads the name of the backing file referenced by the header.
+fn read_backing_file_name(file: &mut RawFile, header: &QcowHeader) -> Result<String> {
+    if header.backing_file_size > MAX_BACKING_FILE_SIZE {
+        return Err(Error::BackingFileTooLong(header.backing_file_size as usize));
+    }
+
+    let mut name = vec![0u8; header.backing_file_size as usize];
+    file.seek(SeekFrom::Start(header.backing_file_offset))
+        .map_err(Error::ReadingHeader)?;
+    file.read_exact(&mut name).map_err(Error::ReadingHeader)?;
+
+    String::from_utf8(name).map_err(|e| Error::InvalidBackingFileName(e.utf8_error()))
+}
+
+// Relative backing file names are relative to the directory of the image referencing them, like
+// qemu does. That directory is found through the path the image file descriptor was opened with.
+fn backing_file_path(file: &RawFile, backing_file_name: &str) -> PathBuf {
+    let backing_file_path = Path::new(backing_file_name);
+    if backing_file_path.is_absolute() {
+        return backing_file_path.to_path_buf();
+    }
+
+    match fs::read_link(format!("/proc/self/fd/{}", file.as_raw_fd())) {
+        Ok(image_path) => match image_path.parent() {
+            Some(image_dir) => image_dir.join(backing_file_path),
+            None => backing_file_path.to_path_buf(),
+        },
+        Err(_) => backing_file_path.to_path_buf(),
+    }
+}
+
+// Returns the offset and size, in the image file, of the data of the compressed cluster described
+// by `l2_entry`.
+fn compressed_cluster_range(l2_entry: u64, cluster_bits: u32) -> (u64, u64) {
+    let offset_bits = 62 - (cluster_bits - 8);
+    let offset = l2_entry & ((0x01 << offset_bits) - 1);
+    let sectors = ((l2_entry >> offset_bits) & ((0x01 << (cluster_bits - 8)) - 1)) + 1;
+    (offset, sectors * 512 - (offset & 511))
+}
+
+// Number of references to each cluster of an image file, as found in its tables.
+struct ClusterReferences {
+    cluster_size: u64,
+    refcounts: Vec<u16>,
+}
+
+impl ClusterReferences {
+    fn new(cluster_size: u64, file_size: u64) -> Self {
+        ClusterReferences {
+            cluster_size,
+            refcounts: vec![0; div_round_up_u64(file_size, cluster_size) as usize],
+        }
+    }
+
+    // Returns true if `address` is the start of a cluster of the file.
+    fn is_valid(&self, address: u64) -> bool {
+        address % self.cluster_size == 0
+            && address / self.cluster_size < self.refcounts.len() as u64
+    }
+
+    // Returns true if the `size` bytes at `offset` are within the file.
+    fn contains(&self, offset: u64, size: u64) -> bool {
+        offset.checked_add(size).map_or(false, |end| {
+            end <= self.refcounts.len() as u64 * self.cluster_size
+        })
+    }
+
+    fn get(&self, address: u64) -> u16 {
+        self.refcounts[(address / self.cluster_size) as usize]
+    }
+
+    fn add(&mut self, address: u64) {
+        let refcount = &mut self.refcounts[(address / self.cluster_size) as usize];
+        *refcount = refcount.saturating_add(1);
+    }
+
+    // Adds a reference to every cluster of the file spanned by the `size` bytes at `offset`.
+    fn add_range(&mut self, offset: u64, size: u64) {
+        let mut address = offset - offset % self.cluster_size;
+        while address < offset.saturating_add(size) && self.is_valid(address) {
+            self.add(address);
+            address += self.cluster_size;
+        }
+    }
+}
+
+// Reads the snapshot table described by `header`.
+fn read_snapshot_table(file: &mut RawFile, header: &QcowHeader) -> io::Result<Vec<QcowSnapshot>> {
+    let mut snapshots = Vec::with_capacity(header.nb_snapshots as usize);
+    if header.nb_snapshots != 0 {
+        file.seek(SeekFrom::Start(header.snapshots_offset))?;
+    }
+    for _ in 0..header.nb_snapshots {
+        snapshots.push(QcowSnapshot::read_from(file, header.size)?);
+    }
+    Ok(snapshots)
+}
+
+// Serializes `snapshots` in the snapshot table format.
+fn snapshot_table(snapshots: &[QcowSnapshot]) -> io::Result<Vec<u8>> {
+    let mut table = Vec::new();
+    for snapshot in snapshots {
+        snapshot.write_to(&mut table)?;
+    }
+    Ok(table)
+}
+
+// Snapshot table entries are padded to a multiple of 8 bytes.
+fn snapshot_entry_padding(entry_size: usize) -> usize {
+    (8 - entry_size % 8) % 8
+}
+
 // Returns an Error if the given offset doesn't align to a cluster boundary.
 fn offset_is_cluster_boundary(offset: u64, cluster_bits: u32) -> Result<()> {
     if offset & ((0x01 << cluster_bits) - 1) != 0 {
@@ -1701,8 +2905,9 @@ pub fn detect_image_type(file: &mut RawFile) -> Result<ImageType> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use flate2::{Compress, Compression, FlushCompress};
     use std::io::{Read, Seek, SeekFrom, Write};
-    use tempfile::tempfile;
+    use tempfile::{tempdir, tempfile};
     use vm_virtio::RawFile;
 
     fn valid_header_v3() -> Vec<u8> {
@@ -1981,6 +3186,333 @@ mod tests {
         });
     }
 
+    #[test]
+    fn backing_file_read_write() {
+        let dir = tempdir().unwrap();
+
+        // Raw backing file filled with a known pattern.
+        let mut backing = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(dir.path().join("backing.raw"))
+            .unwrap();
+        backing.write_all(&vec![0xaau8; 0x20_0000]).unwrap();
+
+        let overlay = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(dir.path().join("overlay.qcow2"))
+            .unwrap();
+        let mut q = QcowFile::new_from_backing(RawFile::new(overlay, false), 3, "backing.raw")
+            .expect("Failed to create overlay.");
+        assert_eq!(q.virtual_size(), 0x20_0000);
+
+        // Unallocated clusters are read from the backing file.
+        let mut buf = [0u8; 0x10];
+        q.seek(SeekFrom::Start(0x1000)).expect("Failed to seek.");
+        q.read_exact(&mut buf).expect("Failed to read.");
+        assert!(buf.iter().all(|b| *b == 0xaa));
+
+        // A partial write keeps the rest of the cluster from the backing file.
+        q.seek(SeekFrom::Start(0x1004)).expect("Failed to seek.");
+        q.write_all(&[0x55u8; 4]).expect("Failed to write.");
+        q.seek(SeekFrom::Start(0x1000)).expect("Failed to seek.");
+        q.read_exact(&mut buf).expect("Failed to read.");
+        assert_eq!(buf[3], 0xaa);
+        assert_eq!(buf[4], 0x55);
+        assert_eq!(buf[7], 0x55);
+        assert_eq!(buf[8], 0xaa);
+
+        // Discarded ranges read as zeroes, not as the backing file data.
+        q.punch_hole(0x10_0000, 0x1_0000)
+            .expect("Failed to punch hole.");
+        q.seek(SeekFrom::Start(0x10_0000)).expect("Failed to seek.");
+        q.read_exact(&mut buf).expect("Failed to read.");
+        assert!(buf.iter().all(|b| *b == 0));
+
+        // The backing file is left untouched.
+        backing.seek(SeekFrom::Start(0x1004)).unwrap();
+        backing.read_exact(&mut buf[..4]).unwrap();
+        assert!(buf[..4].iter().all(|b| *b == 0xaa));
+        drop(q);
+
+        // The backing file is found again when the overlay is reopened.
+        let overlay = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(dir.path().join("overlay.qcow2"))
+            .unwrap();
+        let mut q = QcowFile::from(RawFile::new(overlay, false)).expect("Failed to open overlay.");
+        q.seek(SeekFrom::Start(0x1000)).expect("Failed to seek.");
+        q.read_exact(&mut buf).expect("Failed to read.");
+        assert_eq!(buf[3], 0xaa);
+        assert_eq!(buf[4], 0x55);
+    }
+
+    #[test]
+    fn convert_backing_file_overlay() {
+        let dir = tempdir().unwrap();
+
+        let mut backing = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(dir.path().join("backing.raw"))
+            .unwrap();
+        let backing_data: Vec<u8> = (0..0x20_0000u32).map(|i| (i % 251) as u8).collect();
+        backing.write_all(&backing_data).unwrap();
+
+        let overlay = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(dir.path().join("overlay.qcow2"))
+            .unwrap();
+        let mut q = QcowFile::new_from_backing(RawFile::new(overlay, false), 3, "backing.raw")
+            .expect("Failed to create overlay.");
+        q.seek(SeekFrom::Start(0x1_1000)).expect("Failed to seek.");
+        q.write_all(&[0x55u8; 0x100]).expect("Failed to write.");
+        q.punch_hole(0x10_0000, 0x1_0000)
+            .expect("Failed to punch hole.");
+        q.flush().expect("Failed to flush.");
+        drop(q);
+
+        let mut expected = backing_data;
+        expected[0x1_1000..0x1_1100]
+            .iter_mut()
+            .for_each(|b| *b = 0x55);
+        expected[0x10_0000..0x11_0000]
+            .iter_mut()
+            .for_each(|b| *b = 0);
+
+        for qcow2 in [false, true].iter() {
+            let src = OpenOptions::new()
+                .read(true)
+                .open(dir.path().join("overlay.qcow2"))
+                .unwrap();
+            let dst = tempfile().unwrap();
+            let dst_type = if *qcow2 {
+                ImageType::Qcow2
+            } else {
+                ImageType::Raw
+            };
+            convert(
+                RawFile::new(src, false),
+                RawFile::new(dst.try_clone().unwrap(), false),
+                dst_type,
+            )
+            .expect("Failed to convert.");
+
+            let mut converted = vec![0u8; expected.len()];
+            if *qcow2 {
+                let mut dst = QcowFile::from(RawFile::new(dst, false)).unwrap();
+                assert!(dst.backing_file_name().unwrap().is_none());
+                dst.seek(SeekFrom::Start(0)).unwrap();
+                dst.read_exact(&mut converted).unwrap();
+            } else {
+                let mut dst = RawFile::new(dst, false);
+                dst.seek(SeekFrom::Start(0)).unwrap();
+                dst.read_exact(&mut converted).unwrap();
+            }
+            assert!(converted == expected, "qcow2: {}", qcow2);
+        }
+    }
+
+    #[test]
+    fn read_write_compressed_cluster() {
+        with_default_file(0x10_0000, false, |mut q| {
+            let cluster_size = q.raw_file.cluster_size();
+            // Allocate the first cluster, to get an L2 table and a data cluster to overwrite.
+            q.write_all(&vec![0u8; cluster_size as usize])
+                .expect("Failed to write.");
+            q.flush().expect("Failed to flush.");
+            let l2_addr = q.l1_table()[0];
+            let data_addr = q.l2_table(0).unwrap().unwrap()[0] & L2_TABLE_OFFSET_MASK;
+            let mut raw_file = q.raw_file.file_mut().try_clone().unwrap();
+            drop(q);
+
+            // Replace the cluster with its compressed counterpart.
+            let data: Vec<u8> = (0..cluster_size).map(|i| (i % 251) as u8).collect();
+            let mut compressed = vec![0u8; cluster_size as usize];
+            let mut compress = Compress::new(Compression::default(), false);
+            compress
+                .compress(&data, &mut compressed, FlushCompress::Finish)
+                .unwrap();
+            let compressed_len = compress.total_out();
+            raw_file.seek(SeekFrom::Start(data_addr)).unwrap();
+            raw_file
+                .write_all(&compressed[..compressed_len as usize])
+                .unwrap();
+            let offset_bits = 62 - (DEFAULT_CLUSTER_BITS - 8);
+            let sectors = div_round_up_u64(compressed_len, 512);
+            let l2_entry = COMPRESSED_FLAG | ((sectors - 1) << offset_bits) | data_addr;
+            raw_file.seek(SeekFrom::Start(l2_addr)).unwrap();
+            raw_file.write_u64::<BigEndian>(l2_entry).unwrap();
+
+            let mut q = QcowFile::from(raw_file).expect("Failed to open compressed image.");
+            let mut buf = vec![0u8; cluster_size as usize];
+            q.seek(SeekFrom::Start(0)).expect("Failed to seek.");
+            q.read_exact(&mut buf).expect("Failed to read.");
+            assert_eq!(buf, data);
+
+            // Writing to the compressed cluster moves it to a new uncompressed cluster.
+            q.seek(SeekFrom::Start(0x10)).expect("Failed to seek.");
+            q.write_all(&[0x55u8; 4]).expect("Failed to write.");
+            assert_eq!(q.l2_table(0).unwrap().unwrap()[0] & COMPRESSED_FLAG, 0);
+            q.seek(SeekFrom::Start(0)).expect("Failed to seek.");
+            q.read_exact(&mut buf).expect("Failed to read.");
+            assert_eq!(&buf[..0x10], &data[..0x10]);
+            assert_eq!(&buf[0x10..0x14], &[0x55u8; 4]);
+            assert_eq!(&buf[0x14..], &data[0x14..]);
+        });
+    }
+
+    #[test]
+    fn snapshot_create_apply_delete() {
+        with_default_file(0x10_0000, false, |mut q| {
+            let cluster_size = q.raw_file.cluster_size() as usize;
+            let first = vec![0x55u8; cluster_size];
+            let second = vec![0xaau8; cluster_size];
+            let mut buf = vec![0u8; cluster_size];
+
+            q.write_all(&first).expect("Failed to write.");
+            q.create_snapshot("first")
+                .expect("Failed to create snapshot.");
+            assert!(q.create_snapshot("first").is_err());
+
+            // Writing after the snapshot doesn't change its content.
+            q.seek(SeekFrom::Start(0)).expect("Failed to seek.");
+            q.write_all(&second).expect("Failed to write.");
+            q.seek(SeekFrom::Start(0)).expect("Failed to seek.");
+            q.read_exact(&mut buf).expect("Failed to read.");
+            assert_eq!(buf, second);
+
+            // The snapshot table is persisted in the image.
+            q.flush().expect("Failed to flush.");
+            let raw_file = q.raw_file.file_mut().try_clone().unwrap();
+            drop(q);
+            let mut q = QcowFile::from(raw_file).expect("Failed to reopen image.");
+            assert_eq!(q.snapshots().len(), 1);
+            assert_eq!(q.snapshots()[0].id, "1");
+            assert_eq!(q.snapshots()[0].name, "first");
+            assert_eq!(q.snapshots()[0].disk_size, 0x10_0000);
+
+            q.apply_snapshot("first")
+                .expect("Failed to apply snapshot.");
+            q.seek(SeekFrom::Start(0)).expect("Failed to seek.");
+            q.read_exact(&mut buf).expect("Failed to read.");
+            assert_eq!(buf, first);
+
+            // Deleting the snapshot leaves the image content alone.
+            q.delete_snapshot("1").expect("Failed to delete snapshot.");
+            assert!(q.snapshots().is_empty());
+            assert!(q.apply_snapshot("first").is_err());
+            q.seek(SeekFrom::Start(0)).expect("Failed to seek.");
+            q.read_exact(&mut buf).expect("Failed to read.");
+            assert_eq!(buf, first);
+
+            // Only the image references its clusters now, they are written in place.
+            let data_addr = q.l2_table(0).unwrap().unwrap()[0];
+            assert_ne!(data_addr & CLUSTER_USED_FLAG, 0);
+            q.seek(SeekFrom::Start(0)).expect("Failed to seek.");
+            q.write_all(&second).expect("Failed to write.");
+            assert_eq!(q.l2_table(0).unwrap().unwrap()[0], data_addr);
+        });
+    }
+
+    #[test]
+    fn resize_grow() {
+        with_default_file(0x10_0000, false, |mut q| {
+            let data = [0x55u8; 0x1000];
+            let mut buf = [0u8; 0x1000];
+            q.seek(SeekFrom::Start(0xf_f000)).expect("Failed to seek.");
+            q.write_all(&data).expect("Failed to write.");
+
+            assert!(q.resize(0x8_0000).is_err());
+            assert_eq!(q.virtual_size(), 0x10_0000);
+
+            // Growing within the current L1 table.
+            q.resize(0x20_0000).expect("Failed to resize.");
+            assert_eq!(q.seek(SeekFrom::End(0)).unwrap(), 0x20_0000);
+            q.seek(SeekFrom::Start(0x1f_f000)).expect("Failed to seek.");
+            q.read_exact(&mut buf).expect("Failed to read.");
+            assert_eq!(buf, [0u8; 0x1000]);
+
+            // Growing past the current L1 table moves it.
+            let l1_table_offset = q.header.l1_table_offset;
+            q.resize(0x1_0000_0000).expect("Failed to resize.");
+            assert_ne!(q.header.l1_table_offset, l1_table_offset);
+            assert_eq!(q.l1_table().len(), 8);
+            q.seek(SeekFrom::Start(0xffff_f000))
+                .expect("Failed to seek.");
+            q.write_all(&data).expect("Failed to write.");
+
+            // The new size is persisted in the image, and the data is kept.
+            q.flush().expect("Failed to flush.");
+            let raw_file = q.raw_file.file_mut().try_clone().unwrap();
+            drop(q);
+            let mut q = QcowFile::from(raw_file).expect("Failed to reopen image.");
+            assert_eq!(q.virtual_size(), 0x1_0000_0000);
+            q.seek(SeekFrom::Start(0xf_f000)).expect("Failed to seek.");
+            q.read_exact(&mut buf).expect("Failed to read.");
+            assert_eq!(buf, data);
+            q.seek(SeekFrom::Start(0xffff_f000))
+                .expect("Failed to seek.");
+            q.read_exact(&mut buf).expect("Failed to read.");
+            assert_eq!(buf, data);
+        });
+    }
+
+    #[test]
+    fn check_and_repair() {
+        with_default_file(0x10_0000, false, |mut q| {
+            let cluster_size = q.raw_file.cluster_size();
+            let data = vec![0x55u8; cluster_size as usize * 2];
+            q.write_all(&data).expect("Failed to write.");
+            q.create_snapshot("first")
+                .expect("Failed to create snapshot.");
+            assert!(q.check(false).expect("Failed to check.").is_clean());
+            q.delete_snapshot("first")
+                .expect("Failed to delete snapshot.");
+            assert!(q.check(false).expect("Failed to check.").is_clean());
+
+            // Leak a cluster, drop the reference to a data cluster and point an L2 entry past the
+            // end of the file.
+            q.append_clusters(1).expect("Failed to allocate cluster.");
+            let l2_addr = q.l1_table()[0];
+            let data_addr = q.l2_table(0).unwrap().unwrap()[1] & L2_TABLE_OFFSET_MASK;
+            q.set_cluster_refcount(data_addr, 0)
+                .expect("Failed to set refcount.");
+            q.raw_file
+                .write_pointer_table(l2_addr + 2 * size_of::<u64>() as u64, &[0x7fff_0000], 0)
+                .expect("Failed to write L2 entry.");
+            q.flush().expect("Failed to flush.");
+
+            let expected = CheckResult {
+                corruptions: 1,
+                leaks: 1,
+                invalid_entries: 1,
+                copied_flag_mismatches: 0,
+            };
+            assert_eq!(q.check(false).expect("Failed to check."), expected);
+            assert_eq!(q.check(false).expect("Failed to check."), expected);
+            assert_eq!(q.check(true).expect("Failed to repair."), expected);
+            assert!(q.check(false).expect("Failed to check.").is_clean());
+
+            // The data is kept, and the repair is persisted in the image.
+            let raw_file = q.raw_file.file_mut().try_clone().unwrap();
+            drop(q);
+            let mut q = QcowFile::from(raw_file).expect("Failed to reopen image.");
+            assert!(q.check(false).expect("Failed to check.").is_clean());
+            let mut buf = vec![0u8; cluster_size as usize * 3];
+            q.read_exact(&mut buf).expect("Failed to read.");
+            assert_eq!(&buf[..data.len()], &data[..]);
+            assert!(buf[data.len()..].iter().all(|b| *b == 0));
+        });
+    }
+
     #[test]
     fn write_zeroes_full_cluster() {
         // Choose a size that is larger than a cluster.
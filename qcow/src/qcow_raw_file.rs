@@ -2,9 +2,10 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE-BSD-3-Clause file.
 
-use std::io::{self, BufWriter, Seek, SeekFrom};
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
 use std::mem::size_of;
 
+use crate::COMPRESSED_FLAG;
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use vm_virtio::RawFile;
 use vmm_sys_util::write_zeroes::WriteZeroes;
@@ -58,7 +59,8 @@ impl QcowRawFile {
     }
 
     /// Writes `table` of u64 pointers to `offset` in the file.
-    /// `non_zero_flags` will be ORed with all non-zero values in `table`.
+    /// `non_zero_flags` will be ORed with all non-zero values in `table`, except for compressed
+    /// cluster entries which are written as is.
     /// writing.
     pub fn write_pointer_table(
         &mut self,
@@ -69,8 +71,8 @@ impl QcowRawFile {
         self.file.seek(SeekFrom::Start(offset))?;
         let mut buffer = BufWriter::with_capacity(table.len() * size_of::<u64>(), &mut self.file);
         for addr in table {
-            let val = if *addr == 0 {
-                0
+            let val = if *addr == 0 || *addr & COMPRESSED_FLAG != 0 {
+                *addr
             } else {
                 *addr | non_zero_flags
             };
@@ -137,6 +139,13 @@ impl QcowRawFile {
         self.file.write_zeroes(cluster_size)?;
         Ok(())
     }
+
+    /// Writes `data` to the cluster at `address`, padding it with zeros to the cluster size.
+    pub fn write_cluster(&mut self, address: u64, mut data: Vec<u8>) -> io::Result<()> {
+        data.resize(self.cluster_size as usize, 0);
+        self.file.seek(SeekFrom::Start(address))?;
+        self.file.write_all(&data)
+    }
 }
 
 impl Clone for QcowRawFile {
@@ -0,0 +1,382 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+#[macro_use(crate_authors)]
+extern crate clap;
+extern crate qcow;
+extern crate vm_virtio;
+extern crate vmm;
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use qcow::{ImageType, QcowFile};
+use std::fs::{self, OpenOptions};
+use std::io::{Seek, SeekFrom};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process;
+use vm_virtio::RawFile;
+
+#[derive(Debug)]
+enum Error {
+    OpenImage(std::io::Error),
+    CreateImage(std::io::Error),
+    RenameImage(std::io::Error),
+    SameImage,
+    InvalidSize(vmm::config::Error),
+    InvalidVersion(std::num::ParseIntError),
+    InvalidFormat(String),
+    MissingSize,
+    Qcow(qcow::Error),
+    ImageSize(std::io::Error),
+    ResizeRawImage(std::io::Error),
+    CheckRawImage,
+    InconsistentImage(qcow::CheckResult),
+}
+
+fn open_image(path: &str, writable: bool) -> Result<RawFile, Error> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(writable)
+        .open(path)
+        .map_err(Error::OpenImage)?;
+
+    Ok(RawFile::new(file, false))
+}
+
+fn create_image_file(path: &str) -> Result<RawFile, Error> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+        .map_err(Error::CreateImage)?;
+
+    Ok(RawFile::new(file, false))
+}
+
+fn parse_format(format: &str) -> Result<ImageType, Error> {
+    match format {
+        "raw" => Ok(ImageType::Raw),
+        "qcow2" => Ok(ImageType::Qcow2),
+        _ => Err(Error::InvalidFormat(format.to_owned())),
+    }
+}
+
+fn create_command(
+    path: &str,
+    size: Option<&str>,
+    backing_file: Option<&str>,
+    version: &str,
+) -> Result<(), Error> {
+    let version = version.parse().map_err(Error::InvalidVersion)?;
+    let size = size
+        .map(vmm::config::parse_size)
+        .transpose()
+        .map_err(Error::InvalidSize)?;
+    let file = create_image_file(path)?;
+
+    let mut qcow = match (backing_file, size) {
+        (Some(backing_file), _) => {
+            QcowFile::new_from_backing(file, version, backing_file).map_err(Error::Qcow)?
+        }
+        (None, Some(size)) => QcowFile::new(file, version, size).map_err(Error::Qcow)?,
+        (None, None) => return Err(Error::MissingSize),
+    };
+
+    // An overlay is as large as its backing file, unless asked to be larger.
+    if let Some(size) = size {
+        if size > qcow.header().size {
+            qcow.resize(size).map_err(Error::Qcow)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn info_command(path: &str) -> Result<(), Error> {
+    let mut file = open_image(path, false)?;
+    let disk_size = file.metadata().map_err(Error::OpenImage)?.blocks() * 512;
+
+    println!("image: {}", path);
+    match qcow::detect_image_type(&mut file).map_err(Error::Qcow)? {
+        ImageType::Raw => {
+            let virtual_size = file.seek(SeekFrom::End(0)).map_err(Error::ImageSize)?;
+            println!("file format: raw");
+            println!("virtual size: {} bytes", virtual_size);
+            println!("disk size: {} bytes", disk_size);
+        }
+        ImageType::Qcow2 => {
+            let mut qcow = QcowFile::from(file).map_err(Error::Qcow)?;
+            let header = *qcow.header();
+            println!("file format: qcow2");
+            println!("virtual size: {} bytes", header.size);
+            println!("disk size: {} bytes", disk_size);
+            println!("cluster size: {}", 1u64 << header.cluster_bits);
+            println!("version: {}", header.version);
+            if let Some(backing_file) = qcow.backing_file_name().map_err(Error::Qcow)? {
+                println!("backing file: {}", backing_file);
+            }
+            if !qcow.snapshots().is_empty() {
+                println!("snapshots:");
+                println!(
+                    "{:<8} {:<24} {:>16} {:>12}",
+                    "ID", "TAG", "DISK SIZE", "DATE"
+                );
+                for snapshot in qcow.snapshots() {
+                    println!(
+                        "{:<8} {:<24} {:>16} {:>12}",
+                        snapshot.id, snapshot.name, snapshot.disk_size, snapshot.date_sec
+                    );
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn check_command(path: &str, repair: bool) -> Result<(), Error> {
+    let mut file = open_image(path, repair)?;
+    if let ImageType::Raw = qcow::detect_image_type(&mut file).map_err(Error::Qcow)? {
+        return Err(Error::CheckRawImage);
+    }
+
+    let mut qcow = QcowFile::from(file).map_err(Error::Qcow)?;
+    let mut result = qcow.check(repair).map_err(Error::Qcow)?;
+    if result.is_clean() {
+        println!("No errors were found on the image.");
+        return Ok(());
+    }
+
+    println!("{} clusters with a refcount too low", result.corruptions);
+    println!("{} leaked clusters", result.leaks);
+    println!("{} invalid L1/L2 table entries", result.invalid_entries);
+    println!(
+        "{} L2 entries with a wrong COPIED flag",
+        result.copied_flag_mismatches
+    );
+
+    if repair {
+        result = qcow.check(false).map_err(Error::Qcow)?;
+        if result.is_clean() {
+            println!("The image was repaired.");
+            return Ok(());
+        }
+    }
+
+    Err(Error::InconsistentImage(result))
+}
+
+// Resolves the path of an image which may not exist yet, through its parent
+// directory.
+fn canonicalize_image_path(path: &Path) -> std::io::Result<PathBuf> {
+    if path.exists() {
+        return path.canonicalize();
+    }
+
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::InvalidInput))?;
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+
+    Ok(parent.canonicalize()?.join(file_name))
+}
+
+fn convert_command(src_path: &str, dst_path: &str, format: &str) -> Result<(), Error> {
+    let dst_type = parse_format(format)?;
+    let src_canonical = Path::new(src_path)
+        .canonicalize()
+        .map_err(Error::OpenImage)?;
+    let dst_canonical = canonicalize_image_path(Path::new(dst_path)).map_err(Error::CreateImage)?;
+    if src_canonical == dst_canonical {
+        return Err(Error::SameImage);
+    }
+
+    let src_file = open_image(src_path, false)?;
+
+    // The image is converted into a temporary file next to the destination,
+    // so that an existing destination is only replaced once the conversion
+    // has succeeded.
+    let mut tmp_name = std::ffi::OsString::from(".");
+    tmp_name.push(dst_canonical.file_name().unwrap());
+    tmp_name.push(format!(".{}.tmp", process::id()));
+    let tmp_path = dst_canonical.with_file_name(tmp_name);
+    let tmp_file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&tmp_path)
+        .map_err(Error::CreateImage)?;
+
+    let result = qcow::convert(src_file, RawFile::new(tmp_file, false), dst_type)
+        .map_err(Error::Qcow)
+        .and_then(|_| fs::rename(&tmp_path, &dst_canonical).map_err(Error::RenameImage));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+
+    result
+}
+
+fn resize_command(path: &str, size: &str) -> Result<(), Error> {
+    let mut file = open_image(path, true)?;
+
+    // A leading '+' grows the image by the given size.
+    let grow = size.starts_with('+');
+    let size = size.trim_start_matches('+');
+    let size = vmm::config::parse_size(size).map_err(Error::InvalidSize)?;
+
+    match qcow::detect_image_type(&mut file).map_err(Error::Qcow)? {
+        ImageType::Raw => {
+            let current_size = file.seek(SeekFrom::End(0)).map_err(Error::ImageSize)?;
+            let size = if grow { current_size + size } else { size };
+            file.set_len(size).map_err(Error::ResizeRawImage)
+        }
+        ImageType::Qcow2 => {
+            let mut qcow = QcowFile::from(file).map_err(Error::Qcow)?;
+            let size = if grow {
+                qcow.header().size + size
+            } else {
+                size
+            };
+            qcow.resize(size).map_err(Error::Qcow)
+        }
+    }
+}
+
+fn do_command(matches: &ArgMatches) -> Result<(), Error> {
+    match matches.subcommand() {
+        ("create", Some(create_matches)) => create_command(
+            create_matches.value_of("path").unwrap(),
+            create_matches.value_of("size"),
+            create_matches.value_of("backing_file"),
+            create_matches.value_of("version").unwrap(),
+        ),
+        ("info", Some(info_matches)) => info_command(info_matches.value_of("path").unwrap()),
+        ("check", Some(check_matches)) => check_command(
+            check_matches.value_of("path").unwrap(),
+            check_matches.is_present("repair"),
+        ),
+        ("convert", Some(convert_matches)) => convert_command(
+            convert_matches.value_of("src_path").unwrap(),
+            convert_matches.value_of("dst_path").unwrap(),
+            convert_matches.value_of("format").unwrap(),
+        ),
+        ("resize", Some(resize_matches)) => resize_command(
+            resize_matches.value_of("path").unwrap(),
+            resize_matches.value_of("size").unwrap(),
+        ),
+        _ => unreachable!(),
+    }
+}
+
+fn main() {
+    let app = App::new("ch-img")
+        .author(crate_authors!())
+        .setting(AppSettings::SubcommandRequired)
+        .about("Create, inspect and modify disk images for cloud-hypervisor.")
+        .subcommand(
+            SubCommand::with_name("create")
+                .about("Create a qcow2 image")
+                .arg(
+                    Arg::with_name("path")
+                        .index(1)
+                        .required(true)
+                        .help("<image_path>"),
+                )
+                .arg(Arg::with_name("size").index(2).help(
+                    "Virtual size of the image (in bytes, or suffixed with K, M or G), \
+                     defaults to the size of the backing file",
+                ))
+                .arg(
+                    Arg::with_name("backing_file")
+                        .long("backing-file")
+                        .help("Image holding the data not written to the new image yet")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("version")
+                        .long("version")
+                        .help("qcow2 version")
+                        .takes_value(true)
+                        .possible_values(&["2", "3"])
+                        .default_value("3"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("info")
+                .about("Show information about an image")
+                .arg(
+                    Arg::with_name("path")
+                        .index(1)
+                        .required(true)
+                        .help("<image_path>"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("check")
+                .about("Check the consistency of a qcow2 image")
+                .arg(
+                    Arg::with_name("path")
+                        .index(1)
+                        .required(true)
+                        .help("<image_path>"),
+                )
+                .arg(
+                    Arg::with_name("repair")
+                        .long("repair")
+                        .help("Fix the refcounts and the L1/L2 tables of the image"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("convert")
+                .about("Convert an image between the raw and qcow2 formats")
+                .arg(
+                    Arg::with_name("src_path")
+                        .index(1)
+                        .required(true)
+                        .help("<source_image_path>"),
+                )
+                .arg(
+                    Arg::with_name("dst_path")
+                        .index(2)
+                        .required(true)
+                        .help("<destination_image_path>"),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .help("Format of the destination image")
+                        .takes_value(true)
+                        .possible_values(&["raw", "qcow2"])
+                        .default_value("qcow2"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("resize")
+                .about("Resize an image, qcow2 images can only grow")
+                .arg(
+                    Arg::with_name("path")
+                        .index(1)
+                        .required(true)
+                        .help("<image_path>"),
+                )
+                .arg(Arg::with_name("size").index(2).required(true).help(
+                    "New virtual size of the image (in bytes, or suffixed with K, M or G), \
+                     or the size to grow it by when prefixed with '+'",
+                )),
+        );
+
+    let matches = app.get_matches();
+
+    if let Err(e) = do_command(&matches) {
+        eprintln!("Error running command: {:?}", e);
+        process::exit(1)
+    };
+}
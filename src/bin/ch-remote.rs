@@ -22,7 +22,18 @@ enum Error {
     ServerResponse(StatusCode),
     InvalidCPUCount(std::num::ParseIntError),
     InvalidMemorySize(std::num::ParseIntError),
+    InvalidDiskSize(std::num::ParseIntError),
     AddDeviceConfig(vmm::config::Error),
+    AddDiskConfig(vmm::config::Error),
+    AddNetConfig(vmm::config::Error),
+    AddFsConfig(vmm::config::Error),
+    AddPmemConfig(vmm::config::Error),
+    AddVsockConfig(vmm::config::Error),
+    RestoreConfig(vmm::config::Error),
+    DiskRateLimitConfig(vmm::config::Error),
+    NetRateLimitConfig(vmm::config::Error),
+    InfoParsing(serde_json::Error),
+    MissingConfig,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -123,12 +134,12 @@ fn parse_http_response(socket: &mut UnixStream) -> Result<Option<String>, Error>
     Ok(content_length.and(Some(String::from(&res[body_offset.unwrap()..]))))
 }
 
-fn simple_api_command(
+fn api_request(
     socket: &mut UnixStream,
     method: &str,
     c: &str,
     request_body: Option<&str>,
-) -> Result<(), Error> {
+) -> Result<Option<String>, Error> {
     socket
         .write_all(
             format!(
@@ -155,12 +166,36 @@ fn simple_api_command(
 
     socket.flush().map_err(Error::Socket)?;
 
-    if let Some(body) = parse_http_response(socket)? {
+    parse_http_response(socket)
+}
+
+fn simple_api_command(
+    socket: &mut UnixStream,
+    method: &str,
+    c: &str,
+    request_body: Option<&str>,
+) -> Result<(), Error> {
+    if let Some(body) = api_request(socket, method, c, request_body)? {
         println!("{}", body);
     }
     Ok(())
 }
 
+fn config_api_command(socket: &mut UnixStream) -> Result<(), Error> {
+    let info = api_request(socket, "GET", "info", None)?.ok_or(Error::MissingConfig)?;
+    let info: serde_json::Value = serde_json::from_str(&info).map_err(Error::InfoParsing)?;
+
+    // The VM configuration is printed in a format that can be fed back to
+    // cloud-hypervisor through --config, or to the vm.create API.
+    let config = info.get("config").ok_or(Error::MissingConfig)?;
+    println!(
+        "{}",
+        serde_json::to_string_pretty(config).map_err(Error::InfoParsing)?
+    );
+
+    Ok(())
+}
+
 fn resize_api_command(
     socket: &mut UnixStream,
     cpus: Option<&str>,
@@ -191,6 +226,17 @@ fn resize_api_command(
     )
 }
 
+fn add_disk_api_command(socket: &mut UnixStream, config: &str) -> Result<(), Error> {
+    let disk_config = vmm::config::DiskConfig::parse(config).map_err(Error::AddDiskConfig)?;
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "add-disk",
+        Some(&serde_json::to_string(&disk_config).unwrap()),
+    )
+}
+
 fn add_device_api_command(socket: &mut UnixStream, config: &str) -> Result<(), Error> {
     let device_config = vmm::config::DeviceConfig::parse(config).map_err(Error::AddDeviceConfig)?;
 
@@ -202,6 +248,61 @@ fn add_device_api_command(socket: &mut UnixStream, config: &str) -> Result<(), E
     )
 }
 
+fn add_net_api_command(socket: &mut UnixStream, config: &str) -> Result<(), Error> {
+    let net_config = vmm::config::NetConfig::parse(config).map_err(Error::AddNetConfig)?;
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "add-net",
+        Some(&serde_json::to_string(&net_config).unwrap()),
+    )
+}
+
+fn add_fs_api_command(socket: &mut UnixStream, config: &str) -> Result<(), Error> {
+    let fs_config = vmm::config::FsConfig::parse(config).map_err(Error::AddFsConfig)?;
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "add-fs",
+        Some(&serde_json::to_string(&fs_config).unwrap()),
+    )
+}
+
+fn add_pmem_api_command(socket: &mut UnixStream, config: &str) -> Result<(), Error> {
+    let pmem_config = vmm::config::PmemConfig::parse(config).map_err(Error::AddPmemConfig)?;
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "add-pmem",
+        Some(&serde_json::to_string(&pmem_config).unwrap()),
+    )
+}
+
+fn add_vsock_api_command(socket: &mut UnixStream, config: &str) -> Result<(), Error> {
+    let vsock_config = vmm::config::VsockConfig::parse(config).map_err(Error::AddVsockConfig)?;
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "add-vsock",
+        Some(&serde_json::to_string(&vsock_config).unwrap()),
+    )
+}
+
+fn remove_net_api_command(socket: &mut UnixStream, id: &str) -> Result<(), Error> {
+    let remove_net_data = vmm::api::VmRemoveDeviceData { id: id.to_owned() };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "remove-net",
+        Some(&serde_json::to_string(&remove_net_data).unwrap()),
+    )
+}
+
 fn remove_device_api_command(socket: &mut UnixStream, id: &str) -> Result<(), Error> {
     let remove_device_data = vmm::api::VmRemoveDeviceData { id: id.to_owned() };
 
@@ -213,12 +314,157 @@ fn remove_device_api_command(socket: &mut UnixStream, id: &str) -> Result<(), Er
     )
 }
 
+fn snapshot_api_command(socket: &mut UnixStream, url: &str) -> Result<(), Error> {
+    let snapshot_config = vmm::api::VmSnapshotConfig {
+        destination_url: String::from(url),
+    };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "snapshot",
+        Some(&serde_json::to_string(&snapshot_config).unwrap()),
+    )
+}
+
+fn disk_snapshot_api_command(
+    socket: &mut UnixStream,
+    command: &str,
+    id: &str,
+    name: &str,
+) -> Result<(), Error> {
+    let disk_snapshot_data = vmm::api::VmDiskSnapshotData {
+        id: id.to_owned(),
+        name: name.to_owned(),
+    };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        command,
+        Some(&serde_json::to_string(&disk_snapshot_data).unwrap()),
+    )
+}
+
+fn set_disk_rate_limit_api_command(
+    socket: &mut UnixStream,
+    id: &str,
+    limits: Option<&str>,
+) -> Result<(), Error> {
+    let rate_limiter_config = if let Some(limits) = limits {
+        vmm::config::parse_rate_limiter_config(limits).map_err(Error::DiskRateLimitConfig)?
+    } else {
+        Default::default()
+    };
+
+    let disk_rate_limit_data = vmm::api::VmDiskRateLimitData {
+        id: id.to_owned(),
+        rate_limiter_config,
+    };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "set-disk-rate-limit",
+        Some(&serde_json::to_string(&disk_rate_limit_data).unwrap()),
+    )
+}
+
+fn resize_disk_api_command(socket: &mut UnixStream, id: &str, size: &str) -> Result<(), Error> {
+    let resize_disk_data = vmm::api::VmResizeDiskData {
+        id: id.to_owned(),
+        desired_size: size.parse().map_err(Error::InvalidDiskSize)?,
+    };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "resize-disk",
+        Some(&serde_json::to_string(&resize_disk_data).unwrap()),
+    )
+}
+
+fn set_net_rate_limit_api_command(
+    socket: &mut UnixStream,
+    id: &str,
+    limits: Option<&str>,
+) -> Result<(), Error> {
+    let limits = limits.unwrap_or("");
+    let net_rate_limit_data = vmm::api::VmNetRateLimitData {
+        id: id.to_owned(),
+        rx_rate_limiter_config: vmm::config::parse_net_rate_limiter_config(limits, "rx")
+            .map_err(Error::NetRateLimitConfig)?,
+        tx_rate_limiter_config: vmm::config::parse_net_rate_limiter_config(limits, "tx")
+            .map_err(Error::NetRateLimitConfig)?,
+    };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "set-net-rate-limit",
+        Some(&serde_json::to_string(&net_rate_limit_data).unwrap()),
+    )
+}
+
+fn set_net_link_api_command(socket: &mut UnixStream, id: &str, state: &str) -> Result<(), Error> {
+    let net_link_data = vmm::api::VmNetLinkData {
+        id: id.to_owned(),
+        up: state == "up",
+    };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "set-net-link",
+        Some(&serde_json::to_string(&net_link_data).unwrap()),
+    )
+}
+
+fn restore_api_command(socket: &mut UnixStream, config: &str) -> Result<(), Error> {
+    let restore_config = vmm::config::RestoreConfig::parse(config).map_err(Error::RestoreConfig)?;
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "restore",
+        Some(&serde_json::to_string(&restore_config).unwrap()),
+    )
+}
+
+fn send_migration_api_command(socket: &mut UnixStream, url: &str) -> Result<(), Error> {
+    let migration_data = vmm::api::VmSendMigrationData {
+        destination_url: String::from(url),
+    };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "send-migration",
+        Some(&serde_json::to_string(&migration_data).unwrap()),
+    )
+}
+
+fn receive_migration_api_command(socket: &mut UnixStream, url: &str) -> Result<(), Error> {
+    let migration_data = vmm::api::VmReceiveMigrationData {
+        receiver_url: String::from(url),
+    };
+
+    simple_api_command(
+        socket,
+        "PUT",
+        "receive-migration",
+        Some(&serde_json::to_string(&migration_data).unwrap()),
+    )
+}
+
 fn do_command(matches: &ArgMatches) -> Result<(), Error> {
     let mut socket =
         UnixStream::connect(matches.value_of("api-socket").unwrap()).map_err(Error::Socket)?;
 
     match matches.subcommand_name() {
         Some("info") => simple_api_command(&mut socket, "GET", "info", None),
+        Some("counters") => simple_api_command(&mut socket, "GET", "counters", None),
+        Some("config") => config_api_command(&mut socket),
         Some("resize") => resize_api_command(
             &mut socket,
             matches
@@ -238,6 +484,54 @@ fn do_command(matches: &ArgMatches) -> Result<(), Error> {
                 .value_of("device_config")
                 .unwrap(),
         ),
+        Some("add-disk") => add_disk_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("add-disk")
+                .unwrap()
+                .value_of("disk_config")
+                .unwrap(),
+        ),
+        Some("add-net") => add_net_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("add-net")
+                .unwrap()
+                .value_of("net_config")
+                .unwrap(),
+        ),
+        Some("add-fs") => add_fs_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("add-fs")
+                .unwrap()
+                .value_of("fs_config")
+                .unwrap(),
+        ),
+        Some("add-pmem") => add_pmem_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("add-pmem")
+                .unwrap()
+                .value_of("pmem_config")
+                .unwrap(),
+        ),
+        Some("add-vsock") => add_vsock_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("add-vsock")
+                .unwrap()
+                .value_of("vsock_config")
+                .unwrap(),
+        ),
+        Some("remove-net") => remove_net_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("remove-net")
+                .unwrap()
+                .value_of("id")
+                .unwrap(),
+        ),
         Some("remove-device") => remove_device_api_command(
             &mut socket,
             matches
@@ -246,6 +540,81 @@ fn do_command(matches: &ArgMatches) -> Result<(), Error> {
                 .value_of("id")
                 .unwrap(),
         ),
+        Some("snapshot") => snapshot_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("snapshot")
+                .unwrap()
+                .value_of("snapshot_config")
+                .unwrap(),
+        ),
+        Some("restore") => restore_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("restore")
+                .unwrap()
+                .value_of("restore_config")
+                .unwrap(),
+        ),
+        Some(command @ "create-disk-snapshot")
+        | Some(command @ "apply-disk-snapshot")
+        | Some(command @ "delete-disk-snapshot") => {
+            let disk_snapshot_matches = matches.subcommand_matches(command).unwrap();
+            disk_snapshot_api_command(
+                &mut socket,
+                command,
+                disk_snapshot_matches.value_of("id").unwrap(),
+                disk_snapshot_matches.value_of("name").unwrap(),
+            )
+        }
+        Some("set-disk-rate-limit") => {
+            let rate_limit_matches = matches.subcommand_matches("set-disk-rate-limit").unwrap();
+            set_disk_rate_limit_api_command(
+                &mut socket,
+                rate_limit_matches.value_of("id").unwrap(),
+                rate_limit_matches.value_of("limits"),
+            )
+        }
+        Some("set-net-rate-limit") => {
+            let rate_limit_matches = matches.subcommand_matches("set-net-rate-limit").unwrap();
+            set_net_rate_limit_api_command(
+                &mut socket,
+                rate_limit_matches.value_of("id").unwrap(),
+                rate_limit_matches.value_of("limits"),
+            )
+        }
+        Some("set-net-link") => {
+            let net_link_matches = matches.subcommand_matches("set-net-link").unwrap();
+            set_net_link_api_command(
+                &mut socket,
+                net_link_matches.value_of("id").unwrap(),
+                net_link_matches.value_of("state").unwrap(),
+            )
+        }
+        Some("resize-disk") => {
+            let resize_disk_matches = matches.subcommand_matches("resize-disk").unwrap();
+            resize_disk_api_command(
+                &mut socket,
+                resize_disk_matches.value_of("id").unwrap(),
+                resize_disk_matches.value_of("size").unwrap(),
+            )
+        }
+        Some("send-migration") => send_migration_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("send-migration")
+                .unwrap()
+                .value_of("send_migration_config")
+                .unwrap(),
+        ),
+        Some("receive-migration") => receive_migration_api_command(
+            &mut socket,
+            matches
+                .subcommand_matches("receive-migration")
+                .unwrap()
+                .value_of("receive_migration_config")
+                .unwrap(),
+        ),
         Some(c) => simple_api_command(&mut socket, "PUT", c, None),
         None => unreachable!(),
     }
@@ -272,12 +641,70 @@ fn main() {
                      \"path=<device_path>,iommu=on|off,id=<device_id>\"",
                 )),
         )
+        .subcommand(
+            SubCommand::with_name("add-disk")
+                .about("Add virtio-blk disk")
+                .arg(Arg::with_name("disk_config").index(1).help(
+                    "Disk parameters \
+                     \"path=<disk_image_path>,readonly=on|off,iommu=on|off,\
+                     num_queues=<number_of_queues>,queue_size=<size_of_each_queue>,\
+                     vhost_user=<vhost_user_enable>,socket=<vhost_user_socket_path>,\
+                     wce=<true|false, default true>,id=<device_id>,lock=on|off\"",
+                )),
+        )
+        .subcommand(
+            SubCommand::with_name("add-net")
+                .about("Add virtio-net device")
+                .arg(Arg::with_name("net_config").index(1).help(
+                    "Network parameters \
+                     \"tap=<if_name>,ip=<ip_addr>,mask=<net_mask>,mac=<mac_addr>,iommu=on|off,\
+                     num_queues=<number_of_queues>,queue_size=<size_of_each_queue>,\
+                     vhost_user=<vhost_user_enable>,socket=<vhost_user_socket_path>,\
+                     id=<device_id>,\
+                     user=on|off,hostfwd=<tcp|udp>:[<host_ip>:]<host_port>:<guest_port>,\
+                     host_loopback=on|off\"",
+                )),
+        )
+        .subcommand(
+            SubCommand::with_name("add-fs")
+                .about("Add virtio-fs device")
+                .arg(Arg::with_name("fs_config").index(1).help(
+                    "virtio-fs parameters \
+                     \"tag=<tag_name>,sock=<socket_path>,num_queues=<number_of_queues>,\
+                     queue_size=<size_of_each_queue>,dax=on|off,cache_size=<DAX cache size: \
+                     default 8Gib>,id=<device_id>\"",
+                )),
+        )
+        .subcommand(
+            SubCommand::with_name("add-pmem")
+                .about("Add virtio-pmem device")
+                .arg(Arg::with_name("pmem_config").index(1).help(
+                    "Persistent memory parameters \
+                     \"file=<backing_file_path>,size=<persistent_memory_size>,iommu=on|off,\
+                     mergeable=on|off,id=<device_id>\"",
+                )),
+        )
+        .subcommand(
+            SubCommand::with_name("add-vsock")
+                .about("Add vsock device")
+                .arg(Arg::with_name("vsock_config").index(1).help(
+                    "Virtio VSOCK parameters \
+                     \"cid=<context_id>,sock=<socket_path>,iommu=on|off,id=<device_id>\"",
+                )),
+        )
+        .subcommand(
+            SubCommand::with_name("remove-net")
+                .about("Remove virtio-net device")
+                .arg(Arg::with_name("id").index(1).help("<device_id>")),
+        )
         .subcommand(
             SubCommand::with_name("remove-device")
-                .about("Remove VFIO device")
+                .about("Remove VFIO or virtio device")
                 .arg(Arg::with_name("id").index(1).help("<device_id>")),
         )
         .subcommand(SubCommand::with_name("info").about("Info on the VM"))
+        .subcommand(SubCommand::with_name("counters").about("Counters from the VM"))
+        .subcommand(SubCommand::with_name("config").about("Current configuration of the VM"))
         .subcommand(SubCommand::with_name("pause").about("Pause the VM"))
         .subcommand(SubCommand::with_name("reboot").about("Reboot the VM"))
         .subcommand(
@@ -299,7 +726,105 @@ fn main() {
                 ),
         )
         .subcommand(SubCommand::with_name("resume").about("Resume the VM"))
-        .subcommand(SubCommand::with_name("shutdown").about("Shutdown the VM"));
+        .subcommand(SubCommand::with_name("shutdown").about("Shutdown the VM"))
+        .subcommand(
+            SubCommand::with_name("snapshot")
+                .about("Create a snapshot from a paused VM")
+                .arg(
+                    Arg::with_name("snapshot_config")
+                        .index(1)
+                        .help("<destination_url>"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("restore")
+                .about("Restore a VM from a snapshot")
+                .arg(
+                    Arg::with_name("restore_config")
+                        .index(1)
+                        .help("Restore parameters \"source_url=<source_url>\""),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("create-disk-snapshot")
+                .about("Create an internal snapshot of a qcow2 disk of a paused VM")
+                .arg(Arg::with_name("id").index(1).help("<disk_id>"))
+                .arg(Arg::with_name("name").index(2).help("Snapshot name")),
+        )
+        .subcommand(
+            SubCommand::with_name("apply-disk-snapshot")
+                .about("Revert a qcow2 disk of a paused VM to an internal snapshot")
+                .arg(Arg::with_name("id").index(1).help("<disk_id>"))
+                .arg(Arg::with_name("name").index(2).help("Snapshot name or ID")),
+        )
+        .subcommand(
+            SubCommand::with_name("delete-disk-snapshot")
+                .about("Delete an internal snapshot of a qcow2 disk of a paused VM")
+                .arg(Arg::with_name("id").index(1).help("<disk_id>"))
+                .arg(Arg::with_name("name").index(2).help("Snapshot name or ID")),
+        )
+        .subcommand(
+            SubCommand::with_name("set-disk-rate-limit")
+                .about("Update the bandwidth and IOPS limits of a disk, removing them if omitted")
+                .arg(Arg::with_name("id").index(1).help("<disk_id>"))
+                .arg(Arg::with_name("limits").index(2).help(
+                    "Limits \"bw=<bytes_per_second>,bw_burst=<bytes>,\
+                     iops=<operations_per_second>,iops_burst=<operations>\"",
+                )),
+        )
+        .subcommand(
+            SubCommand::with_name("set-net-rate-limit")
+                .about(
+                    "Update the bandwidth and packet rate limits of a network interface, \
+                     removing them if omitted",
+                )
+                .arg(Arg::with_name("id").index(1).help("<net_id>"))
+                .arg(Arg::with_name("limits").index(2).help(
+                    "Limits \"rx_bw=<bytes_per_second>,rx_bw_burst=<bytes>,\
+                     rx_pps=<packets_per_second>,rx_pps_burst=<packets>,\
+                     tx_bw=<bytes_per_second>,tx_bw_burst=<bytes>,\
+                     tx_pps=<packets_per_second>,tx_pps_burst=<packets>\"",
+                )),
+        )
+        .subcommand(
+            SubCommand::with_name("set-net-link")
+                .about("Set the link state a network interface reports to the guest")
+                .arg(Arg::with_name("id").index(1).help("<net_id>"))
+                .arg(
+                    Arg::with_name("state")
+                        .index(2)
+                        .possible_values(&["up", "down"])
+                        .help("Link state"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("resize-disk")
+                .about("Grow a disk of the VM")
+                .arg(Arg::with_name("id").index(1).help("<disk_id>"))
+                .arg(
+                    Arg::with_name("size")
+                        .index(2)
+                        .help("New disk size (in bytes)"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("send-migration")
+                .about("Migrate the VM to another VMM")
+                .arg(
+                    Arg::with_name("send_migration_config")
+                        .index(1)
+                        .help("<destination_url>"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("receive-migration")
+                .about("Receive a VM migrated from another VMM")
+                .arg(
+                    Arg::with_name("receive_migration_config")
+                        .index(1)
+                        .help("<receiver_url>"),
+                ),
+        );
 
     let matches = app.get_matches();
 
@@ -24,7 +24,11 @@ fn main() {
                 .help(
                     "vhost-user-net backend parameters \
                      \"ip=<ip_addr>,mask=<net_mask>,sock=<socket_path>,\
-                     num_queues=<number_of_queues>,queue_size=<size_of_each_queue>,tap=<if_name>\"",
+                     num_queues=<number_of_queues>,queue_size=<size_of_each_queue>,tap=<if_name>,\
+                     rx_bw=<bytes_per_second>,rx_bw_burst=<bytes>,\
+                     rx_pps=<packets_per_second>,rx_pps_burst=<packets>,\
+                     tx_bw=<bytes_per_second>,tx_bw_burst=<bytes>,\
+                     tx_pps=<packets_per_second>,tx_pps_burst=<packets>\"",
                 )
                 .takes_value(true)
                 .min_values(1),
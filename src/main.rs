@@ -3,6 +3,7 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
+extern crate event_monitor;
 extern crate vmm;
 extern crate vmm_sys_util;
 
@@ -12,6 +13,9 @@ extern crate clap;
 use clap::{App, Arg, ArgGroup, ArgMatches};
 use libc::EFD_NONBLOCK;
 use log::LevelFilter;
+use std::fs::File;
+use std::os::unix::io::FromRawFd;
+use std::path::Path;
 use std::sync::mpsc::channel;
 use std::sync::{Arc, Mutex};
 use std::{env, process};
@@ -86,6 +90,20 @@ fn create_app<'a, 'b>(
         .group(ArgGroup::with_name("vm-config").multiple(true))
         .group(ArgGroup::with_name("vmm-config").multiple(true))
         .group(ArgGroup::with_name("logging").multiple(true))
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .help(
+                    "Path to a JSON file describing the complete VM configuration, \
+                     using the /vm.create API request format",
+                )
+                .takes_value(true)
+                .conflicts_with_all(&[
+                    "cpus", "memory", "kernel", "cmdline", "disk", "net", "rng", "fs", "pmem",
+                    "serial", "console", "device", "vsock", "restore",
+                ])
+                .min_values(1),
+        )
         .arg(
             Arg::with_name("cpus")
                 .long("cpus")
@@ -126,7 +144,9 @@ fn create_app<'a, 'b>(
                      \"path=<disk_image_path>,readonly=on|off,iommu=on|off,\
                      num_queues=<number_of_queues>,queue_size=<size_of_each_queue>,\
                      vhost_user=<vhost_user_enable>,socket=<vhost_user_socket_path>,\
-                     wce=<true|false, default true>\"",
+                     wce=<true|false, default true>,id=<device_id>,lock=on|off,\
+                     bw=<bytes_per_second>,bw_burst=<bytes>,\
+                     iops=<operations_per_second>,iops_burst=<operations>\"",
                 )
                 .takes_value(true)
                 .min_values(1)
@@ -139,7 +159,14 @@ fn create_app<'a, 'b>(
                     "Network parameters \
                      \"tap=<if_name>,ip=<ip_addr>,mask=<net_mask>,mac=<mac_addr>,iommu=on|off,\
                      num_queues=<number_of_queues>,queue_size=<size_of_each_queue>,\
-                     vhost_user=<vhost_user_enable>,socket=<vhost_user_socket_path>\"",
+                     vhost_user=<vhost_user_enable>,socket=<vhost_user_socket_path>,\
+                     id=<device_id>,\
+                     rx_bw=<bytes_per_second>,rx_bw_burst=<bytes>,\
+                     rx_pps=<packets_per_second>,rx_pps_burst=<packets>,\
+                     tx_bw=<bytes_per_second>,tx_bw_burst=<bytes>,\
+                     tx_pps=<packets_per_second>,tx_pps_burst=<packets>,\
+                     user=on|off,hostfwd=<tcp|udp>:[<host_ip>:]<host_port>:<guest_port>,\
+                     host_loopback=on|off\"",
                 )
                 .takes_value(true)
                 .min_values(1)
@@ -161,7 +188,7 @@ fn create_app<'a, 'b>(
                     "virtio-fs parameters \
                      \"tag=<tag_name>,sock=<socket_path>,num_queues=<number_of_queues>,\
                      queue_size=<size_of_each_queue>,dax=on|off,cache_size=<DAX cache size: \
-                     default 8Gib>\"",
+                     default 8Gib>,id=<device_id>\"",
                 )
                 .takes_value(true)
                 .min_values(1)
@@ -173,7 +200,7 @@ fn create_app<'a, 'b>(
                 .help(
                     "Persistent memory parameters \
                      \"file=<backing_file_path>,size=<persistent_memory_size>,iommu=on|off,\
-                     mergeable=on|off\"",
+                     mergeable=on|off,id=<device_id>\"",
                 )
                 .takes_value(true)
                 .min_values(1)
@@ -221,7 +248,8 @@ fn create_app<'a, 'b>(
             Arg::with_name("vsock")
                 .long("vsock")
                 .help(
-                    "Virtio VSOCK parameters \"cid=<context_id>,sock=<socket_path>,iommu=on|off\"",
+                    "Virtio VSOCK parameters \
+                     \"cid=<context_id>,sock=<socket_path>,iommu=on|off,id=<device_id>\"",
                 )
                 .takes_value(true)
                 .min_values(1)
@@ -251,13 +279,28 @@ fn create_app<'a, 'b>(
                 .default_value(&api_server_path)
                 .group("vmm-config"),
         )
+        .arg(
+            Arg::with_name("event-monitor")
+                .long("event-monitor")
+                .help(
+                    "File to report the VMM and guest events on, as JSON objects. \
+                     \"path=<path/to/a/file>\" or \"fd=<fd>\"",
+                )
+                .takes_value(true)
+                .min_values(1)
+                .group("vmm-config"),
+        )
         .arg(
             Arg::with_name("net-backend")
                 .long("net-backend")
                 .help(
                     "vhost-user-net backend parameters \
                      \"ip=<ip_addr>,mask=<net_mask>,sock=<socket_path>,\
-                     num_queues=<number_of_queues>,queue_size=<size_of_each_queue>,tap=<if_name>\"",
+                     num_queues=<number_of_queues>,queue_size=<size_of_each_queue>,tap=<if_name>,\
+                     rx_bw=<bytes_per_second>,rx_bw_burst=<bytes>,\
+                     rx_pps=<packets_per_second>,rx_pps_burst=<packets>,\
+                     tx_bw=<bytes_per_second>,tx_bw_burst=<bytes>,\
+                     tx_pps=<packets_per_second>,tx_pps_burst=<packets>\"",
                 )
                 .takes_value(true)
                 .conflicts_with_all(&["block-backend", "kernel"])
@@ -269,22 +312,81 @@ fn create_app<'a, 'b>(
                 .help(
                     "vhost-user-block backend parameters \
                      \"image=<image_path>,sock=<socket_path>,num_queues=<number_of_queues>,\
-                     readonly=true|false,direct=true|false,poll_queue=true|false\"",
+                     readonly=true|false,direct=true|false,poll_queue=true|false,\
+                     lock=true|false\"",
                 )
                 .takes_value(true)
                 .conflicts_with_all(&["net-backend", "kernel"])
                 .min_values(1),
         )
+        .arg(
+            Arg::with_name("restore")
+                .long("restore")
+                .help("Restore from a VM snapshot. \"source_url=<source_url>\"")
+                .takes_value(true)
+                .conflicts_with("kernel")
+                .min_values(1)
+                .group("vmm-config"),
+        )
+}
+
+fn open_event_monitor(monitor: &str) -> std::io::Result<File> {
+    if monitor.starts_with("path=") {
+        File::create(&monitor[5..])
+    } else if monitor.starts_with("fd=") {
+        let fd = monitor[3..]
+            .parse::<i32>()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        // The file descriptor has been handed over to the VMM, which now
+        // owns it.
+        Ok(unsafe { File::from_raw_fd(fd) })
+    } else {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "expected path=<path> or fd=<fd>",
+        ))
+    }
 }
 
 fn start_vmm(cmd_arguments: ArgMatches) {
-    let vm_params = config::VmParams::from_arg_matches(&cmd_arguments);
-    let vm_config = match config::VmConfig::parse(vm_params) {
-        Ok(config) => config,
-        Err(e) => {
-            println!("Failed parsing parameters {:?}", e);
-            process::exit(1);
+    if let Some(monitor) = cmd_arguments.value_of("event-monitor") {
+        match open_event_monitor(monitor) {
+            Ok(file) => event_monitor::set_monitor(file),
+            Err(e) => {
+                println!("Failed opening the event monitor {:?}", e);
+                process::exit(1);
+            }
         }
+    }
+
+    let vm_config = if let Some(config_file) = cmd_arguments.value_of("config") {
+        match config::VmConfig::from_file(Path::new(config_file)) {
+            Ok(config) => config,
+            Err(e) => {
+                println!("Failed loading configuration file {:?}", e);
+                process::exit(1);
+            }
+        }
+    } else {
+        let vm_params = config::VmParams::from_arg_matches(&cmd_arguments);
+        match config::VmConfig::parse(vm_params) {
+            Ok(config) => config,
+            Err(e) => {
+                println!("Failed parsing parameters {:?}", e);
+                process::exit(1);
+            }
+        }
+    };
+
+    let restore_config = match cmd_arguments.value_of("restore") {
+        Some(restore) => match config::RestoreConfig::parse(restore) {
+            Ok(config) => Some(config),
+            Err(e) => {
+                println!("Failed parsing restore parameters {:?}", e);
+                process::exit(1);
+            }
+        },
+        None => None,
     };
 
     let api_socket_path = cmd_arguments
@@ -320,7 +422,18 @@ fn start_vmm(cmd_arguments: ArgMatches) {
         }
     };
 
-    if cmd_arguments.is_present("vm-config") && vm_config.valid() {
+    if let Some(restore_config) = restore_config {
+        // Restore the VM from the snapshot. The VM is left paused until it
+        // gets resumed through the API.
+        vmm::api::vm_restore(
+            api_evt.try_clone().unwrap(),
+            api_request_sender,
+            Arc::new(restore_config),
+        )
+        .expect("Could not restore the VM");
+    } else if (cmd_arguments.is_present("config") || cmd_arguments.is_present("vm-config"))
+        && vm_config.valid()
+    {
         // Create and boot the VM based off the VM config we just built.
         let sender = api_request_sender.clone();
         vmm::api::vm_create(
@@ -414,7 +527,10 @@ extern crate credibility;
 #[cfg(test)]
 mod unit_tests {
     use crate::{create_app, prepare_default_values};
+    use std::fs::File;
+    use std::io::Write;
     use std::path::PathBuf;
+    use tempdir::TempDir;
     use vmm::config::{
         CmdlineConfig, ConsoleConfig, ConsoleOutputMode, CpusConfig, MemoryConfig, RngConfig,
         VmConfig, VmParams,
@@ -690,6 +806,21 @@ mod unit_tests {
                 }"#,
                 true,
             ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--disk",
+                    "path=/path/to/disk/1,bw=10485760,bw_burst=20971520,iops=100",
+                    "path=/path/to/disk/2,iops_burst=100",
+                ],
+                r#"{
+                    "disks": [
+                        {"path": "/path/to/disk/1", "rate_limiter_config": {"bandwidth": {"rate": 10485760, "burst": 20971520}, "ops": {"rate": 100}}},
+                        {"path": "/path/to/disk/2"}
+                    ]
+                }"#,
+                true,
+            ),
         ]
         .iter()
         .for_each(|(cli, openapi, equal)| {
@@ -859,6 +990,48 @@ mod unit_tests {
                 }"#,
                 true,
             ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--net",
+                    "mac=12:34:56:78:90:ab,tap=tap0,rx_bw=10485760,rx_bw_burst=20971520,tx_pps=1000",
+                ],
+                r#"{
+                    "net": [
+                        {"mac": "12:34:56:78:90:ab", "tap": "tap0", "rx_rate_limiter_config": {"bandwidth": {"rate": 10485760, "burst": 20971520}}, "tx_rate_limiter_config": {"ops": {"rate": 1000}}}
+                    ]
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--net",
+                    "mac=12:34:56:78:90:ab,user=on,hostfwd=tcp:2222:22,hostfwd=udp:0.0.0.0:5353:53",
+                ],
+                r#"{
+                    "net": [
+                        {"mac": "12:34:56:78:90:ab", "user": true, "port_forwards": [
+                            {"protocol": "Tcp", "host_port": 2222, "guest_port": 22},
+                            {"protocol": "Udp", "host_ip": "0.0.0.0", "host_port": 5353, "guest_port": 53}
+                        ]}
+                    ]
+                }"#,
+                true,
+            ),
+            (
+                vec![
+                    "cloud-hypervisor",
+                    "--net",
+                    "mac=12:34:56:78:90:ab,user=on,host_loopback=on",
+                ],
+                r#"{
+                    "net": [
+                        {"mac": "12:34:56:78:90:ab", "user": true, "host_loopback": true}
+                    ]
+                }"#,
+                true,
+            ),
         ]
         .iter()
         .for_each(|(cli, openapi, equal)| {
@@ -1316,4 +1489,82 @@ mod unit_tests {
             compare_vm_config_cli_vs_json(cli, openapi, *equal);
         });
     }
+
+    #[test]
+    fn test_valid_vm_config_file() {
+        let cli = vec![
+            "cloud-hypervisor",
+            "--cpus",
+            "boot=2",
+            "--kernel",
+            "/path/to/kernel",
+            "--disk",
+            "path=/path/to/disk/1,iommu=on",
+        ];
+        let config = r#"{
+            "cpus": {"boot_vcpus": 2, "max_vcpus": 2},
+            "kernel": {"path": "/path/to/kernel"},
+            "disks": [
+                {"path": "/path/to/disk/1", "iommu": true}
+            ]
+        }"#;
+
+        let tmp_dir = TempDir::new("ch").unwrap();
+        let config_path = tmp_dir.path().join("vm.json");
+        File::create(&config_path)
+            .unwrap()
+            .write_all(config.as_bytes())
+            .unwrap();
+
+        test_block!(tb, "", {
+            aver_eq!(
+                tb,
+                get_vm_config_from_vec(&cli),
+                VmConfig::from_file(&config_path).unwrap()
+            );
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_invalid_vm_config_file_conflicts() {
+        let (default_vcpus, default_memory, default_rng) = prepare_default_values();
+
+        // Parameters the configuration file already holds are rejected,
+        // instead of being silently ignored.
+        vec![
+            vec!["--cpus", "boot=2"],
+            vec!["--memory", "size=1G"],
+            vec!["--kernel", "/path/to/kernel"],
+            vec!["--cmdline", "console=ttyS0"],
+            vec!["--disk", "path=/path/to/disk"],
+            vec!["--net", "tap=tap0"],
+            vec!["--rng", "src=/dev/random"],
+            vec!["--fs", "tag=virtiofs,sock=/tmp/virtiofs.sock"],
+            vec!["--pmem", "file=/tmp/pmem,size=128M"],
+            vec!["--serial", "tty"],
+            vec!["--console", "off"],
+            vec!["--device", "path=/path/to/device"],
+            vec!["--vsock", "cid=3,sock=/tmp/vsock"],
+            vec!["--restore", "source_url=/path/to/snapshot"],
+        ]
+        .iter()
+        .for_each(|args| {
+            let mut cli = vec!["cloud-hypervisor", "--config", "/path/to/vm.json"];
+            cli.extend(args);
+
+            assert!(
+                create_app(&default_vcpus, &default_memory, &default_rng, "")
+                    .get_matches_from_safe(cli)
+                    .is_err()
+            );
+        });
+
+        // Default values don't conflict with the configuration file.
+        assert!(
+            create_app(&default_vcpus, &default_memory, &default_rng, "")
+                .get_matches_from_safe(vec!["cloud-hypervisor", "--config", "/path/to/vm.json"])
+                .is_ok()
+        );
+    }
 }
@@ -1176,6 +1176,235 @@ mod tests {
         });
     }
 
+    // Spawn a VMM using the raw OS disk of the guest as an additional disk,
+    // with the given lock parameter.
+    fn spawn_disk_lock_guest(guest: &Guest, lock: &str) -> Child {
+        GuestCommand::new(guest)
+            .args(&["--cpus", "boot=1"])
+            .args(&["--memory", "size=512M"])
+            .args(&["--kernel", guest.fw_path.as_str()])
+            .args(&[
+                "--disk",
+                format!(
+                    "path={}",
+                    guest.disk_config.disk(DiskType::OperatingSystem).unwrap()
+                )
+                .as_str(),
+                format!(
+                    "path={}",
+                    guest.disk_config.disk(DiskType::CloudInit).unwrap()
+                )
+                .as_str(),
+                format!(
+                    "path={},lock={}",
+                    guest
+                        .disk_config
+                        .disk(DiskType::RawOperatingSystem)
+                        .unwrap(),
+                    lock
+                )
+                .as_str(),
+            ])
+            .default_net()
+            .spawn()
+            .unwrap()
+    }
+
+    // Spawn a second VMM, without any network, using the same additional
+    // disk as spawn_disk_lock_guest().
+    fn spawn_disk_lock_contender(guest: &Guest, lock: &str) -> Child {
+        GuestCommand::new(guest)
+            .args(&["--cpus", "boot=1"])
+            .args(&["--memory", "size=512M"])
+            .args(&["--kernel", guest.fw_path.as_str()])
+            .args(&[
+                "--disk",
+                format!(
+                    "path={},lock={}",
+                    guest
+                        .disk_config
+                        .disk(DiskType::RawOperatingSystem)
+                        .unwrap(),
+                    lock
+                )
+                .as_str(),
+            ])
+            .spawn()
+            .unwrap()
+    }
+
+    #[cfg_attr(not(feature = "mmio"), test)]
+    fn test_disk_lock() {
+        test_block!(tb, "", {
+            let mut clear = ClearDiskConfig::new();
+            let guest = Guest::new(&mut clear);
+
+            let mut cloud_child = spawn_disk_lock_guest(&guest, "on");
+            thread::sleep(std::time::Duration::new(20, 0));
+
+            // Check the disk has been plugged in the guest.
+            aver_eq!(
+                tb,
+                guest
+                    .ssh_command("lsblk | grep -c vdc")
+                    .unwrap_or_default()
+                    .trim()
+                    .parse::<u32>()
+                    .unwrap_or_default(),
+                1
+            );
+
+            // Opening the disk from another VMM fails, unless that VMM opts
+            // out of locking.
+            for lock in &["on", "off"] {
+                let mut contender = spawn_disk_lock_contender(&guest, lock);
+                thread::sleep(std::time::Duration::new(5, 0));
+                let status = contender.try_wait().unwrap();
+                let _ = contender.kill();
+                let _ = contender.wait();
+                if *lock == "on" {
+                    aver!(tb, status.map_or(false, |s| !s.success()));
+                } else {
+                    aver!(tb, status.is_none());
+                }
+            }
+
+            // The lock is released along with the VM.
+            let _ = cloud_child.kill();
+            let _ = cloud_child.wait();
+
+            let mut contender = spawn_disk_lock_contender(&guest, "on");
+            thread::sleep(std::time::Duration::new(5, 0));
+            aver!(tb, contender.try_wait().unwrap().is_none());
+            let _ = contender.kill();
+            let _ = contender.wait();
+
+            Ok(())
+        });
+    }
+
+    #[cfg_attr(not(feature = "mmio"), test)]
+    fn test_disk_lock_off() {
+        test_block!(tb, "", {
+            let mut clear = ClearDiskConfig::new();
+            let guest = Guest::new(&mut clear);
+
+            let mut cloud_child = spawn_disk_lock_guest(&guest, "off");
+            thread::sleep(std::time::Duration::new(20, 0));
+
+            aver_eq!(
+                tb,
+                guest
+                    .ssh_command("lsblk | grep -c vdc")
+                    .unwrap_or_default()
+                    .trim()
+                    .parse::<u32>()
+                    .unwrap_or_default(),
+                1
+            );
+
+            // The disk isn't locked, it can be shared with another VMM.
+            let mut contender = spawn_disk_lock_contender(&guest, "on");
+            thread::sleep(std::time::Duration::new(5, 0));
+            aver!(tb, contender.try_wait().unwrap().is_none());
+            let _ = contender.kill();
+            let _ = contender.wait();
+
+            let _ = cloud_child.kill();
+            let _ = cloud_child.wait();
+
+            Ok(())
+        });
+    }
+
+    #[cfg_attr(not(feature = "mmio"), test)]
+    // Migrate a VM between two VMMs running on the same host, which requires
+    // the disk image locks to be handed over from the source VMM to the
+    // destination one.
+    fn test_local_migration_disk_lock() {
+        test_block!(tb, "", {
+            let mut clear = ClearDiskConfig::new();
+            let guest = Guest::new(&mut clear);
+
+            let src_api_socket = temp_api_path(&guest.tmp_dir);
+            let dest_api_socket = String::from(
+                guest
+                    .tmp_dir
+                    .path()
+                    .join("cloud-hypervisor-dest.sock")
+                    .to_str()
+                    .unwrap(),
+            );
+            let migration_url = format!(
+                "unix:{}",
+                guest
+                    .tmp_dir
+                    .path()
+                    .join("migration.sock")
+                    .to_str()
+                    .unwrap()
+            );
+
+            let mut src_child = GuestCommand::new(&guest)
+                .args(&["--api-socket", &src_api_socket])
+                .args(&["--cpus", "boot=1"])
+                .args(&["--memory", "size=512M"])
+                .args(&["--kernel", guest.fw_path.as_str()])
+                .default_disks()
+                .default_net()
+                .spawn()
+                .unwrap();
+            let mut dest_child = GuestCommand::new(&guest)
+                .args(&["--api-socket", &dest_api_socket])
+                .spawn()
+                .unwrap();
+
+            thread::sleep(std::time::Duration::new(20, 0));
+            aver_eq!(tb, guest.get_cpu_count().unwrap_or_default(), 1);
+
+            // The destination VMM waits for the migration in the background.
+            let receiver = {
+                let dest_api_socket = dest_api_socket.clone();
+                let migration_url = migration_url.clone();
+                thread::spawn(move || {
+                    remote_command(&dest_api_socket, "receive-migration", Some(&migration_url))
+                })
+            };
+            thread::sleep(std::time::Duration::new(2, 0));
+
+            aver!(
+                tb,
+                remote_command(&src_api_socket, "send-migration", Some(&migration_url))
+            );
+            aver!(tb, receiver.join().unwrap());
+
+            // The source VMM exits once the VM runs in the destination one.
+            thread::sleep(std::time::Duration::new(2, 0));
+            aver!(
+                tb,
+                src_child.try_wait().unwrap().map_or(false, |s| s.success())
+            );
+
+            // The migrated VM keeps on running, with a writable disk.
+            aver_eq!(tb, guest.get_cpu_count().unwrap_or_default(), 1);
+            aver_eq!(
+                tb,
+                guest
+                    .ssh_command("echo migrated > migrated && sync && cat migrated")
+                    .unwrap_or_default()
+                    .trim(),
+                "migrated"
+            );
+
+            let _ = src_child.kill();
+            let _ = src_child.wait();
+            let _ = dest_child.kill();
+            let _ = dest_child.wait();
+
+            Ok(())
+        });
+    }
+
     fn test_vhost_user_net(
         tap: Option<&str>,
         num_queues: usize,
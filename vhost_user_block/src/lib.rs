@@ -34,10 +34,12 @@ use std::{convert, error, fmt, io};
 use vhost_rs::vhost_user::message::*;
 use vhost_user_backend::{VhostUserBackend, VhostUserDaemon, Vring, VringWorker};
 use virtio_bindings::bindings::virtio_blk::*;
-use virtio_bindings::bindings::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
+use virtio_bindings::bindings::virtio_ring::{
+    VIRTIO_RING_F_EVENT_IDX, VIRTIO_RING_F_INDIRECT_DESC,
+};
 use vm_memory::{Bytes, GuestMemoryError, GuestMemoryMmap};
-use vm_virtio::block::{build_disk_image_id, Request};
-use vmm_sys_util::eventfd::EventFd;
+use vm_virtio::block::{build_disk_image_id, DiskIovecs, Request};
+use vmm_sys_util::{eventfd::EventFd, write_zeroes::PunchHole};
 
 const QUEUE_SIZE: usize = 1024;
 const SECTOR_SHIFT: u8 = 9;
@@ -48,8 +50,24 @@ const BLK_SIZE: u32 = 512;
 // and the overhead of the emulation layer.
 const POLL_QUEUE_US: u128 = 50;
 
-trait DiskFile: Read + Seek + Write + Send + Sync {}
-impl<D: Read + Seek + Write + Send + Sync> DiskFile for D {}
+trait DiskFile: Read + Seek + Write + PunchHole + DiskIovecs + Send + Sync {}
+impl<D: Read + Seek + Write + PunchHole + DiskIovecs + Send + Sync> DiskFile for D {}
+
+impl PunchHole for Box<dyn DiskFile> {
+    fn punch_hole(&mut self, offset: u64, length: u64) -> io::Result<()> {
+        (**self).punch_hole(offset, length)
+    }
+}
+
+impl DiskIovecs for Box<dyn DiskFile> {
+    unsafe fn read_iovecs(&mut self, iovecs: &[libc::iovec]) -> io::Result<usize> {
+        (**self).read_iovecs(iovecs)
+    }
+
+    unsafe fn write_iovecs(&mut self, iovecs: &[libc::iovec]) -> io::Result<usize> {
+        (**self).write_iovecs(iovecs)
+    }
+}
 
 pub type Result<T> = std::result::Result<T, Error>;
 pub type VhostUserBackendResult<T> = std::result::Result<T, std::io::Error>;
@@ -74,6 +92,10 @@ pub enum Error {
     ParseBlkNumQueuesParam(std::num::ParseIntError),
     /// Failed to parse the poll_queue parameter.
     ParsePollQueueParam,
+    /// Failed to parse the lock parameter.
+    ParseLockParam,
+    /// Can't lock image file, it may be in use by another process.
+    LockImage(io::Error),
     /// Failed to handle event other than input event.
     HandleEventNotEpollIn,
     /// Failed to create kill eventfd
@@ -116,6 +138,7 @@ impl VhostUserBlkBackend {
         rdonly: bool,
         direct: bool,
         poll_queue: bool,
+        lock: bool,
     ) -> Result<Self> {
         let mut options = OpenOptions::new();
         options.read(true);
@@ -124,6 +147,9 @@ impl VhostUserBlkBackend {
             options.custom_flags(libc::O_DIRECT);
         }
         let image: File = options.open(&image_path).unwrap();
+        if lock {
+            vm_virtio::lock_disk_image(&image, rdonly).map_err(Error::LockImage)?;
+        }
         let mut raw_img: vm_virtio::RawFile = vm_virtio::RawFile::new(image, direct);
 
         let image_id = build_disk_image_id(&PathBuf::from(&image_path));
@@ -138,13 +164,24 @@ impl VhostUserBlkBackend {
 
         config.capacity = nsectors;
         config.blk_size = BLK_SIZE;
-        config.size_max = 65535;
-        config.seg_max = 128 - 2;
+        config.size_max = std::u32::MAX;
+        config.seg_max = QUEUE_SIZE as u32 - 2;
         config.min_io_size = 1;
         config.opt_io_size = 1;
         config.num_queues = num_queues as u16;
         config.wce = 1;
 
+        // Discarded and zeroed ranges are deallocated from the image, which
+        // makes no sense for a read-only disk.
+        if !rdonly {
+            config.max_discard_sectors = std::u32::MAX;
+            config.max_discard_seg = 1;
+            config.discard_sector_alignment = 1;
+            config.max_write_zeroes_sectors = std::u32::MAX;
+            config.max_write_zeroes_seg = 1;
+            config.write_zeroes_may_unmap = 1;
+        }
+
         Ok(VhostUserBlkBackend {
             mem: None,
             vring_worker: None,
@@ -233,12 +270,17 @@ impl VhostUserBackend for VhostUserBlkBackend {
     fn features(&self) -> u64 {
         let mut avail_features = 1 << VIRTIO_BLK_F_MQ
             | 1 << VIRTIO_BLK_F_CONFIG_WCE
+            | 1 << VIRTIO_BLK_F_SEG_MAX
+            | 1 << VIRTIO_BLK_F_SIZE_MAX
             | 1 << VIRTIO_RING_F_EVENT_IDX
+            | 1 << VIRTIO_RING_F_INDIRECT_DESC
             | 1 << VIRTIO_F_VERSION_1
             | VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits();
 
         if self.rdonly {
             avail_features |= 1 << VIRTIO_BLK_F_RO;
+        } else {
+            avail_features |= 1 << VIRTIO_BLK_F_DISCARD | 1 << VIRTIO_BLK_F_WRITE_ZEROES;
         }
         avail_features
     }
@@ -333,6 +375,7 @@ pub struct VhostUserBlkBackendConfig<'a> {
     pub readonly: bool,
     pub direct: bool,
     pub poll_queue: bool,
+    pub lock: bool,
 }
 
 impl<'a> VhostUserBlkBackendConfig<'a> {
@@ -345,6 +388,7 @@ impl<'a> VhostUserBlkBackendConfig<'a> {
         let mut readonly: bool = false;
         let mut direct: bool = false;
         let mut poll_queue: bool = true;
+        let mut lock: bool = true;
 
         for param in params_list.iter() {
             if param.starts_with("image=") {
@@ -368,6 +412,11 @@ impl<'a> VhostUserBlkBackendConfig<'a> {
                     Ok(b) => b,
                     Err(_) => return Err(Error::ParsePollQueueParam),
                 }
+            } else if param.starts_with("lock=") {
+                lock = match param[5..].parse::<bool>() {
+                    Ok(b) => b,
+                    Err(_) => return Err(Error::ParseLockParam),
+                }
             }
         }
 
@@ -390,6 +439,7 @@ impl<'a> VhostUserBlkBackendConfig<'a> {
             readonly,
             direct,
             poll_queue,
+            lock,
         })
     }
 }
@@ -403,16 +453,20 @@ pub fn start_block_backend(backend_command: &str) {
         }
     };
 
-    let blk_backend = Arc::new(RwLock::new(
-        VhostUserBlkBackend::new(
-            backend_config.image.to_string(),
-            backend_config.num_queues,
-            backend_config.readonly,
-            backend_config.direct,
-            backend_config.poll_queue,
-        )
-        .unwrap(),
-    ));
+    let blk_backend = match VhostUserBlkBackend::new(
+        backend_config.image.to_string(),
+        backend_config.num_queues,
+        backend_config.readonly,
+        backend_config.direct,
+        backend_config.poll_queue,
+        backend_config.lock,
+    ) {
+        Ok(backend) => Arc::new(RwLock::new(backend)),
+        Err(e) => {
+            error!("Failed creating the block backend: {:?}", e);
+            process::exit(1);
+        }
+    };
 
     debug!("blk_backend is created!\n");
 
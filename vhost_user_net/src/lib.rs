@@ -13,26 +13,26 @@ extern crate vhost_user_backend;
 extern crate vm_virtio;
 
 use epoll;
-use libc::{self, EAGAIN, EFD_NONBLOCK};
+use libc::{self, EFD_NONBLOCK};
 use log::*;
 use net_util::Tap;
 use std::convert::TryFrom;
 use std::fmt;
-use std::io::Read;
 use std::io::{self};
 use std::net::Ipv4Addr;
 use std::os::unix::io::AsRawFd;
 use std::process;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 use std::vec::Vec;
 use vhost_rs::vhost_user::message::*;
 use vhost_rs::vhost_user::Error as VhostUserError;
 use vhost_user_backend::{VhostUserBackend, VhostUserDaemon, Vring, VringWorker};
 use virtio_bindings::bindings::virtio_net::*;
 use vm_memory::GuestMemoryMmap;
-use vm_virtio::net_util::{open_tap, RxVirtio, TxVirtio};
-use vm_virtio::Queue;
-use vmm_sys_util::eventfd::EventFd;
+use vm_virtio::net_util::{open_tap, RxStatus, RxVirtio, TxVirtio};
+use vm_virtio::{Queue, RateLimiter, RateLimiterConfig, TokenBucketConfig};
+use vmm_sys_util::{eventfd::EventFd, timerfd::TimerFd};
 
 pub type VhostUserResult<T> = std::result::Result<T, VhostUserError>;
 pub type Result<T> = std::result::Result<T, Error>;
@@ -44,14 +44,14 @@ pub enum Error {
     BadActivate,
     /// Failed to create kill eventfd
     CreateKillEventFd(io::Error),
+    /// Failed to create rate limiter timerfd
+    CreateRateLimiterTimerFd(io::Error),
     /// Failed to add event.
     EpollCtl(io::Error),
     /// Fail to wait event.
     EpollWait(io::Error),
     /// Failed to create EventFd.
     EpollCreateFd,
-    /// Failed to read Tap.
-    FailedReadTap,
     /// Failed to signal used queue.
     FailedSignalingUsedQueue,
     /// Failed to handle event other than input event.
@@ -74,6 +74,8 @@ pub enum Error {
     ParseQueueNumParam(std::num::ParseIntError),
     /// Failed to parse queue size.
     ParseQueueSizeParam(std::num::ParseIntError),
+    /// Failed to parse bandwidth or packet rate limits.
+    ParseRateLimiterParam(std::num::ParseIntError),
     /// Open tap device failed.
     OpenTap(vm_virtio::net_util::Error),
 }
@@ -100,28 +102,47 @@ pub struct VhostUserNetBackend {
     rxs: Vec<RxVirtio>,
     txs: Vec<TxVirtio>,
     rx_tap_listenings: Vec<bool>,
+    rx_rate_limiter_timers: Vec<TimerFd>,
+    tx_rate_limiter_timers: Vec<TimerFd>,
     num_queues: usize,
     queue_size: u16,
 }
 
 impl VhostUserNetBackend {
     /// Create a new virtio network device with the given TAP interface.
-    pub fn new_with_tap(taps: Vec<Tap>, num_queues: usize, queue_size: u16) -> Result<Self> {
+    /// The received and transmitted traffic are limited, across all the
+    /// queue pairs, by the given rate limiter configurations.
+    pub fn new_with_tap(
+        taps: Vec<Tap>,
+        num_queues: usize,
+        queue_size: u16,
+        rx_rate_limiter_config: &RateLimiterConfig,
+        tx_rate_limiter_config: &RateLimiterConfig,
+    ) -> Result<Self> {
         let mut taps_v: Vec<(Tap, usize)> = Vec::new();
         for (i, tap) in taps.iter().enumerate() {
             taps_v.push((tap.clone(), num_queues + i));
         }
 
+        let rx_rate_limiter = Arc::new(Mutex::new(RateLimiter::new(rx_rate_limiter_config)));
+        let tx_rate_limiter = Arc::new(Mutex::new(RateLimiter::new(tx_rate_limiter_config)));
+
         let mut rxs: Vec<RxVirtio> = Vec::new();
         let mut txs: Vec<TxVirtio> = Vec::new();
         let mut rx_tap_listenings: Vec<bool> = Vec::new();
+        let mut rx_rate_limiter_timers: Vec<TimerFd> = Vec::new();
+        let mut tx_rate_limiter_timers: Vec<TimerFd> = Vec::new();
 
         for _ in 0..taps.len() {
-            let rx = RxVirtio::new();
+            let mut rx = RxVirtio::new();
+            rx.rate_limiter = rx_rate_limiter.clone();
             rxs.push(rx);
-            let tx = TxVirtio::new();
+            let mut tx = TxVirtio::new();
+            tx.rate_limiter = tx_rate_limiter.clone();
             txs.push(tx);
             rx_tap_listenings.push(false);
+            rx_rate_limiter_timers.push(TimerFd::new().map_err(Error::CreateRateLimiterTimerFd)?);
+            tx_rate_limiter_timers.push(TimerFd::new().map_err(Error::CreateRateLimiterTimerFd)?);
         }
 
         Ok(VhostUserNetBackend {
@@ -132,6 +153,8 @@ impl VhostUserNetBackend {
             rxs,
             txs,
             rx_tap_listenings,
+            rx_rate_limiter_timers,
+            tx_rate_limiter_timers,
             num_queues,
             queue_size,
         })
@@ -145,110 +168,138 @@ impl VhostUserNetBackend {
         num_queues: usize,
         queue_size: u16,
         ifname: Option<&str>,
+        rx_rate_limiter_config: &RateLimiterConfig,
+        tx_rate_limiter_config: &RateLimiterConfig,
     ) -> Result<Self> {
         let taps = open_tap(ifname, Some(ip_addr), Some(netmask), num_queues / 2)
             .map_err(Error::OpenTap)?;
 
-        Self::new_with_tap(taps, num_queues, queue_size)
+        Self::new_with_tap(
+            taps,
+            num_queues,
+            queue_size,
+            rx_rate_limiter_config,
+            tx_rate_limiter_config,
+        )
     }
 
-    // Copies a single frame from `self.rx.frame_buf` into the guest. Returns true
-    // if a buffer was used, and false if the frame must be deferred until a buffer
-    // is made available by the driver.
-    fn rx_single_frame(&mut self, mut queue: &mut Queue, index: usize) -> Result<bool> {
-        let mem = self.mem.as_ref().ok_or(Error::NoMemoryConfigured)?;
+    // The rate limiter timers events come after the tap events and the
+    // kill event.
+    fn rx_rate_limiter_event(&self, index: usize) -> u16 {
+        (self.num_queues + self.num_queues / 2 + 1 + index) as u16
+    }
 
-        let next_desc = queue.iter(&mem).next();
+    fn tx_rate_limiter_event(&self, index: usize) -> u16 {
+        self.rx_rate_limiter_event(self.num_queues / 2 + index)
+    }
 
-        if next_desc.is_none() {
-            // Queue has no available descriptors
-            if self.rx_tap_listenings[index] {
-                self.vring_worker
-                    .as_ref()
-                    .unwrap()
-                    .unregister_listener(
-                        self.taps[index].0.as_raw_fd(),
-                        epoll::Events::EPOLLIN,
-                        u64::try_from(self.taps[index].1).unwrap(),
-                    )
-                    .unwrap();
-                self.rx_tap_listenings[index] = false;
-            }
-            return Ok(false);
+    fn stop_rx_tap_listening(&mut self, index: usize) {
+        if self.rx_tap_listenings[index] {
+            self.vring_worker
+                .as_ref()
+                .unwrap()
+                .unregister_listener(
+                    self.taps[index].0.as_raw_fd(),
+                    epoll::Events::EPOLLIN,
+                    u64::try_from(self.taps[index].1).unwrap(),
+                )
+                .unwrap();
+            self.rx_tap_listenings[index] = false;
         }
+    }
 
-        let write_complete = self.rxs[index].process_desc_chain(&mem, next_desc, &mut queue);
+    // Stops reading from the tap until the rate limiter timer expires, as the
+    // frame that was just read can't be handed to the guest yet.
+    fn throttle_rx(&mut self, index: usize, delay: Duration) {
+        self.stop_rx_tap_listening(index);
 
-        Ok(write_complete)
+        match self.rx_rate_limiter_timers[index].reset(delay, None) {
+            Ok(()) => self.rxs[index].throttled = true,
+            Err(e) => error!("Failed to arm the rx rate limiter timer: {:?}", e),
+        }
     }
 
     fn process_rx(&mut self, vring: &mut Vring, index: usize) -> Result<()> {
+        let mem = self.mem.as_ref().ok_or(Error::NoMemoryConfigured)?;
+
         // Read as many frames as possible.
-        loop {
-            match self.read_tap(index) {
-                Ok(count) => {
-                    self.rxs[index].bytes_read = count;
-                    if !self.rx_single_frame(&mut vring.mut_queue(), index)? {
-                        self.rxs[index].deferred_frame = true;
-                        break;
-                    }
-                }
-                Err(e) => {
-                    // The tap device is non-blocking, so any error aside from EAGAIN is
-                    // unexpected.
-                    match e.raw_os_error() {
-                        Some(err) if err == EAGAIN => (),
-                        _ => {
-                            error!("Failed to read tap: {:?}", e);
-                            return Err(Error::FailedReadTap);
-                        }
-                    };
-                    break;
-                }
-            }
+        let status = self.rxs[index].process_desc_chain(
+            &mem,
+            &mut self.taps[index].0,
+            &mut vring.mut_queue(),
+        );
+        match status {
+            // Stop listening to the tap until the driver makes buffers
+            // available.
+            RxStatus::QueueFull => self.stop_rx_tap_listening(index),
+            RxStatus::Throttled(delay) => self.throttle_rx(index, delay),
+            RxStatus::TapEmpty => (),
         }
+
         if self.rxs[index].deferred_irqs {
             self.rxs[index].deferred_irqs = false;
             vring.signal_used_queue().unwrap();
-            Ok(())
-        } else {
-            Ok(())
         }
+
+        Ok(())
     }
 
-    fn resume_rx(&mut self, vring: &mut Vring, index: usize) -> Result<()> {
-        if self.rxs[index].deferred_frame {
-            if self.rx_single_frame(&mut vring.mut_queue(), index)? {
-                self.rxs[index].deferred_frame = false;
-                // process_rx() was interrupted possibly before consuming all
-                // packets in the tap; try continuing now.
-                self.process_rx(vring, index)
-            } else if self.rxs[index].deferred_irqs {
-                self.rxs[index].deferred_irqs = false;
-                vring.signal_used_queue().unwrap();
-                Ok(())
-            } else {
-                Ok(())
-            }
-        } else {
-            Ok(())
+    // Listens to the tap again, and receives the frames left on it.
+    fn restart_rx(&mut self, vring: &mut Vring, index: usize) -> Result<()> {
+        if !self.rx_tap_listenings[index] {
+            self.vring_worker
+                .as_ref()
+                .unwrap()
+                .register_listener(
+                    self.taps[index].0.as_raw_fd(),
+                    epoll::Events::EPOLLIN,
+                    u64::try_from(self.taps[index].1).unwrap(),
+                )
+                .map_err(Error::EpollCtl)?;
+            self.rx_tap_listenings[index] = true;
         }
+
+        self.process_rx(vring, index)
     }
 
     fn process_tx(&mut self, mut queue: &mut Queue, index: usize) -> Result<()> {
         let mem = self.mem.as_ref().ok_or(Error::NoMemoryConfigured)?;
 
-        self.txs[index].process_desc_chain(&mem, &mut self.taps[index].0, &mut queue);
+        if let Some(delay) =
+            self.txs[index].process_desc_chain(&mem, &mut self.taps[index].0, &mut queue)
+        {
+            match self.tx_rate_limiter_timers[index].reset(delay, None) {
+                Ok(()) => self.txs[index].throttled = true,
+                Err(e) => error!("Failed to arm the tx rate limiter timer: {:?}", e),
+            }
+        }
 
         Ok(())
     }
 
-    fn read_tap(&mut self, index: usize) -> io::Result<usize> {
-        self.taps[index].0.read(&mut self.rxs[index].frame_buf)
-    }
+    pub fn set_vring_worker(&mut self, vring_worker: Option<Arc<VringWorker>>) -> Result<()> {
+        if let Some(vring_worker) = vring_worker.as_ref() {
+            for index in 0..self.taps.len() {
+                vring_worker
+                    .register_listener(
+                        self.rx_rate_limiter_timers[index].as_raw_fd(),
+                        epoll::Events::EPOLLIN,
+                        u64::from(self.rx_rate_limiter_event(index)),
+                    )
+                    .map_err(Error::EpollCtl)?;
+                vring_worker
+                    .register_listener(
+                        self.tx_rate_limiter_timers[index].as_raw_fd(),
+                        epoll::Events::EPOLLIN,
+                        u64::from(self.tx_rate_limiter_event(index)),
+                    )
+                    .map_err(Error::EpollCtl)?;
+            }
+        }
 
-    pub fn set_vring_worker(&mut self, vring_worker: Option<Arc<VringWorker>>) {
         self.vring_worker = vring_worker;
+
+        Ok(())
     }
 }
 
@@ -295,44 +346,49 @@ impl VhostUserBackend for VhostUserNetBackend {
 
         let tap_start_index = self.num_queues as u16;
         let tap_end_index = (self.num_queues + self.num_queues / 2 - 1) as u16;
+        let rx_rate_limiter_start_index = self.rx_rate_limiter_event(0);
+        let tx_rate_limiter_start_index = self.tx_rate_limiter_event(0);
+        let tx_rate_limiter_end_index = self.tx_rate_limiter_event(self.num_queues / 2 - 1);
 
         match device_event {
             x if ((x < self.num_queues as u16) && (x % 2 == 0)) => {
                 let index = (x / 2) as usize;
-                let mut vring = vrings[x as usize].write().unwrap();
-                self.resume_rx(&mut vring, index)?;
-
-                if !self.rx_tap_listenings[index] {
-                    self.vring_worker.as_ref().unwrap().register_listener(
-                        self.taps[index].0.as_raw_fd(),
-                        epoll::Events::EPOLLIN,
-                        u64::try_from(self.taps[index].1).unwrap(),
-                    )?;
-                    self.rx_tap_listenings[index] = true;
+                // While throttled, frames wait for the rate limiter timer.
+                if !self.rxs[index].throttled {
+                    let mut vring = vrings[x as usize].write().unwrap();
+                    self.restart_rx(&mut vring, index)?;
                 }
             }
             x if ((x < self.num_queues as u16) && (x % 2 != 0)) => {
                 let index = ((x - 1) / 2) as usize;
-                let mut vring = vrings[x as usize].write().unwrap();
+                // While throttled, frames wait for the rate limiter timer.
+                if !self.txs[index].throttled {
+                    let mut vring = vrings[x as usize].write().unwrap();
+                    self.process_tx(&mut vring.mut_queue(), index)?;
+                }
+            }
+            x if x >= rx_rate_limiter_start_index && x < tx_rate_limiter_start_index => {
+                let index = (x - rx_rate_limiter_start_index) as usize;
+                if let Err(e) = self.rx_rate_limiter_timers[index].wait() {
+                    error!("Failed to get rx rate limiter event: {:?}", e);
+                }
+                self.rxs[index].throttled = false;
+                let mut vring = vrings[2 * index].write().unwrap();
+                self.restart_rx(&mut vring, index)?;
+            }
+            x if x >= tx_rate_limiter_start_index && x <= tx_rate_limiter_end_index => {
+                let index = (x - tx_rate_limiter_start_index) as usize;
+                if let Err(e) = self.tx_rate_limiter_timers[index].wait() {
+                    error!("Failed to get tx rate limiter event: {:?}", e);
+                }
+                self.txs[index].throttled = false;
+                let mut vring = vrings[2 * index + 1].write().unwrap();
                 self.process_tx(&mut vring.mut_queue(), index)?;
             }
             x if x >= tap_start_index && x <= tap_end_index => {
                 let index = x as usize - self.num_queues;
                 let mut vring = vrings[2 * index].write().unwrap();
-                if self.rxs[index].deferred_frame
-                // Process a deferred frame first if available. Don't read from tap again
-                // until we manage to receive this deferred frame.
-                {
-                    if self.rx_single_frame(&mut vring.mut_queue(), index)? {
-                        self.rxs[index].deferred_frame = false;
-                        self.process_rx(&mut vring, index)?;
-                    } else if self.rxs[index].deferred_irqs {
-                        self.rxs[index].deferred_irqs = false;
-                        vring.signal_used_queue()?;
-                    }
-                } else {
-                    self.process_rx(&mut vring, index)?;
-                }
+                self.process_rx(&mut vring, index)?;
             }
             _ => return Err(Error::HandleEventUnknownEvent.into()),
         }
@@ -354,6 +410,8 @@ pub struct VhostUserNetBackendConfig<'a> {
     pub num_queues: usize,
     pub queue_size: u16,
     pub tap: Option<&'a str>,
+    pub rx_rate_limiter_config: RateLimiterConfig,
+    pub tx_rate_limiter_config: RateLimiterConfig,
 }
 
 impl<'a> VhostUserNetBackendConfig<'a> {
@@ -366,6 +424,14 @@ impl<'a> VhostUserNetBackendConfig<'a> {
         let mut num_queues_str: &str = "";
         let mut queue_size_str: &str = "";
         let mut tap_str: &str = "";
+        let mut rx_bw_str: &str = "";
+        let mut rx_bw_burst_str: &str = "";
+        let mut rx_pps_str: &str = "";
+        let mut rx_pps_burst_str: &str = "";
+        let mut tx_bw_str: &str = "";
+        let mut tx_bw_burst_str: &str = "";
+        let mut tx_pps_str: &str = "";
+        let mut tx_pps_burst_str: &str = "";
 
         for param in params_list.iter() {
             if param.starts_with("ip=") {
@@ -380,6 +446,22 @@ impl<'a> VhostUserNetBackendConfig<'a> {
                 queue_size_str = &param[11..];
             } else if param.starts_with("tap=") {
                 tap_str = &param[4..];
+            } else if param.starts_with("rx_bw=") {
+                rx_bw_str = &param[6..];
+            } else if param.starts_with("rx_bw_burst=") {
+                rx_bw_burst_str = &param[12..];
+            } else if param.starts_with("rx_pps=") {
+                rx_pps_str = &param[7..];
+            } else if param.starts_with("rx_pps_burst=") {
+                rx_pps_burst_str = &param[13..];
+            } else if param.starts_with("tx_bw=") {
+                tx_bw_str = &param[6..];
+            } else if param.starts_with("tx_bw_burst=") {
+                tx_bw_burst_str = &param[12..];
+            } else if param.starts_with("tx_pps=") {
+                tx_pps_str = &param[7..];
+            } else if param.starts_with("tx_pps_burst=") {
+                tx_pps_burst_str = &param[13..];
             }
         }
 
@@ -408,6 +490,15 @@ impl<'a> VhostUserNetBackendConfig<'a> {
             tap = Some(tap_str);
         }
 
+        let rx_rate_limiter_config = RateLimiterConfig {
+            bandwidth: parse_token_bucket(rx_bw_str, rx_bw_burst_str)?,
+            ops: parse_token_bucket(rx_pps_str, rx_pps_burst_str)?,
+        };
+        let tx_rate_limiter_config = RateLimiterConfig {
+            bandwidth: parse_token_bucket(tx_bw_str, tx_bw_burst_str)?,
+            ops: parse_token_bucket(tx_pps_str, tx_pps_burst_str)?,
+        };
+
         Ok(VhostUserNetBackendConfig {
             ip,
             mask,
@@ -415,10 +506,29 @@ impl<'a> VhostUserNetBackendConfig<'a> {
             num_queues,
             queue_size,
             tap,
+            rx_rate_limiter_config,
+            tx_rate_limiter_config,
         })
     }
 }
 
+// Parses a rate (per second) and an optional burst size into a token bucket
+// configuration. No rate means no limit.
+fn parse_token_bucket(rate: &str, burst: &str) -> Result<Option<TokenBucketConfig>> {
+    if rate.is_empty() {
+        return Ok(None);
+    }
+
+    let rate = rate.parse().map_err(Error::ParseRateLimiterParam)?;
+    let burst = if !burst.is_empty() {
+        burst.parse().map_err(Error::ParseRateLimiterParam)?
+    } else {
+        0
+    };
+
+    Ok(Some(TokenBucketConfig { rate, burst }))
+}
+
 pub fn start_net_backend(backend_command: &str) {
     let backend_config = match VhostUserNetBackendConfig::parse(backend_command) {
         Ok(config) => config,
@@ -435,6 +545,8 @@ pub fn start_net_backend(backend_command: &str) {
             backend_config.num_queues,
             backend_config.queue_size,
             backend_config.tap,
+            &backend_config.rx_rate_limiter_config,
+            &backend_config.tx_rate_limiter_config,
         )
         .unwrap(),
     ));
@@ -448,10 +560,14 @@ pub fn start_net_backend(backend_command: &str) {
 
     let vring_worker = net_daemon.get_vring_worker();
 
-    net_backend
+    if let Err(e) = net_backend
         .write()
         .unwrap()
-        .set_vring_worker(Some(vring_worker));
+        .set_vring_worker(Some(vring_worker))
+    {
+        println!("Failed to register the rate limiter timers: {:?}", e);
+        process::exit(1);
+    }
 
     if let Err(e) = net_daemon.start() {
         println!(
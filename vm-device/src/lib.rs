@@ -1,9 +1,17 @@
+extern crate anyhow;
 extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
 extern crate thiserror;
 extern crate vm_memory;
 
 pub mod interrupt;
 
+use anyhow::anyhow;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
 use vm_memory::{
     Address, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion, GuestRegionMmap,
     MemoryRegionAddress,
@@ -30,6 +38,12 @@ pub enum MigratableError {
 
     #[error("Failed to resume migratable component: {0}")]
     Resume(#[source] anyhow::Error),
+
+    #[error("Failed to snapshot migratable component: {0}")]
+    Snapshot(#[source] anyhow::Error),
+
+    #[error("Failed to restore migratable component: {0}")]
+    Restore(#[source] anyhow::Error),
 }
 
 /// A Pausable component can be paused and resumed.
@@ -41,8 +55,127 @@ pub trait Pausable {
     fn resume(&mut self) -> std::result::Result<(), MigratableError>;
 }
 
+/// A snapshot data section is an opaque, serialized piece of a component
+/// state. Each component decides how to serialize its own state.
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct SnapshotDataSection {
+    /// The section identifier.
+    pub id: String,
+
+    /// The section serialized snapshot.
+    pub snapshot: Vec<u8>,
+}
+
+/// A snapshot is a tree of component snapshots. A component snapshot holds
+/// its own data sections, together with the snapshots of all the components
+/// it manages (e.g. the CPU manager snapshot contains one snapshot per vCPU).
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct Snapshot {
+    /// The snapshot identifier.
+    pub id: String,
+
+    /// The snapshots of the components this component owns.
+    pub snapshots: BTreeMap<String, Box<Snapshot>>,
+
+    /// The component data sections.
+    pub snapshot_data: HashMap<String, SnapshotDataSection>,
+}
+
+impl Snapshot {
+    /// Create an empty snapshot.
+    pub fn new(id: &str) -> Self {
+        Snapshot {
+            id: id.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Add a sub-component's snapshot to the snapshot.
+    pub fn add_snapshot(&mut self, snapshot: Snapshot) {
+        self.snapshots
+            .insert(snapshot.id.clone(), Box::new(snapshot));
+    }
+
+    /// Add a data section to the snapshot.
+    pub fn add_data_section(&mut self, section: SnapshotDataSection) {
+        self.snapshot_data.insert(section.id.clone(), section);
+    }
+
+    /// Create a snapshot holding a single data section, made of the
+    /// component versioned state.
+    pub fn new_from_state<T: VersionedState>(
+        id: &str,
+        state: &T,
+    ) -> std::result::Result<Self, MigratableError> {
+        let section = VersionedStateSection {
+            version: T::VERSION,
+            state,
+        };
+
+        let mut snapshot = Snapshot::new(id);
+        snapshot.add_data_section(SnapshotDataSection {
+            id: format!("{}-section", id),
+            snapshot: serde_json::to_vec(&section)
+                .map_err(|e| MigratableError::Snapshot(e.into()))?,
+        });
+
+        Ok(snapshot)
+    }
+
+    /// Get the component versioned state back from a snapshot created
+    /// through new_from_state(). A state saved with a different version
+    /// is rejected.
+    pub fn to_state<T: VersionedState>(&self, id: &str) -> std::result::Result<T, MigratableError> {
+        let section = self
+            .snapshot_data
+            .get(&format!("{}-section", id))
+            .ok_or_else(|| MigratableError::Restore(anyhow!("Missing {} state section", id)))?;
+        let section: VersionedStateSection<T> = serde_json::from_slice(&section.snapshot)
+            .map_err(|e| MigratableError::Restore(e.into()))?;
+
+        if section.version != T::VERSION {
+            return Err(MigratableError::Restore(anyhow!(
+                "Unsupported {} state version {}, expected {}",
+                id,
+                section.version,
+                T::VERSION
+            )));
+        }
+
+        Ok(section.state)
+    }
+}
+
+/// A component state that can be saved into a snapshot.
+/// The version is saved together with the state, and must be bumped any
+/// time the state content changes in an incompatible way.
+pub trait VersionedState: Serialize + DeserializeOwned {
+    const VERSION: u16;
+}
+
+#[derive(Deserialize, Serialize)]
+struct VersionedStateSection<T> {
+    version: u16,
+    state: T,
+}
+
 /// A snapshotable component can be snapshoted.
-pub trait Snapshotable {}
+pub trait Snapshotable {
+    /// The snapshotable component id.
+    fn id(&self) -> String {
+        String::new()
+    }
+
+    /// Take a component snapshot.
+    fn snapshot(&self) -> std::result::Result<Snapshot, MigratableError> {
+        Ok(Snapshot::new(&self.id()))
+    }
+
+    /// Restore a component from its snapshot.
+    fn restore(&mut self, _snapshot: Snapshot) -> std::result::Result<(), MigratableError> {
+        Ok(())
+    }
+}
 
 /// Trait to be implemented by any component (device, CPU, RAM, etc) that
 /// can be migrated.
@@ -113,4 +246,54 @@ mod tests {
         );
         assert_eq!(unsafe { ptr0.offset(0x100) }, ptr1);
     }
+
+    #[test]
+    fn test_snapshot_serialization() {
+        let mut child = Snapshot::new("child");
+        child.add_data_section(SnapshotDataSection {
+            id: "child-section".to_string(),
+            snapshot: vec![0xde, 0xad, 0xbe, 0xef],
+        });
+
+        let mut parent = Snapshot::new("parent");
+        parent.add_snapshot(child);
+
+        let serialized = serde_json::to_vec(&parent).unwrap();
+        let restored: Snapshot = serde_json::from_slice(&serialized).unwrap();
+
+        assert_eq!(restored.id, "parent");
+        let child = restored.snapshots.get("child").unwrap();
+        assert_eq!(
+            child.snapshot_data.get("child-section").unwrap().snapshot,
+            vec![0xde, 0xad, 0xbe, 0xef]
+        );
+    }
+
+    #[derive(Debug, Deserialize, PartialEq, Serialize)]
+    struct TestState {
+        value: u64,
+    }
+
+    impl VersionedState for TestState {
+        const VERSION: u16 = 1;
+    }
+
+    #[derive(Deserialize, Serialize)]
+    struct TestStateV2 {
+        value: u64,
+    }
+
+    impl VersionedState for TestStateV2 {
+        const VERSION: u16 = 2;
+    }
+
+    #[test]
+    fn test_versioned_state() {
+        let state = TestState { value: 42 };
+        let snapshot = Snapshot::new_from_state("test", &state).unwrap();
+
+        assert_eq!(snapshot.to_state::<TestState>("test").unwrap(), state);
+        assert!(snapshot.to_state::<TestState>("other").is_err());
+        assert!(snapshot.to_state::<TestStateV2>("test").is_err());
+    }
 }
@@ -10,14 +10,17 @@
 
 use super::Error as DeviceError;
 use super::{
-    ActivateError, ActivateResult, DescriptorChain, DeviceEventT, Queue, VirtioDevice,
+    ActivateError, ActivateResult, DescriptorChain, DeviceEventT, Queue, RateLimiter, VirtioDevice,
     VirtioDeviceType, VirtioInterruptType,
 };
 use crate::VirtioInterrupt;
+use anyhow::anyhow;
 use epoll;
+use io_uring::{opcode, squeue, IoUring, Probe};
 use libc::{c_void, EFD_NONBLOCK};
 use std::alloc::{alloc_zeroed, dealloc, Layout};
 use std::cmp;
+use std::collections::{BTreeMap, HashMap};
 use std::convert::TryInto;
 use std::fs::{File, Metadata};
 use std::io::{self, Read, Seek, SeekFrom, Write};
@@ -27,16 +30,25 @@ use std::os::unix::io::{AsRawFd, RawFd};
 use std::path::PathBuf;
 use std::result;
 use std::slice;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use virtio_bindings::bindings::virtio_blk::*;
-use vm_device::{Migratable, MigratableError, Pausable, Snapshotable};
+use virtio_bindings::bindings::virtio_ring::VIRTIO_RING_F_INDIRECT_DESC;
+use vm_device::{
+    get_host_address_range, Migratable, MigratableError, Pausable, Snapshot, Snapshotable,
+    VersionedState,
+};
 use vm_memory::{
-    ByteValued, Bytes, GuestAddress, GuestAddressSpace, GuestMemory, GuestMemoryAtomic,
+    Address, ByteValued, Bytes, GuestAddress, GuestAddressSpace, GuestMemory, GuestMemoryAtomic,
     GuestMemoryError, GuestMemoryMmap,
 };
-use vmm_sys_util::{eventfd::EventFd, seek_hole::SeekHole, write_zeroes::PunchHole};
+use vmm_sys_util::{
+    eventfd::EventFd,
+    seek_hole::SeekHole,
+    timerfd::TimerFd,
+    write_zeroes::{PunchHole, WriteZeroes},
+};
 
 const SECTOR_SHIFT: u8 = 9;
 pub const SECTOR_SIZE: u64 = (0x01 as u64) << SECTOR_SHIFT;
@@ -49,6 +61,19 @@ pub const KILL_EVENT: DeviceEventT = 1;
 pub const BLOCK_EVENTS_COUNT: usize = 2;
 // The device should be paused.
 const PAUSE_EVENT: DeviceEventT = 3;
+// Asynchronous I/O requests have completed.
+const IO_URING_EVENT: DeviceEventT = 4;
+// Throttled requests can be retried.
+const RATE_LIMITER_EVENT: DeviceEventT = 5;
+
+const BLOCK_SNAPSHOT_ID: &str = "virtio-block";
+
+// Size of a discard or write zeroes segment, as described by the virtio
+// specification.
+const DISCARD_WRITE_ZEROES_SEGMENT_SIZE: u32 = 16;
+
+// Maximum number of buffers a single vectored I/O system call accepts.
+const IOV_MAX: usize = 1024;
 
 #[derive(Debug)]
 pub enum Error {
@@ -64,10 +89,16 @@ pub enum Error {
     DescriptorChainTooShort,
     /// Guest gave us a descriptor that was too short to use.
     DescriptorLengthTooSmall,
+    /// Guest gave us data descriptors whose total length overflows.
+    DataLengthOverflow,
+    /// Guest gave us an invalid indirect descriptor table.
+    InvalidIndirectDescriptor,
     /// Getting a block's metadata fails for any reason.
     GetFileMetadata,
     /// The requested operation would cause a seek beyond disk end.
     InvalidOffset,
+    /// The discard or write zeroes request data isn't made of full segments.
+    InvalidDiscardWriteZeroesSegment,
 }
 
 #[derive(Debug)]
@@ -77,6 +108,10 @@ pub enum ExecuteError {
     Read(GuestMemoryError),
     Seek(io::Error),
     Write(GuestMemoryError),
+    Discard(io::Error),
+    WriteZeroes(io::Error),
+    SubmitIoUring(io::Error),
+    AsyncIo(io::Error),
     Unsupported(u32),
 }
 
@@ -88,13 +123,84 @@ impl ExecuteError {
             ExecuteError::Read(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::Seek(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::Write(_) => VIRTIO_BLK_S_IOERR,
+            ExecuteError::Discard(_) => VIRTIO_BLK_S_IOERR,
+            ExecuteError::WriteZeroes(_) => VIRTIO_BLK_S_IOERR,
+            ExecuteError::SubmitIoUring(_) => VIRTIO_BLK_S_IOERR,
+            ExecuteError::AsyncIo(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::Unsupported(_) => VIRTIO_BLK_S_UNSUPP,
         }
     }
 }
 
-pub trait DiskFile: Read + Seek + Write + Clone {}
-impl<D: Read + Seek + Write + Clone> DiskFile for D {}
+pub trait DiskFile: Read + Seek + Write + PunchHole + WriteZeroes + DiskIovecs + Clone {}
+impl<D: Read + Seek + Write + PunchHole + WriteZeroes + DiskIovecs + Clone> DiskFile for D {}
+
+/// Moves data between a disk image, at its current position, and guest
+/// memory described by raw iovecs. The iovecs are built from descriptors the
+/// guest controls and may overlap, so they must never be turned into Rust
+/// references.
+pub trait DiskIovecs {
+    /// Reads into the iovecs, and returns the number of bytes read.
+    ///
+    /// # Safety
+    ///
+    /// The iovecs must point to memory which stays mapped during the call.
+    unsafe fn read_iovecs(&mut self, iovecs: &[libc::iovec]) -> io::Result<usize>;
+
+    /// Writes from the iovecs, and returns the number of bytes written.
+    ///
+    /// # Safety
+    ///
+    /// The iovecs must point to memory which stays mapped during the call.
+    unsafe fn write_iovecs(&mut self, iovecs: &[libc::iovec]) -> io::Result<usize>;
+}
+
+/// Reads into the first non-empty iovec through an intermediate buffer, for
+/// the disk images which can't read into guest memory directly.
+///
+/// # Safety
+///
+/// The iovecs must point to memory which stays mapped during the call.
+pub unsafe fn read_iovecs_bounced<T: Read>(
+    disk: &mut T,
+    iovecs: &[libc::iovec],
+) -> io::Result<usize> {
+    let iovec = match iovecs.iter().find(|iovec| iovec.iov_len > 0) {
+        Some(iovec) => iovec,
+        None => return Ok(0),
+    };
+
+    let mut buf = vec![0u8; iovec.iov_len];
+    let read = disk.read(&mut buf)?;
+    std::ptr::copy_nonoverlapping(buf.as_ptr(), iovec.iov_base as *mut u8, read);
+    Ok(read)
+}
+
+/// Writes from the first non-empty iovec through an intermediate buffer, for
+/// the disk images which can't write from guest memory directly.
+///
+/// # Safety
+///
+/// The iovecs must point to memory which stays mapped during the call.
+pub unsafe fn write_iovecs_bounced<T: Write>(
+    disk: &mut T,
+    iovecs: &[libc::iovec],
+) -> io::Result<usize> {
+    let iovec = match iovecs.iter().find(|iovec| iovec.iov_len > 0) {
+        Some(iovec) => iovec,
+        None => return Ok(0),
+    };
+
+    let mut buf = vec![0u8; iovec.iov_len];
+    std::ptr::copy_nonoverlapping(iovec.iov_base as *const u8, buf.as_mut_ptr(), buf.len());
+    disk.write(&buf)
+}
+
+/// Disk images whose size can be changed while in use.
+pub trait DiskResize {
+    /// Sets the size of the disk, as seen by the guest, to `size` bytes.
+    fn resize(&mut self, size: u64) -> io::Result<()>;
+}
 
 #[derive(Debug)]
 pub struct RawFile {
@@ -348,6 +454,50 @@ impl Write for RawFile {
     }
 }
 
+impl DiskIovecs for RawFile {
+    unsafe fn read_iovecs(&mut self, iovecs: &[libc::iovec]) -> io::Result<usize> {
+        // With O_DIRECT, the buffers are bounced one at a time to honor the
+        // alignment constraints.
+        if self.alignment != 0 {
+            return read_iovecs_bounced(self, iovecs);
+        }
+
+        let ret = libc::readv(
+            self.file.as_raw_fd(),
+            iovecs.as_ptr(),
+            iovecs.len() as libc::c_int,
+        );
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let r: usize = ret.try_into().unwrap();
+        self.position = self.position.checked_add(r.try_into().unwrap()).unwrap();
+        Ok(r)
+    }
+
+    unsafe fn write_iovecs(&mut self, iovecs: &[libc::iovec]) -> io::Result<usize> {
+        // With O_DIRECT, the buffers are bounced one at a time to honor the
+        // alignment constraints.
+        if self.alignment != 0 {
+            return write_iovecs_bounced(self, iovecs);
+        }
+
+        let ret = libc::writev(
+            self.file.as_raw_fd(),
+            iovecs.as_ptr(),
+            iovecs.len() as libc::c_int,
+        );
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let r: usize = ret.try_into().unwrap();
+        self.position = self.position.checked_add(r.try_into().unwrap()).unwrap();
+        Ok(r)
+    }
+}
+
 impl Seek for RawFile {
     fn seek(&mut self, newpos: SeekFrom) -> std::io::Result<u64> {
         match self.file.seek(newpos) {
@@ -360,6 +510,12 @@ impl Seek for RawFile {
     }
 }
 
+impl DiskResize for RawFile {
+    fn resize(&mut self, size: u64) -> io::Result<()> {
+        self.set_len(size)
+    }
+}
+
 impl PunchHole for RawFile {
     fn punch_hole(&mut self, offset: u64, length: u64) -> std::io::Result<()> {
         self.file.punch_hole(offset, length)
@@ -392,6 +548,12 @@ impl SeekHole for RawFile {
     }
 }
 
+impl AsRawFd for RawFile {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
 impl Clone for RawFile {
     fn clone(&self) -> Self {
         RawFile {
@@ -408,6 +570,8 @@ pub enum RequestType {
     Out,
     Flush,
     GetDeviceID,
+    Discard,
+    WriteZeroes,
     Unsupported(u32),
 }
 
@@ -421,6 +585,8 @@ pub fn request_type(
         VIRTIO_BLK_T_OUT => Ok(RequestType::Out),
         VIRTIO_BLK_T_FLUSH => Ok(RequestType::Flush),
         VIRTIO_BLK_T_GET_ID => Ok(RequestType::GetDeviceID),
+        VIRTIO_BLK_T_DISCARD => Ok(RequestType::Discard),
+        VIRTIO_BLK_T_WRITE_ZEROES => Ok(RequestType::WriteZeroes),
         t => Ok(RequestType::Unsupported(t)),
     }
 }
@@ -467,10 +633,58 @@ pub fn build_disk_image_id(disk_path: &PathBuf) -> Vec<u8> {
     default_disk_image_id
 }
 
+/// Takes a whole-file OFD (open file description) lock on a disk image, so
+/// that it can't be opened read-write by anyone else. Read-only images take a
+/// shared lock, which only conflicts with read-write users. Conflicting locks
+/// fail with EAGAIN or EACCES.
+pub fn lock_disk_image(image: &File, readonly: bool) -> io::Result<()> {
+    let lock_type = if readonly {
+        libc::F_RDLCK
+    } else {
+        libc::F_WRLCK
+    };
+
+    set_disk_image_lock(image, lock_type as libc::c_short)
+}
+
+/// Releases the lock taken by lock_disk_image(). The lock is also released
+/// once all the file descriptors sharing the open file description are closed.
+pub fn unlock_disk_image(image: &File) -> io::Result<()> {
+    set_disk_image_lock(image, libc::F_UNLCK as libc::c_short)
+}
+
+fn set_disk_image_lock(image: &File, lock_type: libc::c_short) -> io::Result<()> {
+    let flock = libc::flock {
+        l_type: lock_type,
+        l_whence: libc::SEEK_SET as libc::c_short,
+        l_start: 0,
+        l_len: 0,
+        l_pid: 0,
+    };
+
+    // Safe because the file descriptor is valid and we check the return value.
+    let ret = unsafe { libc::fcntl(image.as_raw_fd(), libc::F_OFD_SETLK, &flock) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct DiscardWriteZeroesSegment {
+    sector: u64,
+    num_sectors: u32,
+    flags: u32,
+}
+
+unsafe impl ByteValued for DiscardWriteZeroesSegment {}
+
 pub struct Request {
     request_type: RequestType,
     sector: u64,
-    data_addr: GuestAddress,
+    data_descriptors: Vec<(GuestAddress, u32)>,
     data_len: u32,
     pub status_addr: GuestAddress,
 }
@@ -480,6 +694,18 @@ impl Request {
         avail_desc: &DescriptorChain,
         mem: &GuestMemoryMmap,
     ) -> result::Result<Request, Error> {
+        // With indirect descriptors, the whole request is described by the
+        // table the head descriptor points to.
+        let indirect_desc;
+        let avail_desc = if avail_desc.is_indirect() {
+            indirect_desc = avail_desc
+                .new_from_indirect()
+                .map_err(|_| Error::InvalidIndirectDescriptor)?;
+            &indirect_desc
+        } else {
+            avail_desc
+        };
+
         // The head contains the request type which MUST be readable.
         if avail_desc.is_write_only() {
             return Err(Error::UnexpectedWriteOnlyDescriptor);
@@ -488,41 +714,46 @@ impl Request {
         let mut req = Request {
             request_type: request_type(&mem, avail_desc.addr)?,
             sector: sector(&mem, avail_desc.addr)?,
-            data_addr: GuestAddress(0),
+            data_descriptors: Vec::new(),
             data_len: 0,
             status_addr: GuestAddress(0),
         };
 
-        let data_desc;
-        let status_desc;
-        let desc = avail_desc
+        let mut desc = avail_desc
             .next_descriptor()
             .ok_or(Error::DescriptorChainTooShort)?;
 
-        if !desc.has_next() {
-            status_desc = desc;
-            // Only flush requests are allowed to skip the data descriptor.
-            if req.request_type != RequestType::Flush {
-                return Err(Error::DescriptorChainTooShort);
-            }
-        } else {
-            data_desc = desc;
-            status_desc = data_desc
-                .next_descriptor()
-                .ok_or(Error::DescriptorChainTooShort)?;
-
-            if data_desc.is_write_only() && req.request_type == RequestType::Out {
+        // Every descriptor between the header and the status carries data.
+        while desc.has_next() {
+            if desc.is_write_only()
+                && (req.request_type == RequestType::Out
+                    || req.request_type == RequestType::Discard
+                    || req.request_type == RequestType::WriteZeroes)
+            {
                 return Err(Error::UnexpectedWriteOnlyDescriptor);
             }
-            if !data_desc.is_write_only() && req.request_type == RequestType::In {
+            if !desc.is_write_only() && req.request_type == RequestType::In {
                 return Err(Error::UnexpectedReadOnlyDescriptor);
             }
-            if !data_desc.is_write_only() && req.request_type == RequestType::GetDeviceID {
+            if !desc.is_write_only() && req.request_type == RequestType::GetDeviceID {
                 return Err(Error::UnexpectedReadOnlyDescriptor);
             }
 
-            req.data_addr = data_desc.addr;
-            req.data_len = data_desc.len;
+            req.data_descriptors.push((desc.addr, desc.len));
+            req.data_len = req
+                .data_len
+                .checked_add(desc.len)
+                .ok_or(Error::DataLengthOverflow)?;
+
+            desc = desc
+                .next_descriptor()
+                .ok_or(Error::DescriptorChainTooShort)?;
+        }
+        let status_desc = desc;
+
+        // Only flush requests are allowed to skip the data descriptor.
+        if req.data_descriptors.is_empty() && req.request_type != RequestType::Flush {
+            return Err(Error::DescriptorChainTooShort);
         }
 
         // The status MUST always be writable.
@@ -539,14 +770,16 @@ impl Request {
         Ok(req)
     }
 
-    #[allow(clippy::ptr_arg)]
-    pub fn execute<T: Seek + Read + Write>(
-        &self,
-        disk: &mut T,
-        disk_nsectors: u64,
-        mem: &GuestMemoryMmap,
-        disk_id: &Vec<u8>,
-    ) -> result::Result<u32, ExecuteError> {
+    // Only the data transferred to or from the disk counts against the
+    // bandwidth limit.
+    fn rate_limited_bytes(&self) -> u64 {
+        match self.request_type {
+            RequestType::In | RequestType::Out => u64::from(self.data_len),
+            _ => 0,
+        }
+    }
+
+    fn check_bounds(&self, disk_nsectors: u64) -> result::Result<(), ExecuteError> {
         let mut top: u64 = u64::from(self.data_len) / SECTOR_SIZE;
         if u64::from(self.data_len) % SECTOR_SIZE != 0 {
             top += 1;
@@ -558,18 +791,63 @@ impl Request {
             return Err(ExecuteError::BadRequest(Error::InvalidOffset));
         }
 
+        Ok(())
+    }
+
+    // Translates the data descriptors into iovecs pointing to the guest
+    // memory backing them, so that the whole request can be transferred with
+    // vectored I/O. The guest may make the descriptors overlap, which is why
+    // they are kept as raw pointers.
+    fn host_iovecs(&self, mem: &GuestMemoryMmap) -> result::Result<Vec<libc::iovec>, Error> {
+        self.data_descriptors
+            .iter()
+            .map(|(data_addr, data_len)| {
+                let buf = get_host_address_range(mem, *data_addr, *data_len as usize)
+                    .ok_or(Error::CheckedOffset(*data_addr, *data_len as usize))?;
+                Ok(libc::iovec {
+                    iov_base: buf as *mut libc::c_void,
+                    iov_len: *data_len as libc::size_t,
+                })
+            })
+            .collect()
+    }
+
+    #[allow(clippy::ptr_arg)]
+    pub fn execute<T: Seek + Read + Write + PunchHole + WriteZeroes + DiskIovecs>(
+        &self,
+        disk: &mut T,
+        disk_nsectors: u64,
+        mem: &GuestMemoryMmap,
+        disk_id: &Vec<u8>,
+    ) -> result::Result<u32, ExecuteError> {
+        // The sectors affected by discard and write zeroes requests are
+        // described by the segments, not by the request header.
+        if self.request_type == RequestType::Discard
+            || self.request_type == RequestType::WriteZeroes
+        {
+            return self.execute_discard_write_zeroes(disk, disk_nsectors, mem);
+        }
+
+        self.check_bounds(disk_nsectors)?;
+
         disk.seek(SeekFrom::Start(self.sector << SECTOR_SHIFT))
             .map_err(ExecuteError::Seek)?;
 
         match self.request_type {
             RequestType::In => {
-                mem.read_exact_from(self.data_addr, disk, self.data_len as usize)
-                    .map_err(ExecuteError::Read)?;
+                let iovecs = self.host_iovecs(mem).map_err(ExecuteError::BadRequest)?;
+                // Safe because the iovecs point to guest memory, which stays
+                // mapped for as long as `mem` is held.
+                unsafe { read_iovecs_exact(disk, iovecs) }
+                    .map_err(|e| ExecuteError::Read(GuestMemoryError::IOError(e)))?;
                 return Ok(self.data_len);
             }
             RequestType::Out => {
-                mem.write_all_to(self.data_addr, disk, self.data_len as usize)
-                    .map_err(ExecuteError::Write)?;
+                let iovecs = self.host_iovecs(mem).map_err(ExecuteError::BadRequest)?;
+                // Safe because the iovecs point to guest memory, which stays
+                // mapped for as long as `mem` is held.
+                unsafe { write_iovecs_all(disk, iovecs) }
+                    .map_err(|e| ExecuteError::Write(GuestMemoryError::IOError(e)))?;
             }
             RequestType::Flush => match disk.flush() {
                 Ok(_) => {
@@ -578,61 +856,451 @@ impl Request {
                 Err(e) => return Err(ExecuteError::Flush(e)),
             },
             RequestType::GetDeviceID => {
-                if (self.data_len as usize) < disk_id.len() {
+                let (data_addr, data_len) = self.data_descriptors[0];
+                if (data_len as usize) < disk_id.len() {
                     return Err(ExecuteError::BadRequest(Error::InvalidOffset));
                 }
-                mem.write_slice(&disk_id.as_slice(), self.data_addr)
+                mem.write_slice(&disk_id.as_slice(), data_addr)
                     .map_err(ExecuteError::Write)?;
             }
+            RequestType::Discard | RequestType::WriteZeroes => unreachable!(),
             RequestType::Unsupported(t) => return Err(ExecuteError::Unsupported(t)),
         };
         Ok(0)
     }
+
+    fn execute_discard_write_zeroes<T: Seek + Write + PunchHole + WriteZeroes>(
+        &self,
+        disk: &mut T,
+        disk_nsectors: u64,
+        mem: &GuestMemoryMmap,
+    ) -> result::Result<u32, ExecuteError> {
+        let mut segment_addrs = Vec::new();
+        for (data_addr, data_len) in self.data_descriptors.iter() {
+            if data_len % DISCARD_WRITE_ZEROES_SEGMENT_SIZE != 0 {
+                return Err(ExecuteError::BadRequest(
+                    Error::InvalidDiscardWriteZeroesSegment,
+                ));
+            }
+            for i in 0..(data_len / DISCARD_WRITE_ZEROES_SEGMENT_SIZE) {
+                segment_addrs.push(
+                    data_addr
+                        .checked_add(u64::from(i * DISCARD_WRITE_ZEROES_SEGMENT_SIZE))
+                        .ok_or(ExecuteError::BadRequest(Error::InvalidOffset))?,
+                );
+            }
+        }
+
+        for segment_addr in segment_addrs {
+            let segment: DiscardWriteZeroesSegment =
+                mem.read_obj(segment_addr).map_err(ExecuteError::Read)?;
+
+            let top = segment
+                .sector
+                .checked_add(u64::from(segment.num_sectors))
+                .ok_or(ExecuteError::BadRequest(Error::InvalidOffset))?;
+            if top > disk_nsectors {
+                return Err(ExecuteError::BadRequest(Error::InvalidOffset));
+            }
+
+            let offset = segment.sector << SECTOR_SHIFT;
+            let length = u64::from(segment.num_sectors) << SECTOR_SHIFT;
+
+            if self.request_type == RequestType::Discard {
+                // The flags are reserved for discard requests.
+                if segment.flags != 0 {
+                    return Err(ExecuteError::Unsupported(VIRTIO_BLK_T_DISCARD));
+                }
+
+                disk.punch_hole(offset, length)
+                    .map_err(ExecuteError::Discard)?;
+            } else {
+                if segment.flags & !VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP != 0 {
+                    return Err(ExecuteError::Unsupported(VIRTIO_BLK_T_WRITE_ZEROES));
+                }
+
+                // Whether or not the guest allows the range to be unmapped,
+                // WriteZeroes deallocates it when the image supports it,
+                // and falls back to writing zeroes otherwise.
+                disk.seek(SeekFrom::Start(offset))
+                    .map_err(ExecuteError::Seek)?;
+                disk.write_all_zeroes(length as usize)
+                    .map_err(ExecuteError::WriteZeroes)?;
+            }
+        }
+
+        Ok(0)
+    }
+}
+
+// Reads from the disk until all the iovecs are filled, issuing as few
+// vectored reads as the disk image allows.
+//
+// Unsafe because the iovecs must point to memory which stays mapped during
+// the call.
+unsafe fn read_iovecs_exact<T: DiskIovecs>(
+    disk: &mut T,
+    mut iovecs: Vec<libc::iovec>,
+) -> io::Result<()> {
+    iovecs.retain(|iovec| iovec.iov_len > 0);
+    let mut first = 0;
+    while first < iovecs.len() {
+        let last = cmp::min(first + IOV_MAX, iovecs.len());
+        let read = match disk.read_iovecs(&iovecs[first..last]) {
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            Ok(read) => read,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        first = skip_iovecs(&mut iovecs, first, read);
+    }
+
+    Ok(())
+}
+
+// Writes all the iovecs to the disk, issuing as few vectored writes as the
+// disk image allows.
+//
+// Unsafe because the iovecs must point to memory which stays mapped during
+// the call.
+unsafe fn write_iovecs_all<T: DiskIovecs>(
+    disk: &mut T,
+    mut iovecs: Vec<libc::iovec>,
+) -> io::Result<()> {
+    iovecs.retain(|iovec| iovec.iov_len > 0);
+    let mut first = 0;
+    while first < iovecs.len() {
+        let last = cmp::min(first + IOV_MAX, iovecs.len());
+        let written = match disk.write_iovecs(&iovecs[first..last]) {
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+            Ok(written) => written,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        first = skip_iovecs(&mut iovecs, first, written);
+    }
+
+    Ok(())
+}
+
+// Skips `len` bytes of the iovecs starting at `first`, which can end in the
+// middle of an iovec. Returns the index of the first iovec left.
+fn skip_iovecs(iovecs: &mut [libc::iovec], mut first: usize, mut len: usize) -> usize {
+    while len > 0 {
+        let iovec = &mut iovecs[first];
+        if len < iovec.iov_len {
+            iovec.iov_base = (iovec.iov_base as *mut u8).wrapping_add(len) as *mut libc::c_void;
+            iovec.iov_len -= len;
+            len = 0;
+        } else {
+            len -= iovec.iov_len;
+            first += 1;
+        }
+    }
+
+    first
+}
+
+/// Checks whether the running kernel provides everything the io_uring based
+/// block I/O engine relies on.
+pub fn block_io_uring_is_supported() -> bool {
+    let io_uring = match IoUring::new(1) {
+        Ok(io_uring) => io_uring,
+        Err(e) => {
+            info!("io_uring not supported: failed to create instance: {}", e);
+            return false;
+        }
+    };
+
+    let submitter = io_uring.submitter();
+
+    let event_fd = match EventFd::new(EFD_NONBLOCK) {
+        Ok(fd) => fd,
+        Err(e) => {
+            info!("io_uring not supported: failed to create eventfd: {}", e);
+            return false;
+        }
+    };
+    if let Err(e) = submitter.register_eventfd(event_fd.as_raw_fd()) {
+        info!("io_uring not supported: failed to register eventfd: {}", e);
+        return false;
+    }
+
+    let mut probe = Probe::new();
+    if let Err(e) = submitter.register_probe(&mut probe) {
+        info!("io_uring not supported: failed to register probe: {}", e);
+        return false;
+    }
+
+    if !probe.is_supported(opcode::Readv::CODE)
+        || !probe.is_supported(opcode::Writev::CODE)
+        || !probe.is_supported(opcode::Fsync::CODE)
+    {
+        info!("io_uring not supported: missing readv, writev or fsync operation");
+        return false;
+    }
+
+    true
+}
+
+// A request submitted to io_uring, waiting for its completion. The iovecs
+// must outlive the submission as older kernels only read them once the
+// operation is started.
+struct InflightRequest {
+    request: Request,
+    iovecs: Vec<libc::iovec>,
+}
+
+// Safe because the iovecs only point into guest memory, which outlives any
+// request in flight.
+unsafe impl Send for InflightRequest {}
+
+/// Asynchronous block I/O engine, submitting the read, write and flush
+/// requests of one queue to an io_uring instance.
+struct BlockIoUring {
+    io_uring: IoUring,
+    evt: EventFd,
+    disk_image_fd: RawFd,
+    inflight: HashMap<u16, InflightRequest>,
+}
+
+impl BlockIoUring {
+    fn new(disk_image_fd: RawFd, queue_size: u16) -> io::Result<Self> {
+        let io_uring = IoUring::new(u32::from(queue_size))?;
+        let evt = EventFd::new(EFD_NONBLOCK)?;
+        io_uring.submitter().register_eventfd(evt.as_raw_fd())?;
+
+        Ok(BlockIoUring {
+            io_uring,
+            evt,
+            disk_image_fd,
+            inflight: HashMap::new(),
+        })
+    }
+
+    // Only the requests moving data to or from the image are worth running
+    // asynchronously, the others are still executed in place. So are the
+    // requests with more buffers than a single readv or writev can take,
+    // which the driver isn't supposed to send given seg_max.
+    fn handles(request: &Request) -> bool {
+        match request.request_type {
+            RequestType::In | RequestType::Out => request.data_descriptors.len() <= IOV_MAX,
+            RequestType::Flush => true,
+            _ => false,
+        }
+    }
+
+    fn submit(
+        &mut self,
+        desc_index: u16,
+        request: Request,
+        mem: &GuestMemoryMmap,
+        disk_nsectors: u64,
+    ) -> result::Result<(), (Request, ExecuteError)> {
+        if let Err(e) = request.check_bounds(disk_nsectors) {
+            return Err((request, e));
+        }
+
+        let iovecs = match request.host_iovecs(mem) {
+            Ok(iovecs) => iovecs,
+            Err(e) => return Err((request, ExecuteError::BadRequest(e))),
+        };
+
+        let fd = opcode::types::Fd(self.disk_image_fd);
+        let offset = (request.sector << SECTOR_SHIFT) as libc::off_t;
+        let user_data = u64::from(desc_index);
+        let entry = match request.request_type {
+            RequestType::In => opcode::Readv::new(fd, iovecs.as_ptr(), iovecs.len() as u32)
+                .offset(offset)
+                .build(),
+            RequestType::Out => opcode::Writev::new(fd, iovecs.as_ptr(), iovecs.len() as u32)
+                .offset(offset)
+                .build(),
+            RequestType::Flush => opcode::Fsync::new(fd).build(),
+            t => unreachable!("{:?} requests are not submitted to io_uring", t),
+        }
+        .flags(squeue::Flags::ASYNC)
+        .user_data(user_data);
+
+        let (submitter, sq, _) = self.io_uring.split();
+        let mut avail_sq = sq.available();
+        // Safe because the iovecs and the guest buffers they point to are
+        // kept alive until the request completes.
+        if unsafe { avail_sq.push(entry) }.is_err() {
+            return Err((
+                request,
+                ExecuteError::SubmitIoUring(io::Error::from_raw_os_error(libc::EBUSY)),
+            ));
+        }
+        avail_sq.sync();
+
+        self.inflight
+            .insert(desc_index, InflightRequest { request, iovecs });
+
+        // The entry stays in the submission queue if the kernel can't take
+        // it right now, and goes with the next submission.
+        if let Err(e) = submitter.submit() {
+            warn!("Failed to submit io_uring requests: {}", e);
+        }
+
+        Ok(())
+    }
+
+    // Collects the requests io_uring is done with, along with their outcome.
+    fn completions(&mut self) -> Vec<(u16, Request, result::Result<u32, ExecuteError>)> {
+        let mut completed = Vec::new();
+        for entry in self.io_uring.completion().available() {
+            let desc_index = entry.user_data() as u16;
+            let inflight = match self.inflight.remove(&desc_index) {
+                Some(inflight) => inflight,
+                None => {
+                    error!("Unknown io_uring completion for descriptor {}", desc_index);
+                    continue;
+                }
+            };
+            let request = inflight.request;
+
+            let res = entry.result();
+            let result = if res < 0 {
+                Err(ExecuteError::AsyncIo(io::Error::from_raw_os_error(-res)))
+            } else {
+                match request.request_type {
+                    RequestType::In if res as u32 != request.data_len => Err(
+                        ExecuteError::AsyncIo(io::Error::from(io::ErrorKind::UnexpectedEof)),
+                    ),
+                    RequestType::In => Ok(request.data_len),
+                    RequestType::Out if res as u32 != request.data_len => Err(
+                        ExecuteError::AsyncIo(io::Error::from(io::ErrorKind::WriteZero)),
+                    ),
+                    _ => Ok(0),
+                }
+            };
+
+            completed.push((desc_index, request, result));
+        }
+
+        completed
+    }
+
+    // Blocks until at least one of the requests in flight completes.
+    fn wait(&mut self) -> io::Result<()> {
+        self.io_uring.submit_and_wait(1).map(|_| ())
+    }
+}
+
+// Accounts for a request that is done, and reports its status to the guest.
+// Returns the number of bytes written to the guest buffers.
+fn complete_request(
+    mem: &GuestMemoryMmap,
+    counters: &BlockCounters,
+    request: &Request,
+    result: result::Result<u32, ExecuteError>,
+) -> u32 {
+    let (len, status) = match result {
+        Ok(len) => {
+            match request.request_type {
+                RequestType::In => {
+                    counters
+                        .read_bytes
+                        .fetch_add(u64::from(request.data_len), Ordering::Relaxed);
+                    counters.read_ops.fetch_add(1, Ordering::Relaxed);
+                }
+                RequestType::Out => {
+                    counters
+                        .write_bytes
+                        .fetch_add(u64::from(request.data_len), Ordering::Relaxed);
+                    counters.write_ops.fetch_add(1, Ordering::Relaxed);
+                }
+                _ => {}
+            }
+            (len, VIRTIO_BLK_S_OK)
+        }
+        Err(e) => {
+            error!("Failed to execute request: {:?}", e);
+            // We need at least 1 byte for the status.
+            (1, e.status())
+        }
+    };
+    // We use unwrap because the request parsing process already checked that the
+    // status_addr was valid.
+    mem.write_obj(status, request.status_addr).unwrap();
+
+    len
+}
+
+/// Block I/O statistics, shared by all the queues of a block device.
+#[derive(Default)]
+struct BlockCounters {
+    read_bytes: AtomicU64,
+    read_ops: AtomicU64,
+    write_bytes: AtomicU64,
+    write_ops: AtomicU64,
 }
 
 struct BlockEpollHandler<T: DiskFile> {
     queue: Queue,
     mem: GuestMemoryAtomic<GuestMemoryMmap>,
     disk_image: Arc<Mutex<T>>,
-    disk_nsectors: u64,
+    disk_image_fd: Option<RawFd>,
+    disk_nsectors: Arc<AtomicU64>,
     interrupt_cb: Arc<dyn VirtioInterrupt>,
     disk_image_id: Vec<u8>,
     kill_evt: EventFd,
     pause_evt: EventFd,
+    counters: Arc<BlockCounters>,
+    rate_limiter: Arc<Mutex<RateLimiter>>,
+    rate_limiter_timer: TimerFd,
+    throttled: bool,
 }
 
 impl<T: DiskFile> BlockEpollHandler<T> {
-    fn process_queue(&mut self) -> bool {
+    fn process_queue(&mut self, mut io_uring: Option<&mut BlockIoUring>) -> bool {
         let queue = &mut self.queue;
 
         let mut used_desc_heads = Vec::new();
         let mut used_count = 0;
+        let mut refill_delay = None;
         let mem = self.mem.memory();
         for avail_desc in queue.iter(&mem) {
             let len;
             match Request::parse(&avail_desc, &mem) {
                 Ok(request) => {
-                    let mut disk_image_locked = self.disk_image.lock().unwrap();
-                    let mut disk_image = disk_image_locked.deref_mut();
-                    let status = match request.execute(
-                        &mut disk_image,
-                        self.disk_nsectors,
-                        &mem,
-                        &self.disk_image_id,
-                    ) {
-                        Ok(l) => {
-                            len = l;
-                            VIRTIO_BLK_S_OK
+                    // A throttled request is left on the queue, and retried
+                    // once the rate limiter has enough tokens for it.
+                    let bytes = request.rate_limited_bytes();
+                    let mut rate_limiter = self.rate_limiter.lock().unwrap();
+                    if !rate_limiter.consume(bytes) {
+                        refill_delay = Some(rate_limiter.refill_delay(bytes));
+                        break;
+                    }
+                    drop(rate_limiter);
+
+                    let (request, result) = match io_uring.as_mut() {
+                        Some(io_uring) if BlockIoUring::handles(&request) => {
+                            // The descriptor is returned to the guest once
+                            // io_uring reports the request as completed.
+                            match io_uring.submit(
+                                avail_desc.index,
+                                request,
+                                &mem,
+                                self.disk_nsectors.load(Ordering::Acquire),
+                            ) {
+                                Ok(()) => continue,
+                                Err((request, e)) => (request, Err(e)),
+                            }
                         }
-                        Err(e) => {
-                            error!("Failed to execute request: {:?}", e);
-                            len = 1; // We need at least 1 byte for the status.
-                            e.status()
+                        _ => {
+                            let mut disk_image_locked = self.disk_image.lock().unwrap();
+                            let result = request.execute(
+                                disk_image_locked.deref_mut(),
+                                self.disk_nsectors.load(Ordering::Acquire),
+                                &mem,
+                                &self.disk_image_id,
+                            );
+                            (request, result)
                         }
                     };
-                    // We use unwrap because the request parsing process already checked that the
-                    // status_addr was valid.
-                    mem.write_obj(status, request.status_addr).unwrap();
+                    len = complete_request(&mem, &self.counters, &request, result);
                 }
                 Err(e) => {
                     error!("Failed to parse available descriptor chain: {:?}", e);
@@ -643,12 +1311,43 @@ impl<T: DiskFile> BlockEpollHandler<T> {
             used_count += 1;
         }
 
+        if let Some(delay) = refill_delay {
+            queue.go_to_previous_position();
+            match self.rate_limiter_timer.reset(delay, None) {
+                Ok(()) => self.throttled = true,
+                Err(e) => error!("Failed to arm the rate limiter timer: {:?}", e),
+            }
+        }
+
         for &(desc_index, len) in used_desc_heads.iter() {
             queue.add_used(&mem, desc_index, len);
         }
         used_count > 0
     }
 
+    fn process_io_uring_completions(&mut self, io_uring: &mut BlockIoUring) -> bool {
+        let mem = self.mem.memory();
+        let mut used_count = 0;
+        for (desc_index, request, result) in io_uring.completions() {
+            let len = complete_request(&mem, &self.counters, &request, result);
+            self.queue.add_used(&mem, desc_index, len);
+            used_count += 1;
+        }
+        used_count > 0
+    }
+
+    // Waits for all the requests in flight, so that the guest memory and the
+    // image are left untouched while the device is paused.
+    fn drain_io_uring(&mut self, io_uring: &mut BlockIoUring) -> result::Result<(), DeviceError> {
+        while !io_uring.inflight.is_empty() {
+            io_uring.wait().map_err(DeviceError::IoError)?;
+            if self.process_io_uring_completions(io_uring) {
+                self.signal_used_queue()?;
+            }
+        }
+        Ok(())
+    }
+
     fn signal_used_queue(&self) -> result::Result<(), DeviceError> {
         self.interrupt_cb
             .trigger(&VirtioInterruptType::Queue, Some(&self.queue))
@@ -664,10 +1363,11 @@ impl<T: DiskFile> BlockEpollHandler<T> {
         mut disk_image: T,
         disk_path: &PathBuf,
     ) -> result::Result<(), DeviceError> {
-        self.disk_nsectors = disk_image
+        let disk_size = disk_image
             .seek(SeekFrom::End(0))
-            .map_err(DeviceError::IoError)?
-            / SECTOR_SIZE;
+            .map_err(DeviceError::IoError)?;
+        self.disk_nsectors
+            .store(disk_size / SECTOR_SIZE, Ordering::Release);
         self.disk_image_id = build_disk_image_id(disk_path);
         self.disk_image = Arc::new(Mutex::new(disk_image));
         Ok(())
@@ -703,6 +1403,36 @@ impl<T: DiskFile> BlockEpollHandler<T> {
             epoll::Event::new(epoll::Events::EPOLLIN, u64::from(PAUSE_EVENT)),
         )
         .map_err(DeviceError::EpollCtl)?;
+        epoll::ctl(
+            epoll_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            self.rate_limiter_timer.as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, u64::from(RATE_LIMITER_EVENT)),
+        )
+        .map_err(DeviceError::EpollCtl)?;
+
+        // Raw images are accessed asynchronously through io_uring, unless
+        // the instance can't be set up, in which case requests are executed
+        // synchronously.
+        let mut io_uring = match self.disk_image_fd {
+            Some(fd) => match BlockIoUring::new(fd, self.queue.actual_size()) {
+                Ok(io_uring) => Some(io_uring),
+                Err(e) => {
+                    warn!("Falling back to synchronous block I/O: {}", e);
+                    None
+                }
+            },
+            None => None,
+        };
+        if let Some(io_uring) = io_uring.as_ref() {
+            epoll::ctl(
+                epoll_fd,
+                epoll::ControlOptions::EPOLL_CTL_ADD,
+                io_uring.evt.as_raw_fd(),
+                epoll::Event::new(epoll::Events::EPOLLIN, u64::from(IO_URING_EVENT)),
+            )
+            .map_err(DeviceError::EpollCtl)?;
+        }
 
         const EPOLL_EVENTS_LEN: usize = 100;
         let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); EPOLL_EVENTS_LEN];
@@ -730,10 +1460,38 @@ impl<T: DiskFile> BlockEpollHandler<T> {
 
                 match ev_type {
                     QUEUE_AVAIL_EVENT => {
+                        self.queue.notified();
+                        // While throttled, new requests wait for the rate
+                        // limiter timer, behind the throttled ones.
                         if let Err(e) = queue_evt.read() {
                             error!("Failed to get queue event: {:?}", e);
                             break 'epoll;
-                        } else if self.process_queue() {
+                        } else if !self.throttled && self.process_queue(io_uring.as_mut()) {
+                            if let Err(e) = self.signal_used_queue() {
+                                error!("Failed to signal used queue: {:?}", e);
+                                break 'epoll;
+                            }
+                        }
+                    }
+                    RATE_LIMITER_EVENT => {
+                        if let Err(e) = self.rate_limiter_timer.wait() {
+                            error!("Failed to get rate limiter event: {:?}", e);
+                            break 'epoll;
+                        }
+                        self.throttled = false;
+                        if self.process_queue(io_uring.as_mut()) {
+                            if let Err(e) = self.signal_used_queue() {
+                                error!("Failed to signal used queue: {:?}", e);
+                                break 'epoll;
+                            }
+                        }
+                    }
+                    IO_URING_EVENT => {
+                        let io_uring = io_uring.as_mut().unwrap();
+                        if let Err(e) = io_uring.evt.read() {
+                            error!("Failed to get io_uring event: {:?}", e);
+                            break 'epoll;
+                        } else if self.process_io_uring_completions(io_uring) {
                             if let Err(e) = self.signal_used_queue() {
                                 error!("Failed to signal used queue: {:?}", e);
                                 break 'epoll;
@@ -748,6 +1506,12 @@ impl<T: DiskFile> BlockEpollHandler<T> {
                         // Drain pause event
                         let _ = self.pause_evt.read();
                         debug!("PAUSE_EVENT received, pausing virtio-block epoll loop");
+                        if let Some(io_uring) = io_uring.as_mut() {
+                            if let Err(e) = self.drain_io_uring(io_uring) {
+                                error!("Failed to drain io_uring requests: {:?}", e);
+                                break 'epoll;
+                            }
+                        }
                         // We loop here to handle spurious park() returns.
                         // Until we have not resumed, the paused boolean will
                         // be true.
@@ -806,8 +1570,10 @@ unsafe impl ByteValued for VirtioBlockConfig {}
 pub struct Block<T: DiskFile> {
     kill_evt: Option<EventFd>,
     disk_image: Arc<Mutex<T>>,
+    disk_image_fd: Option<RawFd>,
     disk_path: PathBuf,
-    disk_nsectors: u64,
+    // Shared with the I/O threads, as it changes when the disk is resized.
+    disk_nsectors: Arc<AtomicU64>,
     avail_features: u64,
     acked_features: u64,
     config: VirtioBlockConfig,
@@ -817,6 +1583,8 @@ pub struct Block<T: DiskFile> {
     pause_evt: Option<EventFd>,
     paused: Arc<AtomicBool>,
     queue_size: Vec<u16>,
+    counters: Arc<BlockCounters>,
+    rate_limiter: Arc<Mutex<RateLimiter>>,
 }
 
 impl<T: DiskFile> Block<T> {
@@ -840,7 +1608,11 @@ impl<T: DiskFile> Block<T> {
             );
         }
 
-        let mut avail_features = (1u64 << VIRTIO_F_VERSION_1) | (1u64 << VIRTIO_BLK_F_FLUSH);
+        let mut avail_features = (1u64 << VIRTIO_F_VERSION_1)
+            | (1u64 << VIRTIO_BLK_F_FLUSH)
+            | (1u64 << VIRTIO_BLK_F_SEG_MAX)
+            | (1u64 << VIRTIO_BLK_F_SIZE_MAX)
+            | (1u64 << VIRTIO_RING_F_INDIRECT_DESC);
 
         if iommu {
             avail_features |= 1u64 << VIRTIO_F_IOMMU_PLATFORM;
@@ -853,6 +1625,11 @@ impl<T: DiskFile> Block<T> {
         let disk_nsectors = disk_size / SECTOR_SIZE;
         let mut config = VirtioBlockConfig {
             capacity: disk_nsectors,
+            // A request can be split across any number of data descriptors,
+            // as long as the header and the status fit in the same chain,
+            // and a single readv or writev can take them all.
+            seg_max: cmp::min(cmp::max(u32::from(queue_size), 3) - 2, IOV_MAX as u32),
+            size_max: std::u32::MAX,
             ..Default::default()
         };
 
@@ -861,11 +1638,24 @@ impl<T: DiskFile> Block<T> {
             config.num_queues = num_queues as u16;
         }
 
+        // Discarded and zeroed ranges are deallocated from the image, which
+        // makes no sense for a read-only disk.
+        if !is_disk_read_only {
+            avail_features |= (1u64 << VIRTIO_BLK_F_DISCARD) | (1u64 << VIRTIO_BLK_F_WRITE_ZEROES);
+            config.max_discard_sectors = std::u32::MAX;
+            config.max_discard_seg = 1;
+            config.discard_sector_alignment = 1;
+            config.max_write_zeroes_sectors = std::u32::MAX;
+            config.max_write_zeroes_seg = 1;
+            config.write_zeroes_may_unmap = 1;
+        }
+
         Ok(Block {
             kill_evt: None,
             disk_image: Arc::new(Mutex::new(disk_image)),
+            disk_image_fd: None,
             disk_path,
-            disk_nsectors,
+            disk_nsectors: Arc::new(AtomicU64::new(disk_nsectors)),
             avail_features,
             acked_features: 0u64,
             config,
@@ -875,10 +1665,142 @@ impl<T: DiskFile> Block<T> {
             pause_evt: None,
             paused: Arc::new(AtomicBool::new(false)),
             queue_size: vec![queue_size; num_queues],
+            counters: Arc::new(BlockCounters::default()),
+            rate_limiter: Arc::new(Mutex::new(RateLimiter::default())),
         })
     }
 }
 
+impl Block<RawFile> {
+    /// Create a new virtio block device that operates on the given raw
+    /// file, relying on io_uring to process the I/O requests asynchronously.
+    ///
+    /// The caller is expected to check the host kernel supports it with
+    /// `block_io_uring_is_supported()`.
+    pub fn new_io_uring(
+        disk_image: RawFile,
+        disk_path: PathBuf,
+        is_disk_read_only: bool,
+        iommu: bool,
+        num_queues: usize,
+        queue_size: u16,
+    ) -> io::Result<Self> {
+        let disk_image_fd = disk_image.as_raw_fd();
+        let mut block = Self::new(
+            disk_image,
+            disk_path,
+            is_disk_read_only,
+            iommu,
+            num_queues,
+            queue_size,
+        )?;
+        block.disk_image_fd = Some(disk_image_fd);
+
+        Ok(block)
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct BlockState {
+    pub disk_path: PathBuf,
+    pub disk_nsectors: u64,
+    pub avail_features: u64,
+    pub acked_features: u64,
+    pub config: Vec<u8>,
+}
+
+impl VersionedState for BlockState {
+    const VERSION: u16 = 1;
+}
+
+impl<T: DiskFile> Block<T> {
+    /// Returns the disk image the device operates on, shared with its
+    /// I/O threads.
+    pub fn disk_image(&self) -> Arc<Mutex<T>> {
+        self.disk_image.clone()
+    }
+
+    /// Returns the rate limiter shared by all the queues of the device, so
+    /// that its limits can be updated at runtime.
+    pub fn rate_limiter(&self) -> Arc<Mutex<RateLimiter>> {
+        self.rate_limiter.clone()
+    }
+
+    fn state(&self) -> BlockState {
+        BlockState {
+            disk_path: self.disk_path.clone(),
+            disk_nsectors: self.disk_nsectors.load(Ordering::Acquire),
+            avail_features: self.avail_features,
+            acked_features: self.acked_features,
+            config: self.config.as_slice().to_vec(),
+        }
+    }
+
+    fn set_state(&mut self, state: &BlockState) -> result::Result<(), MigratableError> {
+        let disk_nsectors = self.disk_nsectors.load(Ordering::Acquire);
+        if state.disk_nsectors != disk_nsectors {
+            return Err(MigratableError::Restore(anyhow!(
+                "Disk {:?} size changed from {} to {} sectors",
+                self.disk_path,
+                state.disk_nsectors,
+                disk_nsectors
+            )));
+        }
+        if state.config.len() != self.config.as_slice().len() {
+            return Err(MigratableError::Restore(anyhow!(
+                "Invalid virtio-block configuration space size"
+            )));
+        }
+
+        self.avail_features = state.avail_features;
+        self.acked_features = state.acked_features;
+        self.config.as_mut_slice().copy_from_slice(&state.config);
+
+        Ok(())
+    }
+}
+
+/// Virtio block devices whose disk can be resized while the guest is running.
+pub trait ResizableBlock: Send {
+    /// Grows the disk to `size` bytes, and notifies the guest of its new capacity.
+    fn resize(&mut self, size: u64) -> io::Result<()>;
+}
+
+impl<T: DiskFile + DiskResize + Send> ResizableBlock for Block<T> {
+    fn resize(&mut self, size: u64) -> io::Result<()> {
+        if size % SECTOR_SIZE != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "disk size {} is not a multiple of sector size {}",
+                    size, SECTOR_SIZE
+                ),
+            ));
+        }
+        // Requests in flight were checked against the current capacity, the
+        // disk can't be shrunk under them.
+        let disk_nsectors = size / SECTOR_SIZE;
+        if disk_nsectors < self.disk_nsectors.load(Ordering::Acquire) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "shrinking the disk is not supported",
+            ));
+        }
+
+        self.disk_image.lock().unwrap().resize(size)?;
+        self.disk_nsectors.store(disk_nsectors, Ordering::Release);
+        self.config.capacity = disk_nsectors;
+
+        // Until the device is activated, the driver reads the capacity from
+        // the configuration space anyway.
+        if let Some(interrupt_cb) = &self.interrupt_cb {
+            interrupt_cb.trigger(&VirtioInterruptType::Config, None)?;
+        }
+
+        Ok(())
+    }
+}
+
 impl<T: DiskFile> Drop for Block<T> {
     fn drop(&mut self) {
         if let Some(kill_evt) = self.kill_evt.take() {
@@ -999,15 +1921,25 @@ impl<T: 'static + DiskFile + Send> VirtioDevice for Block<T> {
 
         let mut epoll_threads = Vec::new();
         for _ in 0..self.queue_size.len() {
+            let rate_limiter_timer = TimerFd::new().map_err(|e| {
+                error!("failed creating rate limiter TimerFd: {}", e);
+                ActivateError::BadActivate
+            })?;
+
             let mut handler = BlockEpollHandler {
                 queue: queues.remove(0),
                 mem: mem.clone(),
                 disk_image: self.disk_image.clone(),
-                disk_nsectors: self.disk_nsectors,
+                disk_image_fd: self.disk_image_fd,
+                disk_nsectors: self.disk_nsectors.clone(),
                 interrupt_cb: interrupt_cb.clone(),
                 disk_image_id: disk_image_id.clone(),
                 kill_evt: kill_evt.try_clone().unwrap(),
                 pause_evt: pause_evt.try_clone().unwrap(),
+                counters: self.counters.clone(),
+                rate_limiter: self.rate_limiter.clone(),
+                rate_limiter_timer,
+                throttled: false,
             };
 
             let queue_evt = queue_evts.remove(0);
@@ -1048,8 +1980,710 @@ impl<T: 'static + DiskFile + Send> VirtioDevice for Block<T> {
             self.queue_evts.take().unwrap(),
         ))
     }
+
+    fn counters(&self) -> Option<BTreeMap<&'static str, u64>> {
+        let mut counters = BTreeMap::new();
+
+        counters.insert(
+            "read_bytes",
+            self.counters.read_bytes.load(Ordering::Relaxed),
+        );
+        counters.insert("read_ops", self.counters.read_ops.load(Ordering::Relaxed));
+        counters.insert(
+            "write_bytes",
+            self.counters.write_bytes.load(Ordering::Relaxed),
+        );
+        counters.insert("write_ops", self.counters.write_ops.load(Ordering::Relaxed));
+
+        Some(counters)
+    }
 }
 
 virtio_pausable!(Block, T: 'static + DiskFile + Send);
-impl<T: 'static + DiskFile + Send> Snapshotable for Block<T> {}
+impl<T: 'static + DiskFile + Send> Snapshotable for Block<T> {
+    fn id(&self) -> String {
+        BLOCK_SNAPSHOT_ID.to_string()
+    }
+
+    fn snapshot(&self) -> result::Result<Snapshot, MigratableError> {
+        Snapshot::new_from_state(&self.id(), &self.state())
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> result::Result<(), MigratableError> {
+        self.set_state(&snapshot.to_state(&self.id())?)
+    }
+}
 impl<T: 'static + DiskFile + Send> Migratable for Block<T> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::tests::VirtQueue as GuestQ;
+    use crate::queue::{VIRTQ_DESC_F_INDIRECT, VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+
+    const DISK_NSECTORS: u64 = 16;
+    const SEGMENTS_ADDR: GuestAddress = GuestAddress(0x1000);
+
+    fn create_disk() -> RawFile {
+        let mut disk = RawFile::new(tempfile::tempfile().unwrap(), false);
+        disk.write_all(&[0xff; (DISK_NSECTORS * SECTOR_SIZE) as usize])
+            .unwrap();
+        disk
+    }
+
+    fn read_sector(disk: &mut RawFile, sector: u64) -> Vec<u8> {
+        let mut data = vec![0; SECTOR_SIZE as usize];
+        disk.seek(SeekFrom::Start(sector << SECTOR_SHIFT)).unwrap();
+        disk.read_exact(&mut data).unwrap();
+        data
+    }
+
+    // Builds a discard or write zeroes request carrying the given segments,
+    // which are written to the guest memory.
+    fn discard_write_zeroes_request(
+        mem: &GuestMemoryMmap,
+        request_type: RequestType,
+        segments: &[DiscardWriteZeroesSegment],
+    ) -> Request {
+        let mut data_len = 0;
+        for segment in segments.iter() {
+            mem.write_obj(*segment, SEGMENTS_ADDR.unchecked_add(u64::from(data_len)))
+                .unwrap();
+            data_len += DISCARD_WRITE_ZEROES_SEGMENT_SIZE;
+        }
+
+        Request {
+            request_type,
+            sector: 0,
+            data_descriptors: vec![(SEGMENTS_ADDR, data_len)],
+            data_len,
+            status_addr: GuestAddress(0x2000),
+        }
+    }
+
+    fn segment(sector: u64, num_sectors: u32, flags: u32) -> DiscardWriteZeroesSegment {
+        DiscardWriteZeroesSegment {
+            sector,
+            num_sectors,
+            flags,
+        }
+    }
+
+    #[test]
+    fn test_discard_write_zeroes() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut disk = create_disk();
+
+        // Single sectors aren't aligned on the host block size, the data
+        // around them must be preserved.
+        let req = discard_write_zeroes_request(
+            &mem,
+            RequestType::Discard,
+            &[segment(1, 1, 0), segment(4, 3, 0)],
+        );
+        assert_eq!(
+            req.execute_discard_write_zeroes(&mut disk, DISK_NSECTORS, &mem)
+                .unwrap(),
+            0
+        );
+
+        let req = discard_write_zeroes_request(
+            &mem,
+            RequestType::WriteZeroes,
+            &[
+                segment(9, 1, 0),
+                segment(11, 2, VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP),
+            ],
+        );
+        assert_eq!(
+            req.execute_discard_write_zeroes(&mut disk, DISK_NSECTORS, &mem)
+                .unwrap(),
+            0
+        );
+
+        for sector in 0..DISK_NSECTORS {
+            let expected = match sector {
+                1 | 4..=6 | 9 | 11..=12 => 0,
+                _ => 0xff,
+            };
+            assert_eq!(
+                read_sector(&mut disk, sector),
+                vec![expected; SECTOR_SIZE as usize],
+                "sector {}",
+                sector
+            );
+        }
+    }
+
+    #[test]
+    fn test_discard_write_zeroes_partial_segment() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut disk = create_disk();
+
+        for request_type in [RequestType::Discard, RequestType::WriteZeroes].iter() {
+            let mut req = discard_write_zeroes_request(&mem, *request_type, &[segment(0, 1, 0)]);
+            req.data_descriptors[0].1 += 1;
+            req.data_len += 1;
+
+            match req.execute_discard_write_zeroes(&mut disk, DISK_NSECTORS, &mem) {
+                Err(ExecuteError::BadRequest(Error::InvalidDiscardWriteZeroesSegment)) => (),
+                r => panic!("unexpected result {:?}", r),
+            }
+        }
+
+        assert_eq!(read_sector(&mut disk, 0), vec![0xff; SECTOR_SIZE as usize]);
+    }
+
+    #[test]
+    fn test_discard_write_zeroes_out_of_bounds() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut disk = create_disk();
+
+        for request_type in [RequestType::Discard, RequestType::WriteZeroes].iter() {
+            for out_of_bounds in [
+                segment(DISK_NSECTORS - 1, 2, 0),
+                segment(DISK_NSECTORS, 1, 0),
+                segment(u64::MAX, 1, 0),
+            ]
+            .iter()
+            {
+                let req = discard_write_zeroes_request(&mem, *request_type, &[*out_of_bounds]);
+                match req.execute_discard_write_zeroes(&mut disk, DISK_NSECTORS, &mem) {
+                    Err(ExecuteError::BadRequest(Error::InvalidOffset)) => (),
+                    r => panic!("unexpected result {:?}", r),
+                }
+            }
+        }
+
+        assert_eq!(
+            read_sector(&mut disk, DISK_NSECTORS - 1),
+            vec![0xff; SECTOR_SIZE as usize]
+        );
+    }
+
+    #[test]
+    fn test_discard_write_zeroes_flags() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut disk = create_disk();
+
+        // The unmap flag is only defined for write zeroes requests.
+        let req = discard_write_zeroes_request(
+            &mem,
+            RequestType::Discard,
+            &[segment(0, 1, VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP)],
+        );
+        match req.execute_discard_write_zeroes(&mut disk, DISK_NSECTORS, &mem) {
+            Err(ExecuteError::Unsupported(VIRTIO_BLK_T_DISCARD)) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        let req = discard_write_zeroes_request(
+            &mem,
+            RequestType::WriteZeroes,
+            &[segment(0, 1, VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP << 1)],
+        );
+        match req.execute_discard_write_zeroes(&mut disk, DISK_NSECTORS, &mem) {
+            Err(ExecuteError::Unsupported(VIRTIO_BLK_T_WRITE_ZEROES)) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        assert_eq!(read_sector(&mut disk, 0), vec![0xff; SECTOR_SIZE as usize]);
+    }
+
+    const MEM_SIZE: usize = 0x10_0000;
+    const DESC_TABLE_ADDR: GuestAddress = GuestAddress(0x4000);
+    const INDIRECT_TABLE_ADDR: GuestAddress = GuestAddress(0x8000);
+    const HEADER_ADDR: GuestAddress = GuestAddress(0x1_0000);
+    const STATUS_ADDR: GuestAddress = GuestAddress(0x1_1000);
+
+    fn write_descriptor(
+        mem: &GuestMemoryMmap,
+        table_addr: GuestAddress,
+        index: u16,
+        addr: GuestAddress,
+        len: u32,
+        flags: u16,
+    ) {
+        let desc_addr = table_addr.unchecked_add(u64::from(index) * 16);
+        mem.write_obj(addr.raw_value(), desc_addr).unwrap();
+        mem.write_obj(len, desc_addr.unchecked_add(8)).unwrap();
+        mem.write_obj(flags, desc_addr.unchecked_add(12)).unwrap();
+        mem.write_obj(index + 1, desc_addr.unchecked_add(14))
+            .unwrap();
+    }
+
+    // Writes a request made of a header, the data buffers and a status to
+    // the descriptor table at table_addr, and returns the number of
+    // descriptors in the chain.
+    fn write_request_chain(
+        mem: &GuestMemoryMmap,
+        table_addr: GuestAddress,
+        request_type: u32,
+        sector: u64,
+        bufs: &[(GuestAddress, u32)],
+    ) -> u16 {
+        mem.write_obj(request_type, HEADER_ADDR).unwrap();
+        mem.write_obj(sector, HEADER_ADDR.unchecked_add(8)).unwrap();
+        write_descriptor(mem, table_addr, 0, HEADER_ADDR, 16, VIRTQ_DESC_F_NEXT);
+
+        let data_flags = if request_type == VIRTIO_BLK_T_IN {
+            VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE
+        } else {
+            VIRTQ_DESC_F_NEXT
+        };
+        for (i, (addr, len)) in bufs.iter().enumerate() {
+            write_descriptor(mem, table_addr, i as u16 + 1, *addr, *len, data_flags);
+        }
+
+        let num_descs = bufs.len() as u16 + 2;
+        write_descriptor(
+            mem,
+            table_addr,
+            num_descs - 1,
+            STATUS_ADDR,
+            1,
+            VIRTQ_DESC_F_WRITE,
+        );
+
+        num_descs
+    }
+
+    fn parse_request(
+        mem: &GuestMemoryMmap,
+        request_type: u32,
+        sector: u64,
+        bufs: &[(GuestAddress, u32)],
+        indirect: bool,
+    ) -> Request {
+        let table_size = if indirect {
+            let num_descs =
+                write_request_chain(mem, INDIRECT_TABLE_ADDR, request_type, sector, bufs);
+            write_descriptor(
+                mem,
+                DESC_TABLE_ADDR,
+                0,
+                INDIRECT_TABLE_ADDR,
+                u32::from(num_descs) * 16,
+                VIRTQ_DESC_F_INDIRECT,
+            );
+            1
+        } else {
+            write_request_chain(mem, DESC_TABLE_ADDR, request_type, sector, bufs)
+        };
+
+        let head = DescriptorChain::checked_new(mem, DESC_TABLE_ADDR, table_size, 0, None).unwrap();
+        Request::parse(&head, mem).unwrap()
+    }
+
+    // Writes the guest buffers to the disk from the given sector, reads them
+    // back into other buffers, and checks the disk and the guest buffers.
+    fn check_data_transfer(bufs_len: &[u32], indirect: bool) {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+        let mut disk = create_disk();
+        let sector = 1;
+
+        let mut out_bufs = Vec::new();
+        let mut in_bufs = Vec::new();
+        let mut data = Vec::new();
+        let mut addr = GuestAddress(0x2_0000);
+        for (i, len) in bufs_len.iter().enumerate() {
+            // Leave a gap between the buffers, so that they are not
+            // contiguous in guest memory.
+            let buf = vec![(i % 255) as u8 + 1; *len as usize];
+            mem.write_slice(&buf, addr).unwrap();
+            out_bufs.push((addr, *len));
+            in_bufs.push((addr.unchecked_add(0x4_0000), *len));
+            data.extend_from_slice(&buf);
+            addr = addr.unchecked_add(u64::from(*len) + 8);
+        }
+
+        let req = parse_request(&mem, VIRTIO_BLK_T_OUT, sector, &out_bufs, indirect);
+        assert_eq!(req.data_descriptors.len(), bufs_len.len());
+        assert_eq!(
+            req.execute(&mut disk, DISK_NSECTORS, &mem, &Vec::new())
+                .unwrap(),
+            0
+        );
+
+        let mut disk_data = vec![0; data.len()];
+        disk.seek(SeekFrom::Start(sector << SECTOR_SHIFT)).unwrap();
+        disk.read_exact(&mut disk_data).unwrap();
+        assert_eq!(disk_data, data);
+        assert_eq!(read_sector(&mut disk, 0), vec![0xff; SECTOR_SIZE as usize]);
+
+        let req = parse_request(&mem, VIRTIO_BLK_T_IN, sector, &in_bufs, indirect);
+        assert_eq!(
+            req.execute(&mut disk, DISK_NSECTORS, &mem, &Vec::new())
+                .unwrap(),
+            data.len() as u32
+        );
+
+        let mut guest_data = Vec::new();
+        for (addr, len) in in_bufs.iter() {
+            let mut buf = vec![0; *len as usize];
+            mem.read_slice(&mut buf, *addr).unwrap();
+            guest_data.extend_from_slice(&buf);
+        }
+        assert_eq!(guest_data, data);
+    }
+
+    #[test]
+    fn test_multi_descriptor_request() {
+        check_data_transfer(&[0x200, 0x80, 0x380], false);
+    }
+
+    #[test]
+    fn test_indirect_request() {
+        check_data_transfer(&[0x100, 0x300, 0x1, 0x1ff], true);
+    }
+
+    #[test]
+    fn test_seg_max_overflow() {
+        let disk = create_disk();
+        let block = Block::new(disk, PathBuf::new(), false, false, 1, 2048).unwrap();
+        let seg_max = block.config.seg_max;
+        assert_eq!(seg_max, IOV_MAX as u32);
+
+        // A driver ignoring seg_max still gets its request fully executed,
+        // through more than one readv or writev.
+        check_data_transfer(&[4; IOV_MAX + 2], true);
+
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+        let bufs: Vec<(GuestAddress, u32)> = (0..IOV_MAX + 2)
+            .map(|i| (GuestAddress(0x2_0000 + i as u64 * 4), 4))
+            .collect();
+        let req = parse_request(&mem, VIRTIO_BLK_T_OUT, 0, &bufs, true);
+        assert!(!BlockIoUring::handles(&req));
+        let req = parse_request(&mem, VIRTIO_BLK_T_OUT, 0, &bufs[..IOV_MAX], true);
+        assert!(BlockIoUring::handles(&req));
+    }
+
+    #[test]
+    fn test_overlapping_descriptors() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+        let mut disk = create_disk();
+        let data: Vec<u8> = (0..SECTOR_SIZE).map(|i| i as u8).collect();
+        disk.seek(SeekFrom::Start(0)).unwrap();
+        disk.write_all(&data).unwrap();
+
+        // The guest can point several descriptors to the same memory, the
+        // last read to land there wins.
+        let addr = GuestAddress(0x2_0000);
+        let half = (SECTOR_SIZE / 2) as u32;
+        let bufs = [(addr, half), (addr, half)];
+        let req = parse_request(&mem, VIRTIO_BLK_T_IN, 0, &bufs, false);
+        assert_eq!(
+            req.execute(&mut disk, DISK_NSECTORS, &mem, &Vec::new())
+                .unwrap(),
+            SECTOR_SIZE as u32
+        );
+        let mut buf = vec![0; half as usize];
+        mem.read_slice(&mut buf, addr).unwrap();
+        assert_eq!(buf, &data[half as usize..]);
+
+        let req = parse_request(&mem, VIRTIO_BLK_T_OUT, 1, &bufs, false);
+        req.execute(&mut disk, DISK_NSECTORS, &mem, &Vec::new())
+            .unwrap();
+        let mut expected = data[half as usize..].to_vec();
+        expected.extend_from_slice(&data[half as usize..]);
+        assert_eq!(read_sector(&mut disk, 1), expected);
+    }
+
+    // Builds a request transferring data between the given guest buffers
+    // and the disk, from the given sector.
+    fn io_request(
+        request_type: RequestType,
+        sector: u64,
+        data_descriptors: &[(GuestAddress, u32)],
+    ) -> Request {
+        Request {
+            request_type,
+            sector,
+            data_descriptors: data_descriptors.to_vec(),
+            data_len: data_descriptors.iter().map(|(_, len)| len).sum(),
+            status_addr: STATUS_ADDR,
+        }
+    }
+
+    // Waits for all the requests in flight, and returns their outcome sorted
+    // by descriptor index.
+    fn io_uring_completions(
+        io_uring: &mut BlockIoUring,
+    ) -> Vec<(u16, Request, result::Result<u32, ExecuteError>)> {
+        let mut completed = Vec::new();
+        while !io_uring.inflight.is_empty() {
+            io_uring.wait().unwrap();
+            completed.extend(io_uring.completions());
+        }
+        completed.sort_by_key(|(desc_index, _, _)| *desc_index);
+        completed
+    }
+
+    #[test]
+    fn test_io_uring_submit_complete() {
+        if !block_io_uring_is_supported() {
+            return;
+        }
+
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+        let mut disk = create_disk();
+        let mut io_uring = BlockIoUring::new(disk.as_raw_fd(), 16).unwrap();
+
+        let data: Vec<u8> = (0..0x400).map(|i| i as u8).collect();
+        mem.write_slice(&data, GuestAddress(0x2_0000)).unwrap();
+        let out_bufs = [
+            (GuestAddress(0x2_0000), 0x100),
+            (GuestAddress(0x2_0100), 0x300),
+        ];
+        let req = io_request(RequestType::Out, 2, &out_bufs);
+        assert!(io_uring.submit(0, req, &mem, DISK_NSECTORS).is_ok());
+        let req = io_request(RequestType::Flush, 0, &[]);
+        assert!(io_uring.submit(1, req, &mem, DISK_NSECTORS).is_ok());
+        assert_eq!(io_uring.inflight.len(), 2);
+
+        let completed = io_uring_completions(&mut io_uring);
+        assert_eq!(completed.len(), 2);
+        for (i, (desc_index, _, result)) in completed.iter().enumerate() {
+            assert_eq!(*desc_index, i as u16);
+            assert_eq!(*result.as_ref().unwrap(), 0);
+        }
+        let mut disk_data = vec![0; data.len()];
+        disk.seek(SeekFrom::Start(2 << SECTOR_SHIFT)).unwrap();
+        disk.read_exact(&mut disk_data).unwrap();
+        assert_eq!(disk_data, data);
+
+        let in_bufs = [
+            (GuestAddress(0x3_0000), 0x200),
+            (GuestAddress(0x3_1000), 0x200),
+        ];
+        let req = io_request(RequestType::In, 2, &in_bufs);
+        assert!(io_uring.submit(2, req, &mem, DISK_NSECTORS).is_ok());
+
+        let completed = io_uring_completions(&mut io_uring);
+        assert_eq!(completed.len(), 1);
+        assert_eq!(completed[0].0, 2);
+        assert_eq!(*completed[0].2.as_ref().unwrap(), 0x400);
+        let mut guest_data = vec![0; data.len()];
+        mem.read_slice(&mut guest_data[..0x200], GuestAddress(0x3_0000))
+            .unwrap();
+        mem.read_slice(&mut guest_data[0x200..], GuestAddress(0x3_1000))
+            .unwrap();
+        assert_eq!(guest_data, data);
+    }
+
+    #[test]
+    fn test_io_uring_errors() {
+        if !block_io_uring_is_supported() {
+            return;
+        }
+
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+        let disk = create_disk();
+        let mut io_uring = BlockIoUring::new(disk.as_raw_fd(), 16).unwrap();
+        let bufs = [(GuestAddress(0x2_0000), SECTOR_SIZE as u32 * 2)];
+
+        // Requests which can't be submitted are handed back right away.
+        let req = io_request(RequestType::In, DISK_NSECTORS - 1, &bufs);
+        match io_uring.submit(0, req, &mem, DISK_NSECTORS) {
+            Err((_, ExecuteError::BadRequest(Error::InvalidOffset))) => (),
+            Err((_, e)) => panic!("unexpected error {:?}", e),
+            Ok(()) => panic!("request submitted"),
+        }
+        let req = io_request(
+            RequestType::In,
+            0,
+            &[(GuestAddress(MEM_SIZE as u64), 0x200)],
+        );
+        match io_uring.submit(0, req, &mem, DISK_NSECTORS) {
+            Err((_, ExecuteError::BadRequest(Error::CheckedOffset(_, _)))) => (),
+            Err((_, e)) => panic!("unexpected error {:?}", e),
+            Ok(()) => panic!("request submitted"),
+        }
+        assert!(io_uring.inflight.is_empty());
+
+        // Reading past the end of an image shrunk behind our back completes
+        // short, which is reported as an error.
+        let req = io_request(RequestType::In, DISK_NSECTORS - 1, &bufs);
+        assert!(io_uring.submit(1, req, &mem, DISK_NSECTORS + 1).is_ok());
+        let mut completed = io_uring_completions(&mut io_uring);
+        assert_eq!(completed.len(), 1);
+        match completed.remove(0) {
+            (1, _, Err(ExecuteError::AsyncIo(e))) => {
+                assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof)
+            }
+            (_, _, r) => panic!("unexpected result {:?}", r),
+        }
+
+        // So are the errors of the operation itself, which reach the guest
+        // as I/O errors.
+        let read_only = File::open(format!("/proc/self/fd/{}", disk.as_raw_fd())).unwrap();
+        let mut io_uring = BlockIoUring::new(read_only.as_raw_fd(), 16).unwrap();
+        let req = io_request(RequestType::Out, 0, &bufs);
+        assert!(io_uring.submit(2, req, &mem, DISK_NSECTORS).is_ok());
+        let mut completed = io_uring_completions(&mut io_uring);
+        assert_eq!(completed.len(), 1);
+        let (desc_index, request, result) = completed.remove(0);
+        assert_eq!(desc_index, 2);
+        match result {
+            Err(ExecuteError::AsyncIo(ref e)) => assert_eq!(e.raw_os_error(), Some(libc::EBADF)),
+            ref r => panic!("unexpected result {:?}", r),
+        }
+
+        let counters = BlockCounters::default();
+        mem.write_obj(VIRTIO_BLK_S_OK, STATUS_ADDR).unwrap();
+        assert_eq!(complete_request(&mem, &counters, &request, result), 1);
+        assert_eq!(
+            mem.read_obj::<u32>(STATUS_ADDR).unwrap(),
+            VIRTIO_BLK_S_IOERR
+        );
+        assert_eq!(counters.write_ops.load(Ordering::Relaxed), 0);
+    }
+
+    #[derive(Default)]
+    struct CountingInterrupt {
+        count: AtomicU64,
+    }
+
+    impl VirtioInterrupt for CountingInterrupt {
+        fn trigger(
+            &self,
+            _int_type: &VirtioInterruptType,
+            _queue: Option<&Queue>,
+        ) -> result::Result<(), io::Error> {
+            self.count.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_io_uring_drain() {
+        if !block_io_uring_is_supported() {
+            return;
+        }
+
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+        let vq = GuestQ::new(GuestAddress(0), &mem, 16);
+
+        // Write a different pattern to each of the first sectors.
+        const NUM_REQUESTS: u16 = 3;
+        for i in 0..NUM_REQUESTS {
+            let header_addr = HEADER_ADDR.unchecked_add(u64::from(i) * 0x100);
+            let data_addr = GuestAddress(0x2_0000 + u64::from(i) * 0x1000);
+            let status_addr = STATUS_ADDR.unchecked_add(u64::from(i) * 0x10);
+            mem.write_obj(VIRTIO_BLK_T_OUT, header_addr).unwrap();
+            mem.write_obj(u64::from(i), header_addr.unchecked_add(8))
+                .unwrap();
+            mem.write_slice(&[i as u8 + 1; SECTOR_SIZE as usize], data_addr)
+                .unwrap();
+            mem.write_obj(VIRTIO_BLK_S_UNSUPP, status_addr).unwrap();
+
+            let head = i * 3;
+            vq.dtable[head as usize].set(header_addr.raw_value(), 16, VIRTQ_DESC_F_NEXT, head + 1);
+            vq.dtable[head as usize + 1].set(
+                data_addr.raw_value(),
+                SECTOR_SIZE as u32,
+                VIRTQ_DESC_F_NEXT,
+                head + 2,
+            );
+            vq.dtable[head as usize + 2].set(status_addr.raw_value(), 1, VIRTQ_DESC_F_WRITE, 0);
+            vq.avail.ring[i as usize].set(head);
+        }
+        vq.avail.idx.set(NUM_REQUESTS);
+
+        let disk = create_disk();
+        let mut io_uring = BlockIoUring::new(disk.as_raw_fd(), 16).unwrap();
+        let interrupt = Arc::new(CountingInterrupt::default());
+        let mut handler = BlockEpollHandler {
+            queue: vq.create_queue(),
+            mem: GuestMemoryAtomic::new(mem.clone()),
+            disk_image: Arc::new(Mutex::new(disk)),
+            disk_image_fd: Some(io_uring.disk_image_fd),
+            disk_nsectors: Arc::new(AtomicU64::new(DISK_NSECTORS)),
+            interrupt_cb: interrupt.clone(),
+            disk_image_id: Vec::new(),
+            kill_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            pause_evt: EventFd::new(EFD_NONBLOCK).unwrap(),
+            counters: Arc::new(BlockCounters::default()),
+            rate_limiter: Arc::new(Mutex::new(RateLimiter::default())),
+            rate_limiter_timer: TimerFd::new().unwrap(),
+            throttled: false,
+        };
+
+        // The requests are only returned to the guest once completed.
+        assert!(!handler.process_queue(Some(&mut io_uring)));
+        assert_eq!(io_uring.inflight.len(), NUM_REQUESTS as usize);
+
+        // Pausing waits for all of them, leaving nothing in flight behind.
+        handler.drain_io_uring(&mut io_uring).unwrap();
+        assert!(io_uring.inflight.is_empty());
+        assert!(interrupt.count.load(Ordering::SeqCst) > 0);
+        assert_eq!(vq.used.idx.get(), NUM_REQUESTS);
+        let mut used_heads: Vec<u32> = (0..NUM_REQUESTS as usize)
+            .map(|i| vq.used.ring[i].get().id)
+            .collect();
+        used_heads.sort_unstable();
+        assert_eq!(used_heads, vec![0, 3, 6]);
+        assert_eq!(
+            handler.counters.write_ops.load(Ordering::Relaxed),
+            u64::from(NUM_REQUESTS)
+        );
+
+        let mut disk = handler.disk_image.lock().unwrap();
+        for i in 0..NUM_REQUESTS {
+            let status_addr = STATUS_ADDR.unchecked_add(u64::from(i) * 0x10);
+            assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
+            assert_eq!(
+                read_sector(&mut disk, u64::from(i)),
+                vec![i as u8 + 1; SECTOR_SIZE as usize]
+            );
+        }
+    }
+
+    fn open_image(path: &std::path::Path, readonly: bool) -> File {
+        std::fs::OpenOptions::new()
+            .read(true)
+            .write(!readonly)
+            .open(path)
+            .unwrap()
+    }
+
+    fn is_lock_conflict(e: io::Error) -> bool {
+        matches!(e.raw_os_error(), Some(libc::EAGAIN) | Some(libc::EACCES))
+    }
+
+    #[test]
+    fn test_disk_image_lock() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+
+        // A disk opened read-write can't be opened a second time.
+        let image = open_image(file.path(), false);
+        lock_disk_image(&image, false).unwrap();
+        let other = open_image(file.path(), false);
+        assert!(is_lock_conflict(
+            lock_disk_image(&other, false).unwrap_err()
+        ));
+        assert!(is_lock_conflict(lock_disk_image(&other, true).unwrap_err()));
+
+        // Clones of the image share its lock.
+        let clone = image.try_clone().unwrap();
+        lock_disk_image(&clone, false).unwrap();
+
+        // The disk can be used again once unlocked.
+        unlock_disk_image(&image).unwrap();
+        lock_disk_image(&other, false).unwrap();
+        unlock_disk_image(&other).unwrap();
+
+        // Read-only users don't conflict with each other.
+        let reader = open_image(file.path(), true);
+        lock_disk_image(&reader, true).unwrap();
+        lock_disk_image(&other, true).unwrap();
+        assert!(is_lock_conflict(
+            lock_disk_image(&image, false).unwrap_err()
+        ));
+
+        // Closing the image releases its lock.
+        drop(reader);
+        unlock_disk_image(&other).unwrap();
+        lock_disk_image(&image, false).unwrap();
+    }
+}
@@ -7,6 +7,7 @@ use super::{
     VirtioInterruptType, VIRTIO_F_IOMMU_PLATFORM, VIRTIO_F_VERSION_1,
 };
 use crate::VirtioInterrupt;
+use anyhow::anyhow;
 use epoll;
 use libc::EFD_NONBLOCK;
 use std;
@@ -20,7 +21,7 @@ use std::result;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
-use vm_device::{Migratable, MigratableError, Pausable, Snapshotable};
+use vm_device::{Migratable, MigratableError, Pausable, Snapshot, Snapshotable, VersionedState};
 use vm_memory::{ByteValued, Bytes, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap};
 use vmm_sys_util::eventfd::EventFd;
 
@@ -43,6 +44,8 @@ const PAUSE_EVENT: DeviceEventT = 5;
 //Console size feature bit
 const VIRTIO_CONSOLE_F_SIZE: u64 = 0;
 
+const CONSOLE_SNAPSHOT_ID: &str = "virtio-console";
+
 #[derive(Copy, Clone, Debug, Default)]
 #[repr(C)]
 pub struct VirtioConsoleConfig {
@@ -229,6 +232,7 @@ impl ConsoleEpollHandler {
 
                 match ev_type {
                     INPUT_QUEUE_EVENT => {
+                        self.queues[0].notified();
                         if let Err(e) = self.input_queue_evt.read() {
                             error!("Failed to get queue event: {:?}", e);
                             break 'epoll;
@@ -240,6 +244,7 @@ impl ConsoleEpollHandler {
                         }
                     }
                     OUTPUT_QUEUE_EVENT => {
+                        self.queues[1].notified();
                         if let Err(e) = self.output_queue_evt.read() {
                             error!("Failed to get queue event: {:?}", e);
                             break 'epoll;
@@ -398,6 +403,53 @@ impl Console {
             console_input,
         ))
     }
+
+    fn state(&self) -> ConsoleState {
+        ConsoleState {
+            avail_features: self.avail_features,
+            acked_features: self.acked_features,
+            config: self.config.lock().unwrap().as_slice().to_vec(),
+            in_buffer: self
+                .input
+                .in_buffer
+                .lock()
+                .unwrap()
+                .iter()
+                .cloned()
+                .collect(),
+        }
+    }
+
+    fn set_state(&mut self, state: &ConsoleState) -> result::Result<(), MigratableError> {
+        let mut config = self.config.lock().unwrap();
+        if state.config.len() != config.as_slice().len() {
+            return Err(MigratableError::Restore(anyhow!(
+                "Invalid virtio-console configuration space size"
+            )));
+        }
+
+        self.avail_features = state.avail_features;
+        self.acked_features = state.acked_features;
+        self.input
+            .acked_features
+            .store(state.acked_features, Ordering::Relaxed);
+        config.as_mut_slice().copy_from_slice(&state.config);
+        *self.input.in_buffer.lock().unwrap() = state.in_buffer.iter().cloned().collect();
+
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ConsoleState {
+    pub avail_features: u64,
+    pub acked_features: u64,
+    pub config: Vec<u8>,
+    pub in_buffer: Vec<u8>,
+}
+
+impl VersionedState for ConsoleState {
+    const VERSION: u16 = 1;
 }
 
 impl Drop for Console {
@@ -563,5 +615,17 @@ impl VirtioDevice for Console {
 }
 
 virtio_pausable!(Console);
-impl Snapshotable for Console {}
+impl Snapshotable for Console {
+    fn id(&self) -> String {
+        CONSOLE_SNAPSHOT_ID.to_string()
+    }
+
+    fn snapshot(&self) -> result::Result<Snapshot, MigratableError> {
+        Snapshot::new_from_state(&self.id(), &self.state())
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> result::Result<(), MigratableError> {
+        self.set_state(&snapshot.to_state(&self.id())?)
+    }
+}
 impl Migratable for Console {}
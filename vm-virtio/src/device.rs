@@ -7,6 +7,7 @@
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
 use super::*;
+use std::collections::BTreeMap;
 use std::sync::Arc;
 use vm_memory::{GuestAddress, GuestMemoryAtomic, GuestMemoryMmap, GuestUsize};
 use vmm_sys_util::eventfd::EventFd;
@@ -106,6 +107,11 @@ pub trait VirtioDevice: Send {
     /// every device as part of shutting down the VM. Acting on the device
     /// after a shutdown() can lead to unpredictable results.
     fn shutdown(&mut self) {}
+
+    /// Returns the device specific counters, if any.
+    fn counters(&self) -> Option<BTreeMap<&'static str, u64>> {
+        None
+    }
 }
 
 /// Trait providing address translation the same way a physical DMA remapping
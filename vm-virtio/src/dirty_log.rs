@@ -0,0 +1,210 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Logging of the guest pages written by the VMM.
+//!
+//! KVM only logs the guest pages written by the vCPUs. The pages written by
+//! the VMM itself, when emulating virtio devices, are logged here instead.
+//! Those writes all land in device writable descriptors or in the used ring,
+//! so the pages are logged when a descriptor chain is handed back to the
+//! guest through `Queue::add_used()`, before the driver can see it.
+//!
+//! Guest memory written by vhost-user backends or by VFIO devices is not
+//! logged, as it never goes through the VMM queues.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::RwLock;
+use vm_memory::{Address, GuestAddress};
+
+/// Size of the pages being logged, matching the KVM dirty log granularity.
+pub const DIRTY_LOG_PAGE_SIZE: u64 = 4096;
+
+// Dirty pages of a guest RAM region, one bit per page.
+struct RegionBitmap {
+    // Page frame number of the first page of the region.
+    first: u64,
+    count: u64,
+    bitmap: Vec<AtomicU64>,
+}
+
+impl RegionBitmap {
+    fn new(start: GuestAddress, size: u64) -> Self {
+        let count = (size + DIRTY_LOG_PAGE_SIZE - 1) / DIRTY_LOG_PAGE_SIZE;
+        RegionBitmap {
+            first: start.raw_value() / DIRTY_LOG_PAGE_SIZE,
+            count,
+            bitmap: (0..(count + 63) / 64).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    fn contains(&self, page: u64) -> bool {
+        page >= self.first && page - self.first < self.count
+    }
+
+    fn clear(&self) {
+        for word in self.bitmap.iter() {
+            word.store(0, Ordering::Release);
+        }
+    }
+}
+
+/// Guest pages written by the VMM, tracked with one atomic bitmap per guest
+/// RAM region so that the devices never contend on a lock to log a page.
+#[derive(Default)]
+pub struct DirtyLog {
+    enabled: AtomicBool,
+    // Only locked for writing when a region is added, which is rare.
+    regions: RwLock<Vec<RegionBitmap>>,
+}
+
+impl DirtyLog {
+    pub fn new() -> Self {
+        DirtyLog::default()
+    }
+
+    /// Track the pages of a new guest RAM region. Pages outside of any
+    /// region are never logged.
+    pub fn add_region(&self, start: GuestAddress, size: u64) {
+        self.regions
+            .write()
+            .unwrap()
+            .push(RegionBitmap::new(start, size));
+    }
+
+    /// Start logging, forgetting about any previously logged page.
+    pub fn start(&self) {
+        self.clear();
+        self.enabled.store(true, Ordering::SeqCst);
+    }
+
+    /// Stop logging, forgetting about any previously logged page.
+    pub fn stop(&self) {
+        self.enabled.store(false, Ordering::SeqCst);
+        self.clear();
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Acquire)
+    }
+
+    fn clear(&self) {
+        for region in self.regions.read().unwrap().iter() {
+            region.clear();
+        }
+    }
+
+    /// Log the pages covering the [addr, addr + len) guest range as dirty.
+    pub fn mark_dirty(&self, addr: GuestAddress, len: u64) {
+        if len == 0 || !self.is_enabled() {
+            return;
+        }
+
+        let first = addr.raw_value() / DIRTY_LOG_PAGE_SIZE;
+        let last = addr.raw_value().saturating_add(len - 1) / DIRTY_LOG_PAGE_SIZE;
+        let regions = self.regions.read().unwrap();
+        for page in first..=last {
+            if let Some(region) = regions.iter().find(|r| r.contains(page)) {
+                let bit = page - region.first;
+                region.bitmap[(bit / 64) as usize].fetch_or(1 << (bit % 64), Ordering::AcqRel);
+            }
+        }
+    }
+
+    /// Return the dirty pages of the guest RAM region starting at `start` as
+    /// a bitmap, using the same layout as the KVM dirty log: one bit per
+    /// page, the first page being the least significant bit of the first
+    /// word. The returned pages are no longer considered dirty. An unknown
+    /// region has no dirty page.
+    pub fn take_bitmap(&self, start: GuestAddress, size: u64) -> Vec<u64> {
+        let first = start.raw_value() / DIRTY_LOG_PAGE_SIZE;
+        let count = (size + DIRTY_LOG_PAGE_SIZE - 1) / DIRTY_LOG_PAGE_SIZE;
+
+        let regions = self.regions.read().unwrap();
+        match regions
+            .iter()
+            .find(|r| r.first == first && r.count == count)
+        {
+            // Each word is atomically swapped, so that a page logged
+            // concurrently is either returned now or by the next call.
+            Some(region) => region
+                .bitmap
+                .iter()
+                .map(|word| word.swap(0, Ordering::AcqRel))
+                .collect(),
+            None => vec![0u64; ((count + 63) / 64) as usize],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dirty_log() {
+        let log = DirtyLog::new();
+        log.add_region(GuestAddress(0), 0x10000);
+        log.add_region(GuestAddress(0x40000), 0x41000);
+
+        // Nothing is logged until logging starts.
+        log.mark_dirty(GuestAddress(0x1000), 0x10);
+        assert_eq!(log.take_bitmap(GuestAddress(0), 0x10000), vec![0]);
+
+        log.start();
+        log.mark_dirty(GuestAddress(0x1ff0), 0x20);
+        log.mark_dirty(GuestAddress(0x42000), 0x1000);
+        assert_eq!(log.take_bitmap(GuestAddress(0), 0x10000), vec![0b110]);
+        assert_eq!(log.take_bitmap(GuestAddress(0), 0x10000), vec![0]);
+        assert_eq!(
+            log.take_bitmap(GuestAddress(0x40000), 0x41000),
+            vec![0b100, 0]
+        );
+
+        // Pages outside of the regions are ignored.
+        log.mark_dirty(GuestAddress(0xf000), 0x2000);
+        assert_eq!(log.take_bitmap(GuestAddress(0), 0x10000), vec![1 << 15]);
+        assert_eq!(log.take_bitmap(GuestAddress(0x20000), 0x1000), vec![0]);
+
+        log.mark_dirty(GuestAddress(0x3000), 0x1);
+        log.stop();
+        assert_eq!(log.take_bitmap(GuestAddress(0), 0x10000), vec![0]);
+    }
+
+    #[test]
+    fn test_dirty_log_concurrent() {
+        let log = std::sync::Arc::new(DirtyLog::new());
+        log.add_region(GuestAddress(0), 0x100_0000);
+        log.start();
+
+        // Pages logged while the bitmap is being taken are never lost.
+        let writers: Vec<_> = (0..4u64)
+            .map(|i| {
+                let log = log.clone();
+                std::thread::spawn(move || {
+                    for page in (i..0x1000).step_by(4) {
+                        log.mark_dirty(GuestAddress(page * DIRTY_LOG_PAGE_SIZE), 1);
+                    }
+                })
+            })
+            .collect();
+        let mut dirty = 0;
+        for _ in 0..100 {
+            dirty += log
+                .take_bitmap(GuestAddress(0), 0x100_0000)
+                .iter()
+                .map(|w| w.count_ones())
+                .sum::<u32>();
+        }
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        dirty += log
+            .take_bitmap(GuestAddress(0), 0x100_0000)
+            .iter()
+            .map(|w| w.count_ones())
+            .sum::<u32>();
+        assert_eq!(dirty, 0x1000);
+    }
+}
@@ -21,7 +21,10 @@ use std::result;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, RwLock};
 use std::thread;
-use vm_device::{ExternalDmaMapping, Migratable, MigratableError, Pausable, Snapshotable};
+use vm_device::{
+    ExternalDmaMapping, Migratable, MigratableError, Pausable, Snapshot, Snapshotable,
+    VersionedState,
+};
 use vm_memory::{
     Address, ByteValued, Bytes, GuestAddress, GuestAddressSpace, GuestMemoryAtomic,
     GuestMemoryError, GuestMemoryMmap,
@@ -302,6 +305,8 @@ const VIRTIO_IOMMU_FAULT_R_DOMAIN: u32 = 1;
 #[allow(unused)]
 const VIRTIO_IOMMU_FAULT_R_MAPPING: u32 = 2;
 
+const IOMMU_SNAPSHOT_ID: &str = "virtio-iommu";
+
 /// Fault reporting through eventq
 #[allow(unused)]
 #[derive(Copy, Clone, Debug, Default)]
@@ -703,6 +708,7 @@ impl IommuEpollHandler {
 
                 match ev_type {
                     REQUEST_Q_EVENT => {
+                        self.queues[0].notified();
                         if let Err(e) = self.queue_evts[0].read() {
                             error!("Failed to get queue event: {:?}", e);
                             break 'epoll;
@@ -714,6 +720,7 @@ impl IommuEpollHandler {
                         }
                     }
                     EVENT_Q_EVENT => {
+                        self.queues[1].notified();
                         if let Err(e) = self.queue_evts[1].read() {
                             error!("Failed to get queue event: {:?}", e);
                             break 'epoll;
@@ -881,6 +888,63 @@ impl Iommu {
     pub fn add_external_mapping(&mut self, device_id: u32, mapping: Arc<dyn ExternalDmaMapping>) {
         self.ext_mapping.insert(device_id, mapping);
     }
+
+    fn state(&self) -> IommuState {
+        let endpoints = self
+            .mapping
+            .endpoints
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(&endpoint, &domain)| (endpoint, domain))
+            .collect();
+
+        let mut mappings = Vec::new();
+        for (&domain, domain_mappings) in self.mapping.mappings.read().unwrap().iter() {
+            for (&iova, mapping) in domain_mappings.iter() {
+                mappings.push((domain, iova, mapping.gpa, mapping.size));
+            }
+        }
+
+        IommuState {
+            avail_features: self.avail_features,
+            acked_features: self.acked_features,
+            endpoints,
+            mappings,
+        }
+    }
+
+    fn set_state(&mut self, state: &IommuState) -> result::Result<(), MigratableError> {
+        self.avail_features = state.avail_features;
+        self.acked_features = state.acked_features;
+
+        *self.mapping.endpoints.write().unwrap() = state.endpoints.iter().cloned().collect();
+
+        let mut mappings = self.mapping.mappings.write().unwrap();
+        mappings.clear();
+        for &(domain, iova, gpa, size) in state.mappings.iter() {
+            mappings
+                .entry(domain)
+                .or_insert_with(BTreeMap::new)
+                .insert(iova, Mapping { gpa, size });
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct IommuState {
+    pub avail_features: u64,
+    pub acked_features: u64,
+    /// (endpoint, domain) attachments.
+    pub endpoints: Vec<(u32, u32)>,
+    /// (domain, iova, gpa, size) mappings.
+    pub mappings: Vec<(u32, u64, u64, u64)>,
+}
+
+impl VersionedState for IommuState {
+    const VERSION: u16 = 1;
 }
 
 impl Drop for Iommu {
@@ -1038,5 +1102,17 @@ impl VirtioDevice for Iommu {
 }
 
 virtio_pausable!(Iommu);
-impl Snapshotable for Iommu {}
+impl Snapshotable for Iommu {
+    fn id(&self) -> String {
+        IOMMU_SNAPSHOT_ID.to_string()
+    }
+
+    fn snapshot(&self) -> result::Result<Snapshot, MigratableError> {
+        Snapshot::new_from_state(&self.id(), &self.state())
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> result::Result<(), MigratableError> {
+        self.set_state(&snapshot.to_state(&self.id())?)
+    }
+}
 impl Migratable for Iommu {}
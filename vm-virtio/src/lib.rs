@@ -10,12 +10,17 @@
 
 //! Implements virtio devices, queues, and transport mechanisms.
 
+extern crate anyhow;
 extern crate arc_swap;
 extern crate epoll;
+extern crate io_uring;
 #[macro_use]
 extern crate log;
 #[cfg(feature = "pci_support")]
 extern crate pci;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
 extern crate vhost_rs;
 extern crate virtio_bindings;
 extern crate vm_device;
@@ -28,11 +33,13 @@ use std::io;
 mod device;
 pub mod block;
 mod console;
+pub mod dirty_log;
 mod iommu;
 pub mod net;
 pub mod net_util;
 mod pmem;
 pub mod queue;
+pub mod rate_limiter;
 mod rng;
 pub mod vsock;
 
@@ -42,11 +49,13 @@ pub mod vhost_user;
 pub use self::block::*;
 pub use self::console::*;
 pub use self::device::*;
+pub use self::dirty_log::*;
 pub use self::iommu::*;
 pub use self::net::*;
 pub use self::net_util::*;
 pub use self::pmem::*;
 pub use self::queue::*;
+pub use self::rate_limiter::*;
 pub use self::rng::*;
 pub use self::vsock::*;
 
@@ -66,7 +75,7 @@ const VIRTIO_F_IN_ORDER: u32 = 35;
 #[allow(dead_code)]
 #[allow(non_camel_case_types)]
 #[repr(C)]
-enum VirtioDeviceType {
+pub enum VirtioDeviceType {
     TYPE_NET = 1,
     TYPE_BLOCK = 2,
     TYPE_CONSOLE = 3,
@@ -163,7 +172,6 @@ pub enum Error {
         event_type: &'static str,
         underlying: io::Error,
     },
-    FailedReadTap,
     FailedSignalingUsedQueue(io::Error),
     PayloadExpected,
     UnknownEvent {
@@ -7,37 +7,44 @@
 
 use super::net_util::{
     build_net_config_space, build_net_config_space_with_mq, open_tap, register_listener,
-    unregister_listener, CtrlVirtio, NetCtrlEpollHandler, RxVirtio, TxVirtio, VirtioNetConfig,
-    KILL_EVENT, NET_EVENTS_COUNT, PAUSE_EVENT, RX_QUEUE_EVENT, RX_TAP_EVENT, TX_QUEUE_EVENT,
+    unregister_listener, CtrlVirtio, NetCounters, NetCtrlEpollHandler, RxFilter, RxFilterState,
+    RxStatus, RxVirtio, TxVirtio, VirtioNetConfig, KILL_EVENT, NET_EVENTS_COUNT, PAUSE_EVENT,
+    RX_QUEUE_EVENT, RX_RATE_LIMITER_EVENT, RX_TAP_EVENT, TX_QUEUE_EVENT, TX_RATE_LIMITER_EVENT,
 };
 use super::Error as DeviceError;
 use super::{
-    ActivateError, ActivateResult, Queue, VirtioDevice, VirtioDeviceType, VirtioInterruptType,
+    ActivateError, ActivateResult, Queue, RateLimiter, VirtioDevice, VirtioDeviceType,
+    VirtioInterruptType,
 };
 use crate::VirtioInterrupt;
+use anyhow::anyhow;
 use epoll;
-use libc::EAGAIN;
 use libc::EFD_NONBLOCK;
-use net_util::{MacAddr, Tap};
+use net_util::{start_user_net, MacAddr, Tap, UserNetConfig, UserNetError};
 use std::cmp;
-use std::io::Read;
+use std::collections::BTreeMap;
 use std::io::{self, Write};
 use std::net::Ipv4Addr;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::result;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Duration;
 use std::vec::Vec;
 use virtio_bindings::bindings::virtio_net::*;
-use vm_device::{Migratable, MigratableError, Pausable, Snapshotable};
+use vm_device::{Migratable, MigratableError, Pausable, Snapshot, Snapshotable, VersionedState};
 use vm_memory::{ByteValued, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap};
-use vmm_sys_util::eventfd::EventFd;
+use vmm_sys_util::{eventfd::EventFd, timerfd::TimerFd};
+
+const NET_SNAPSHOT_ID: &str = "virtio-net";
 
 #[derive(Debug)]
 pub enum Error {
     /// Failed to open taps.
     OpenTap(super::net_util::Error),
+    /// Failed to start the userspace network stack.
+    StartUserNet(UserNetError),
 }
 
 pub type Result<T> = result::Result<T, Error>;
@@ -52,6 +59,8 @@ struct NetEpollHandler {
     pause_evt: EventFd,
     epoll_fd: RawFd,
     rx_tap_listening: bool,
+    rx_rate_limiter_timer: TimerFd,
+    tx_rate_limiter_timer: TimerFd,
 }
 
 impl NetEpollHandler {
@@ -64,56 +73,42 @@ impl NetEpollHandler {
             })
     }
 
-    // Copies a single frame from `self.rx.frame_buf` into the guest. Returns true
-    // if a buffer was used, and false if the frame must be deferred until a buffer
-    // is made available by the driver.
-    fn rx_single_frame(&mut self, mut queue: &mut Queue) -> bool {
-        let mem = self.mem.memory();
-        let next_desc = queue.iter(&mem).next();
-
-        if next_desc.is_none() {
-            // Queue has no available descriptors
-            if self.rx_tap_listening {
-                unregister_listener(
-                    self.epoll_fd,
-                    self.tap.as_raw_fd(),
-                    epoll::Events::EPOLLIN,
-                    u64::from(RX_TAP_EVENT),
-                )
-                .unwrap();
-                self.rx_tap_listening = false;
-            }
-            return false;
+    fn stop_rx_tap_listening(&mut self) {
+        if self.rx_tap_listening {
+            unregister_listener(
+                self.epoll_fd,
+                self.tap.as_raw_fd(),
+                epoll::Events::EPOLLIN,
+                u64::from(RX_TAP_EVENT),
+            )
+            .unwrap();
+            self.rx_tap_listening = false;
         }
+    }
+
+    // Stops reading from the tap until the rate limiter timer expires, as the
+    // frame that was just read can't be handed to the guest yet.
+    fn throttle_rx(&mut self, delay: Duration) {
+        self.stop_rx_tap_listening();
 
-        self.rx.process_desc_chain(&mem, next_desc, &mut queue)
+        match self.rx_rate_limiter_timer.reset(delay, None) {
+            Ok(()) => self.rx.throttled = true,
+            Err(e) => error!("Failed to arm the rx rate limiter timer: {:?}", e),
+        }
     }
 
-    fn process_rx(&mut self, queue: &mut Queue) -> result::Result<(), DeviceError> {
+    fn process_rx(&mut self, mut queue: &mut Queue) -> result::Result<(), DeviceError> {
+        let mem = self.mem.memory();
+
         // Read as many frames as possible.
-        loop {
-            match self.read_tap() {
-                Ok(count) => {
-                    self.rx.bytes_read = count;
-                    if !self.rx_single_frame(queue) {
-                        self.rx.deferred_frame = true;
-                        break;
-                    }
-                }
-                Err(e) => {
-                    // The tap device is non-blocking, so any error aside from EAGAIN is
-                    // unexpected.
-                    match e.raw_os_error() {
-                        Some(err) if err == EAGAIN => (),
-                        _ => {
-                            error!("Failed to read tap: {:?}", e);
-                            return Err(DeviceError::FailedReadTap);
-                        }
-                    };
-                    break;
-                }
-            }
+        match self.rx.process_desc_chain(&mem, &mut self.tap, &mut queue) {
+            // Stop listening to the tap until the driver makes buffers
+            // available.
+            RxStatus::QueueFull => self.stop_rx_tap_listening(),
+            RxStatus::Throttled(delay) => self.throttle_rx(delay),
+            RxStatus::TapEmpty => (),
         }
+
         if self.rx.deferred_irqs {
             self.rx.deferred_irqs = false;
             self.signal_used_queue(queue)
@@ -122,42 +117,42 @@ impl NetEpollHandler {
         }
     }
 
-    fn resume_rx(&mut self, queue: &mut Queue) -> result::Result<(), DeviceError> {
-        if self.rx.deferred_frame {
-            if self.rx_single_frame(queue) {
-                self.rx.deferred_frame = false;
-                // process_rx() was interrupted possibly before consuming all
-                // packets in the tap; try continuing now.
-                self.process_rx(queue)
-            } else if self.rx.deferred_irqs {
-                self.rx.deferred_irqs = false;
-                self.signal_used_queue(queue)
-            } else {
-                Ok(())
-            }
-        } else {
-            Ok(())
-        }
-    }
-
     fn process_tx(&mut self, mut queue: &mut Queue) -> result::Result<(), DeviceError> {
         let mem = self.mem.memory();
 
-        self.tx.process_desc_chain(&mem, &mut self.tap, &mut queue);
+        if let Some(delay) = self.tx.process_desc_chain(&mem, &mut self.tap, &mut queue) {
+            match self.tx_rate_limiter_timer.reset(delay, None) {
+                Ok(()) => self.tx.throttled = true,
+                Err(e) => error!("Failed to arm the tx rate limiter timer: {:?}", e),
+            }
+        }
 
         Ok(())
     }
 
-    fn read_tap(&mut self) -> io::Result<usize> {
-        self.tap.read(&mut self.rx.frame_buf)
-    }
-
-    fn handle_rx_event(&mut self, mut queue: &mut Queue, queue_evt: &EventFd) {
+    fn handle_rx_event(&mut self, queue: &mut Queue, queue_evt: &EventFd) {
+        queue.notified();
         if let Err(e) = queue_evt.read() {
             error!("Failed to get rx queue event: {:?}", e);
         }
 
-        self.resume_rx(&mut queue).unwrap();
+        // While throttled, frames wait for the rate limiter timer.
+        if !self.rx.throttled {
+            self.restart_rx(queue);
+        }
+    }
+
+    fn handle_rx_rate_limiter_event(&mut self, queue: &mut Queue) {
+        if let Err(e) = self.rx_rate_limiter_timer.wait() {
+            error!("Failed to get rx rate limiter event: {:?}", e);
+        }
+
+        self.rx.throttled = false;
+        self.restart_rx(queue);
+    }
+
+    // Listens to the tap again, and receives the frames left on it.
+    fn restart_rx(&mut self, queue: &mut Queue) {
         if !self.rx_tap_listening {
             register_listener(
                 self.epoll_fd,
@@ -168,31 +163,32 @@ impl NetEpollHandler {
             .unwrap();
             self.rx_tap_listening = true;
         }
+        self.process_rx(queue).unwrap();
     }
 
     fn handle_tx_event(&mut self, mut queue: &mut Queue, queue_evt: &EventFd) {
+        queue.notified();
         if let Err(e) = queue_evt.read() {
             error!("Failed to get tx queue event: {:?}", e);
         }
 
+        // While throttled, frames wait for the rate limiter timer.
+        if !self.tx.throttled {
+            self.process_tx(&mut queue).unwrap();
+        }
+    }
+
+    fn handle_tx_rate_limiter_event(&mut self, mut queue: &mut Queue) {
+        if let Err(e) = self.tx_rate_limiter_timer.wait() {
+            error!("Failed to get tx rate limiter event: {:?}", e);
+        }
+
+        self.tx.throttled = false;
         self.process_tx(&mut queue).unwrap();
     }
 
     fn handle_rx_tap_event(&mut self, mut queue: &mut Queue) {
-        if self.rx.deferred_frame
-        // Process a deferred frame first if available. Don't read from tap again
-        // until we manage to receive this deferred frame.
-        {
-            if self.rx_single_frame(&mut queue) {
-                self.rx.deferred_frame = false;
-                self.process_rx(&mut queue).unwrap();
-            } else if self.rx.deferred_irqs {
-                self.rx.deferred_irqs = false;
-                self.signal_used_queue(&queue).unwrap();
-            }
-        } else {
-            self.process_rx(&mut queue).unwrap();
-        }
+        self.process_rx(&mut queue).unwrap();
     }
 
     fn run(
@@ -233,6 +229,20 @@ impl NetEpollHandler {
             epoll::Event::new(epoll::Events::EPOLLIN, u64::from(PAUSE_EVENT)),
         )
         .map_err(DeviceError::EpollCtl)?;
+        epoll::ctl(
+            self.epoll_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            self.rx_rate_limiter_timer.as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, u64::from(RX_RATE_LIMITER_EVENT)),
+        )
+        .map_err(DeviceError::EpollCtl)?;
+        epoll::ctl(
+            self.epoll_fd,
+            epoll::ControlOptions::EPOLL_CTL_ADD,
+            self.tx_rate_limiter_timer.as_raw_fd(),
+            epoll::Event::new(epoll::Events::EPOLLIN, u64::from(TX_RATE_LIMITER_EVENT)),
+        )
+        .map_err(DeviceError::EpollCtl)?;
 
         let mut events = vec![epoll::Event::new(epoll::Events::empty(), 0); NET_EVENTS_COUNT];
 
@@ -267,6 +277,12 @@ impl NetEpollHandler {
                     RX_TAP_EVENT => {
                         self.handle_rx_tap_event(&mut queues[0]);
                     }
+                    RX_RATE_LIMITER_EVENT => {
+                        self.handle_rx_rate_limiter_event(&mut queues[0]);
+                    }
+                    TX_RATE_LIMITER_EVENT => {
+                        self.handle_tx_rate_limiter_event(&mut queues[1]);
+                    }
                     KILL_EVENT => {
                         debug!("KILL_EVENT received, stopping epoll loop");
                         break 'epoll;
@@ -275,6 +291,15 @@ impl NetEpollHandler {
                         // Drain pause event
                         let _ = self.pause_evt.read();
                         debug!("PAUSE_EVENT received, pausing virtio-net epoll loop");
+                        self.rx
+                            .complete_throttled_frame(&self.mem.memory(), &mut queues[0]);
+                        if self.rx.deferred_irqs {
+                            self.rx.deferred_irqs = false;
+                            if let Err(e) = self.signal_used_queue(&queues[0]) {
+                                error!("Failed to signal used queue: {:?}", e);
+                                break 'epoll;
+                            }
+                        }
                         // We loop here to handle spurious park() returns.
                         // Until we have not resumed, the paused boolean will
                         // be true.
@@ -305,6 +330,17 @@ pub struct Net {
     ctrl_queue_epoll_thread: Option<thread::JoinHandle<result::Result<(), DeviceError>>>,
     paused: Arc<AtomicBool>,
     queue_size: Vec<u16>,
+    counters: Arc<NetCounters>,
+    rx_rate_limiter: Arc<Mutex<RateLimiter>>,
+    tx_rate_limiter: Arc<Mutex<RateLimiter>>,
+    // Shared with the control queue, through which the driver updates it.
+    rx_filter: Arc<Mutex<RxFilter>>,
+    // Set when the filter has been restored from a snapshot, so that it isn't
+    // reset when activating the device.
+    rx_filter_restored: bool,
+    // Status field of the configuration space, shared with the control
+    // queue through which the driver acknowledges the announcements.
+    status: Arc<AtomicU16>,
 }
 
 impl Net {
@@ -328,7 +364,14 @@ impl Net {
             avail_features |= 1u64 << VIRTIO_F_IOMMU_PLATFORM;
         }
 
-        avail_features |= 1 << VIRTIO_NET_F_CTRL_VQ;
+        avail_features |= 1 << VIRTIO_NET_F_CTRL_VQ
+            | 1 << VIRTIO_NET_F_CTRL_VLAN
+            | 1 << VIRTIO_NET_F_STATUS
+            | 1 << VIRTIO_NET_F_GUEST_ANNOUNCE;
+        // Received frames can only be filtered based on a known MAC address.
+        if guest_mac.is_some() {
+            avail_features |= 1 << VIRTIO_NET_F_CTRL_RX | 1 << VIRTIO_NET_F_CTRL_MAC_ADDR;
+        }
         let queue_num = num_queues + 1;
 
         let mut config = VirtioNetConfig::default();
@@ -351,6 +394,14 @@ impl Net {
             ctrl_queue_epoll_thread: None,
             paused: Arc::new(AtomicBool::new(false)),
             queue_size: vec![queue_size; queue_num],
+            counters: Arc::new(NetCounters::default()),
+            rx_rate_limiter: Arc::new(Mutex::new(RateLimiter::default())),
+            tx_rate_limiter: Arc::new(Mutex::new(RateLimiter::default())),
+            rx_filter: Arc::new(Mutex::new(RxFilter::new(MacAddr::from_bytes_unchecked(
+                &config.mac,
+            )))),
+            rx_filter_restored: false,
+            status: Arc::new(AtomicU16::new(VIRTIO_NET_S_LINK_UP as u16)),
         })
     }
 
@@ -369,6 +420,124 @@ impl Net {
 
         Self::new_with_tap(taps, guest_mac, iommu, num_queues, queue_size)
     }
+
+    /// Create a new virtio network device backed by a userspace network
+    /// stack, which requires neither a tap interface nor any privilege.
+    pub fn new_user(config: UserNetConfig, iommu: bool, queue_size: u16) -> Result<Self> {
+        let guest_mac = config.guest_mac;
+        let tap = start_user_net(config).map_err(Error::StartUserNet)?;
+        let mut net = Self::new_with_tap(vec![tap], Some(guest_mac), iommu, 2, queue_size)?;
+
+        // The userspace stack only handles complete frames, with valid
+        // checksums, coming from the guest.
+        net.avail_features &=
+            !(1 << VIRTIO_NET_F_CSUM | 1 << VIRTIO_NET_F_HOST_TSO4 | 1 << VIRTIO_NET_F_HOST_UFO);
+
+        Ok(net)
+    }
+
+    /// Returns the rate limiter of the received traffic, shared by all the
+    /// queue pairs of the device, so that its limits can be updated at runtime.
+    pub fn rx_rate_limiter(&self) -> Arc<Mutex<RateLimiter>> {
+        self.rx_rate_limiter.clone()
+    }
+
+    /// Returns the rate limiter of the transmitted traffic, shared by all the
+    /// queue pairs of the device, so that its limits can be updated at runtime.
+    pub fn tx_rate_limiter(&self) -> Arc<Mutex<RateLimiter>> {
+        self.tx_rate_limiter.clone()
+    }
+
+    /// Sets the link state reported to the guest, and notifies it of the
+    /// change.
+    pub fn set_link_up(&self, up: bool) -> io::Result<()> {
+        let link_up = VIRTIO_NET_S_LINK_UP as u16;
+        if up {
+            self.status.fetch_or(link_up, Ordering::SeqCst);
+        } else {
+            self.status.fetch_and(!link_up, Ordering::SeqCst);
+        }
+
+        // Until the device is activated, the driver reads the status from
+        // the configuration space anyway.
+        if let Some(interrupt_cb) = &self.interrupt_cb {
+            interrupt_cb.trigger(&VirtioInterruptType::Config, None)?;
+        }
+
+        Ok(())
+    }
+
+    // Asks the driver to announce the guest addresses to the network, which
+    // may have forgotten them while the guest was paused or migrated.
+    fn announce(&self) -> io::Result<()> {
+        // Nothing to do if the device was reset, as the driver then
+        // announces the addresses again when it brings the link up.
+        if self.pause_evt.is_none() || (self.acked_features & 1 << VIRTIO_NET_F_GUEST_ANNOUNCE) == 0
+        {
+            return Ok(());
+        }
+
+        self.status
+            .fetch_or(VIRTIO_NET_S_ANNOUNCE as u16, Ordering::SeqCst);
+        if let Some(interrupt_cb) = &self.interrupt_cb {
+            interrupt_cb.trigger(&VirtioInterruptType::Config, None)?;
+        }
+
+        Ok(())
+    }
+
+    fn state(&self) -> NetState {
+        let mut config = self.config;
+        config.status = self.status.load(Ordering::SeqCst);
+
+        NetState {
+            avail_features: self.avail_features,
+            acked_features: self.acked_features,
+            config: config.as_slice().to_vec(),
+            // The filter is reset when the device gets activated, it only
+            // matters while the device is active.
+            rx_filter: self
+                .interrupt_cb
+                .as_ref()
+                .map(|_| self.rx_filter.lock().unwrap().state()),
+        }
+    }
+
+    fn set_state(&mut self, state: &NetState) -> result::Result<(), MigratableError> {
+        if state.config.len() != self.config.as_slice().len() {
+            return Err(MigratableError::Restore(anyhow!(
+                "Invalid virtio-net configuration space size"
+            )));
+        }
+
+        if let Some(rx_filter) = state.rx_filter.as_ref() {
+            self.rx_filter
+                .lock()
+                .unwrap()
+                .set_state(rx_filter)
+                .map_err(|e| MigratableError::Restore(anyhow!("{:?}", e)))?;
+        }
+        self.rx_filter_restored = state.rx_filter.is_some();
+
+        self.avail_features = state.avail_features;
+        self.acked_features = state.acked_features;
+        self.config.as_mut_slice().copy_from_slice(&state.config);
+        self.status.store(self.config.status, Ordering::SeqCst);
+
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct NetState {
+    pub avail_features: u64,
+    pub acked_features: u64,
+    pub config: Vec<u8>,
+    pub rx_filter: Option<RxFilterState>,
+}
+
+impl VersionedState for NetState {
+    const VERSION: u16 = 2;
 }
 
 impl Drop for Net {
@@ -406,7 +575,14 @@ impl VirtioDevice for Net {
     }
 
     fn read_config(&self, offset: u64, mut data: &mut [u8]) {
-        let config_slice = self.config.as_slice();
+        // The driver may have changed the MAC address through the control
+        // queue.
+        let mut config = self.config;
+        config
+            .mac
+            .copy_from_slice(self.rx_filter.lock().unwrap().mac().get_bytes());
+        config.status = self.status.load(Ordering::SeqCst);
+        let config_slice = config.as_slice();
         let config_len = config_slice.len() as u64;
         if offset >= config_len {
             error!("Failed to read config space");
@@ -420,7 +596,12 @@ impl VirtioDevice for Net {
     }
 
     fn write_config(&mut self, offset: u64, data: &[u8]) {
-        let config_slice = self.config.as_mut_slice();
+        // Only the MAC address is writable, by legacy drivers. It is reset to
+        // the configured one along with the device.
+        let mut rx_filter = self.rx_filter.lock().unwrap();
+        let mut config = self.config;
+        config.mac.copy_from_slice(rx_filter.mac().get_bytes());
+        let config_slice = config.as_mut_slice();
         let data_len = data.len() as u64;
         let config_len = config_slice.len() as u64;
         if offset + data_len > config_len {
@@ -428,7 +609,8 @@ impl VirtioDevice for Net {
             return;
         }
         let (_, right) = config_slice.split_at_mut(offset as usize);
-        right.copy_from_slice(&data[..]);
+        right[..data.len()].copy_from_slice(&data[..]);
+        rx_filter.set_mac(MacAddr::from_bytes_unchecked(&config.mac));
     }
 
     fn activate(
@@ -479,16 +661,31 @@ impl VirtioDevice for Net {
             }
             self.queue_evts = Some(tmp_queue_evts);
 
+            self.status
+                .fetch_and(!(VIRTIO_NET_S_ANNOUNCE as u16), Ordering::SeqCst);
+            // The driver doesn't set the filter again once restored.
+            if self.rx_filter_restored {
+                self.rx_filter_restored = false;
+            } else {
+                self.rx_filter.lock().unwrap().reset(
+                    MacAddr::from_bytes_unchecked(&self.config.mac),
+                    (self.acked_features & 1 << VIRTIO_NET_F_CTRL_VLAN) != 0,
+                );
+            }
+
             let queue_num = queues.len();
             if (self.acked_features & 1 << VIRTIO_NET_F_CTRL_VQ) != 0 && queue_num % 2 != 0 {
                 let cvq_queue = queues.remove(queue_num - 1);
                 let cvq_queue_evt = queue_evts.remove(queue_num - 1);
+                let mut ctrl_q = CtrlVirtio::new(cvq_queue, cvq_queue_evt);
+                ctrl_q.rx_filter = self.rx_filter.clone();
+                ctrl_q.config_status = self.status.clone();
 
                 let mut ctrl_handler = NetCtrlEpollHandler {
                     mem: mem.clone(),
                     kill_evt: kill_evt.try_clone().unwrap(),
                     pause_evt: pause_evt.try_clone().unwrap(),
-                    ctrl_q: CtrlVirtio::new(cvq_queue, cvq_queue_evt),
+                    ctrl_q,
                     epoll_fd: 0,
                 };
 
@@ -505,10 +702,22 @@ impl VirtioDevice for Net {
 
             let mut epoll_threads = Vec::new();
             for _ in 0..taps.len() {
-                let rx = RxVirtio::new();
-                let tx = TxVirtio::new();
+                let mut rx = RxVirtio::new();
+                rx.counters = self.counters.clone();
+                rx.rate_limiter = self.rx_rate_limiter.clone();
+                rx.rx_filter = self.rx_filter.clone();
+                let mut tx = TxVirtio::new();
+                tx.counters = self.counters.clone();
+                tx.rate_limiter = self.tx_rate_limiter.clone();
                 let rx_tap_listening = false;
 
+                let (rx_rate_limiter_timer, tx_rate_limiter_timer) = TimerFd::new()
+                    .and_then(|t| Ok((t, TimerFd::new()?)))
+                    .map_err(|e| {
+                        error!("failed creating rate limiter TimerFd: {}", e);
+                        ActivateError::BadActivate
+                    })?;
+
                 let mut queue_pair = Vec::new();
                 queue_pair.push(queues.remove(0));
                 queue_pair.push(queues.remove(0));
@@ -527,6 +736,8 @@ impl VirtioDevice for Net {
                     pause_evt: pause_evt.try_clone().unwrap(),
                     epoll_fd: 0,
                     rx_tap_listening,
+                    rx_rate_limiter_timer,
+                    tx_rate_limiter_timer,
                 };
 
                 let paused = self.paused.clone();
@@ -564,8 +775,57 @@ impl VirtioDevice for Net {
             self.queue_evts.take().unwrap(),
         ))
     }
+
+    fn counters(&self) -> Option<BTreeMap<&'static str, u64>> {
+        let mut counters = BTreeMap::new();
+
+        counters.insert("rx_bytes", self.counters.rx_bytes.load(Ordering::Relaxed));
+        counters.insert("rx_frames", self.counters.rx_frames.load(Ordering::Relaxed));
+        counters.insert(
+            "rx_dropped",
+            self.counters.rx_dropped.load(Ordering::Relaxed),
+        );
+        counters.insert("tx_bytes", self.counters.tx_bytes.load(Ordering::Relaxed));
+        counters.insert("tx_frames", self.counters.tx_frames.load(Ordering::Relaxed));
+        counters.insert(
+            "tx_dropped",
+            self.counters.tx_dropped.load(Ordering::Relaxed),
+        );
+
+        Some(counters)
+    }
 }
 
-virtio_ctrl_q_pausable!(Net);
-impl Snapshotable for Net {}
+virtio_pausable_trait!(Net);
+
+impl Pausable for Net {
+    fn pause(&mut self) -> result::Result<(), MigratableError> {
+        self.virtio_pause()
+    }
+
+    fn resume(&mut self) -> result::Result<(), MigratableError> {
+        self.virtio_resume()?;
+
+        if let Some(ctrl_queue_epoll_thread) = &self.ctrl_queue_epoll_thread {
+            ctrl_queue_epoll_thread.thread().unpark();
+        }
+
+        self.announce().map_err(|e| {
+            MigratableError::Resume(anyhow!("Failed to request a guest announce: {:?}", e))
+        })
+    }
+}
+impl Snapshotable for Net {
+    fn id(&self) -> String {
+        NET_SNAPSHOT_ID.to_string()
+    }
+
+    fn snapshot(&self) -> result::Result<Snapshot, MigratableError> {
+        Snapshot::new_from_state(&self.id(), &self.state())
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> result::Result<(), MigratableError> {
+        self.set_state(&snapshot.to_state(&self.id())?)
+    }
+}
 impl Migratable for Net {}
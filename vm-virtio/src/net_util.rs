@@ -3,18 +3,20 @@
 // SPDX-License-Identifier: Apache-2.0 AND BSD-3-Clause
 
 use super::Error as DeviceError;
-use super::{DescriptorChain, DeviceEventT, Queue};
-use net_util::{MacAddr, Tap, TapError};
+use super::{DescriptorChain, DeviceEventT, Queue, RateLimiter};
+use net_util::{MacAddr, Tap, TapError, MAC_ADDR_LEN};
 use std::cmp;
 use std::fs;
-use std::io::{self, Write};
+use std::io;
 use std::mem;
 use std::net::Ipv4Addr;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::path::Path;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use virtio_bindings::bindings::virtio_net::*;
+use vm_device::get_host_address_range;
 use vm_memory::{
     ByteValued, Bytes, GuestAddress, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryError,
     GuestMemoryMmap,
@@ -23,11 +25,14 @@ use vmm_sys_util::eventfd::EventFd;
 
 type Result<T> = std::result::Result<T, Error>;
 
-/// The maximum buffer size when segmentation offload is enabled. This
-/// includes the 12-byte virtio net header.
-/// http://docs.oasis-open.org/virtio/virtio/v1.0/virtio-v1.0.html#x1-1740003
-const MAX_BUFFER_SIZE: usize = 65562;
-const QUEUE_SIZE: usize = 256;
+// Maximum number of addresses in each of the unicast and multicast MAC
+// address tables set through the control queue.
+const MAC_TABLE_LEN: usize = 64;
+// Maximum size of a command sent through the control queue.
+const MAX_CTRL_COMMAND_SIZE: usize = 4096;
+const MAX_VLAN: usize = 4096;
+const ETH_HDR_LEN: usize = 14;
+const ETH_P_8021Q: u16 = 0x8100;
 
 // The guest has made a buffer available to receive a frame into.
 pub const RX_QUEUE_EVENT: DeviceEventT = 0;
@@ -39,8 +44,12 @@ pub const RX_TAP_EVENT: DeviceEventT = 2;
 pub const KILL_EVENT: DeviceEventT = 3;
 // The device should be paused.
 pub const PAUSE_EVENT: DeviceEventT = 4;
+// Throttled received frames can be retried.
+pub const RX_RATE_LIMITER_EVENT: DeviceEventT = 5;
+// Throttled transmitted frames can be retried.
+pub const TX_RATE_LIMITER_EVENT: DeviceEventT = 6;
 // Number of DeviceEventT events supported by this implementation.
-pub const NET_EVENTS_COUNT: usize = 5;
+pub const NET_EVENTS_COUNT: usize = 7;
 // The device has been dropped.
 const CTRL_QUEUE_EVENT: DeviceEventT = 0;
 // Number of DeviceEventT events supported by this implementation.
@@ -72,10 +81,14 @@ pub enum Error {
     InvalidCtlClass,
     /// Invalid ctrl command
     InvalidCtlCmd,
+    /// Invalid ctrl command data
+    InvalidCtlData,
     /// Invalid descriptor
     InvalidDesc,
     /// Invalid queue pairs number
     InvalidQueuePairsNum,
+    /// Invalid receive filter state
+    InvalidRxFilterState,
     /// Error related to the multiqueue support.
     MultiQueueSupport(String),
     /// No memory passed in.
@@ -98,9 +111,180 @@ pub enum Error {
     TapEnable(TapError),
 }
 
+/// Filters the received frames according to the receive mode, MAC address
+/// tables and VLAN table set by the driver through the control queue.
+#[derive(Clone, Debug)]
+pub struct RxFilter {
+    mac: MacAddr,
+    promisc: bool,
+    allmulti: bool,
+    uni_macs: Vec<MacAddr>,
+    multi_macs: Vec<MacAddr>,
+    // Set when the driver passed more addresses than the tables can hold,
+    // in which case all the frames of that kind are received.
+    uni_overflow: bool,
+    multi_overflow: bool,
+    // One bit per VLAN id.
+    vlans: Vec<u32>,
+}
+
+impl Default for RxFilter {
+    fn default() -> Self {
+        Self::new(MacAddr::from_bytes_unchecked(&[0; MAC_ADDR_LEN]))
+    }
+}
+
+impl RxFilter {
+    /// Creates a filter receiving every frame, as a device does until the
+    /// driver sets its receive mode.
+    pub fn new(mac: MacAddr) -> Self {
+        RxFilter {
+            mac,
+            promisc: true,
+            allmulti: false,
+            uni_macs: Vec::new(),
+            multi_macs: Vec::new(),
+            uni_overflow: false,
+            multi_overflow: false,
+            vlans: vec![!0; MAX_VLAN / 32],
+        }
+    }
+
+    /// Restores the state of a freshly reset device. Tagged frames are only
+    /// received once their VLAN has been added if `vlan_filtering` is set.
+    pub fn reset(&mut self, mac: MacAddr, vlan_filtering: bool) {
+        *self = RxFilter::new(mac);
+        if vlan_filtering {
+            self.vlans = vec![0; MAX_VLAN / 32];
+        }
+    }
+
+    /// MAC address of the device, which the driver may change at runtime.
+    pub fn mac(&self) -> MacAddr {
+        self.mac
+    }
+
+    pub fn set_mac(&mut self, mac: MacAddr) {
+        self.mac = mac;
+    }
+
+    /// Returns whether a frame, starting with its Ethernet header, must be
+    /// received by the guest.
+    pub fn accepts(&self, frame: &[u8]) -> bool {
+        if self.promisc || frame.len() < ETH_HDR_LEN {
+            return true;
+        }
+
+        if frame[12..14] == ETH_P_8021Q.to_be_bytes() && frame.len() >= ETH_HDR_LEN + 2 {
+            let vid = (u16::from_be_bytes([frame[14], frame[15]]) & 0xfff) as usize;
+            if self.vlans[vid / 32] & (1 << (vid % 32)) == 0 {
+                return false;
+            }
+        }
+
+        let dest = MacAddr::from_bytes_unchecked(&frame[..MAC_ADDR_LEN]);
+        if frame[0] & 1 == 0 {
+            self.uni_overflow || dest == self.mac || self.uni_macs.contains(&dest)
+        } else {
+            frame[..MAC_ADDR_LEN] == [0xff; MAC_ADDR_LEN]
+                || self.allmulti
+                || self.multi_overflow
+                || self.multi_macs.contains(&dest)
+        }
+    }
+
+    fn set_vlan(&mut self, vid: usize, enabled: bool) {
+        if enabled {
+            self.vlans[vid / 32] |= 1 << (vid % 32);
+        } else {
+            self.vlans[vid / 32] &= !(1 << (vid % 32));
+        }
+    }
+
+    pub fn state(&self) -> RxFilterState {
+        RxFilterState {
+            mac: self.mac,
+            promisc: self.promisc,
+            allmulti: self.allmulti,
+            uni_macs: self.uni_macs.clone(),
+            multi_macs: self.multi_macs.clone(),
+            uni_overflow: self.uni_overflow,
+            multi_overflow: self.multi_overflow,
+            vlans: self.vlans.clone(),
+        }
+    }
+
+    pub fn set_state(&mut self, state: &RxFilterState) -> Result<()> {
+        if state.uni_macs.len() > MAC_TABLE_LEN
+            || state.multi_macs.len() > MAC_TABLE_LEN
+            || state.vlans.len() != MAX_VLAN / 32
+        {
+            return Err(Error::InvalidRxFilterState);
+        }
+
+        self.mac = state.mac;
+        self.promisc = state.promisc;
+        self.allmulti = state.allmulti;
+        self.uni_macs = state.uni_macs.clone();
+        self.multi_macs = state.multi_macs.clone();
+        self.uni_overflow = state.uni_overflow;
+        self.multi_overflow = state.multi_overflow;
+        self.vlans = state.vlans.clone();
+
+        Ok(())
+    }
+}
+
+/// The receive filter state, as saved in a device snapshot.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct RxFilterState {
+    mac: MacAddr,
+    promisc: bool,
+    allmulti: bool,
+    uni_macs: Vec<MacAddr>,
+    multi_macs: Vec<MacAddr>,
+    uni_overflow: bool,
+    multi_overflow: bool,
+    vlans: Vec<u32>,
+}
+
+// Splits a `virtio_net_ctrl_mac` table, made of a 32 bits number of entries
+// followed by the MAC addresses, from the beginning of `data`. Returns the
+// addresses, unless there are more than the table can hold, and the data
+// following the table.
+fn parse_mac_table(data: &[u8]) -> Result<(Option<Vec<MacAddr>>, &[u8])> {
+    if data.len() < 4 {
+        return Err(Error::InvalidCtlData);
+    }
+    let entries = u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize;
+    let table_len = entries.saturating_mul(MAC_ADDR_LEN);
+    if data.len() - 4 < table_len {
+        return Err(Error::InvalidCtlData);
+    }
+    let (table, rest) = data[4..].split_at(table_len);
+
+    if entries > MAC_TABLE_LEN {
+        return Ok((None, rest));
+    }
+
+    Ok((
+        Some(
+            table
+                .chunks(MAC_ADDR_LEN)
+                .map(MacAddr::from_bytes_unchecked)
+                .collect(),
+        ),
+        rest,
+    ))
+}
+
 pub struct CtrlVirtio {
     pub queue_evt: EventFd,
     pub queue: Queue,
+    pub rx_filter: Arc<Mutex<RxFilter>>,
+    // Status field of the configuration space, in which the driver
+    // acknowledges the announcements.
+    pub config_status: Arc<AtomicU16>,
 }
 
 impl std::clone::Clone for CtrlVirtio {
@@ -108,68 +292,188 @@ impl std::clone::Clone for CtrlVirtio {
         CtrlVirtio {
             queue_evt: self.queue_evt.try_clone().unwrap(),
             queue: self.queue.clone(),
+            rx_filter: self.rx_filter.clone(),
+            config_status: self.config_status.clone(),
         }
     }
 }
 
 impl CtrlVirtio {
     pub fn new(queue: Queue, queue_evt: EventFd) -> Self {
-        CtrlVirtio { queue_evt, queue }
+        CtrlVirtio {
+            queue_evt,
+            queue,
+            rx_filter: Arc::new(Mutex::new(RxFilter::default())),
+            config_status: Arc::new(AtomicU16::new(0)),
+        }
     }
 
-    fn process_mq(&self, mem: &GuestMemoryMmap, avail_desc: DescriptorChain) -> Result<()> {
-        let mq_desc = if avail_desc.has_next() {
-            avail_desc.next_descriptor().unwrap()
-        } else {
+    fn process_mq(&self, cmd: u8, data: &[u8]) -> Result<()> {
+        if u32::from(cmd) != VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET {
+            return Err(Error::InvalidCtlCmd);
+        }
+        if data.len() < 2 {
             return Err(Error::NoQueuePairsNum);
-        };
-        let queue_pairs = mem
-            .read_obj::<u16>(mq_desc.addr)
-            .map_err(Error::GuestMemory)?;
+        }
+        let queue_pairs = u16::from_le_bytes([data[0], data[1]]);
         if (queue_pairs < VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN as u16)
             || (queue_pairs > VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MAX as u16)
         {
             return Err(Error::InvalidQueuePairsNum);
         }
-        let status_desc = if mq_desc.has_next() {
-            mq_desc.next_descriptor().unwrap()
-        } else {
-            return Err(Error::NoQueuePairsNum);
-        };
-        mem.write_obj::<u8>(0, status_desc.addr)
-            .map_err(Error::GuestMemory)?;
 
         Ok(())
     }
 
-    pub fn process_cvq(&mut self, mem: &GuestMemoryMmap) -> Result<()> {
-        let mut used_desc_heads = [(0, 0); QUEUE_SIZE];
-        let mut used_count = 0;
-        if let Some(avail_desc) = self.queue.iter(&mem).next() {
-            used_desc_heads[used_count] = (avail_desc.index, avail_desc.len);
-            used_count += 1;
-            let ctrl_hdr = mem
-                .read_obj::<u16>(avail_desc.addr)
+    fn process_rx_mode(&self, cmd: u8, data: &[u8]) -> Result<()> {
+        let on = *data.first().ok_or(Error::InvalidCtlData)? != 0;
+        let mut rx_filter = self.rx_filter.lock().unwrap();
+        match u32::from(cmd) {
+            VIRTIO_NET_CTRL_RX_PROMISC => rx_filter.promisc = on,
+            VIRTIO_NET_CTRL_RX_ALLMULTI => rx_filter.allmulti = on,
+            _ => return Err(Error::InvalidCtlCmd),
+        }
+
+        Ok(())
+    }
+
+    fn process_mac(&self, cmd: u8, data: &[u8]) -> Result<()> {
+        match u32::from(cmd) {
+            VIRTIO_NET_CTRL_MAC_TABLE_SET => {
+                let (uni_macs, data) = parse_mac_table(data)?;
+                let (multi_macs, _) = parse_mac_table(data)?;
+
+                let mut rx_filter = self.rx_filter.lock().unwrap();
+                rx_filter.uni_overflow = uni_macs.is_none();
+                rx_filter.uni_macs = uni_macs.unwrap_or_default();
+                rx_filter.multi_overflow = multi_macs.is_none();
+                rx_filter.multi_macs = multi_macs.unwrap_or_default();
+            }
+            VIRTIO_NET_CTRL_MAC_ADDR_SET => {
+                let mac =
+                    MacAddr::from_bytes(data.get(..MAC_ADDR_LEN).ok_or(Error::InvalidCtlData)?)
+                        .map_err(|_| Error::InvalidCtlData)?;
+                self.rx_filter.lock().unwrap().set_mac(mac);
+            }
+            _ => return Err(Error::InvalidCtlCmd),
+        }
+
+        Ok(())
+    }
+
+    fn process_vlan(&self, cmd: u8, data: &[u8]) -> Result<()> {
+        if data.len() < 2 {
+            return Err(Error::InvalidCtlData);
+        }
+        let vid = u16::from_le_bytes([data[0], data[1]]) as usize;
+        if vid >= MAX_VLAN {
+            return Err(Error::InvalidCtlData);
+        }
+
+        let mut rx_filter = self.rx_filter.lock().unwrap();
+        match u32::from(cmd) {
+            VIRTIO_NET_CTRL_VLAN_ADD => rx_filter.set_vlan(vid, true),
+            VIRTIO_NET_CTRL_VLAN_DEL => rx_filter.set_vlan(vid, false),
+            _ => return Err(Error::InvalidCtlCmd),
+        }
+
+        Ok(())
+    }
+
+    fn process_announce(&self, cmd: u8) -> Result<()> {
+        if u32::from(cmd) != VIRTIO_NET_CTRL_ANNOUNCE_ACK {
+            return Err(Error::InvalidCtlCmd);
+        }
+        self.config_status
+            .fetch_and(!(VIRTIO_NET_S_ANNOUNCE as u16), Ordering::SeqCst);
+
+        Ok(())
+    }
+
+    // Reads the command from the device readable descriptors of a chain, and
+    // returns it along with the address the status must be written to.
+    fn read_command(
+        mem: &GuestMemoryMmap,
+        desc_chain: DescriptorChain,
+    ) -> Result<(Vec<u8>, GuestAddress)> {
+        let mut command = Vec::new();
+        let mut next_desc = Some(desc_chain);
+
+        while let Some(desc) = next_desc {
+            if desc.is_write_only() {
+                return Ok((command, desc.addr));
+            }
+            let offset = command.len();
+            if offset + desc.len as usize > MAX_CTRL_COMMAND_SIZE {
+                return Err(Error::InvalidDesc);
+            }
+            command.resize(offset + desc.len as usize, 0);
+            mem.read_slice(&mut command[offset..], desc.addr)
                 .map_err(Error::GuestMemory)?;
-            let ctrl_hdr_v = ctrl_hdr.as_slice();
-            let class = ctrl_hdr_v[0];
-            let cmd = ctrl_hdr_v[1];
-            match u32::from(class) {
-                VIRTIO_NET_CTRL_MQ => {
-                    if u32::from(cmd) != VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET {
-                        return Err(Error::InvalidCtlCmd);
-                    }
-                    if let Err(_e) = self.process_mq(&mem, avail_desc) {
-                        return Err(Error::FailedProcessMQ);
-                    }
-                }
-                _ => return Err(Error::InvalidCtlClass),
+            next_desc = desc.next_descriptor();
+        }
+
+        Err(Error::InvalidDesc)
+    }
+
+    // Returns the address of the status the device writes to, which is the
+    // first device writable descriptor of the chain.
+    fn status_addr(desc_chain: &DescriptorChain) -> Option<GuestAddress> {
+        let mut next_desc = Some(desc_chain.clone());
+        while let Some(desc) = next_desc {
+            if desc.is_write_only() {
+                return Some(desc.addr);
             }
-        } else {
-            return Err(Error::InvalidDesc);
+            next_desc = desc.next_descriptor();
         }
-        for &(desc_index, len) in &used_desc_heads[..used_count] {
-            self.queue.add_used(&mem, desc_index, len);
+        None
+    }
+
+    fn process_command(&self, command: &[u8]) -> Result<()> {
+        if command.len() < 2 {
+            return Err(Error::InvalidCtlData);
+        }
+        let (class, cmd, data) = (command[0], command[1], &command[2..]);
+
+        match u32::from(class) {
+            VIRTIO_NET_CTRL_RX => self.process_rx_mode(cmd, data),
+            VIRTIO_NET_CTRL_MAC => self.process_mac(cmd, data),
+            VIRTIO_NET_CTRL_VLAN => self.process_vlan(cmd, data),
+            VIRTIO_NET_CTRL_MQ => self.process_mq(cmd, data),
+            VIRTIO_NET_CTRL_ANNOUNCE => self.process_announce(cmd),
+            _ => Err(Error::InvalidCtlClass),
+        }
+    }
+
+    pub fn process_cvq(&mut self, mem: &GuestMemoryMmap) -> Result<()> {
+        while let Some(avail_desc) = self.queue.iter(&mem).next() {
+            let head_index = avail_desc.index;
+            // A malformed command only fails on its own, and the driver is
+            // told about it whenever the status can be written.
+            let (command, status_addr) = match Self::read_command(mem, avail_desc.clone()) {
+                Ok(command) => command,
+                Err(e) => {
+                    error!("Failed to read control queue command: {:?}", e);
+                    let len = match Self::status_addr(&avail_desc) {
+                        Some(addr) if mem.write_obj(VIRTIO_NET_ERR as u8, addr).is_ok() => 1,
+                        _ => 0,
+                    };
+                    self.queue.add_used(&mem, head_index, len);
+                    continue;
+                }
+            };
+
+            // A command the device doesn't support is reported to the driver.
+            let status = match self.process_command(&command) {
+                Ok(()) => VIRTIO_NET_OK,
+                Err(e) => {
+                    error!("Failed to process control queue command: {:?}", e);
+                    VIRTIO_NET_ERR
+                }
+            };
+            mem.write_obj(status as u8, status_addr)
+                .map_err(Error::GuestMemory)?;
+            self.queue.add_used(&mem, head_index, 1);
         }
 
         Ok(())
@@ -257,6 +561,7 @@ impl NetCtrlEpollHandler {
 
                 match ev_type {
                     CTRL_QUEUE_EVENT => {
+                        self.ctrl_q.queue.notified();
                         let mem = self.mem.memory();
                         if let Err(e) = self.ctrl_q.queue_evt.read() {
                             error!("failed to get ctl queue event: {:?}", e);
@@ -290,10 +595,85 @@ impl NetCtrlEpollHandler {
     }
 }
 
+/// Network traffic statistics, shared by all the queue pairs of a device.
+#[derive(Default)]
+pub struct NetCounters {
+    pub rx_bytes: AtomicU64,
+    pub rx_frames: AtomicU64,
+    pub rx_dropped: AtomicU64,
+    pub tx_bytes: AtomicU64,
+    pub tx_frames: AtomicU64,
+    pub tx_dropped: AtomicU64,
+}
+
+// Maps the buffers of a descriptor chain the device reads from, or writes to
+// when `write_only` is set, so that a frame can be transferred between the
+// guest and the tap without being copied. Returns None if the chain has no
+// such buffer, or if one of them isn't backed by guest memory.
+fn desc_chain_iovecs(
+    mem: &GuestMemoryMmap,
+    desc_chain: DescriptorChain,
+    write_only: bool,
+) -> Option<(Vec<libc::iovec>, usize)> {
+    let mut iovecs = Vec::new();
+    let mut len = 0;
+    let mut next_desc = Some(desc_chain);
+
+    while let Some(desc) = next_desc {
+        if desc.is_write_only() != write_only {
+            break;
+        }
+        let addr = get_host_address_range(mem, desc.addr, desc.len as usize)?;
+        iovecs.push(libc::iovec {
+            iov_base: addr as *mut libc::c_void,
+            iov_len: desc.len as usize,
+        });
+        len += desc.len as usize;
+        next_desc = desc.next_descriptor();
+    }
+
+    if iovecs.is_empty() {
+        None
+    } else {
+        Some((iovecs, len))
+    }
+}
+
+// Copies the beginning of the data held by the buffers described by `iovecs`
+// into `buf`, returning the number of bytes copied.
+//
+// Safe as long as every iovec points to memory valid for reads of its length.
+unsafe fn read_iovecs(iovecs: &[libc::iovec], buf: &mut [u8]) -> usize {
+    let mut count = 0;
+    for iovec in iovecs {
+        let len = cmp::min(iovec.iov_len, buf.len() - count);
+        std::ptr::copy_nonoverlapping(iovec.iov_base as *const u8, buf[count..].as_mut_ptr(), len);
+        count += len;
+        if count == buf.len() {
+            break;
+        }
+    }
+
+    count
+}
+
+// Accounts for a frame of `bytes` bytes against the rate limiter. Returns the
+// delay before retrying if the frame must be deferred.
+fn rate_limit(rate_limiter: &Mutex<RateLimiter>, bytes: usize) -> Option<Duration> {
+    let mut rate_limiter = rate_limiter.lock().unwrap();
+    if rate_limiter.consume(bytes as u64) {
+        None
+    } else {
+        Some(rate_limiter.refill_delay(bytes as u64))
+    }
+}
+
 #[derive(Clone)]
 pub struct TxVirtio {
-    pub iovec: Vec<(GuestAddress, usize)>,
-    pub frame_buf: [u8; MAX_BUFFER_SIZE],
+    pub counters: Arc<NetCounters>,
+    pub rate_limiter: Arc<Mutex<RateLimiter>>,
+    // Frames are left on the queue until the rate limiter timer expires.
+    pub throttled: bool,
 }
 
 impl Default for TxVirtio {
@@ -305,66 +685,79 @@ impl Default for TxVirtio {
 impl TxVirtio {
     pub fn new() -> Self {
         TxVirtio {
-            iovec: Vec::new(),
-            frame_buf: [0u8; MAX_BUFFER_SIZE],
+            counters: Arc::new(NetCounters::default()),
+            rate_limiter: Arc::new(Mutex::new(RateLimiter::default())),
+            throttled: false,
         }
     }
 
-    pub fn process_desc_chain(&mut self, mem: &GuestMemoryMmap, tap: &mut Tap, queue: &mut Queue) {
+    /// Sends the frames available on the queue to the tap. If the rate
+    /// limiter runs out of tokens, the remaining frames are left on the queue
+    /// and the delay before retrying them is returned.
+    pub fn process_desc_chain(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        tap: &mut Tap,
+        queue: &mut Queue,
+    ) -> Option<Duration> {
         while let Some(avail_desc) = queue.iter(&mem).next() {
             let head_index = avail_desc.index;
-            let mut read_count = 0;
-            let mut next_desc = Some(avail_desc);
+            let frame = desc_chain_iovecs(mem, avail_desc, false);
+            let len = frame.as_ref().map_or(0, |(_, len)| *len);
 
-            self.iovec.clear();
-            while let Some(desc) = next_desc {
-                if desc.is_write_only() {
-                    break;
-                }
-                self.iovec.push((desc.addr, desc.len as usize));
-                read_count += desc.len as usize;
-                next_desc = desc.next_descriptor();
+            if let Some(delay) = rate_limit(&self.rate_limiter, len) {
+                queue.go_to_previous_position();
+                return Some(delay);
             }
 
-            read_count = 0;
-            // Copy buffer from across multiple descriptors.
-            // TODO(performance - Issue #420): change this to use `writev()` instead of `write()`
-            // and get rid of the intermediate buffer.
-            for (desc_addr, desc_len) in self.iovec.drain(..) {
-                let limit = cmp::min((read_count + desc_len) as usize, self.frame_buf.len());
-
-                let read_result =
-                    mem.read_slice(&mut self.frame_buf[read_count..limit as usize], desc_addr);
-                match read_result {
-                    Ok(_) => {
-                        // Increment by number of bytes actually read
-                        read_count += limit - read_count;
-                    }
-                    Err(e) => {
-                        println!("Failed to read slice: {:?}", e);
-                        break;
-                    }
+            // Safe because the iovecs point to guest memory, which stays
+            // mapped for as long as `mem` is held.
+            match frame.map(|(iovecs, _)| unsafe { tap.writev(&iovecs) }) {
+                Some(Ok(_)) => {
+                    self.counters
+                        .tx_bytes
+                        .fetch_add(len as u64, Ordering::Relaxed);
+                    self.counters.tx_frames.fetch_add(1, Ordering::Relaxed);
                 }
-            }
-
-            let write_result = tap.write(&self.frame_buf[..read_count]);
-            match write_result {
-                Ok(_) => {}
-                Err(e) => {
+                Some(Err(e)) => {
                     println!("net: tx: error failed to write to tap: {}", e);
+                    self.counters.tx_dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                None => {
+                    error!("net: tx: invalid descriptor chain");
+                    self.counters.tx_dropped.fetch_add(1, Ordering::Relaxed);
                 }
             };
             queue.add_used(&mem, head_index, 0);
         }
+
+        None
     }
 }
 
+/// Reason why `RxVirtio::process_desc_chain` stopped receiving frames.
+#[derive(Debug, PartialEq)]
+pub enum RxStatus {
+    /// There are no more frames to read from the tap.
+    TapEmpty,
+    /// The guest hasn't made any buffer available for the next frame.
+    QueueFull,
+    /// The rate limiter ran out of tokens, receiving can resume after the
+    /// given delay.
+    Throttled(Duration),
+}
+
 #[derive(Clone)]
 pub struct RxVirtio {
-    pub deferred_frame: bool,
     pub deferred_irqs: bool,
-    pub bytes_read: usize,
-    pub frame_buf: [u8; MAX_BUFFER_SIZE],
+    pub counters: Arc<NetCounters>,
+    pub rate_limiter: Arc<Mutex<RateLimiter>>,
+    // Frames are left on the tap until the rate limiter timer expires.
+    pub throttled: bool,
+    pub rx_filter: Arc<Mutex<RxFilter>>,
+    // Head index and length of a frame already read into the guest buffers,
+    // which is only returned to the guest once the rate limiter allows it.
+    throttled_frame: Option<(u16, usize)>,
 }
 
 impl Default for RxVirtio {
@@ -376,61 +769,143 @@ impl Default for RxVirtio {
 impl RxVirtio {
     pub fn new() -> Self {
         RxVirtio {
-            deferred_frame: false,
             deferred_irqs: false,
-            bytes_read: 0,
-            frame_buf: [0u8; MAX_BUFFER_SIZE],
+            counters: Arc::new(NetCounters::default()),
+            rate_limiter: Arc::new(Mutex::new(RateLimiter::default())),
+            throttled: false,
+            rx_filter: Arc::new(Mutex::new(RxFilter::default())),
+            throttled_frame: None,
         }
     }
 
+    /// Reads as many frames as possible from the tap straight into the
+    /// buffers made available by the guest, and tells why it stopped.
     pub fn process_desc_chain(
         &mut self,
         mem: &GuestMemoryMmap,
-        mut next_desc: Option<DescriptorChain>,
+        tap: &mut Tap,
         queue: &mut Queue,
-    ) -> bool {
-        let head_index = next_desc.as_ref().unwrap().index;
-        let mut write_count = 0;
+    ) -> RxStatus {
+        if let Some((head_index, len)) = self.throttled_frame {
+            if let Some(delay) = rate_limit(&self.rate_limiter, len) {
+                return RxStatus::Throttled(delay);
+            }
+            self.throttled_frame = None;
+            self.complete_frame(mem, queue, head_index, len);
+        }
 
-        // Copy from frame into buffer, which may span multiple descriptors.
         loop {
-            match next_desc {
-                Some(desc) => {
-                    if !desc.is_write_only() {
-                        break;
-                    }
-                    let limit = cmp::min(write_count + desc.len as usize, self.bytes_read);
-                    let source_slice = &self.frame_buf[write_count..limit];
-                    let write_result = mem.write_slice(source_slice, desc.addr);
+            let avail_desc = match queue.iter(&mem).next() {
+                Some(desc) => desc,
+                None => return RxStatus::QueueFull,
+            };
+            let head_index = avail_desc.index;
 
-                    match write_result {
-                        Ok(_) => {
-                            write_count = limit;
-                        }
-                        Err(e) => {
-                            error!("Failed to write slice: {:?}", e);
-                            break;
-                        }
-                    };
+            let (iovecs, buf_len) = match desc_chain_iovecs(mem, avail_desc, true) {
+                Some(frame) => frame,
+                None => {
+                    error!("net: rx: invalid descriptor chain");
+                    queue.add_used(&mem, head_index, 0);
+                    self.deferred_irqs = true;
+                    continue;
+                }
+            };
+
+            // The tap fails to read into buffers that can't hold the vnet
+            // header, whatever the frame.
+            if buf_len < vnet_hdr_len() {
+                error!("net: rx: descriptor chain too short for the vnet header");
+                queue.add_used(&mem, head_index, 0);
+                self.deferred_irqs = true;
+                continue;
+            }
 
-                    if write_count >= self.bytes_read {
-                        break;
+            // Safe because the iovecs point to guest memory, which stays
+            // mapped for as long as `mem` is held.
+            let len = match unsafe { tap.readv(&iovecs) } {
+                Ok(len) => len,
+                Err(e) => {
+                    if e.raw_os_error() == Some(libc::EAGAIN) {
+                        queue.go_to_previous_position();
+                        return RxStatus::TapEmpty;
                     }
-                    next_desc = desc.next_descriptor();
-                }
-                None => {
-                    warn!("Receiving buffer is too small to hold frame of current size");
-                    break;
+
+                    // The tap device is non-blocking, so any error aside
+                    // from EAGAIN is unexpected. The buffers are given back
+                    // to the guest, rather than retrying them forever.
+                    error!("net: rx: failed to read from tap: {}", e);
+                    queue.add_used(&mem, head_index, 0);
+                    self.deferred_irqs = true;
+                    self.counters.rx_dropped.fetch_add(1, Ordering::Relaxed);
+                    continue;
                 }
+            };
+
+            // Frames filtered out by the driver settings are discarded, and
+            // the buffers reused for the next frame.
+            if !self.accepts(&iovecs, len) {
+                queue.go_to_previous_position();
+                continue;
+            }
+
+            // The tap reports the full length of a frame that was truncated.
+            if len > buf_len {
+                warn!("Receiving buffer is too small to hold frame of current size");
+                queue.add_used(&mem, head_index, buf_len as u32);
+                self.deferred_irqs = true;
+                self.counters.rx_dropped.fetch_add(1, Ordering::Relaxed);
+                continue;
             }
+
+            if let Some(delay) = rate_limit(&self.rate_limiter, len) {
+                self.throttled_frame = Some((head_index, len));
+                return RxStatus::Throttled(delay);
+            }
+
+            self.complete_frame(mem, queue, head_index, len);
         }
+    }
 
-        queue.add_used(&mem, head_index, write_count as u32);
+    /// Hands the frame waiting for the rate limiter, if any, to the guest
+    /// right away. This is done when pausing the device, so that no
+    /// descriptor chain is held while the queue state is being saved.
+    pub fn complete_throttled_frame(&mut self, mem: &GuestMemoryMmap, queue: &mut Queue) {
+        if let Some((head_index, len)) = self.throttled_frame.take() {
+            self.complete_frame(mem, queue, head_index, len);
+        }
+    }
+
+    fn accepts(&self, iovecs: &[libc::iovec], len: usize) -> bool {
+        let mut header = [0u8; 64];
+        let header_len = cmp::min(len, header.len());
+        // Safe because the iovecs point to guest memory, which stays mapped
+        // for as long as the caller holds it.
+        let header_len = unsafe { read_iovecs(iovecs, &mut header[..header_len]) };
+
+        header_len < vnet_hdr_len()
+            || self
+                .rx_filter
+                .lock()
+                .unwrap()
+                .accepts(&header[vnet_hdr_len()..header_len])
+    }
+
+    fn complete_frame(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        queue: &mut Queue,
+        head_index: u16,
+        len: usize,
+    ) {
+        queue.add_used(&mem, head_index, len as u32);
 
         // Mark that we have at least one pending packet and we need to interrupt the guest.
         self.deferred_irqs = true;
 
-        write_count >= self.bytes_read
+        self.counters
+            .rx_bytes
+            .fetch_add(len as u64, Ordering::Relaxed);
+        self.counters.rx_frames.fetch_add(1, Ordering::Relaxed);
     }
 }
 
@@ -539,3 +1014,334 @@ pub fn open_tap(
     }
     Ok(taps)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::tests::VirtQueue as GuestQ;
+    use crate::queue::{VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+    use crate::{RateLimiterConfig, TokenBucketConfig};
+    use std::fs::{File, OpenOptions};
+    use std::io::Write;
+    use std::os::unix::io::{FromRawFd, IntoRawFd};
+
+    const MAC: [u8; 6] = [0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc];
+
+    fn frame(dest: [u8; 6], vid: Option<u16>) -> Vec<u8> {
+        let mut frame = dest.to_vec();
+        frame.extend_from_slice(&[0x52, 0x54, 0x00, 0x00, 0x00, 0x01]);
+        if let Some(vid) = vid {
+            frame.extend_from_slice(&ETH_P_8021Q.to_be_bytes());
+            frame.extend_from_slice(&vid.to_be_bytes());
+        }
+        frame.extend_from_slice(&[0x08, 0x00]);
+        frame.extend_from_slice(&[0u8; 46]);
+        frame
+    }
+
+    fn mac_table(macs: &[[u8; 6]]) -> Vec<u8> {
+        let mut table = (macs.len() as u32).to_le_bytes().to_vec();
+        for mac in macs {
+            table.extend_from_slice(mac);
+        }
+        table
+    }
+
+    #[test]
+    fn test_rx_filter_mac() {
+        let mut filter = RxFilter::new(MacAddr::from_bytes_unchecked(&MAC));
+        let other = [0x12, 0x34, 0x56, 0x78, 0x9a, 0xbd];
+        let multicast = [0x01, 0x00, 0x5e, 0x00, 0x00, 0x01];
+
+        // Everything is received until the driver sets the receive mode.
+        assert!(filter.accepts(&frame(other, None)));
+
+        filter.promisc = false;
+        assert!(filter.accepts(&frame(MAC, None)));
+        assert!(filter.accepts(&frame([0xff; 6], None)));
+        assert!(!filter.accepts(&frame(other, None)));
+        assert!(!filter.accepts(&frame(multicast, None)));
+
+        let mut tables = mac_table(&[other]);
+        tables.extend(mac_table(&[multicast]));
+        let (uni_macs, rest) = parse_mac_table(&tables).unwrap();
+        let (multi_macs, rest) = parse_mac_table(rest).unwrap();
+        assert!(rest.is_empty());
+        filter.uni_macs = uni_macs.unwrap();
+        filter.multi_macs = multi_macs.unwrap();
+        assert!(filter.accepts(&frame(other, None)));
+        assert!(filter.accepts(&frame(multicast, None)));
+
+        filter.multi_macs.clear();
+        filter.allmulti = true;
+        assert!(filter.accepts(&frame(multicast, None)));
+
+        filter.set_mac(MacAddr::from_bytes_unchecked(&multicast));
+        filter.uni_macs.clear();
+        assert!(!filter.accepts(&frame(MAC, None)));
+    }
+
+    #[test]
+    fn test_rx_filter_vlan() {
+        let mut filter = RxFilter::default();
+        filter.reset(MacAddr::from_bytes_unchecked(&MAC), true);
+        filter.promisc = false;
+
+        assert!(filter.accepts(&frame(MAC, None)));
+        assert!(!filter.accepts(&frame(MAC, Some(100))));
+        filter.set_vlan(100, true);
+        assert!(filter.accepts(&frame(MAC, Some(100))));
+        // The priority bits of the tag are ignored.
+        assert!(filter.accepts(&frame(MAC, Some(0xe000 | 100))));
+        filter.set_vlan(100, false);
+        assert!(!filter.accepts(&frame(MAC, Some(100))));
+
+        filter.reset(MacAddr::from_bytes_unchecked(&MAC), false);
+        filter.promisc = false;
+        assert!(filter.accepts(&frame(MAC, Some(100))));
+    }
+
+    #[test]
+    fn test_rx_filter_state() {
+        let mut filter = RxFilter::default();
+        filter.reset(MacAddr::from_bytes_unchecked(&MAC), true);
+        filter.promisc = false;
+        filter.allmulti = true;
+        filter.uni_macs = vec![MacAddr::from_bytes_unchecked(&[2; 6])];
+        filter.multi_overflow = true;
+        filter.set_vlan(100, true);
+
+        let mut restored = RxFilter::default();
+        restored.set_state(&filter.state()).unwrap();
+        assert_eq!(restored.mac(), filter.mac());
+        assert!(!restored.promisc);
+        assert!(restored.allmulti);
+        assert_eq!(restored.uni_macs, filter.uni_macs);
+        assert!(restored.multi_macs.is_empty());
+        assert!(!restored.uni_overflow);
+        assert!(restored.multi_overflow);
+        assert_eq!(restored.vlans, filter.vlans);
+
+        // The tables must fit in the device.
+        let mut state = filter.state();
+        state.vlans.pop();
+        assert!(restored.set_state(&state).is_err());
+        let mut state = filter.state();
+        state.multi_macs = vec![MacAddr::from_bytes_unchecked(&MAC); MAC_TABLE_LEN + 1];
+        assert!(restored.set_state(&state).is_err());
+    }
+
+    #[test]
+    fn test_parse_mac_table() {
+        // Truncated tables are rejected.
+        assert!(parse_mac_table(&[1, 0]).is_err());
+        assert!(parse_mac_table(&mac_table(&[MAC])[..8]).is_err());
+        assert!(parse_mac_table(&[0xff, 0xff, 0xff, 0xff]).is_err());
+
+        // Too many addresses make the device receive all of them.
+        let table = mac_table(&[MAC; MAC_TABLE_LEN + 1]);
+        let (macs, rest) = parse_mac_table(&table).unwrap();
+        assert!(macs.is_none());
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn test_process_cvq() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = GuestQ::new(GuestAddress(0), &mem, 16);
+        let status_addr = |i: u64| GuestAddress(0x2000 + i * 0x10);
+        for i in 0..3 {
+            mem.write_obj(0xffu8, status_addr(i)).unwrap();
+        }
+
+        // A command larger than the device accepts.
+        vq.dtable[0].set(
+            0x1000,
+            MAX_CTRL_COMMAND_SIZE as u32 + 1,
+            VIRTQ_DESC_F_NEXT,
+            1,
+        );
+        vq.dtable[1].set(status_addr(0).0, 1, VIRTQ_DESC_F_WRITE, 0);
+        // A command without any status.
+        vq.dtable[2].set(0x1000, 2, 0, 0);
+        // A valid command, disabling the promiscuous mode.
+        mem.write_slice(
+            &[
+                VIRTIO_NET_CTRL_RX as u8,
+                VIRTIO_NET_CTRL_RX_PROMISC as u8,
+                0,
+            ],
+            GuestAddress(0x3000),
+        )
+        .unwrap();
+        vq.dtable[3].set(0x3000, 3, VIRTQ_DESC_F_NEXT, 4);
+        vq.dtable[4].set(status_addr(2).0, 1, VIRTQ_DESC_F_WRITE, 0);
+        for (i, head) in [0, 2, 3].iter().enumerate() {
+            vq.avail.ring[i].set(*head);
+        }
+        vq.avail.idx.set(3);
+
+        // The malformed commands don't prevent the next ones from being
+        // processed.
+        let mut ctrl = CtrlVirtio::new(vq.create_queue(), EventFd::new(0).unwrap());
+        ctrl.process_cvq(&mem).unwrap();
+        assert_eq!(vq.used.idx.get(), 3);
+        let used: Vec<(u32, u32)> = (0..3)
+            .map(|i| {
+                let elem = vq.used.ring[i].get();
+                (elem.id, elem.len)
+            })
+            .collect();
+        assert_eq!(used, vec![(0, 1), (2, 0), (3, 1)]);
+        assert_eq!(
+            mem.read_obj::<u8>(status_addr(0)).unwrap(),
+            VIRTIO_NET_ERR as u8
+        );
+        assert_eq!(mem.read_obj::<u8>(status_addr(1)).unwrap(), 0xff);
+        assert_eq!(
+            mem.read_obj::<u8>(status_addr(2)).unwrap(),
+            VIRTIO_NET_OK as u8
+        );
+        assert!(!ctrl.rx_filter.lock().unwrap().promisc);
+    }
+
+    const RX_BUF_ADDR: u64 = 0x1_0000;
+    const RX_BUF_LEN: u32 = 0x1000;
+
+    // Returns a tap reading the frames written to the other end of a socket
+    // pair.
+    fn tap_pair() -> (Tap, File) {
+        let mut fds = [0; 2];
+        // Safe because we check the return value, and fds has room for both
+        // ends.
+        let ret = unsafe {
+            libc::socketpair(
+                libc::AF_UNIX,
+                libc::SOCK_SEQPACKET | libc::SOCK_NONBLOCK,
+                0,
+                fds.as_mut_ptr(),
+            )
+        };
+        assert_eq!(ret, 0);
+        // Safe because nothing else holds onto the fds we just created.
+        unsafe { (Tap::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) }
+    }
+
+    // Writes a broadcast frame preceded by a virtio-net header to the tap,
+    // and returns its length.
+    fn send_frame(peer: &mut File) -> usize {
+        let mut buf = vec![0u8; vnet_hdr_len()];
+        buf.extend(frame([0xff; 6], None));
+        peer.write_all(&buf).unwrap();
+        buf.len()
+    }
+
+    // Makes each descriptor a single buffer of the given length available
+    // to the device.
+    fn add_rx_bufs(vq: &GuestQ, lens: &[u32]) {
+        for (i, len) in lens.iter().enumerate() {
+            let addr = RX_BUF_ADDR + i as u64 * u64::from(RX_BUF_LEN);
+            vq.dtable[i].set(addr, *len, VIRTQ_DESC_F_WRITE, 0);
+            vq.avail.ring[i].set(i as u16);
+        }
+        vq.avail.idx.set(lens.len() as u16);
+    }
+
+    fn used_elems(vq: &GuestQ) -> Vec<(u32, u32)> {
+        (0..vq.used.idx.get() as usize)
+            .map(|i| {
+                let elem = vq.used.ring[i].get();
+                (elem.id, elem.len)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_rx_short_chain() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x2_0000)]).unwrap();
+        let vq = GuestQ::new(GuestAddress(0), &mem, 16);
+        let mut queue = vq.create_queue();
+        let (mut tap, mut peer) = tap_pair();
+        let mut rx = RxVirtio::new();
+
+        // A chain too short for the virtio-net header is returned to the
+        // guest without stalling the queue.
+        let len = send_frame(&mut peer);
+        add_rx_bufs(&vq, &[vnet_hdr_len() as u32 - 1, RX_BUF_LEN]);
+        assert_eq!(
+            rx.process_desc_chain(&mem, &mut tap, &mut queue),
+            RxStatus::QueueFull
+        );
+        assert_eq!(used_elems(&vq), vec![(0, 0), (1, len as u32)]);
+        assert!(rx.deferred_irqs);
+        assert_eq!(rx.counters.rx_frames.load(Ordering::Relaxed), 1);
+
+        // Chains are left to the next frames once the tap is empty.
+        vq.avail.ring[2].set(2);
+        vq.dtable[2].set(RX_BUF_ADDR, RX_BUF_LEN, VIRTQ_DESC_F_WRITE, 0);
+        vq.avail.idx.set(3);
+        assert_eq!(
+            rx.process_desc_chain(&mem, &mut tap, &mut queue),
+            RxStatus::TapEmpty
+        );
+        assert_eq!(vq.used.idx.get(), 2);
+        let len = send_frame(&mut peer);
+        assert_eq!(
+            rx.process_desc_chain(&mem, &mut tap, &mut queue),
+            RxStatus::QueueFull
+        );
+        assert_eq!(used_elems(&vq)[2], (2, len as u32));
+    }
+
+    #[test]
+    fn test_rx_tap_error() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x2_0000)]).unwrap();
+        let vq = GuestQ::new(GuestAddress(0), &mem, 16);
+        let mut queue = vq.create_queue();
+        let file = OpenOptions::new().write(true).open("/dev/null").unwrap();
+        // Safe because the tap takes over the fd from the file.
+        let mut tap = unsafe { Tap::from_raw_fd(file.into_raw_fd()) };
+        let mut rx = RxVirtio::new();
+
+        // Reading from the tap fails with EBADF, and the buffers are given
+        // back to the guest instead of being retried forever.
+        add_rx_bufs(&vq, &[RX_BUF_LEN, RX_BUF_LEN]);
+        assert_eq!(
+            rx.process_desc_chain(&mem, &mut tap, &mut queue),
+            RxStatus::QueueFull
+        );
+        assert_eq!(used_elems(&vq), vec![(0, 0), (1, 0)]);
+        assert!(rx.deferred_irqs);
+        assert_eq!(rx.counters.rx_dropped.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn test_rx_complete_throttled_frame() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x2_0000)]).unwrap();
+        let vq = GuestQ::new(GuestAddress(0), &mem, 16);
+        let mut queue = vq.create_queue();
+        let (mut tap, mut peer) = tap_pair();
+        let mut rx = RxVirtio::new();
+        rx.rate_limiter = Arc::new(Mutex::new(RateLimiter::new(&RateLimiterConfig {
+            bandwidth: None,
+            ops: Some(TokenBucketConfig { rate: 1, burst: 0 }),
+        })));
+
+        // The second frame is held in the guest buffers until the rate
+        // limiter allows it.
+        let len = send_frame(&mut peer);
+        send_frame(&mut peer);
+        add_rx_bufs(&vq, &[RX_BUF_LEN, RX_BUF_LEN]);
+        match rx.process_desc_chain(&mem, &mut tap, &mut queue) {
+            RxStatus::Throttled(_) => (),
+            s => panic!("unexpected status {:?}", s),
+        }
+        assert_eq!(used_elems(&vq), vec![(0, len as u32)]);
+
+        // Pausing hands it to the guest right away, and only once.
+        rx.complete_throttled_frame(&mem, &mut queue);
+        rx.complete_throttled_frame(&mem, &mut queue);
+        assert_eq!(used_elems(&vq), vec![(0, len as u32), (1, len as u32)]);
+        assert_eq!(rx.counters.rx_frames.load(Ordering::Relaxed), 2);
+    }
+}
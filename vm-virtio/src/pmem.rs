@@ -12,6 +12,7 @@ use super::{
     VirtioDeviceType, VIRTIO_F_IOMMU_PLATFORM, VIRTIO_F_VERSION_1,
 };
 use crate::{VirtioInterrupt, VirtioInterruptType};
+use anyhow::anyhow;
 use epoll;
 use libc::EFD_NONBLOCK;
 use std::cmp;
@@ -24,7 +25,7 @@ use std::result;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread;
-use vm_device::{Migratable, MigratableError, Pausable, Snapshotable};
+use vm_device::{Migratable, MigratableError, Pausable, Snapshot, Snapshotable, VersionedState};
 use vm_memory::{
     Address, ByteValued, Bytes, GuestAddress, GuestAddressSpace, GuestMemoryAtomic,
     GuestMemoryError, GuestMemoryMmap, GuestUsize,
@@ -46,6 +47,8 @@ const KILL_EVENT: DeviceEventT = 1;
 // The device should be paused.
 const PAUSE_EVENT: DeviceEventT = 2;
 
+const PMEM_SNAPSHOT_ID: &str = "virtio-pmem";
+
 #[derive(Copy, Clone, Debug, Default)]
 #[repr(C)]
 struct VirtioPmemConfig {
@@ -275,6 +278,7 @@ impl PmemEpollHandler {
 
                 match ev_type {
                     QUEUE_AVAIL_EVENT => {
+                        self.queue.notified();
                         if let Err(e) = self.queue_evt.read() {
                             error!("Failed to get queue event: {:?}", e);
                             break 'epoll;
@@ -350,6 +354,39 @@ impl Pmem {
             paused: Arc::new(AtomicBool::new(false)),
         })
     }
+
+    fn state(&self) -> PmemState {
+        PmemState {
+            avail_features: self.avail_features,
+            acked_features: self.acked_features,
+            config: self.config.as_slice().to_vec(),
+        }
+    }
+
+    fn set_state(&mut self, state: &PmemState) -> result::Result<(), MigratableError> {
+        if state.config.len() != self.config.as_slice().len() {
+            return Err(MigratableError::Restore(anyhow!(
+                "Invalid virtio-pmem configuration space size"
+            )));
+        }
+
+        self.avail_features = state.avail_features;
+        self.acked_features = state.acked_features;
+        self.config.as_mut_slice().copy_from_slice(&state.config);
+
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct PmemState {
+    pub avail_features: u64,
+    pub acked_features: u64,
+    pub config: Vec<u8>,
+}
+
+impl VersionedState for PmemState {
+    const VERSION: u16 = 1;
 }
 
 impl Drop for Pmem {
@@ -506,5 +543,17 @@ impl VirtioDevice for Pmem {
 }
 
 virtio_pausable!(Pmem);
-impl Snapshotable for Pmem {}
+impl Snapshotable for Pmem {
+    fn id(&self) -> String {
+        PMEM_SNAPSHOT_ID.to_string()
+    }
+
+    fn snapshot(&self) -> result::Result<Snapshot, MigratableError> {
+        Snapshot::new_from_state(&self.id(), &self.state())
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> result::Result<(), MigratableError> {
+        self.set_state(&snapshot.to_state(&self.id())?)
+    }
+}
 impl Migratable for Pmem {}
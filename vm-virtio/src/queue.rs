@@ -12,10 +12,11 @@ use std::cmp::min;
 use std::convert::TryInto;
 use std::fmt::{self, Display};
 use std::num::Wrapping;
-use std::sync::atomic::{fence, Ordering};
+use std::sync::atomic::{fence, AtomicU16, AtomicU64, Ordering};
 use std::sync::Arc;
 
 use crate::device::VirtioIommuRemapping;
+use crate::dirty_log::DirtyLog;
 use vm_memory::{
     Address, ByteValued, Bytes, GuestAddress, GuestMemory, GuestMemoryMmap, GuestUsize,
 };
@@ -290,6 +291,7 @@ pub struct AvailIter<'a, 'b> {
     last_index: Wrapping<u16>,
     queue_size: u16,
     next_avail: &'b mut Wrapping<u16>,
+    shared_next_avail: Option<&'b AtomicU16>,
     iommu_mapping_cb: Option<Arc<VirtioIommuRemapping>>,
 }
 
@@ -303,6 +305,7 @@ impl<'a, 'b> AvailIter<'a, 'b> {
             last_index: Wrapping(0),
             queue_size: 0,
             next_avail: q_next_avail,
+            shared_next_avail: None,
             iommu_mapping_cb: None,
         }
     }
@@ -342,11 +345,40 @@ impl<'a, 'b> Iterator for AvailIter<'a, 'b> {
         );
         if ret.is_some() {
             *self.next_avail += Wrapping(1);
+            if let Some(shared_next_avail) = self.shared_next_avail {
+                shared_next_avail.store(self.next_avail.0, Ordering::Release);
+            }
         }
         ret
     }
 }
 
+/// The virtio queue state, as saved in a device snapshot. The used ring
+/// index is not part of it, as it is restored from the used ring in guest
+/// memory.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct QueueState {
+    max_size: u16,
+    size: u16,
+    ready: bool,
+    vector: u16,
+    desc_table: u64,
+    avail_ring: u64,
+    used_ring: u64,
+    next_avail: u16,
+}
+
+/// Activity counters of a virtio queue. They are shared by all the copies of
+/// the queue, so that the transport can report the activity of the queues
+/// owned by the device threads.
+#[derive(Default)]
+pub struct QueueCounters {
+    /// Notifications received from the driver
+    pub notifications: AtomicU64,
+    /// Interrupts sent to the driver
+    pub interrupts: AtomicU64,
+}
+
 #[derive(Clone)]
 /// A virtio queue's parameters.
 pub struct Queue {
@@ -374,7 +406,18 @@ pub struct Queue {
     pub next_avail: Wrapping<u16>,
     pub next_used: Wrapping<u16>,
 
+    /// Copy of next_avail shared by all the copies of the queue. The device
+    /// thread owning the queue keeps it up to date, so that the transport
+    /// can save it.
+    shared_next_avail: Arc<AtomicU16>,
+
     pub iommu_mapping_cb: Option<Arc<VirtioIommuRemapping>>,
+
+    /// Log of the guest pages written by the device
+    pub dirty_log: Option<Arc<DirtyLog>>,
+
+    /// Activity counters
+    pub counters: Arc<QueueCounters>,
 }
 
 impl Queue {
@@ -390,14 +433,66 @@ impl Queue {
             used_ring: GuestAddress(0),
             next_avail: Wrapping(0),
             next_used: Wrapping(0),
+            shared_next_avail: Arc::new(AtomicU16::new(0)),
             iommu_mapping_cb: None,
+            dirty_log: None,
+            counters: Arc::new(QueueCounters::default()),
         }
     }
 
+    /// Account for a notification received from the driver.
+    pub fn notified(&self) {
+        self.counters.notifications.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Account for an interrupt sent to the driver.
+    pub fn interrupted(&self) {
+        self.counters.interrupts.fetch_add(1, Ordering::Relaxed);
+    }
+
     pub fn get_max_size(&self) -> u16 {
         self.max_size
     }
 
+    pub fn state(&self) -> QueueState {
+        QueueState {
+            max_size: self.max_size,
+            size: self.size,
+            ready: self.ready,
+            vector: self.vector,
+            desc_table: self.desc_table.raw_value(),
+            avail_ring: self.avail_ring.raw_value(),
+            used_ring: self.used_ring.raw_value(),
+            next_avail: self.shared_next_avail.load(Ordering::Acquire),
+        }
+    }
+
+    /// Restore the queue from its saved state. The used ring index is read
+    /// back from the used ring in guest memory. The devices hand all the
+    /// descriptor chains they consumed back to the guest when paused, so
+    /// both indexes usually match.
+    pub fn set_state(&mut self, mem: &GuestMemoryMmap, state: &QueueState) -> Result<(), Error> {
+        self.max_size = state.max_size;
+        self.size = state.size;
+        self.ready = state.ready;
+        self.vector = state.vector;
+        self.desc_table = GuestAddress(state.desc_table);
+        self.avail_ring = GuestAddress(state.avail_ring);
+        self.used_ring = GuestAddress(state.used_ring);
+
+        if self.ready {
+            let used_index: u16 = mem
+                .read_obj(self.used_ring.unchecked_add(2))
+                .map_err(|_| Error::GuestMemoryError)?;
+            self.next_avail = Wrapping(state.next_avail);
+            self.next_used = Wrapping(used_index);
+        }
+        self.shared_next_avail
+            .store(self.next_avail.0, Ordering::Release);
+
+        Ok(())
+    }
+
     pub fn enable(&mut self, set: bool) {
         self.ready = set;
 
@@ -428,6 +523,9 @@ impl Queue {
     pub fn reset(&mut self) {
         self.ready = false;
         self.size = self.max_size;
+        self.next_avail = Wrapping(0);
+        self.next_used = Wrapping(0);
+        self.shared_next_avail.store(0, Ordering::Release);
     }
 
     pub fn is_valid(&self, mem: &GuestMemoryMmap) -> bool {
@@ -516,6 +614,7 @@ impl Queue {
             last_index: Wrapping(last_index),
             queue_size,
             next_avail: &mut self.next_avail,
+            shared_next_avail: Some(&self.shared_next_avail),
             iommu_mapping_cb: self.iommu_mapping_cb.clone(),
         }
     }
@@ -589,6 +688,14 @@ impl Queue {
 
         self.next_used += Wrapping(1);
 
+        // The descriptor chain must be walked before the index update, as
+        // the driver is free to reuse its descriptors as soon as it sees it.
+        if let Some(dirty_log) = &self.dirty_log {
+            if dirty_log.is_enabled() {
+                self.log_dirty_pages(mem, dirty_log, desc_index, used_elem);
+            }
+        }
+
         // This fence ensures all descriptor writes are visible before the index update is.
         fence(Ordering::Release);
 
@@ -598,11 +705,49 @@ impl Queue {
         Some(self.next_used.0)
     }
 
+    // Log the pages the device may have written for the given descriptor
+    // chain: all its device writable buffers, and the used ring.
+    fn log_dirty_pages(
+        &self,
+        mem: &GuestMemoryMmap,
+        dirty_log: &DirtyLog,
+        desc_index: u16,
+        used_elem: GuestAddress,
+    ) {
+        dirty_log.mark_dirty(self.used_ring, 4);
+        dirty_log.mark_dirty(used_elem, 8);
+
+        let head = match DescriptorChain::checked_new(
+            mem,
+            self.desc_table,
+            self.actual_size(),
+            desc_index,
+            self.iommu_mapping_cb.clone(),
+        ) {
+            Some(head) => head,
+            None => return,
+        };
+
+        for desc in head {
+            if desc.is_indirect() {
+                if let Ok(indirect) = desc.new_from_indirect() {
+                    for desc in indirect.into_iter().writable() {
+                        dirty_log.mark_dirty(desc.addr, u64::from(desc.len));
+                    }
+                }
+            } else if desc.is_write_only() {
+                dirty_log.mark_dirty(desc.addr, u64::from(desc.len));
+            }
+        }
+    }
+
     /// Goes back one position in the available descriptor chain offered by the driver.
     /// Rust does not support bidirectional iterators. This is the only way to revert the effect
     /// of an iterator increment on the queue.
     pub fn go_to_previous_position(&mut self) {
         self.next_avail -= Wrapping(1);
+        self.shared_next_avail
+            .store(self.next_avail.0, Ordering::Release);
     }
 }
 
@@ -1068,4 +1213,73 @@ pub(crate) mod tests {
         assert_eq!(x.id, 1);
         assert_eq!(x.len, 0x1000);
     }
+
+    #[test]
+    fn test_add_used_dirty_log() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+        assert!(vq.end().0 < 0x1000);
+
+        // A device readable buffer, followed by a device writable one
+        // spanning two pages.
+        vq.dtable[0].set(0x2000, 0x100, VIRTQ_DESC_F_NEXT, 1);
+        vq.dtable[1].set(0x3800, 0x1000, VIRTQ_DESC_F_WRITE, 0);
+
+        let dirty_log = Arc::new(DirtyLog::new());
+        dirty_log.add_region(GuestAddress(0), 0x10000);
+        let mut q = vq.create_queue();
+        q.dirty_log = Some(dirty_log.clone());
+
+        // Nothing is logged until logging starts.
+        q.add_used(m, 0, 0x1000);
+        assert_eq!(dirty_log.take_bitmap(GuestAddress(0), 0x10000), vec![0]);
+
+        // The used ring and the writable buffer are logged.
+        dirty_log.start();
+        q.add_used(m, 0, 0x1000);
+        assert_eq!(vq.used.idx.get(), 2);
+        assert_eq!(
+            dirty_log.take_bitmap(GuestAddress(0), 0x10000),
+            vec![0b11001]
+        );
+    }
+
+    #[test]
+    fn test_queue_state() {
+        let m = &GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+
+        let mut q = vq.create_queue();
+        q.vector = 3;
+        for i in 0..4 {
+            vq.dtable[i].set(0x1000 * (i + 1) as u64, 0x1000, 0, 0);
+            vq.avail.ring[i].set(i as u16);
+        }
+        vq.avail.idx.set(4);
+
+        // The device thread works on its own copy of the queue. It consumed
+        // four chains, gave one back and completed two of them.
+        let mut device_queue = q.clone();
+        assert_eq!(device_queue.iter(m).count(), 4);
+        device_queue.go_to_previous_position();
+        device_queue.add_used(m, 0, 0x1000);
+        device_queue.add_used(m, 1, 0x1000);
+
+        let mut restored = Queue::new(16);
+        restored.set_state(m, &q.state()).unwrap();
+        assert!(restored.ready);
+        assert_eq!(restored.vector, 3);
+        assert_eq!(restored.desc_table, q.desc_table);
+        assert_eq!(restored.avail_ring, q.avail_ring);
+        assert_eq!(restored.used_ring, q.used_ring);
+        assert_eq!(restored.next_used.0, 2);
+        assert_eq!(restored.next_avail.0, 3);
+        assert_eq!(restored.state().next_avail, 3);
+
+        // A reset queue starts over from the beginning of the rings.
+        restored.reset();
+        assert_eq!(restored.next_avail.0, 0);
+        assert_eq!(restored.next_used.0, 0);
+        assert_eq!(restored.state().next_avail, 0);
+    }
 }
@@ -0,0 +1,304 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Token bucket based rate limiting of the I/O performed by a virtio device.
+//!
+//! A `RateLimiter` holds up to two token buckets: one counting bytes and one
+//! counting operations. Each bucket is refilled at a fixed rate, and can hold
+//! up to its burst size. A request is let through only when both buckets have
+//! enough tokens for it, otherwise the device is expected to retry it later.
+
+use std::cmp;
+use std::time::{Duration, Instant};
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+// Shortest delay before retrying a throttled request. A zero timer duration
+// would disarm the timer instead.
+const MIN_REFILL_DELAY: Duration = Duration::from_millis(1);
+// Longest delay before retrying a throttled request, so that limits updated
+// at runtime are picked up promptly.
+const MAX_REFILL_DELAY: Duration = Duration::from_millis(100);
+
+/// Configuration of a token bucket.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct TokenBucketConfig {
+    /// Number of tokens added to the bucket every second. Zero means no
+    /// limit.
+    pub rate: u64,
+    /// Maximum number of tokens the bucket can hold, allowing short bursts
+    /// above `rate`. Defaults to one second worth of tokens when lower than
+    /// `rate`.
+    #[serde(default)]
+    pub burst: u64,
+}
+
+/// Configuration of a rate limiter, with optional bandwidth (bytes) and
+/// operations limits.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct RateLimiterConfig {
+    #[serde(default)]
+    pub bandwidth: Option<TokenBucketConfig>,
+    #[serde(default)]
+    pub ops: Option<TokenBucketConfig>,
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    rate: u64,
+    capacity: u64,
+    budget: u64,
+    // Tokens consumed beyond the budget by a request larger than the bucket,
+    // paid back before the budget grows again.
+    debt: u64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    // Buckets start full, so that a burst is allowed right away.
+    fn new(config: &TokenBucketConfig, now: Instant) -> Option<Self> {
+        if config.rate == 0 {
+            return None;
+        }
+
+        let capacity = cmp::max(config.rate, config.burst);
+        Some(TokenBucket {
+            rate: config.rate,
+            capacity,
+            budget: capacity,
+            debt: 0,
+            last_refill: now,
+        })
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_nanos();
+        let tokens = elapsed * u128::from(self.rate) / NANOS_PER_SEC;
+        if tokens == 0 {
+            return;
+        }
+
+        // Only account for the time the new tokens correspond to, so that
+        // slow rates don't lose the fractions of tokens between refills.
+        let tokens = cmp::min(tokens, u128::from(u64::MAX)) as u64;
+        let paid = cmp::min(tokens, self.debt);
+        self.debt -= paid;
+        self.budget = self.budget.saturating_add(tokens - paid);
+        if self.budget >= self.capacity {
+            self.budget = self.capacity;
+            self.last_refill = now;
+        } else {
+            let refill_time = u128::from(tokens) * NANOS_PER_SEC / u128::from(self.rate);
+            self.last_refill += Duration::from_nanos(refill_time as u64);
+        }
+    }
+
+    // Tokens that must be available before consuming `tokens`. A request
+    // larger than the bucket can't wait for more than a full bucket.
+    fn required(&self, tokens: u64) -> u64 {
+        cmp::min(tokens, self.capacity)
+    }
+
+    fn has_budget(&self, tokens: u64) -> bool {
+        self.debt == 0 && self.budget >= self.required(tokens)
+    }
+
+    fn consume(&mut self, tokens: u64) {
+        if tokens <= self.budget {
+            self.budget -= tokens;
+        } else {
+            self.debt += tokens - self.budget;
+            self.budget = 0;
+        }
+    }
+
+    fn refill_delay(&self, tokens: u64) -> Duration {
+        let missing = u128::from(self.debt) + u128::from(self.required(tokens))
+            - u128::from(cmp::min(self.budget, self.required(tokens)));
+        let nanos = missing * NANOS_PER_SEC / u128::from(self.rate);
+        Duration::from_nanos(cmp::min(nanos, u128::from(u64::MAX)) as u64)
+    }
+}
+
+/// Limits the bandwidth and the number of operations of a device.
+///
+/// A rate limiter without any bucket lets everything through.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    bandwidth: Option<TokenBucket>,
+    ops: Option<TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimiterConfig) -> Self {
+        let now = Instant::now();
+        RateLimiter {
+            bandwidth: config
+                .bandwidth
+                .as_ref()
+                .and_then(|c| TokenBucket::new(c, now)),
+            ops: config.ops.as_ref().and_then(|c| TokenBucket::new(c, now)),
+        }
+    }
+
+    /// Replaces the limits, starting again from full buckets.
+    pub fn update(&mut self, config: &RateLimiterConfig) {
+        *self = RateLimiter::new(config);
+    }
+
+    /// Returns true if neither the bandwidth nor the operations are limited.
+    pub fn is_unlimited(&self) -> bool {
+        self.bandwidth.is_none() && self.ops.is_none()
+    }
+
+    /// Accounts for one operation of `bytes` bytes if both buckets have
+    /// enough tokens for it. Returns false, without consuming anything, if
+    /// the operation must be retried later.
+    pub fn consume(&mut self, bytes: u64) -> bool {
+        self.consume_at(bytes, Instant::now())
+    }
+
+    /// Returns how long to wait before retrying an operation of `bytes`
+    /// bytes that was just throttled.
+    pub fn refill_delay(&self, bytes: u64) -> Duration {
+        let delay = cmp::max(
+            self.bandwidth
+                .as_ref()
+                .map_or(Duration::from_secs(0), |b| b.refill_delay(bytes)),
+            self.ops
+                .as_ref()
+                .map_or(Duration::from_secs(0), |b| b.refill_delay(1)),
+        );
+
+        cmp::min(cmp::max(delay, MIN_REFILL_DELAY), MAX_REFILL_DELAY)
+    }
+
+    fn consume_at(&mut self, bytes: u64, now: Instant) -> bool {
+        for bucket in self.bandwidth.iter_mut().chain(self.ops.iter_mut()) {
+            bucket.refill(now);
+        }
+
+        if let Some(bandwidth) = self.bandwidth.as_ref() {
+            if !bandwidth.has_budget(bytes) {
+                return false;
+            }
+        }
+        if let Some(ops) = self.ops.as_ref() {
+            if !ops.has_budget(1) {
+                return false;
+            }
+        }
+
+        if let Some(bandwidth) = self.bandwidth.as_mut() {
+            bandwidth.consume(bytes);
+        }
+        if let Some(ops) = self.ops.as_mut() {
+            ops.consume(1);
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(bandwidth: Option<(u64, u64)>, ops: Option<(u64, u64)>) -> RateLimiterConfig {
+        RateLimiterConfig {
+            bandwidth: bandwidth.map(|(rate, burst)| TokenBucketConfig { rate, burst }),
+            ops: ops.map(|(rate, burst)| TokenBucketConfig { rate, burst }),
+        }
+    }
+
+    #[test]
+    fn unlimited() {
+        let mut limiter = RateLimiter::new(&config(None, Some((0, 0))));
+        assert!(limiter.is_unlimited());
+        let now = Instant::now();
+        for _ in 0..1000 {
+            assert!(limiter.consume_at(1 << 30, now));
+        }
+    }
+
+    #[test]
+    fn ops_limit_and_burst() {
+        let mut limiter = RateLimiter::new(&config(None, Some((10, 20))));
+        assert!(!limiter.is_unlimited());
+        let start = Instant::now();
+
+        // The whole burst is available right away.
+        for _ in 0..20 {
+            assert!(limiter.consume_at(4096, start));
+        }
+        assert!(!limiter.consume_at(4096, start));
+        assert_eq!(limiter.refill_delay(4096), Duration::from_millis(100));
+
+        // Then operations are let through at the configured rate.
+        assert!(!limiter.consume_at(4096, start + Duration::from_millis(50)));
+        assert!(limiter.consume_at(4096, start + Duration::from_millis(100)));
+        assert!(!limiter.consume_at(4096, start + Duration::from_millis(150)));
+        assert!(limiter.consume_at(4096, start + Duration::from_millis(200)));
+
+        // The bucket never holds more than the burst.
+        let later = start + Duration::from_secs(60);
+        for _ in 0..20 {
+            assert!(limiter.consume_at(4096, later));
+        }
+        assert!(!limiter.consume_at(4096, later));
+    }
+
+    #[test]
+    fn bandwidth_limit() {
+        let mut limiter = RateLimiter::new(&config(Some((1000, 0)), None));
+        let start = Instant::now();
+
+        assert!(limiter.consume_at(950, start));
+        assert!(!limiter.consume_at(100, start));
+        assert_eq!(limiter.refill_delay(100), Duration::from_millis(50));
+        assert!(limiter.consume_at(100, start + Duration::from_millis(50)));
+
+        // Long waits are capped, so that updated limits are noticed.
+        assert!(!limiter.consume_at(1000, start + Duration::from_millis(50)));
+        assert_eq!(limiter.refill_delay(1000), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn request_larger_than_bucket() {
+        let mut limiter = RateLimiter::new(&config(Some((1000, 0)), None));
+        let start = Instant::now();
+
+        // An oversized request goes through with a full bucket, and the
+        // excess is paid back before anything else is let through.
+        assert!(limiter.consume_at(3000, start));
+        assert!(!limiter.consume_at(1, start + Duration::from_millis(1999)));
+        assert!(limiter.consume_at(1, start + Duration::from_millis(2001)));
+    }
+
+    #[test]
+    fn both_buckets_required() {
+        let mut limiter = RateLimiter::new(&config(Some((1000, 0)), Some((100, 0))));
+        let start = Instant::now();
+
+        assert!(limiter.consume_at(1000, start));
+        // No bandwidth left, the operation must not be consumed.
+        assert!(!limiter.consume_at(1, start));
+        for _ in 0..99 {
+            assert!(limiter.consume_at(0, start));
+        }
+        assert!(!limiter.consume_at(0, start));
+    }
+
+    #[test]
+    fn update_limits() {
+        let mut limiter = RateLimiter::new(&config(None, Some((1, 0))));
+        let now = Instant::now();
+        assert!(limiter.consume_at(0, now));
+        assert!(!limiter.consume_at(0, now));
+
+        limiter.update(&RateLimiterConfig::default());
+        assert!(limiter.is_unlimited());
+        assert!(limiter.consume_at(0, now));
+    }
+}
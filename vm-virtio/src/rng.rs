@@ -18,7 +18,7 @@ use std::result;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread;
-use vm_device::{Migratable, MigratableError, Pausable, Snapshotable};
+use vm_device::{Migratable, MigratableError, Pausable, Snapshot, Snapshotable, VersionedState};
 use vm_memory::{Bytes, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap};
 use vmm_sys_util::eventfd::EventFd;
 
@@ -28,6 +28,8 @@ const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE];
 
 // New descriptors are pending on the virtio queue.
 const QUEUE_AVAIL_EVENT: DeviceEventT = 0;
+
+const RNG_SNAPSHOT_ID: &str = "virtio-rng";
 // The device has been dropped.
 const KILL_EVENT: DeviceEventT = 1;
 // The device should be paused.
@@ -140,6 +142,7 @@ impl RngEpollHandler {
 
                 match ev_type {
                     QUEUE_AVAIL_EVENT => {
+                        self.queues[0].notified();
                         if let Err(e) = self.queue_evt.read() {
                             error!("Failed to get queue event: {:?}", e);
                             break 'epoll;
@@ -211,6 +214,30 @@ impl Rng {
             paused: Arc::new(AtomicBool::new(false)),
         })
     }
+
+    fn state(&self) -> RngState {
+        RngState {
+            avail_features: self.avail_features,
+            acked_features: self.acked_features,
+        }
+    }
+
+    fn set_state(&mut self, state: &RngState) -> result::Result<(), MigratableError> {
+        self.avail_features = state.avail_features;
+        self.acked_features = state.acked_features;
+
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct RngState {
+    pub avail_features: u64,
+    pub acked_features: u64,
+}
+
+impl VersionedState for RngState {
+    const VERSION: u16 = 1;
 }
 
 impl Drop for Rng {
@@ -357,5 +384,17 @@ impl VirtioDevice for Rng {
 }
 
 virtio_pausable!(Rng);
-impl Snapshotable for Rng {}
+impl Snapshotable for Rng {
+    fn id(&self) -> String {
+        RNG_SNAPSHOT_ID.to_string()
+    }
+
+    fn snapshot(&self) -> result::Result<Snapshot, MigratableError> {
+        Snapshot::new_from_state(&self.id(), &self.state())
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> result::Result<(), MigratableError> {
+        self.set_state(&snapshot.to_state(&self.id())?)
+    }
+}
 impl Migratable for Rng {}
@@ -4,18 +4,19 @@
 
 use crate::transport::{VirtioTransport, NOTIFY_REG_OFFSET};
 use crate::{
-    Queue, VirtioDevice, VirtioInterrupt, VirtioInterruptType, DEVICE_ACKNOWLEDGE, DEVICE_DRIVER,
-    DEVICE_DRIVER_OK, DEVICE_FAILED, DEVICE_FEATURES_OK, DEVICE_INIT,
-    INTERRUPT_STATUS_CONFIG_CHANGED, INTERRUPT_STATUS_USED_RING,
+    DirtyLog, Queue, QueueCounters, QueueState, VirtioDevice, VirtioInterrupt, VirtioInterruptType,
+    DEVICE_ACKNOWLEDGE, DEVICE_DRIVER, DEVICE_DRIVER_OK, DEVICE_FAILED, DEVICE_FEATURES_OK,
+    DEVICE_INIT, INTERRUPT_STATUS_CONFIG_CHANGED, INTERRUPT_STATUS_USED_RING,
 };
+use anyhow::anyhow;
 use byteorder::{ByteOrder, LittleEndian};
 use devices::BusDevice;
 use libc::EFD_NONBLOCK;
 use std::result;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use vm_device::interrupt::InterruptSourceGroup;
-use vm_device::{Migratable, MigratableError, Pausable, Snapshotable};
+use vm_device::{Migratable, MigratableError, Pausable, Snapshot, Snapshotable, VersionedState};
 use vm_memory::{GuestAddress, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap};
 use vmm_sys_util::{errno::Result, eventfd::EventFd};
 
@@ -24,18 +25,23 @@ const VENDOR_ID: u32 = 0;
 const MMIO_MAGIC_VALUE: u32 = 0x7472_6976;
 const MMIO_VERSION: u32 = 2;
 
+const MMIO_SNAPSHOT_ID: &str = "virtio-mmio";
+
 pub struct VirtioInterruptIntx {
     interrupt_status: Arc<AtomicUsize>,
+    config_generation: Arc<AtomicU32>,
     interrupt: Arc<Box<dyn InterruptSourceGroup>>,
 }
 
 impl VirtioInterruptIntx {
     pub fn new(
         interrupt_status: Arc<AtomicUsize>,
+        config_generation: Arc<AtomicU32>,
         interrupt: Arc<Box<dyn InterruptSourceGroup>>,
     ) -> Self {
         VirtioInterruptIntx {
             interrupt_status,
+            config_generation,
             interrupt,
         }
     }
@@ -45,11 +51,21 @@ impl VirtioInterrupt for VirtioInterruptIntx {
     fn trigger(
         &self,
         int_type: &VirtioInterruptType,
-        _queue: Option<&Queue>,
+        queue: Option<&Queue>,
     ) -> std::result::Result<(), std::io::Error> {
         let status = match int_type {
-            VirtioInterruptType::Config => INTERRUPT_STATUS_CONFIG_CHANGED,
-            VirtioInterruptType::Queue => INTERRUPT_STATUS_USED_RING,
+            VirtioInterruptType::Config => {
+                // Let the driver know the configuration it may be reading
+                // has changed.
+                self.config_generation.fetch_add(1, Ordering::SeqCst);
+                INTERRUPT_STATUS_CONFIG_CHANGED
+            }
+            VirtioInterruptType::Queue => {
+                if let Some(q) = queue {
+                    q.interrupted();
+                }
+                INTERRUPT_STATUS_USED_RING
+            }
         };
         self.interrupt_status
             .fetch_or(status as usize, Ordering::SeqCst);
@@ -58,6 +74,23 @@ impl VirtioInterrupt for VirtioInterruptIntx {
     }
 }
 
+#[derive(Deserialize, Serialize)]
+pub struct MmioDeviceState {
+    pub device_activated: bool,
+    pub features_select: u32,
+    pub acked_features_select: u32,
+    pub queue_select: u32,
+    pub interrupt_status: usize,
+    pub driver_status: u32,
+    pub config_generation: u32,
+    pub queues: Vec<QueueState>,
+    pub shm_region_select: u32,
+}
+
+impl VersionedState for MmioDeviceState {
+    const VERSION: u16 = 2;
+}
+
 /// Implements the
 /// [MMIO](http://docs.oasis-open.org/virtio/virtio/v1.0/cs04/virtio-v1.0-cs04.html#x1-1090002)
 /// transport for virtio devices.
@@ -82,7 +115,7 @@ pub struct MmioDevice {
     interrupt_status: Arc<AtomicUsize>,
     interrupt_cb: Option<Arc<dyn VirtioInterrupt>>,
     driver_status: u32,
-    config_generation: u32,
+    config_generation: Arc<AtomicU32>,
     queues: Vec<Queue>,
     queue_evts: Vec<EventFd>,
     mem: Option<GuestMemoryAtomic<GuestMemoryMmap>>,
@@ -94,6 +127,7 @@ impl MmioDevice {
     pub fn new(
         mem: GuestMemoryAtomic<GuestMemoryMmap>,
         device: Arc<Mutex<dyn VirtioDevice>>,
+        dirty_log: Option<Arc<DirtyLog>>,
     ) -> Result<MmioDevice> {
         let device_clone = device.clone();
         let locked_device = device_clone.lock().unwrap();
@@ -104,7 +138,11 @@ impl MmioDevice {
         let queues = locked_device
             .queue_max_sizes()
             .iter()
-            .map(|&s| Queue::new(s))
+            .map(|&s| {
+                let mut queue = Queue::new(s);
+                queue.dirty_log = dirty_log.clone();
+                queue
+            })
             .collect();
         Ok(MmioDevice {
             device,
@@ -115,7 +153,7 @@ impl MmioDevice {
             interrupt_status: Arc::new(AtomicUsize::new(0)),
             interrupt_cb: None,
             driver_status: DEVICE_INIT,
-            config_generation: 0,
+            config_generation: Arc::new(AtomicU32::new(0)),
             queues,
             queue_evts,
             mem: Some(mem),
@@ -162,9 +200,80 @@ impl MmioDevice {
         }
     }
 
+    fn activate_device(&mut self) {
+        if let Some(interrupt_cb) = self.interrupt_cb.take() {
+            if self.mem.is_some() {
+                let mem = self.mem.as_ref().unwrap().clone();
+                self.device
+                    .lock()
+                    .unwrap()
+                    .activate(
+                        mem,
+                        interrupt_cb,
+                        self.queues.clone(),
+                        self.queue_evts.split_off(0),
+                    )
+                    .expect("Failed to activate device");
+                self.device_activated = true;
+            }
+        }
+    }
+
+    fn state(&self) -> MmioDeviceState {
+        MmioDeviceState {
+            device_activated: self.device_activated,
+            features_select: self.features_select,
+            acked_features_select: self.acked_features_select,
+            queue_select: self.queue_select,
+            interrupt_status: self.interrupt_status.load(Ordering::SeqCst),
+            driver_status: self.driver_status,
+            config_generation: self.config_generation.load(Ordering::SeqCst),
+            queues: self.queues.iter().map(Queue::state).collect(),
+            shm_region_select: self.shm_region_select,
+        }
+    }
+
+    fn set_state(&mut self, state: &MmioDeviceState) -> result::Result<(), MigratableError> {
+        if state.queues.len() != self.queues.len() {
+            return Err(MigratableError::Restore(anyhow!(
+                "Invalid number of virtio queues {}, expected {}",
+                state.queues.len(),
+                self.queues.len()
+            )));
+        }
+
+        self.features_select = state.features_select;
+        self.acked_features_select = state.acked_features_select;
+        self.queue_select = state.queue_select;
+        self.interrupt_status
+            .store(state.interrupt_status, Ordering::SeqCst);
+        self.driver_status = state.driver_status;
+        self.config_generation
+            .store(state.config_generation, Ordering::SeqCst);
+        self.shm_region_select = state.shm_region_select;
+
+        if let Some(mem) = self.mem.as_ref() {
+            let mem = mem.memory();
+            for (queue, queue_state) in self.queues.iter_mut().zip(state.queues.iter()) {
+                queue
+                    .set_state(&mem, queue_state)
+                    .map_err(|e| MigratableError::Restore(anyhow!("{}", e)))?;
+            }
+        }
+
+        // The device state has already been restored, so that the device
+        // resumes from where it was when being activated again.
+        if state.device_activated && !self.device_activated {
+            self.activate_device();
+        }
+
+        Ok(())
+    }
+
     pub fn assign_interrupt(&mut self, interrupt: Arc<Box<dyn InterruptSourceGroup>>) {
         self.interrupt_cb = Some(Arc::new(VirtioInterruptIntx::new(
             self.interrupt_status.clone(),
+            self.config_generation.clone(),
             interrupt,
         )));
     }
@@ -178,6 +287,10 @@ impl VirtioTransport for MmioDevice {
             .map(|event| (event, notify_base))
             .collect()
     }
+
+    fn queue_counters(&self) -> Vec<Arc<QueueCounters>> {
+        self.queues.iter().map(|q| q.counters.clone()).collect()
+    }
 }
 
 impl BusDevice for MmioDevice {
@@ -201,7 +314,7 @@ impl BusDevice for MmioDevice {
                     0x44 => self.with_queue(0, |q| q.ready as u32),
                     0x60 => self.interrupt_status.load(Ordering::SeqCst) as u32,
                     0x70 => self.driver_status,
-                    0xfc => self.config_generation,
+                    0xfc => self.config_generation.load(Ordering::SeqCst),
                     0xb0..=0xbc => {
                         // For no SHM region or invalid region the kernel looks for length of -1
                         let (shm_offset, shm_len) = if let Some(shm_regions) =
@@ -324,22 +437,7 @@ impl BusDevice for MmioDevice {
         }
 
         if !self.device_activated && self.is_driver_ready() && self.are_queues_valid() {
-            if let Some(interrupt_cb) = self.interrupt_cb.take() {
-                if self.mem.is_some() {
-                    let mem = self.mem.as_ref().unwrap().clone();
-                    self.device
-                        .lock()
-                        .unwrap()
-                        .activate(
-                            mem,
-                            interrupt_cb,
-                            self.queues.clone(),
-                            self.queue_evts.split_off(0),
-                        )
-                        .expect("Failed to activate device");
-                    self.device_activated = true;
-                }
-            }
+            self.activate_device();
         }
     }
 }
@@ -354,5 +452,65 @@ impl Pausable for MmioDevice {
     }
 }
 
-impl Snapshotable for MmioDevice {}
+impl Snapshotable for MmioDevice {
+    fn id(&self) -> String {
+        MMIO_SNAPSHOT_ID.to_string()
+    }
+
+    fn snapshot(&self) -> result::Result<Snapshot, MigratableError> {
+        Snapshot::new_from_state(&self.id(), &self.state())
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> result::Result<(), MigratableError> {
+        self.set_state(&snapshot.to_state(&self.id())?)
+    }
+}
 impl Migratable for MmioDevice {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vm_device::interrupt::{InterruptIndex, InterruptSourceConfig};
+
+    struct DummyInterrupt;
+
+    impl InterruptSourceGroup for DummyInterrupt {
+        fn trigger(&self, _index: InterruptIndex) -> std::io::Result<()> {
+            Ok(())
+        }
+
+        fn update(
+            &self,
+            _index: InterruptIndex,
+            _config: InterruptSourceConfig,
+        ) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_config_change_bumps_generation() {
+        let interrupt_status = Arc::new(AtomicUsize::new(0));
+        let config_generation = Arc::new(AtomicU32::new(0));
+        let interrupt: Arc<Box<dyn InterruptSourceGroup>> = Arc::new(Box::new(DummyInterrupt));
+        let interrupt_cb = VirtioInterruptIntx::new(
+            interrupt_status.clone(),
+            config_generation.clone(),
+            interrupt,
+        );
+
+        interrupt_cb
+            .trigger(&VirtioInterruptType::Queue, None)
+            .unwrap();
+        assert_eq!(config_generation.load(Ordering::SeqCst), 0);
+
+        interrupt_cb
+            .trigger(&VirtioInterruptType::Config, None)
+            .unwrap();
+        assert_eq!(config_generation.load(Ordering::SeqCst), 1);
+        assert_eq!(
+            interrupt_status.load(Ordering::SeqCst),
+            (INTERRUPT_STATUS_USED_RING | INTERRUPT_STATUS_CONFIG_CHANGED) as usize
+        );
+    }
+}
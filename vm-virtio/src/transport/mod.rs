@@ -2,6 +2,8 @@
 //
 // SPDX-License-Identifier: Apache-2.0
 
+use crate::QueueCounters;
+use std::sync::Arc;
 use vmm_sys_util::eventfd::EventFd;
 #[cfg(feature = "pci_support")]
 mod pci_common_config;
@@ -10,15 +12,16 @@ mod pci_device;
 #[cfg(feature = "pci_support")]
 pub use pci_common_config::VirtioPciCommonConfig;
 #[cfg(feature = "pci_support")]
-pub use pci_device::VirtioPciDevice;
+pub use pci_device::{VirtioPciDevice, VirtioPciDeviceState};
 
 #[cfg(feature = "mmio_support")]
 mod mmio;
 #[cfg(feature = "mmio_support")]
-pub use mmio::MmioDevice;
+pub use mmio::{MmioDevice, MmioDeviceState};
 #[cfg(feature = "mmio_support")]
 pub const NOTIFY_REG_OFFSET: u32 = 0x50;
 
 pub trait VirtioTransport {
     fn ioeventfds(&self, base_addr: u64) -> Vec<(&EventFd, u64)>;
+    fn queue_counters(&self) -> Vec<Arc<QueueCounters>>;
 }
@@ -9,7 +9,7 @@ extern crate byteorder;
 
 use crate::{Queue, VirtioDevice};
 use byteorder::{ByteOrder, LittleEndian};
-use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::atomic::{AtomicU16, AtomicU8, Ordering};
 use std::sync::{Arc, Mutex};
 use vm_memory::GuestAddress;
 
@@ -37,7 +37,7 @@ use vm_memory::GuestAddress;
 /// le64 queue_used;                // 0x30 // read-write
 pub struct VirtioPciCommonConfig {
     pub driver_status: u8,
-    pub config_generation: u8,
+    pub config_generation: Arc<AtomicU8>,
     pub device_feature_select: u32,
     pub driver_feature_select: u32,
     pub queue_select: u16,
@@ -100,7 +100,7 @@ impl VirtioPciCommonConfig {
         // The driver is only allowed to do aligned, properly sized access.
         match offset {
             0x14 => self.driver_status,
-            0x15 => self.config_generation,
+            0x15 => self.config_generation.load(Ordering::SeqCst),
             _ => {
                 warn!("invalid virtio config byte read: 0x{:x}", offset);
                 0
@@ -295,7 +295,7 @@ mod tests {
     fn write_base_regs() {
         let mut regs = VirtioPciCommonConfig {
             driver_status: 0xaa,
-            config_generation: 0x55,
+            config_generation: Arc::new(AtomicU8::new(0x55)),
             device_feature_select: 0x0,
             driver_feature_select: 0x0,
             queue_select: 0xff,
@@ -16,28 +16,30 @@ extern crate vmm_sys_util;
 use super::VirtioPciCommonConfig;
 use crate::transport::VirtioTransport;
 use crate::{
-    Queue, VirtioDevice, VirtioDeviceType, VirtioInterrupt, VirtioInterruptType,
-    VirtioIommuRemapping, DEVICE_ACKNOWLEDGE, DEVICE_DRIVER, DEVICE_DRIVER_OK, DEVICE_FAILED,
-    DEVICE_FEATURES_OK, DEVICE_INIT, VIRTIO_MSI_NO_VECTOR,
+    DirtyLog, Queue, QueueCounters, QueueState, VirtioDevice, VirtioDeviceType, VirtioInterrupt,
+    VirtioInterruptType, VirtioIommuRemapping, DEVICE_ACKNOWLEDGE, DEVICE_DRIVER, DEVICE_DRIVER_OK,
+    DEVICE_FAILED, DEVICE_FEATURES_OK, DEVICE_INIT, VIRTIO_MSI_NO_VECTOR,
 };
+use anyhow::anyhow;
 use devices::BusDevice;
 use libc::EFD_NONBLOCK;
 use pci::{
-    BarReprogrammingParams, MsixCap, MsixConfig, PciBarConfiguration, PciBarRegionType,
-    PciCapability, PciCapabilityID, PciClassCode, PciConfiguration, PciDevice, PciDeviceError,
-    PciHeaderType, PciMassStorageSubclass, PciNetworkControllerSubclass, PciSubclass,
+    BarReprogrammingParams, MsixCap, MsixConfig, MsixConfigState, PciBarConfiguration,
+    PciBarRegionType, PciCapability, PciCapabilityID, PciClassCode, PciConfiguration,
+    PciConfigurationState, PciDevice, PciDeviceError, PciHeaderType, PciMassStorageSubclass,
+    PciNetworkControllerSubclass, PciSubclass,
 };
 use std::any::Any;
 use std::cmp;
 use std::io::Write;
 use std::result;
-use std::sync::atomic::{AtomicU16, AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicU16, AtomicU8, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use vm_allocator::SystemAllocator;
 use vm_device::interrupt::{
     InterruptIndex, InterruptManager, InterruptSourceGroup, MsiIrqGroupConfig,
 };
-use vm_device::{Migratable, MigratableError, Pausable, Snapshotable};
+use vm_device::{Migratable, MigratableError, Pausable, Snapshot, Snapshotable, VersionedState};
 use vm_memory::{
     Address, ByteValued, GuestAddress, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap,
     GuestUsize, Le32,
@@ -258,6 +260,27 @@ const NOTIFY_OFF_MULTIPLIER: u32 = 4; // A dword per notification address.
 const VIRTIO_PCI_VENDOR_ID: u16 = 0x1af4;
 const VIRTIO_PCI_DEVICE_ID_BASE: u16 = 0x1040; // Add to device type to get device ID.
 
+const VIRTIO_PCI_SNAPSHOT_ID: &str = "virtio-pci";
+
+#[derive(Deserialize, Serialize)]
+pub struct VirtioPciDeviceState {
+    pub device_activated: bool,
+    pub interrupt_status: usize,
+    pub queues: Vec<QueueState>,
+    pub driver_status: u8,
+    pub config_generation: u8,
+    pub device_feature_select: u32,
+    pub driver_feature_select: u32,
+    pub queue_select: u16,
+    pub msix_config: u16,
+    pub configuration: PciConfigurationState,
+    pub msix: Option<MsixConfigState>,
+}
+
+impl VersionedState for VirtioPciDeviceState {
+    const VERSION: u16 = 2;
+}
+
 pub struct VirtioPciDevice {
     // PCI configuration registers.
     configuration: PciConfiguration,
@@ -309,6 +332,7 @@ impl VirtioPciDevice {
         device: Arc<Mutex<dyn VirtioDevice>>,
         msix_num: u16,
         iommu_mapping_cb: Option<Arc<VirtioIommuRemapping>>,
+        dirty_log: Option<Arc<DirtyLog>>,
         interrupt_manager: &Arc<dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>>,
     ) -> Result<Self> {
         let device_clone = device.clone();
@@ -323,6 +347,7 @@ impl VirtioPciDevice {
             .map(|&s| {
                 let mut queue = Queue::new(s);
                 queue.iommu_mapping_cb = iommu_mapping_cb.clone();
+                queue.dirty_log = dirty_log.clone();
                 queue
             })
             .collect();
@@ -383,7 +408,7 @@ impl VirtioPciDevice {
             configuration,
             common_config: VirtioPciCommonConfig {
                 driver_status: 0,
-                config_generation: 0,
+                config_generation: Arc::new(AtomicU8::new(0)),
                 device_feature_select: 0,
                 driver_feature_select: 0,
                 queue_select: 0,
@@ -408,6 +433,7 @@ impl VirtioPciDevice {
             virtio_pci_device.virtio_interrupt = Some(Arc::new(VirtioInterruptMsix::new(
                 msix_config.clone(),
                 virtio_pci_device.common_config.msix_config.clone(),
+                virtio_pci_device.common_config.config_generation.clone(),
                 virtio_pci_device.interrupt_source_group.clone(),
             )));
         }
@@ -442,6 +468,99 @@ impl VirtioPciDevice {
         }
     }
 
+    fn activate_device(&mut self) {
+        if let Some(virtio_interrupt) = self.virtio_interrupt.take() {
+            if self.memory.is_some() {
+                let mem = self.memory.as_ref().unwrap().clone();
+                let mut device = self.device.lock().unwrap();
+                device
+                    .activate(
+                        mem,
+                        virtio_interrupt,
+                        self.queues.clone(),
+                        self.queue_evts.split_off(0),
+                    )
+                    .expect("Failed to activate device");
+                self.device_activated = true;
+            }
+        }
+    }
+
+    fn state(&self) -> VirtioPciDeviceState {
+        VirtioPciDeviceState {
+            device_activated: self.device_activated,
+            interrupt_status: self.interrupt_status.load(Ordering::SeqCst),
+            queues: self.queues.iter().map(Queue::state).collect(),
+            driver_status: self.common_config.driver_status,
+            config_generation: self.common_config.config_generation.load(Ordering::SeqCst),
+            device_feature_select: self.common_config.device_feature_select,
+            driver_feature_select: self.common_config.driver_feature_select,
+            queue_select: self.common_config.queue_select,
+            msix_config: self.common_config.msix_config.load(Ordering::SeqCst),
+            configuration: self.configuration.state(),
+            msix: self
+                .msix_config
+                .as_ref()
+                .map(|msix_config| msix_config.lock().unwrap().state()),
+        }
+    }
+
+    fn set_state(&mut self, state: &VirtioPciDeviceState) -> result::Result<(), MigratableError> {
+        if state.queues.len() != self.queues.len() {
+            return Err(MigratableError::Restore(anyhow!(
+                "Invalid number of virtio queues {}, expected {}",
+                state.queues.len(),
+                self.queues.len()
+            )));
+        }
+        if state.msix.is_some() != self.msix_config.is_some() {
+            return Err(MigratableError::Restore(anyhow!(
+                "Mismatching virtio-pci MSI-X configuration"
+            )));
+        }
+
+        self.configuration
+            .set_state(&state.configuration)
+            .map_err(|e| MigratableError::Restore(anyhow!("{}", e)))?;
+        if let (Some(msix_config), Some(msix_state)) = (&self.msix_config, &state.msix) {
+            msix_config
+                .lock()
+                .unwrap()
+                .set_state(msix_state)
+                .map_err(|e| MigratableError::Restore(e.into()))?;
+        }
+
+        self.common_config.driver_status = state.driver_status;
+        self.common_config
+            .config_generation
+            .store(state.config_generation, Ordering::SeqCst);
+        self.common_config.device_feature_select = state.device_feature_select;
+        self.common_config.driver_feature_select = state.driver_feature_select;
+        self.common_config.queue_select = state.queue_select;
+        self.common_config
+            .msix_config
+            .store(state.msix_config, Ordering::SeqCst);
+        self.interrupt_status
+            .store(state.interrupt_status, Ordering::SeqCst);
+
+        if let Some(mem) = self.memory.as_ref() {
+            let mem = mem.memory();
+            for (queue, queue_state) in self.queues.iter_mut().zip(state.queues.iter()) {
+                queue
+                    .set_state(&mem, queue_state)
+                    .map_err(|e| MigratableError::Restore(anyhow!("{}", e)))?;
+            }
+        }
+
+        // The device state has already been restored, so that the device
+        // resumes from where it was when being activated again.
+        if state.device_activated && !self.device_activated {
+            self.activate_device();
+        }
+
+        Ok(())
+    }
+
     pub fn config_bar_addr(&self) -> u64 {
         self.configuration.get_bar_addr(self.settings_bar as usize)
     }
@@ -576,11 +695,16 @@ impl VirtioTransport for VirtioPciDevice {
             })
             .collect()
     }
+
+    fn queue_counters(&self) -> Vec<Arc<QueueCounters>> {
+        self.queues.iter().map(|q| q.counters.clone()).collect()
+    }
 }
 
 pub struct VirtioInterruptMsix {
     msix_config: Arc<Mutex<MsixConfig>>,
     config_vector: Arc<AtomicU16>,
+    config_generation: Arc<AtomicU8>,
     interrupt_source_group: Arc<Box<dyn InterruptSourceGroup>>,
 }
 
@@ -588,11 +712,13 @@ impl VirtioInterruptMsix {
     pub fn new(
         msix_config: Arc<Mutex<MsixConfig>>,
         config_vector: Arc<AtomicU16>,
+        config_generation: Arc<AtomicU8>,
         interrupt_source_group: Arc<Box<dyn InterruptSourceGroup>>,
     ) -> Self {
         VirtioInterruptMsix {
             msix_config,
             config_vector,
+            config_generation,
             interrupt_source_group,
         }
     }
@@ -605,9 +731,16 @@ impl VirtioInterrupt for VirtioInterruptMsix {
         queue: Option<&Queue>,
     ) -> std::result::Result<(), std::io::Error> {
         let vector = match int_type {
-            VirtioInterruptType::Config => self.config_vector.load(Ordering::SeqCst),
+            VirtioInterruptType::Config => {
+                // The driver compares the generation before and after
+                // reading the configuration, it must change before the
+                // driver is notified.
+                self.config_generation.fetch_add(1, Ordering::SeqCst);
+                self.config_vector.load(Ordering::SeqCst)
+            }
             VirtioInterruptType::Queue => {
                 if let Some(q) = queue {
+                    q.interrupted();
                     q.vector
                 } else {
                     0
@@ -853,21 +986,7 @@ impl PciDevice for VirtioPciDevice {
         };
 
         if !self.device_activated && self.is_driver_ready() && self.are_queues_valid() {
-            if let Some(virtio_interrupt) = self.virtio_interrupt.take() {
-                if self.memory.is_some() {
-                    let mem = self.memory.as_ref().unwrap().clone();
-                    let mut device = self.device.lock().unwrap();
-                    device
-                        .activate(
-                            mem,
-                            virtio_interrupt,
-                            self.queues.clone(),
-                            self.queue_evts.split_off(0),
-                        )
-                        .expect("Failed to activate device");
-                    self.device_activated = true;
-                }
-            }
+            self.activate_device();
         }
 
         // Device has been reset by the driver
@@ -916,5 +1035,17 @@ impl Pausable for VirtioPciDevice {
     }
 }
 
-impl Snapshotable for VirtioPciDevice {}
+impl Snapshotable for VirtioPciDevice {
+    fn id(&self) -> String {
+        VIRTIO_PCI_SNAPSHOT_ID.to_string()
+    }
+
+    fn snapshot(&self) -> result::Result<Snapshot, MigratableError> {
+        Snapshot::new_from_state(&self.id(), &self.state())
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> result::Result<(), MigratableError> {
+        self.set_state(&snapshot.to_state(&self.id())?)
+    }
+}
 impl Migratable for VirtioPciDevice {}
@@ -34,6 +34,7 @@ use crate::{
 /// - an event queue FD; and
 /// - a backend FD.
 ///
+use anyhow::anyhow;
 use byteorder::{ByteOrder, LittleEndian};
 use epoll;
 use libc::EFD_NONBLOCK;
@@ -44,7 +45,7 @@ use std::result;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, RwLock};
 use std::thread;
-use vm_device::{Migratable, MigratableError, Pausable, Snapshotable};
+use vm_device::{Migratable, MigratableError, Pausable, Snapshot, Snapshotable, VersionedState};
 use vm_memory::{GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap};
 use vmm_sys_util::eventfd::EventFd;
 
@@ -66,6 +67,8 @@ pub const KILL_EVENT: DeviceEventT = 4;
 const PAUSE_EVENT: DeviceEventT = 5;
 pub const EVENTS_LEN: usize = 6;
 
+const VSOCK_SNAPSHOT_ID: &str = "virtio-vsock";
+
 /// The `VsockEpollHandler` implements the runtime logic of our vsock device:
 /// 1. Respond to TX queue events by wrapping virtio buffers into `VsockPacket`s, then sending those
 ///    packets to the `VsockBackend`;
@@ -295,6 +298,7 @@ where
         match device_event {
             RX_QUEUE_EVENT => {
                 debug!("vsock: RX queue event");
+                self.queues[0].notified();
                 if let Err(e) = self.queue_evts[0].read() {
                     error!("Failed to get RX queue event: {:?}", e);
                     return Err(DeviceError::FailedReadingQueue {
@@ -307,6 +311,7 @@ where
             }
             TX_QUEUE_EVENT => {
                 debug!("vsock: TX queue event");
+                self.queues[1].notified();
                 if let Err(e) = self.queue_evts[1].read() {
                     error!("Failed to get TX queue event: {:?}", e);
                     return Err(DeviceError::FailedReadingQueue {
@@ -325,6 +330,7 @@ where
             }
             EVT_QUEUE_EVENT => {
                 debug!("vsock: EVT queue event");
+                self.queues[2].notified();
                 if let Err(e) = self.queue_evts[2].read() {
                     error!("Failed to get EVT queue event: {:?}", e);
                     return Err(DeviceError::FailedReadingQueue {
@@ -414,6 +420,40 @@ where
             paused: Arc::new(AtomicBool::new(false)),
         })
     }
+
+    fn state(&self) -> VsockState {
+        VsockState {
+            cid: self.cid,
+            avail_features: self.avail_features,
+            acked_features: self.acked_features,
+        }
+    }
+
+    fn set_state(&mut self, state: &VsockState) -> result::Result<(), MigratableError> {
+        if state.cid != self.cid {
+            return Err(MigratableError::Restore(anyhow!(
+                "Vsock CID changed from {} to {}",
+                state.cid,
+                self.cid
+            )));
+        }
+
+        self.avail_features = state.avail_features;
+        self.acked_features = state.acked_features;
+
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct VsockState {
+    pub cid: u64,
+    pub avail_features: u64,
+    pub acked_features: u64,
+}
+
+impl VersionedState for VsockState {
+    const VERSION: u16 = 1;
 }
 
 impl<B> Drop for Vsock<B>
@@ -574,7 +614,22 @@ where
 
 virtio_pausable!(Vsock, T: 'static + VsockBackend + Sync);
 
-impl<B> Snapshotable for Vsock<B> where B: VsockBackend + Sync + 'static {}
+impl<B> Snapshotable for Vsock<B>
+where
+    B: VsockBackend + Sync + 'static,
+{
+    fn id(&self) -> String {
+        VSOCK_SNAPSHOT_ID.to_string()
+    }
+
+    fn snapshot(&self) -> result::Result<Snapshot, MigratableError> {
+        Snapshot::new_from_state(&self.id(), &self.state())
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> result::Result<(), MigratableError> {
+        self.set_state(&snapshot.to_state(&self.id())?)
+    }
+}
 impl<B> Migratable for Vsock<B> where B: VsockBackend + Sync + 'static {}
 
 #[cfg(test)]
@@ -13,7 +13,7 @@ mod device;
 mod packet;
 mod unix;
 
-pub use self::device::Vsock;
+pub use self::device::{Vsock, VsockState};
 pub use self::unix::VsockUnixBackend;
 pub use self::unix::VsockUnixError;
 
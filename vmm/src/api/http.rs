@@ -4,9 +4,12 @@
 //
 
 use crate::api::http_endpoint::{
-    VmActionHandler, VmAddDevice, VmCreate, VmInfo, VmRemoveDevice, VmResize, VmmPing, VmmShutdown,
+    VmActionHandler, VmAddDevice, VmAddDisk, VmAddFs, VmAddNet, VmAddPmem, VmAddVsock, VmCounters,
+    VmCreate, VmDiskSnapshot, VmInfo, VmReceiveMigration, VmRemoveDevice, VmRemoveNet, VmResize,
+    VmResizeDisk, VmRestore, VmSendMigration, VmSetDiskRateLimit, VmSetNetLink, VmSetNetRateLimit,
+    VmSnapshot, VmmPing, VmmShutdown,
 };
-use crate::api::{ApiRequest, VmAction};
+use crate::api::{ApiRequest, DiskSnapshotAction, VmAction};
 use crate::{Error, Result};
 use micro_http::{HttpServer, MediaType, Request, Response, StatusCode, Version};
 use std::collections::HashMap;
@@ -55,6 +58,7 @@ lazy_static! {
         r.routes.insert(endpoint!("/vm.boot"), Box::new(VmActionHandler::new(VmAction::Boot)));
         r.routes.insert(endpoint!("/vm.delete"), Box::new(VmActionHandler::new(VmAction::Delete)));
         r.routes.insert(endpoint!("/vm.info"), Box::new(VmInfo {}));
+        r.routes.insert(endpoint!("/vm.counters"), Box::new(VmCounters {}));
         r.routes.insert(endpoint!("/vm.pause"), Box::new(VmActionHandler::new(VmAction::Pause)));
         r.routes.insert(endpoint!("/vm.resume"), Box::new(VmActionHandler::new(VmAction::Resume)));
         r.routes.insert(endpoint!("/vm.shutdown"), Box::new(VmActionHandler::new(VmAction::Shutdown)));
@@ -63,7 +67,24 @@ lazy_static! {
         r.routes.insert(endpoint!("/vmm.ping"), Box::new(VmmPing {}));
         r.routes.insert(endpoint!("/vm.resize"), Box::new(VmResize {}));
         r.routes.insert(endpoint!("/vm.add-device"), Box::new(VmAddDevice {}));
+        r.routes.insert(endpoint!("/vm.add-disk"), Box::new(VmAddDisk {}));
+        r.routes.insert(endpoint!("/vm.add-net"), Box::new(VmAddNet {}));
+        r.routes.insert(endpoint!("/vm.remove-net"), Box::new(VmRemoveNet {}));
+        r.routes.insert(endpoint!("/vm.add-fs"), Box::new(VmAddFs {}));
+        r.routes.insert(endpoint!("/vm.add-pmem"), Box::new(VmAddPmem {}));
+        r.routes.insert(endpoint!("/vm.add-vsock"), Box::new(VmAddVsock {}));
         r.routes.insert(endpoint!("/vm.remove-device"), Box::new(VmRemoveDevice {}));
+        r.routes.insert(endpoint!("/vm.snapshot"), Box::new(VmSnapshot {}));
+        r.routes.insert(endpoint!("/vm.restore"), Box::new(VmRestore {}));
+        r.routes.insert(endpoint!("/vm.create-disk-snapshot"), Box::new(VmDiskSnapshot::new(DiskSnapshotAction::Create)));
+        r.routes.insert(endpoint!("/vm.apply-disk-snapshot"), Box::new(VmDiskSnapshot::new(DiskSnapshotAction::Apply)));
+        r.routes.insert(endpoint!("/vm.delete-disk-snapshot"), Box::new(VmDiskSnapshot::new(DiskSnapshotAction::Delete)));
+        r.routes.insert(endpoint!("/vm.set-disk-rate-limit"), Box::new(VmSetDiskRateLimit {}));
+        r.routes.insert(endpoint!("/vm.set-net-rate-limit"), Box::new(VmSetNetRateLimit {}));
+        r.routes.insert(endpoint!("/vm.set-net-link"), Box::new(VmSetNetLink {}));
+        r.routes.insert(endpoint!("/vm.resize-disk"), Box::new(VmResizeDisk {}));
+        r.routes.insert(endpoint!("/vm.send-migration"), Box::new(VmSendMigration {}));
+        r.routes.insert(endpoint!("/vm.receive-migration"), Box::new(VmReceiveMigration {}));
 
         r
     };
@@ -5,10 +5,16 @@
 
 use crate::api::http::EndpointHandler;
 use crate::api::{
-    vm_add_device, vm_boot, vm_create, vm_delete, vm_info, vm_pause, vm_reboot, vm_remove_device,
-    vm_resize, vm_resume, vm_shutdown, vmm_ping, vmm_shutdown, ApiError, ApiRequest, ApiResult,
-    DeviceConfig, VmAction, VmConfig, VmRemoveDeviceData, VmResizeData,
+    vm_add_device, vm_add_disk, vm_add_fs, vm_add_net, vm_add_pmem, vm_add_vsock, vm_boot,
+    vm_counters, vm_create, vm_delete, vm_disk_snapshot, vm_info, vm_pause, vm_reboot,
+    vm_receive_migration, vm_remove_device, vm_remove_net, vm_resize, vm_resize_disk, vm_restore,
+    vm_resume, vm_send_migration, vm_set_disk_rate_limit, vm_set_net_link, vm_set_net_rate_limit,
+    vm_shutdown, vm_snapshot, vmm_ping, vmm_shutdown, ApiError, ApiRequest, ApiResult,
+    DeviceConfig, DiskSnapshotAction, VmAction, VmConfig, VmDiskRateLimitData, VmDiskSnapshotData,
+    VmNetLinkData, VmNetRateLimitData, VmReceiveMigrationData, VmRemoveDeviceData, VmResizeData,
+    VmResizeDiskData, VmSendMigrationData, VmSnapshotConfig,
 };
+use crate::config::{DiskConfig, FsConfig, NetConfig, PmemConfig, RestoreConfig, VsockConfig};
 use micro_http::{Body, Method, Request, Response, StatusCode, Version};
 use serde_json::Error as SerdeError;
 use std::sync::mpsc::Sender;
@@ -30,6 +36,9 @@ pub enum HttpError {
     /// Could not get the VM information
     VmInfo(ApiError),
 
+    /// Could not get the VM counters
+    VmCounters(ApiError),
+
     /// Could not pause the VM
     VmPause(ApiError),
 
@@ -51,9 +60,54 @@ pub enum HttpError {
     /// Could not add a device to a VM
     VmAddDevice(ApiError),
 
+    /// Could not add a disk to a VM
+    VmAddDisk(ApiError),
+
+    /// Could not add a network device to a VM
+    VmAddNet(ApiError),
+
+    /// Could not remove a network device from a VM
+    VmRemoveNet(ApiError),
+
+    /// Could not add a virtio-fs device to a VM
+    VmAddFs(ApiError),
+
+    /// Could not add a virtio-pmem device to a VM
+    VmAddPmem(ApiError),
+
+    /// Could not add a vsock device to a VM
+    VmAddVsock(ApiError),
+
     /// Could not remove a device from a VM
     VmRemoveDevice(ApiError),
 
+    /// Could not snapshot a VM
+    VmSnapshot(ApiError),
+
+    /// Could not create, apply or delete a disk snapshot
+    VmDiskSnapshot(ApiError),
+
+    /// Could not update the disk rate limits
+    VmSetDiskRateLimit(ApiError),
+
+    /// Could not update the network rate limits
+    VmSetNetRateLimit(ApiError),
+
+    /// Could not change the network link state
+    VmSetNetLink(ApiError),
+
+    /// Could not resize a disk
+    VmResizeDisk(ApiError),
+
+    /// Could not restore a VM
+    VmRestore(ApiError),
+
+    /// Could not migrate a VM
+    VmSendMigration(ApiError),
+
+    /// Could not receive a migrated VM
+    VmReceiveMigration(ApiError),
+
     /// Could not shut the VMM down
     VmmShutdown(ApiError),
 
@@ -182,6 +236,34 @@ impl EndpointHandler for VmInfo {
     }
 }
 
+// /api/v1/vm.counters handler
+pub struct VmCounters {}
+
+impl EndpointHandler for VmCounters {
+    fn handle_request(
+        &self,
+        req: &Request,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        match req.method() {
+            Method::Get => {
+                match vm_counters(api_notifier, api_sender).map_err(HttpError::VmCounters) {
+                    Ok(counters) => {
+                        let mut response = Response::new(Version::Http11, StatusCode::OK);
+                        let counters_serialized = serde_json::to_string(&counters).unwrap();
+
+                        response.set_body(Body::new(counters_serialized));
+                        response
+                    }
+                    Err(e) => error_response(e, StatusCode::InternalServerError),
+                }
+            }
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
 // /api/v1/vmm.info handler
 pub struct VmmPing {}
 
@@ -309,6 +391,242 @@ impl EndpointHandler for VmAddDevice {
     }
 }
 
+// /api/v1/vm.add-disk handler
+pub struct VmAddDisk {}
+
+impl EndpointHandler for VmAddDisk {
+    fn handle_request(
+        &self,
+        req: &Request,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        match req.method() {
+            Method::Put => {
+                match &req.body {
+                    Some(body) => {
+                        // Deserialize into a DiskConfig
+                        let vm_add_disk_data: DiskConfig = match serde_json::from_slice(body.raw())
+                            .map_err(HttpError::SerdeJsonDeserialize)
+                        {
+                            Ok(config) => config,
+                            Err(e) => return error_response(e, StatusCode::BadRequest),
+                        };
+
+                        // Call vm_add_disk()
+                        match vm_add_disk(api_notifier, api_sender, Arc::new(vm_add_disk_data))
+                            .map_err(HttpError::VmAddDisk)
+                        {
+                            Ok(_) => Response::new(Version::Http11, StatusCode::NoContent),
+                            Err(e) => error_response(e, StatusCode::InternalServerError),
+                        }
+                    }
+
+                    None => Response::new(Version::Http11, StatusCode::BadRequest),
+                }
+            }
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// /api/v1/vm.add-net handler
+pub struct VmAddNet {}
+
+impl EndpointHandler for VmAddNet {
+    fn handle_request(
+        &self,
+        req: &Request,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        match req.method() {
+            Method::Put => {
+                match &req.body {
+                    Some(body) => {
+                        // Deserialize into a NetConfig
+                        let vm_add_net_data: NetConfig = match serde_json::from_slice(body.raw())
+                            .map_err(HttpError::SerdeJsonDeserialize)
+                        {
+                            Ok(config) => config,
+                            Err(e) => return error_response(e, StatusCode::BadRequest),
+                        };
+
+                        // Call vm_add_net()
+                        match vm_add_net(api_notifier, api_sender, Arc::new(vm_add_net_data))
+                            .map_err(HttpError::VmAddNet)
+                        {
+                            Ok(_) => Response::new(Version::Http11, StatusCode::NoContent),
+                            Err(e) => error_response(e, StatusCode::InternalServerError),
+                        }
+                    }
+
+                    None => Response::new(Version::Http11, StatusCode::BadRequest),
+                }
+            }
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// /api/v1/vm.add-fs handler
+pub struct VmAddFs {}
+
+impl EndpointHandler for VmAddFs {
+    fn handle_request(
+        &self,
+        req: &Request,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        match req.method() {
+            Method::Put => {
+                match &req.body {
+                    Some(body) => {
+                        // Deserialize into a FsConfig
+                        let vm_add_fs_data: FsConfig = match serde_json::from_slice(body.raw())
+                            .map_err(HttpError::SerdeJsonDeserialize)
+                        {
+                            Ok(config) => config,
+                            Err(e) => return error_response(e, StatusCode::BadRequest),
+                        };
+
+                        // Call vm_add_fs()
+                        match vm_add_fs(api_notifier, api_sender, Arc::new(vm_add_fs_data))
+                            .map_err(HttpError::VmAddFs)
+                        {
+                            Ok(_) => Response::new(Version::Http11, StatusCode::NoContent),
+                            Err(e) => error_response(e, StatusCode::InternalServerError),
+                        }
+                    }
+
+                    None => Response::new(Version::Http11, StatusCode::BadRequest),
+                }
+            }
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// /api/v1/vm.add-pmem handler
+pub struct VmAddPmem {}
+
+impl EndpointHandler for VmAddPmem {
+    fn handle_request(
+        &self,
+        req: &Request,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        match req.method() {
+            Method::Put => {
+                match &req.body {
+                    Some(body) => {
+                        // Deserialize into a PmemConfig
+                        let vm_add_pmem_data: PmemConfig = match serde_json::from_slice(body.raw())
+                            .map_err(HttpError::SerdeJsonDeserialize)
+                        {
+                            Ok(config) => config,
+                            Err(e) => return error_response(e, StatusCode::BadRequest),
+                        };
+
+                        // Call vm_add_pmem()
+                        match vm_add_pmem(api_notifier, api_sender, Arc::new(vm_add_pmem_data))
+                            .map_err(HttpError::VmAddPmem)
+                        {
+                            Ok(_) => Response::new(Version::Http11, StatusCode::NoContent),
+                            Err(e) => error_response(e, StatusCode::InternalServerError),
+                        }
+                    }
+
+                    None => Response::new(Version::Http11, StatusCode::BadRequest),
+                }
+            }
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// /api/v1/vm.add-vsock handler
+pub struct VmAddVsock {}
+
+impl EndpointHandler for VmAddVsock {
+    fn handle_request(
+        &self,
+        req: &Request,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        match req.method() {
+            Method::Put => {
+                match &req.body {
+                    Some(body) => {
+                        // Deserialize into a VsockConfig
+                        let vm_add_vsock_data: VsockConfig =
+                            match serde_json::from_slice(body.raw())
+                                .map_err(HttpError::SerdeJsonDeserialize)
+                            {
+                                Ok(config) => config,
+                                Err(e) => return error_response(e, StatusCode::BadRequest),
+                            };
+
+                        // Call vm_add_vsock()
+                        match vm_add_vsock(api_notifier, api_sender, Arc::new(vm_add_vsock_data))
+                            .map_err(HttpError::VmAddVsock)
+                        {
+                            Ok(_) => Response::new(Version::Http11, StatusCode::NoContent),
+                            Err(e) => error_response(e, StatusCode::InternalServerError),
+                        }
+                    }
+
+                    None => Response::new(Version::Http11, StatusCode::BadRequest),
+                }
+            }
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// /api/v1/vm.remove-net handler
+pub struct VmRemoveNet {}
+
+impl EndpointHandler for VmRemoveNet {
+    fn handle_request(
+        &self,
+        req: &Request,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        match req.method() {
+            Method::Put => {
+                match &req.body {
+                    Some(body) => {
+                        // Deserialize into a VmRemoveDeviceData
+                        let vm_remove_net_data: VmRemoveDeviceData =
+                            match serde_json::from_slice(body.raw())
+                                .map_err(HttpError::SerdeJsonDeserialize)
+                            {
+                                Ok(config) => config,
+                                Err(e) => return error_response(e, StatusCode::BadRequest),
+                            };
+
+                        // Call vm_remove_net()
+                        match vm_remove_net(api_notifier, api_sender, Arc::new(vm_remove_net_data))
+                            .map_err(HttpError::VmRemoveNet)
+                        {
+                            Ok(_) => Response::new(Version::Http11, StatusCode::NoContent),
+                            Err(e) => error_response(e, StatusCode::InternalServerError),
+                        }
+                    }
+
+                    None => Response::new(Version::Http11, StatusCode::BadRequest),
+                }
+            }
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
 // /api/v1/vm.remove-device handler
 pub struct VmRemoveDevice {}
 
@@ -352,3 +670,397 @@ impl EndpointHandler for VmRemoveDevice {
         }
     }
 }
+
+// /api/v1/vm.snapshot handler
+pub struct VmSnapshot {}
+
+impl EndpointHandler for VmSnapshot {
+    fn handle_request(
+        &self,
+        req: &Request,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        match req.method() {
+            Method::Put => {
+                match &req.body {
+                    Some(body) => {
+                        // Deserialize into a VmSnapshotConfig
+                        let vm_snapshot_data: VmSnapshotConfig =
+                            match serde_json::from_slice(body.raw())
+                                .map_err(HttpError::SerdeJsonDeserialize)
+                            {
+                                Ok(config) => config,
+                                Err(e) => return error_response(e, StatusCode::BadRequest),
+                            };
+
+                        // Call vm_snapshot()
+                        match vm_snapshot(api_notifier, api_sender, Arc::new(vm_snapshot_data))
+                            .map_err(HttpError::VmSnapshot)
+                        {
+                            Ok(_) => Response::new(Version::Http11, StatusCode::NoContent),
+                            Err(e) => error_response(e, StatusCode::InternalServerError),
+                        }
+                    }
+
+                    None => Response::new(Version::Http11, StatusCode::BadRequest),
+                }
+            }
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// /api/v1/vm.create-disk-snapshot, /api/v1/vm.apply-disk-snapshot and
+// /api/v1/vm.delete-disk-snapshot handler
+pub struct VmDiskSnapshot {
+    action: DiskSnapshotAction,
+}
+
+impl VmDiskSnapshot {
+    pub fn new(action: DiskSnapshotAction) -> Self {
+        VmDiskSnapshot { action }
+    }
+}
+
+impl EndpointHandler for VmDiskSnapshot {
+    fn handle_request(
+        &self,
+        req: &Request,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        match req.method() {
+            Method::Put => {
+                match &req.body {
+                    Some(body) => {
+                        // Deserialize into a VmDiskSnapshotData
+                        let vm_disk_snapshot_data: VmDiskSnapshotData =
+                            match serde_json::from_slice(body.raw())
+                                .map_err(HttpError::SerdeJsonDeserialize)
+                            {
+                                Ok(data) => data,
+                                Err(e) => return error_response(e, StatusCode::BadRequest),
+                            };
+
+                        // Call vm_disk_snapshot()
+                        match vm_disk_snapshot(
+                            api_notifier,
+                            api_sender,
+                            self.action,
+                            Arc::new(vm_disk_snapshot_data),
+                        )
+                        .map_err(HttpError::VmDiskSnapshot)
+                        {
+                            Ok(_) => Response::new(Version::Http11, StatusCode::NoContent),
+                            Err(e) => error_response(e, StatusCode::InternalServerError),
+                        }
+                    }
+
+                    None => Response::new(Version::Http11, StatusCode::BadRequest),
+                }
+            }
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// /api/v1/vm.set-disk-rate-limit handler
+pub struct VmSetDiskRateLimit {}
+
+impl EndpointHandler for VmSetDiskRateLimit {
+    fn handle_request(
+        &self,
+        req: &Request,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        match req.method() {
+            Method::Put => {
+                match &req.body {
+                    Some(body) => {
+                        // Deserialize into a VmDiskRateLimitData
+                        let vm_disk_rate_limit_data: VmDiskRateLimitData =
+                            match serde_json::from_slice(body.raw())
+                                .map_err(HttpError::SerdeJsonDeserialize)
+                            {
+                                Ok(data) => data,
+                                Err(e) => return error_response(e, StatusCode::BadRequest),
+                            };
+
+                        // Call vm_set_disk_rate_limit()
+                        match vm_set_disk_rate_limit(
+                            api_notifier,
+                            api_sender,
+                            Arc::new(vm_disk_rate_limit_data),
+                        )
+                        .map_err(HttpError::VmSetDiskRateLimit)
+                        {
+                            Ok(_) => Response::new(Version::Http11, StatusCode::NoContent),
+                            Err(e) => error_response(e, StatusCode::InternalServerError),
+                        }
+                    }
+
+                    None => Response::new(Version::Http11, StatusCode::BadRequest),
+                }
+            }
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// /api/v1/vm.resize-disk handler
+pub struct VmResizeDisk {}
+
+impl EndpointHandler for VmResizeDisk {
+    fn handle_request(
+        &self,
+        req: &Request,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        match req.method() {
+            Method::Put => {
+                match &req.body {
+                    Some(body) => {
+                        // Deserialize into a VmResizeDiskData
+                        let vm_resize_disk_data: VmResizeDiskData =
+                            match serde_json::from_slice(body.raw())
+                                .map_err(HttpError::SerdeJsonDeserialize)
+                            {
+                                Ok(data) => data,
+                                Err(e) => return error_response(e, StatusCode::BadRequest),
+                            };
+
+                        // Call vm_resize_disk()
+                        match vm_resize_disk(
+                            api_notifier,
+                            api_sender,
+                            Arc::new(vm_resize_disk_data),
+                        )
+                        .map_err(HttpError::VmResizeDisk)
+                        {
+                            Ok(_) => Response::new(Version::Http11, StatusCode::NoContent),
+                            Err(e) => error_response(e, StatusCode::InternalServerError),
+                        }
+                    }
+
+                    None => Response::new(Version::Http11, StatusCode::BadRequest),
+                }
+            }
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// /api/v1/vm.set-net-rate-limit handler
+pub struct VmSetNetRateLimit {}
+
+impl EndpointHandler for VmSetNetRateLimit {
+    fn handle_request(
+        &self,
+        req: &Request,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        match req.method() {
+            Method::Put => {
+                match &req.body {
+                    Some(body) => {
+                        // Deserialize into a VmNetRateLimitData
+                        let vm_net_rate_limit_data: VmNetRateLimitData =
+                            match serde_json::from_slice(body.raw())
+                                .map_err(HttpError::SerdeJsonDeserialize)
+                            {
+                                Ok(data) => data,
+                                Err(e) => return error_response(e, StatusCode::BadRequest),
+                            };
+
+                        // Call vm_set_net_rate_limit()
+                        match vm_set_net_rate_limit(
+                            api_notifier,
+                            api_sender,
+                            Arc::new(vm_net_rate_limit_data),
+                        )
+                        .map_err(HttpError::VmSetNetRateLimit)
+                        {
+                            Ok(_) => Response::new(Version::Http11, StatusCode::NoContent),
+                            Err(e) => error_response(e, StatusCode::InternalServerError),
+                        }
+                    }
+
+                    None => Response::new(Version::Http11, StatusCode::BadRequest),
+                }
+            }
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// /api/v1/vm.set-net-link handler
+pub struct VmSetNetLink {}
+
+impl EndpointHandler for VmSetNetLink {
+    fn handle_request(
+        &self,
+        req: &Request,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        match req.method() {
+            Method::Put => {
+                match &req.body {
+                    Some(body) => {
+                        // Deserialize into a VmNetLinkData
+                        let vm_net_link_data: VmNetLinkData =
+                            match serde_json::from_slice(body.raw())
+                                .map_err(HttpError::SerdeJsonDeserialize)
+                            {
+                                Ok(data) => data,
+                                Err(e) => return error_response(e, StatusCode::BadRequest),
+                            };
+
+                        // Call vm_set_net_link()
+                        match vm_set_net_link(api_notifier, api_sender, Arc::new(vm_net_link_data))
+                            .map_err(HttpError::VmSetNetLink)
+                        {
+                            Ok(_) => Response::new(Version::Http11, StatusCode::NoContent),
+                            Err(e) => error_response(e, StatusCode::InternalServerError),
+                        }
+                    }
+
+                    None => Response::new(Version::Http11, StatusCode::BadRequest),
+                }
+            }
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// /api/v1/vm.restore handler
+pub struct VmRestore {}
+
+impl EndpointHandler for VmRestore {
+    fn handle_request(
+        &self,
+        req: &Request,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        match req.method() {
+            Method::Put => {
+                match &req.body {
+                    Some(body) => {
+                        // Deserialize into a RestoreConfig
+                        let vm_restore_data: RestoreConfig =
+                            match serde_json::from_slice(body.raw())
+                                .map_err(HttpError::SerdeJsonDeserialize)
+                            {
+                                Ok(config) => config,
+                                Err(e) => return error_response(e, StatusCode::BadRequest),
+                            };
+
+                        // Call vm_restore()
+                        match vm_restore(api_notifier, api_sender, Arc::new(vm_restore_data))
+                            .map_err(HttpError::VmRestore)
+                        {
+                            Ok(_) => Response::new(Version::Http11, StatusCode::NoContent),
+                            Err(e) => error_response(e, StatusCode::InternalServerError),
+                        }
+                    }
+
+                    None => Response::new(Version::Http11, StatusCode::BadRequest),
+                }
+            }
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// /api/v1/vm.send-migration handler
+pub struct VmSendMigration {}
+
+impl EndpointHandler for VmSendMigration {
+    fn handle_request(
+        &self,
+        req: &Request,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        match req.method() {
+            Method::Put => {
+                match &req.body {
+                    Some(body) => {
+                        // Deserialize into a VmSendMigrationData
+                        let vm_send_migration_data: VmSendMigrationData =
+                            match serde_json::from_slice(body.raw())
+                                .map_err(HttpError::SerdeJsonDeserialize)
+                            {
+                                Ok(config) => config,
+                                Err(e) => return error_response(e, StatusCode::BadRequest),
+                            };
+
+                        // Call vm_send_migration()
+                        match vm_send_migration(
+                            api_notifier,
+                            api_sender,
+                            Arc::new(vm_send_migration_data),
+                        )
+                        .map_err(HttpError::VmSendMigration)
+                        {
+                            Ok(_) => Response::new(Version::Http11, StatusCode::NoContent),
+                            Err(e) => error_response(e, StatusCode::InternalServerError),
+                        }
+                    }
+
+                    None => Response::new(Version::Http11, StatusCode::BadRequest),
+                }
+            }
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
+
+// /api/v1/vm.receive-migration handler
+pub struct VmReceiveMigration {}
+
+impl EndpointHandler for VmReceiveMigration {
+    fn handle_request(
+        &self,
+        req: &Request,
+        api_notifier: EventFd,
+        api_sender: Sender<ApiRequest>,
+    ) -> Response {
+        match req.method() {
+            Method::Put => {
+                match &req.body {
+                    Some(body) => {
+                        // Deserialize into a VmReceiveMigrationData
+                        let vm_receive_migration_data: VmReceiveMigrationData =
+                            match serde_json::from_slice(body.raw())
+                                .map_err(HttpError::SerdeJsonDeserialize)
+                            {
+                                Ok(config) => config,
+                                Err(e) => return error_response(e, StatusCode::BadRequest),
+                            };
+
+                        // Call vm_receive_migration()
+                        match vm_receive_migration(
+                            api_notifier,
+                            api_sender,
+                            Arc::new(vm_receive_migration_data),
+                        )
+                        .map_err(HttpError::VmReceiveMigration)
+                        {
+                            Ok(_) => Response::new(Version::Http11, StatusCode::NoContent),
+                            Err(e) => error_response(e, StatusCode::InternalServerError),
+                        }
+                    }
+
+                    None => Response::new(Version::Http11, StatusCode::BadRequest),
+                }
+            }
+            _ => Response::new(Version::Http11, StatusCode::BadRequest),
+        }
+    }
+}
@@ -36,11 +36,15 @@ pub use self::http::start_http_thread;
 pub mod http;
 pub mod http_endpoint;
 
-use crate::config::{DeviceConfig, VmConfig};
+use crate::config::{
+    DeviceConfig, DiskConfig, FsConfig, NetConfig, PmemConfig, RestoreConfig, VmConfig, VsockConfig,
+};
 use crate::vm::{Error as VmError, VmState};
+use std::collections::BTreeMap;
 use std::io;
 use std::sync::mpsc::{channel, RecvError, SendError, Sender};
 use std::sync::{Arc, Mutex};
+use vm_virtio::RateLimiterConfig;
 use vmm_sys_util::eventfd::EventFd;
 
 /// API errors are sent back from the VMM API server through the ApiResponse.
@@ -73,6 +77,9 @@ pub enum ApiError {
     /// The VM info is not available.
     VmInfo(VmError),
 
+    /// The VM counters are not available.
+    VmCounters(VmError),
+
     /// The VM config is missing.
     VmMissingConfig,
 
@@ -103,8 +110,53 @@ pub enum ApiError {
     /// The device could not be added to the VM.
     VmAddDevice(VmError),
 
+    /// The disk could not be added to the VM.
+    VmAddDisk(VmError),
+
+    /// The network device could not be added to the VM.
+    VmAddNet(VmError),
+
+    /// The network device could not be removed from the VM.
+    VmRemoveNet(VmError),
+
+    /// The virtio-fs device could not be added to the VM.
+    VmAddFs(VmError),
+
+    /// The virtio-pmem device could not be added to the VM.
+    VmAddPmem(VmError),
+
+    /// The vsock device could not be added to the VM.
+    VmAddVsock(VmError),
+
     /// The device could not be removed from the VM.
     VmRemoveDevice(VmError),
+
+    /// The VM could not be snapshotted.
+    VmSnapshot(VmError),
+
+    /// The disk snapshot could not be created, applied or deleted.
+    VmDiskSnapshot(VmError),
+
+    /// The disk rate limits could not be updated.
+    VmSetDiskRateLimit(VmError),
+
+    /// The network rate limits could not be updated.
+    VmSetNetRateLimit(VmError),
+
+    /// The network link state could not be changed.
+    VmSetNetLink(VmError),
+
+    /// The disk could not be resized.
+    VmResizeDisk(VmError),
+
+    /// The VM could not be restored.
+    VmRestore(VmError),
+
+    /// The VM could not be migrated.
+    VmSendMigration(VmError),
+
+    /// The migrated VM could not be received.
+    VmReceiveMigration(VmError),
 }
 pub type ApiResult<T> = std::result::Result<T, ApiError>;
 
@@ -114,6 +166,9 @@ pub struct VmInfo {
     pub state: VmState,
 }
 
+/// Device and vCPU counters, indexed by device or vCPU name.
+pub type VmCounters = BTreeMap<String, BTreeMap<String, u64>>;
+
 #[derive(Clone, Deserialize, Serialize)]
 pub struct VmmPingResponse {
     pub version: String,
@@ -130,6 +185,69 @@ pub struct VmRemoveDeviceData {
     pub id: String,
 }
 
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VmSnapshotConfig {
+    /// The snapshot destination URL
+    pub destination_url: String,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VmDiskSnapshotData {
+    /// The disk identifier
+    pub id: String,
+    /// The snapshot name, or identifier when applying or deleting it
+    pub name: String,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VmResizeDiskData {
+    /// The disk identifier
+    pub id: String,
+    /// The new size of the disk, in bytes
+    pub desired_size: u64,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VmDiskRateLimitData {
+    /// The disk identifier
+    pub id: String,
+    /// The new bandwidth and IOPS limits, unlimited when missing
+    #[serde(default)]
+    pub rate_limiter_config: RateLimiterConfig,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VmNetRateLimitData {
+    /// The network interface identifier
+    pub id: String,
+    /// The new limits of the received traffic, unlimited when missing
+    #[serde(default)]
+    pub rx_rate_limiter_config: RateLimiterConfig,
+    /// The new limits of the transmitted traffic, unlimited when missing
+    #[serde(default)]
+    pub tx_rate_limiter_config: RateLimiterConfig,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VmNetLinkData {
+    /// The network interface identifier
+    pub id: String,
+    /// Whether the link is reported as up to the guest
+    pub up: bool,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VmSendMigrationData {
+    /// The migration destination URL
+    pub destination_url: String,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VmReceiveMigrationData {
+    /// The URL to listen on for the migrated VM
+    pub receiver_url: String,
+}
+
 pub enum ApiResponsePayload {
     /// No data is sent on the channel.
     Empty,
@@ -137,6 +255,9 @@ pub enum ApiResponsePayload {
     /// Virtual machine information
     VmInfo(VmInfo),
 
+    /// Virtual machine counters
+    VmCounters(VmCounters),
+
     /// Vmm ping response
     VmmPing(VmmPingResponse),
 }
@@ -166,6 +287,11 @@ pub enum ApiRequest {
     /// Request the VM information.
     VmInfo(Sender<ApiResponse>),
 
+    /// Request the VM devices and vCPUs counters.
+    /// If the VM is not running, the VMM API server will send a VmCounters
+    /// error back.
+    VmCounters(Sender<ApiResponse>),
+
     /// Request the VMM API server status
     VmmPing(Sender<ApiResponse>),
 
@@ -196,8 +322,62 @@ pub enum ApiRequest {
     /// Add a device to the VM.
     VmAddDevice(Arc<DeviceConfig>, Sender<ApiResponse>),
 
+    /// Add a disk to the VM.
+    VmAddDisk(Arc<DiskConfig>, Sender<ApiResponse>),
+
+    /// Add a network device to the VM.
+    VmAddNet(Arc<NetConfig>, Sender<ApiResponse>),
+
+    /// Remove a network device from the VM.
+    VmRemoveNet(Arc<VmRemoveDeviceData>, Sender<ApiResponse>),
+
+    /// Add a virtio-fs device to the VM.
+    VmAddFs(Arc<FsConfig>, Sender<ApiResponse>),
+
+    /// Add a virtio-pmem device to the VM.
+    VmAddPmem(Arc<PmemConfig>, Sender<ApiResponse>),
+
+    /// Add a vsock device to the VM.
+    VmAddVsock(Arc<VsockConfig>, Sender<ApiResponse>),
+
     /// Remove a device from the VM.
     VmRemoveDevice(Arc<VmRemoveDeviceData>, Sender<ApiResponse>),
+
+    /// Take a VM snapshot.
+    /// The VM must be paused.
+    VmSnapshot(Arc<VmSnapshotConfig>, Sender<ApiResponse>),
+
+    /// Restore a VM from a snapshot.
+    /// The restored VM is left paused.
+    VmRestore(Arc<RestoreConfig>, Sender<ApiResponse>),
+
+    /// Create, apply or delete an internal snapshot of a qcow2 disk.
+    /// The VM must be paused.
+    VmDiskSnapshot(
+        DiskSnapshotAction,
+        Arc<VmDiskSnapshotData>,
+        Sender<ApiResponse>,
+    ),
+
+    /// Update the bandwidth and IOPS limits of a disk.
+    VmSetDiskRateLimit(Arc<VmDiskRateLimitData>, Sender<ApiResponse>),
+
+    /// Update the bandwidth and packet rate limits of a network interface.
+    VmSetNetRateLimit(Arc<VmNetRateLimitData>, Sender<ApiResponse>),
+
+    /// Set the link state a network interface reports to the guest.
+    VmSetNetLink(Arc<VmNetLinkData>, Sender<ApiResponse>),
+
+    /// Grow a disk, and notify the guest of its new capacity.
+    VmResizeDisk(Arc<VmResizeDiskData>, Sender<ApiResponse>),
+
+    /// Migrate the VM to another VMM.
+    /// The VMM exits once the migration succeeded.
+    VmSendMigration(Arc<VmSendMigrationData>, Sender<ApiResponse>),
+
+    /// Wait for a VM migrated from another VMM.
+    /// The received VM is resumed once the migration is complete.
+    VmReceiveMigration(Arc<VmReceiveMigrationData>, Sender<ApiResponse>),
 }
 
 pub fn vm_create(
@@ -303,6 +483,23 @@ pub fn vm_info(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<Vm
     }
 }
 
+pub fn vm_counters(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<VmCounters> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the VM request.
+    api_sender
+        .send(ApiRequest::VmCounters(response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    let vm_counters = response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    match vm_counters {
+        ApiResponsePayload::VmCounters(counters) => Ok(counters),
+        _ => Err(ApiError::ResponsePayloadType),
+    }
+}
+
 pub fn vmm_ping(api_evt: EventFd, api_sender: Sender<ApiRequest>) -> ApiResult<VmmPingResponse> {
     let (response_sender, response_receiver) = channel();
 
@@ -369,6 +566,114 @@ pub fn vm_add_device(
     Ok(())
 }
 
+pub fn vm_add_disk(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<DiskConfig>,
+) -> ApiResult<()> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the VM add-disk request.
+    api_sender
+        .send(ApiRequest::VmAddDisk(data, response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    Ok(())
+}
+
+pub fn vm_add_net(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<NetConfig>,
+) -> ApiResult<()> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the VM add-net request.
+    api_sender
+        .send(ApiRequest::VmAddNet(data, response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    Ok(())
+}
+
+pub fn vm_add_fs(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<FsConfig>,
+) -> ApiResult<()> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the VM add-fs request.
+    api_sender
+        .send(ApiRequest::VmAddFs(data, response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    Ok(())
+}
+
+pub fn vm_add_pmem(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<PmemConfig>,
+) -> ApiResult<()> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the VM add-pmem request.
+    api_sender
+        .send(ApiRequest::VmAddPmem(data, response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    Ok(())
+}
+
+pub fn vm_add_vsock(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VsockConfig>,
+) -> ApiResult<()> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the VM add-vsock request.
+    api_sender
+        .send(ApiRequest::VmAddVsock(data, response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    Ok(())
+}
+
+pub fn vm_remove_net(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmRemoveDeviceData>,
+) -> ApiResult<()> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the VM remove-net request.
+    api_sender
+        .send(ApiRequest::VmRemoveNet(data, response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    Ok(())
+}
+
 pub fn vm_remove_device(
     api_evt: EventFd,
     api_sender: Sender<ApiRequest>,
@@ -386,3 +691,179 @@ pub fn vm_remove_device(
 
     Ok(())
 }
+
+pub fn vm_snapshot(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmSnapshotConfig>,
+) -> ApiResult<()> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the VM snapshot request.
+    api_sender
+        .send(ApiRequest::VmSnapshot(data, response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    Ok(())
+}
+
+/// Represents an internal disk snapshot operation.
+#[derive(Clone, Copy)]
+pub enum DiskSnapshotAction {
+    /// Create a disk snapshot
+    Create,
+
+    /// Revert a disk to a snapshot
+    Apply,
+
+    /// Delete a disk snapshot
+    Delete,
+}
+
+pub fn vm_disk_snapshot(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    action: DiskSnapshotAction,
+    data: Arc<VmDiskSnapshotData>,
+) -> ApiResult<()> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the VM disk snapshot request.
+    api_sender
+        .send(ApiRequest::VmDiskSnapshot(action, data, response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    Ok(())
+}
+
+pub fn vm_set_disk_rate_limit(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmDiskRateLimitData>,
+) -> ApiResult<()> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the VM disk rate limit request.
+    api_sender
+        .send(ApiRequest::VmSetDiskRateLimit(data, response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    Ok(())
+}
+
+pub fn vm_resize_disk(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmResizeDiskData>,
+) -> ApiResult<()> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the VM disk resizing request.
+    api_sender
+        .send(ApiRequest::VmResizeDisk(data, response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    Ok(())
+}
+
+pub fn vm_set_net_rate_limit(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmNetRateLimitData>,
+) -> ApiResult<()> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the VM network rate limit request.
+    api_sender
+        .send(ApiRequest::VmSetNetRateLimit(data, response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    Ok(())
+}
+
+pub fn vm_set_net_link(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmNetLinkData>,
+) -> ApiResult<()> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the VM network link request.
+    api_sender
+        .send(ApiRequest::VmSetNetLink(data, response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    Ok(())
+}
+
+pub fn vm_restore(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<RestoreConfig>,
+) -> ApiResult<()> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the VM restore request.
+    api_sender
+        .send(ApiRequest::VmRestore(data, response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    Ok(())
+}
+
+pub fn vm_send_migration(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmSendMigrationData>,
+) -> ApiResult<()> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the VM send migration request.
+    api_sender
+        .send(ApiRequest::VmSendMigration(data, response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    Ok(())
+}
+
+pub fn vm_receive_migration(
+    api_evt: EventFd,
+    api_sender: Sender<ApiRequest>,
+    data: Arc<VmReceiveMigrationData>,
+) -> ApiResult<()> {
+    let (response_sender, response_receiver) = channel();
+
+    // Send the VM receive migration request.
+    api_sender
+        .send(ApiRequest::VmReceiveMigration(data, response_sender))
+        .map_err(ApiError::RequestSend)?;
+    api_evt.write(1).map_err(ApiError::EventFdWrite)?;
+
+    response_receiver.recv().map_err(ApiError::ResponseRecv)??;
+
+    Ok(())
+}
@@ -8,11 +8,13 @@ extern crate vm_virtio;
 use clap::ArgMatches;
 use net_util::MacAddr;
 use std::convert::From;
-use std::io;
+use std::fs::File;
+use std::io::{self, BufReader};
 use std::net::AddrParseError;
 use std::net::Ipv4Addr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::result;
+use vm_virtio::{RateLimiterConfig, TokenBucketConfig};
 
 pub const DEFAULT_VCPUS: u8 = 1;
 pub const DEFAULT_MEMORY_MB: u64 = 512;
@@ -45,6 +47,8 @@ pub enum Error {
     ParseDiskPollQueueParam(std::str::ParseBoolError),
     /// Failed parsing disk queue size parameter.
     ParseDiskQueueSizeParam(std::num::ParseIntError),
+    /// Failed parsing bandwidth or IOPS limit parameters.
+    ParseRateLimiterParam(std::num::ParseIntError),
     /// Failed to parse vhost parameters
     ParseDiskVhostParam(std::str::ParseBoolError),
     /// Failed parsing disk wce parameter.
@@ -65,6 +69,11 @@ pub enum Error {
     ParseNetVhostParam(std::str::ParseBoolError),
     /// Need a vhost socket
     ParseNetVhostSocketRequired,
+    /// Failed parsing network host port forwarding parameter.
+    ParseNetPortForwardParam,
+    /// Userspace networking is incompatible with tap, vhost-user and
+    /// multiple queue pairs.
+    ParseNetUserIncompatible,
     /// Failed parsing fs tag parameter.
     ParseFsTagParam,
     /// Failed parsing fs socket path parameter.
@@ -105,6 +114,12 @@ pub enum Error {
     ValidateMissingKernelConfig,
     /// Failed parsing generic on|off parameter.
     ParseOnOff,
+    /// Failed parsing restore source_url parameter.
+    ParseRestoreSourceUrlMissing,
+    /// Failed opening the VM configuration file.
+    OpenConfigFile(io::Error),
+    /// Failed parsing the VM configuration file.
+    ParseConfigFile(serde_json::Error),
 }
 pub type Result<T> = result::Result<T, Error>;
 
@@ -161,7 +176,8 @@ impl<'a> VmParams<'a> {
     }
 }
 
-fn parse_size(size: &str) -> Result<u64> {
+/// Parses a size in bytes, optionally suffixed with K, M or G.
+pub fn parse_size(size: &str) -> Result<u64> {
     let s = size.trim();
 
     let shift = if s.ends_with('K') {
@@ -353,6 +369,12 @@ pub struct DiskConfig {
     pub wce: bool,
     #[serde(default = "default_diskconfig_poll_queue")]
     pub poll_queue: bool,
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub rate_limiter_config: Option<RateLimiterConfig>,
+    #[serde(default = "default_diskconfig_lock")]
+    pub lock: bool,
 }
 
 fn default_diskconfig_num_queues() -> usize {
@@ -371,6 +393,10 @@ fn default_diskconfig_poll_queue() -> bool {
     true
 }
 
+fn default_diskconfig_lock() -> bool {
+    true
+}
+
 impl DiskConfig {
     pub fn parse(disk: &str) -> Result<Self> {
         // Split the parameters based on the comma delimiter
@@ -386,6 +412,8 @@ impl DiskConfig {
         let mut vhost_user_str: &str = "";
         let mut wce_str: &str = "";
         let mut poll_queue_str: &str = "";
+        let mut id_str: &str = "";
+        let mut lock_str: &str = "";
 
         for param in params_list.iter() {
             if param.starts_with("path=") {
@@ -408,6 +436,10 @@ impl DiskConfig {
                 wce_str = &param[4..];
             } else if param.starts_with("poll_queue=") {
                 poll_queue_str = &param[11..];
+            } else if param.starts_with("id=") {
+                id_str = &param[3..];
+            } else if param.starts_with("lock=") {
+                lock_str = &param[5..];
             }
         }
 
@@ -417,6 +449,7 @@ impl DiskConfig {
         let mut vhost_socket = None;
         let mut wce: bool = default_diskconfig_wce();
         let mut poll_queue: bool = default_diskconfig_poll_queue();
+        let mut lock: bool = default_diskconfig_lock();
 
         if !num_queues_str.is_empty() {
             num_queues = num_queues_str
@@ -449,6 +482,29 @@ impl DiskConfig {
                 .map_err(Error::ParseDiskPollQueueParam)?;
         }
 
+        if !lock_str.is_empty() {
+            if vhost_socket.is_some() {
+                warn!("lock parameter has no effect when used with vhost_socket");
+            }
+            lock = parse_on_off(lock_str)?;
+        }
+
+        let id = if !id_str.is_empty() {
+            Some(String::from(id_str))
+        } else {
+            None
+        };
+
+        let rate_limiter_config = parse_rate_limiter_config(disk)?;
+        let rate_limiter_config = if rate_limiter_config != RateLimiterConfig::default() {
+            if vhost_user {
+                warn!("bw and iops parameters currently have no effect when used vhost_user=true");
+            }
+            Some(rate_limiter_config)
+        } else {
+            None
+        };
+
         Ok(DiskConfig {
             path: PathBuf::from(path_str),
             readonly: parse_on_off(readonly_str)?,
@@ -460,10 +516,83 @@ impl DiskConfig {
             vhost_user,
             wce,
             poll_queue,
+            id,
+            rate_limiter_config,
+            lock,
         })
     }
 }
 
+/// Parses bandwidth and IOPS limits, given as
+/// "bw=<bytes_per_second>,bw_burst=<bytes>,iops=<operations_per_second>,iops_burst=<operations>".
+/// Missing rates are not limited.
+pub fn parse_rate_limiter_config(limits: &str) -> Result<RateLimiterConfig> {
+    parse_rate_limits(limits, "bw", "iops")
+}
+
+/// Parses the limits of the traffic received ("rx") or transmitted ("tx") by
+/// a network interface, given as
+/// "rx_bw=<bytes_per_second>,rx_bw_burst=<bytes>,rx_pps=<packets_per_second>,rx_pps_burst=<packets>".
+/// Missing rates are not limited.
+pub fn parse_net_rate_limiter_config(limits: &str, direction: &str) -> Result<RateLimiterConfig> {
+    parse_rate_limits(
+        limits,
+        &format!("{}_bw", direction),
+        &format!("{}_pps", direction),
+    )
+}
+
+// Parses the "<bw>", "<bw>_burst", "<ops>" and "<ops>_burst" parameters.
+fn parse_rate_limits(limits: &str, bw: &str, ops: &str) -> Result<RateLimiterConfig> {
+    let bw_burst = format!("{}_burst", bw);
+    let ops_burst = format!("{}_burst", ops);
+
+    let mut bw_str: &str = "";
+    let mut bw_burst_str: &str = "";
+    let mut ops_str: &str = "";
+    let mut ops_burst_str: &str = "";
+
+    for param in limits.split(',') {
+        if let Some(pos) = param.find('=') {
+            let (key, value) = (&param[..pos], &param[pos + 1..]);
+            if key == bw {
+                bw_str = value;
+            } else if key == bw_burst {
+                bw_burst_str = value;
+            } else if key == ops {
+                ops_str = value;
+            } else if key == ops_burst {
+                ops_burst_str = value;
+            }
+        }
+    }
+
+    Ok(RateLimiterConfig {
+        bandwidth: parse_token_bucket(bw_str, bw_burst_str)?,
+        ops: parse_token_bucket(ops_str, ops_burst_str)?,
+    })
+}
+
+// Parses a rate (per second) and an optional burst size into a token bucket
+// configuration. No rate means no limit.
+fn parse_token_bucket(rate: &str, burst: &str) -> Result<Option<TokenBucketConfig>> {
+    if rate.is_empty() {
+        if !burst.is_empty() {
+            warn!("burst parameter ignored without the matching rate");
+        }
+        return Ok(None);
+    }
+
+    let rate = rate.parse().map_err(Error::ParseRateLimiterParam)?;
+    let burst = if !burst.is_empty() {
+        burst.parse().map_err(Error::ParseRateLimiterParam)?
+    } else {
+        0
+    };
+
+    Ok(Some(TokenBucketConfig { rate, burst }))
+}
+
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct NetConfig {
     #[serde(default = "default_netconfig_tap")]
@@ -483,6 +612,18 @@ pub struct NetConfig {
     #[serde(default)]
     pub vhost_user: bool,
     pub vhost_socket: Option<String>,
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(default)]
+    pub rx_rate_limiter_config: Option<RateLimiterConfig>,
+    #[serde(default)]
+    pub tx_rate_limiter_config: Option<RateLimiterConfig>,
+    #[serde(default)]
+    pub user: bool,
+    #[serde(default)]
+    pub port_forwards: Vec<PortForwardConfig>,
+    #[serde(default)]
+    pub host_loopback: bool,
 }
 
 fn default_netconfig_tap() -> Option<String> {
@@ -523,6 +664,10 @@ impl NetConfig {
         let mut queue_size_str: &str = "";
         let mut vhost_socket_str: &str = "";
         let mut vhost_user_str: &str = "";
+        let mut id_str: &str = "";
+        let mut user_str: &str = "";
+        let mut host_loopback_str: &str = "";
+        let mut port_forward_strs: Vec<&str> = Vec::new();
 
         for param in params_list.iter() {
             if param.starts_with("tap=") {
@@ -543,6 +688,14 @@ impl NetConfig {
                 vhost_user_str = &param[11..];
             } else if param.starts_with("socket=") {
                 vhost_socket_str = &param[7..];
+            } else if param.starts_with("id=") {
+                id_str = &param[3..];
+            } else if param.starts_with("user=") {
+                user_str = &param[5..];
+            } else if param.starts_with("hostfwd=") {
+                port_forward_strs.push(&param[8..]);
+            } else if param.starts_with("host_loopback=") {
+                host_loopback_str = &param[14..];
             }
         }
 
@@ -585,6 +738,38 @@ impl NetConfig {
             vhost_socket = Some(vhost_socket_str.to_owned());
         }
 
+        let id = if !id_str.is_empty() {
+            Some(String::from(id_str))
+        } else {
+            None
+        };
+
+        let rx_rate_limiter_config = Some(parse_net_rate_limiter_config(net, "rx")?)
+            .filter(|config| *config != RateLimiterConfig::default());
+        let tx_rate_limiter_config = Some(parse_net_rate_limiter_config(net, "tx")?)
+            .filter(|config| *config != RateLimiterConfig::default());
+        if vhost_socket.is_some()
+            && (rx_rate_limiter_config.is_some() || tx_rate_limiter_config.is_some())
+        {
+            warn!("rx and tx limits have no effect on an external vhost-user-net backend");
+        }
+
+        let user = parse_on_off(user_str)?;
+        let port_forwards = port_forward_strs
+            .iter()
+            .map(|port_forward| PortForwardConfig::parse(port_forward))
+            .collect::<Result<Vec<PortForwardConfig>>>()?;
+        if user && (tap.is_some() || vhost_user || num_queues != 2) {
+            return Err(Error::ParseNetUserIncompatible);
+        }
+        if !user && !port_forwards.is_empty() {
+            warn!("hostfwd parameters have no effect without user=on");
+        }
+        let host_loopback = parse_on_off(host_loopback_str)?;
+        if !user && host_loopback {
+            warn!("host_loopback parameter has no effect without user=on");
+        }
+
         Ok(NetConfig {
             tap,
             ip,
@@ -595,6 +780,73 @@ impl NetConfig {
             queue_size,
             vhost_user,
             vhost_socket,
+            id,
+            rx_rate_limiter_config,
+            tx_rate_limiter_config,
+            user,
+            port_forwards,
+            host_loopback,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum PortForwardProtocol {
+    Tcp,
+    Udp,
+}
+
+/// Forwarding of a host port to the guest, through the userspace network
+/// backend.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct PortForwardConfig {
+    pub protocol: PortForwardProtocol,
+    #[serde(default = "default_portforwardconfig_host_ip")]
+    pub host_ip: Ipv4Addr,
+    pub host_port: u16,
+    pub guest_port: u16,
+}
+
+fn default_portforwardconfig_host_ip() -> Ipv4Addr {
+    Ipv4Addr::LOCALHOST
+}
+
+impl PortForwardConfig {
+    /// Parses "<tcp|udp>:[<host_ip>:]<host_port>:<guest_port>", the host
+    /// port being only reachable through the loopback interface when no host
+    /// address is given.
+    pub fn parse(port_forward: &str) -> Result<Self> {
+        let params: Vec<&str> = port_forward.split(':').collect();
+        let (protocol_str, host_ip_str, host_port_str, guest_port_str) = match params.len() {
+            3 => (params[0], "", params[1], params[2]),
+            4 => (params[0], params[1], params[2], params[3]),
+            _ => return Err(Error::ParseNetPortForwardParam),
+        };
+
+        let protocol = match protocol_str {
+            "tcp" => PortForwardProtocol::Tcp,
+            "udp" => PortForwardProtocol::Udp,
+            _ => return Err(Error::ParseNetPortForwardParam),
+        };
+        let host_ip = if !host_ip_str.is_empty() {
+            host_ip_str
+                .parse()
+                .map_err(|_| Error::ParseNetPortForwardParam)?
+        } else {
+            default_portforwardconfig_host_ip()
+        };
+        let host_port = host_port_str
+            .parse()
+            .map_err(|_| Error::ParseNetPortForwardParam)?;
+        let guest_port = guest_port_str
+            .parse()
+            .map_err(|_| Error::ParseNetPortForwardParam)?;
+
+        Ok(PortForwardConfig {
+            protocol,
+            host_ip,
+            host_port,
+            guest_port,
         })
     }
 }
@@ -650,6 +902,8 @@ pub struct FsConfig {
     pub dax: bool,
     #[serde(default = "default_fsconfig_cache_size")]
     pub cache_size: u64,
+    #[serde(default)]
+    pub id: Option<String>,
 }
 
 fn default_fsconfig_num_queues() -> usize {
@@ -679,6 +933,7 @@ impl FsConfig {
         let mut queue_size_str: &str = "";
         let mut dax_str: &str = "";
         let mut cache_size_str: &str = "";
+        let mut id_str: &str = "";
 
         for param in params_list.iter() {
             if param.starts_with("tag=") {
@@ -693,6 +948,8 @@ impl FsConfig {
                 dax_str = &param[4..];
             } else if param.starts_with("cache_size=") {
                 cache_size_str = &param[11..];
+            } else if param.starts_with("id=") {
+                id_str = &param[3..];
             }
         }
 
@@ -737,6 +994,12 @@ impl FsConfig {
             cache_size = parse_size(cache_size_str)?;
         }
 
+        let id = if !id_str.is_empty() {
+            Some(String::from(id_str))
+        } else {
+            None
+        };
+
         Ok(FsConfig {
             tag: tag.to_string(),
             sock: PathBuf::from(sock),
@@ -744,6 +1007,7 @@ impl FsConfig {
             queue_size,
             dax,
             cache_size,
+            id,
         })
     }
 }
@@ -756,6 +1020,8 @@ pub struct PmemConfig {
     pub iommu: bool,
     #[serde(default)]
     pub mergeable: bool,
+    #[serde(default)]
+    pub id: Option<String>,
 }
 
 impl PmemConfig {
@@ -767,6 +1033,7 @@ impl PmemConfig {
         let mut size_str: &str = "";
         let mut iommu_str: &str = "";
         let mut mergeable_str: &str = "";
+        let mut id_str: &str = "";
 
         for param in params_list.iter() {
             if param.starts_with("file=") {
@@ -777,6 +1044,8 @@ impl PmemConfig {
                 iommu_str = &param[6..];
             } else if param.starts_with("mergeable=") {
                 mergeable_str = &param[10..];
+            } else if param.starts_with("id=") {
+                id_str = &param[3..];
             }
         }
 
@@ -784,11 +1053,18 @@ impl PmemConfig {
             return Err(Error::ParsePmemFileParam);
         }
 
+        let id = if !id_str.is_empty() {
+            Some(String::from(id_str))
+        } else {
+            None
+        };
+
         Ok(PmemConfig {
             file: PathBuf::from(file_str),
             size: parse_size(size_str)?,
             iommu: parse_on_off(iommu_str)?,
             mergeable: parse_on_off(mergeable_str)?,
+            id,
         })
     }
 }
@@ -932,6 +1208,8 @@ pub struct VsockConfig {
     pub sock: PathBuf,
     #[serde(default)]
     pub iommu: bool,
+    #[serde(default)]
+    pub id: Option<String>,
 }
 
 impl VsockConfig {
@@ -942,6 +1220,7 @@ impl VsockConfig {
         let mut cid_str: &str = "";
         let mut sock_str: &str = "";
         let mut iommu_str: &str = "";
+        let mut id_str: &str = "";
 
         for param in params_list.iter() {
             if param.starts_with("cid=") {
@@ -950,6 +1229,8 @@ impl VsockConfig {
                 sock_str = &param[5..];
             } else if param.starts_with("iommu=") {
                 iommu_str = &param[6..];
+            } else if param.starts_with("id=") {
+                id_str = &param[3..];
             }
         }
 
@@ -957,10 +1238,45 @@ impl VsockConfig {
             return Err(Error::ParseVsockSockParam);
         }
 
+        let id = if !id_str.is_empty() {
+            Some(String::from(id_str))
+        } else {
+            None
+        };
+
         Ok(VsockConfig {
             cid: cid_str.parse::<u64>().map_err(Error::ParseVsockCidParam)?,
             sock: PathBuf::from(sock_str),
             iommu: parse_on_off(iommu_str)?,
+            id,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct RestoreConfig {
+    pub source_url: String,
+}
+
+impl RestoreConfig {
+    pub fn parse(restore: &str) -> Result<Self> {
+        // Split the parameters based on the comma delimiter
+        let params_list: Vec<&str> = restore.split(',').collect();
+
+        let mut source_url_str: &str = "";
+
+        for param in params_list.iter() {
+            if param.starts_with("source_url=") {
+                source_url_str = &param[11..];
+            }
+        }
+
+        if source_url_str.is_empty() {
+            return Err(Error::ParseRestoreSourceUrlMissing);
+        }
+
+        Ok(RestoreConfig {
+            source_url: String::from(source_url_str),
         })
     }
 }
@@ -995,6 +1311,32 @@ impl VmConfig {
         self.kernel.is_some()
     }
 
+    /// Loads a complete VM configuration from a JSON file. The file format
+    /// is the same as the `/vm.create` request body.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let file = File::open(path).map_err(Error::OpenConfigFile)?;
+        let mut config: VmConfig =
+            serde_json::from_reader(BufReader::new(file)).map_err(Error::ParseConfigFile)?;
+
+        if config.console.mode == ConsoleOutputMode::Tty
+            && config.serial.mode == ConsoleOutputMode::Tty
+        {
+            return Err(Error::ParseTTYParam);
+        }
+
+        // As with the command line, any device placed behind the virtual
+        // IOMMU requires the virtual IOMMU to be created.
+        config.iommu |= config.rng.iommu
+            || config.console.iommu
+            || config.disks.iter().flatten().any(|d| d.iommu)
+            || config.net.iter().flatten().any(|n| n.iommu)
+            || config.pmem.iter().flatten().any(|p| p.iommu)
+            || config.devices.iter().flatten().any(|d| d.iommu)
+            || config.vsock.iter().flatten().any(|v| v.iommu);
+
+        Ok(config)
+    }
+
     pub fn parse(vm_params: VmParams) -> Result<Self> {
         let mut iommu = false;
 
@@ -11,19 +11,23 @@
 use crate::device_manager::DeviceManager;
 #[cfg(feature = "acpi")]
 use acpi_tables::{aml, aml::Aml, sdt::SDT};
+use anyhow::anyhow;
 #[cfg(feature = "acpi")]
 use arch::layout;
 use devices::{ioapic, BusDevice};
-use kvm_bindings::CpuId;
+use kvm_bindings::{kvm_msr_entry, CpuId, Msrs};
 use kvm_ioctls::*;
 use libc::{c_void, siginfo_t};
 use std::cmp;
+use std::collections::BTreeMap;
 use std::os::unix::thread::JoinHandleExt;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Barrier, Mutex};
 use std::thread;
 use std::{fmt, io, result};
-use vm_device::{Migratable, MigratableError, Pausable, Snapshotable};
+use vm_device::{
+    Migratable, MigratableError, Pausable, Snapshot, SnapshotDataSection, Snapshotable,
+};
 use vm_memory::{Address, GuestAddress, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap};
 use vmm_sys_util::eventfd::EventFd;
 use vmm_sys_util::signal::{register_signal_handler, SIGRTMIN};
@@ -118,6 +122,15 @@ pub enum Error {
 
     /// Asking for more vCPUs that we can have
     DesiredVCPUCountExceedsMax,
+
+    /// Cannot read the vCPU state.
+    VcpuGetState(kvm_ioctls::Error),
+
+    /// Cannot set the vCPU state.
+    VcpuSetState(kvm_ioctls::Error),
+
+    /// The saved vCPU state is invalid.
+    InvalidVcpuState,
 }
 pub type Result<T> = result::Result<T, Error>;
 
@@ -229,6 +242,88 @@ struct InterruptSourceOverride {
     pub flags: u16,
 }
 
+fn kvm_struct_to_bytes<T: Copy>(kvm_struct: &T) -> Vec<u8> {
+    // Safe because KVM structures are plain old data, and we only read
+    // size_of::<T>() bytes from a valid reference.
+    unsafe {
+        std::slice::from_raw_parts(
+            kvm_struct as *const T as *const u8,
+            std::mem::size_of::<T>(),
+        )
+    }
+    .to_vec()
+}
+
+fn kvm_struct_from_bytes<T: Copy>(bytes: &[u8]) -> Result<T> {
+    if bytes.len() != std::mem::size_of::<T>() {
+        return Err(Error::InvalidVcpuState);
+    }
+
+    // Safe because KVM structures are plain old data, and we checked the
+    // buffer is exactly the size of the structure.
+    unsafe {
+        let mut kvm_struct: T = std::mem::zeroed();
+        std::ptr::copy_nonoverlapping(
+            bytes.as_ptr(),
+            &mut kvm_struct as *mut T as *mut u8,
+            bytes.len(),
+        );
+        Ok(kvm_struct)
+    }
+}
+
+/// The KVM state of a vCPU, as saved in a vCPU snapshot.
+/// KVM structures are stored as raw bytes since they are plain C structures.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct VcpuKvmState {
+    msrs: Vec<(u32, u64)>,
+    regs: Vec<u8>,
+    sregs: Vec<u8>,
+    fpu: Vec<u8>,
+    lapic: Vec<u8>,
+    xsave: Vec<u8>,
+    xcrs: Vec<u8>,
+    mp_state: Vec<u8>,
+}
+
+/// Number of vCPU exits, per exit reason.
+#[derive(Default)]
+struct VcpuCounters {
+    io_in: AtomicU64,
+    io_out: AtomicU64,
+    mmio_read: AtomicU64,
+    mmio_write: AtomicU64,
+    ioapic_eoi: AtomicU64,
+    shutdown: AtomicU64,
+}
+
+impl VcpuCounters {
+    fn values(&self) -> BTreeMap<String, u64> {
+        let mut values = BTreeMap::new();
+
+        values.insert("io_in".to_string(), self.io_in.load(Ordering::Relaxed));
+        values.insert("io_out".to_string(), self.io_out.load(Ordering::Relaxed));
+        values.insert(
+            "mmio_read".to_string(),
+            self.mmio_read.load(Ordering::Relaxed),
+        );
+        values.insert(
+            "mmio_write".to_string(),
+            self.mmio_write.load(Ordering::Relaxed),
+        );
+        values.insert(
+            "ioapic_eoi".to_string(),
+            self.ioapic_eoi.load(Ordering::Relaxed),
+        );
+        values.insert(
+            "shutdown".to_string(),
+            self.shutdown.load(Ordering::Relaxed),
+        );
+
+        values
+    }
+}
+
 /// A wrapper around creating and using a kvm-based VCPU.
 pub struct Vcpu {
     fd: VcpuFd,
@@ -237,6 +332,7 @@ pub struct Vcpu {
     mmio_bus: Arc<devices::Bus>,
     ioapic: Option<Arc<Mutex<ioapic::Ioapic>>>,
     vm_ts: std::time::Instant,
+    counters: Arc<VcpuCounters>,
 }
 
 impl Vcpu {
@@ -263,10 +359,11 @@ impl Vcpu {
             mmio_bus,
             ioapic,
             vm_ts: creation_ts,
+            counters: Arc::new(VcpuCounters::default()),
         })
     }
 
-    /// Configures a x86_64 specific vcpu and should be called once per vcpu from the vcpu's thread.
+    /// Configures a x86_64 specific vcpu and should be called once per vcpu.
     ///
     /// # Arguments
     ///
@@ -311,10 +408,12 @@ impl Vcpu {
         match self.fd.run() {
             Ok(run) => match run {
                 VcpuExit::IoIn(addr, data) => {
+                    self.counters.io_in.fetch_add(1, Ordering::Relaxed);
                     self.io_bus.read(u64::from(addr), data);
                     Ok(true)
                 }
                 VcpuExit::IoOut(addr, data) => {
+                    self.counters.io_out.fetch_add(1, Ordering::Relaxed);
                     if addr == DEBUG_IOPORT && data.len() == 1 {
                         self.log_debug_ioport(data[0]);
                     }
@@ -322,20 +421,24 @@ impl Vcpu {
                     Ok(true)
                 }
                 VcpuExit::MmioRead(addr, data) => {
+                    self.counters.mmio_read.fetch_add(1, Ordering::Relaxed);
                     self.mmio_bus.read(addr as u64, data);
                     Ok(true)
                 }
                 VcpuExit::MmioWrite(addr, data) => {
+                    self.counters.mmio_write.fetch_add(1, Ordering::Relaxed);
                     self.mmio_bus.write(addr as u64, data);
                     Ok(true)
                 }
                 VcpuExit::IoapicEoi(vector) => {
+                    self.counters.ioapic_eoi.fetch_add(1, Ordering::Relaxed);
                     if let Some(ioapic) = &self.ioapic {
                         ioapic.lock().unwrap().end_of_interrupt(vector);
                     }
                     Ok(true)
                 }
                 VcpuExit::Shutdown => {
+                    self.counters.shutdown.fetch_add(1, Ordering::Relaxed);
                     // Triple fault to trigger a reboot
                     Ok(false)
                 }
@@ -355,6 +458,73 @@ impl Vcpu {
         }
     }
 
+    /// Saves the vCPU KVM state.
+    ///
+    /// The vCPU must not be running when its state is saved.
+    pub fn state(&self) -> Result<VcpuKvmState> {
+        let mut msrs = arch::x86_64::regs::create_msr_entries();
+        let nmsrs = self.fd.get_msrs(&mut msrs).map_err(Error::VcpuGetState)?;
+        let msrs = msrs.as_slice()[..nmsrs]
+            .iter()
+            .map(|entry| (entry.index, entry.data))
+            .collect();
+
+        Ok(VcpuKvmState {
+            msrs,
+            regs: kvm_struct_to_bytes(&self.fd.get_regs().map_err(Error::VcpuGetState)?),
+            sregs: kvm_struct_to_bytes(&self.fd.get_sregs().map_err(Error::VcpuGetState)?),
+            fpu: kvm_struct_to_bytes(&self.fd.get_fpu().map_err(Error::VcpuGetState)?),
+            lapic: kvm_struct_to_bytes(&self.fd.get_lapic().map_err(Error::VcpuGetState)?),
+            xsave: kvm_struct_to_bytes(&self.fd.get_xsave().map_err(Error::VcpuGetState)?),
+            xcrs: kvm_struct_to_bytes(&self.fd.get_xcrs().map_err(Error::VcpuGetState)?),
+            mp_state: kvm_struct_to_bytes(&self.fd.get_mp_state().map_err(Error::VcpuGetState)?),
+        })
+    }
+
+    /// Restores a previously saved vCPU KVM state.
+    ///
+    /// The special registers must be set before the LAPIC, as the LAPIC
+    /// base address is part of them.
+    pub fn set_state(&self, state: &VcpuKvmState) -> Result<()> {
+        self.fd
+            .set_sregs(&kvm_struct_from_bytes(&state.sregs)?)
+            .map_err(Error::VcpuSetState)?;
+        self.fd
+            .set_regs(&kvm_struct_from_bytes(&state.regs)?)
+            .map_err(Error::VcpuSetState)?;
+        self.fd
+            .set_fpu(&kvm_struct_from_bytes(&state.fpu)?)
+            .map_err(Error::VcpuSetState)?;
+        self.fd
+            .set_xsave(&kvm_struct_from_bytes(&state.xsave)?)
+            .map_err(Error::VcpuSetState)?;
+        self.fd
+            .set_xcrs(&kvm_struct_from_bytes(&state.xcrs)?)
+            .map_err(Error::VcpuSetState)?;
+
+        let msr_entries: Vec<kvm_msr_entry> = state
+            .msrs
+            .iter()
+            .map(|(index, data)| kvm_msr_entry {
+                index: *index,
+                data: *data,
+                ..Default::default()
+            })
+            .collect();
+        self.fd
+            .set_msrs(&Msrs::from_entries(&msr_entries))
+            .map_err(Error::VcpuSetState)?;
+
+        self.fd
+            .set_lapic(&kvm_struct_from_bytes(&state.lapic)?)
+            .map_err(Error::VcpuSetState)?;
+        self.fd
+            .set_mp_state(kvm_struct_from_bytes(&state.mp_state)?)
+            .map_err(Error::VcpuSetState)?;
+
+        Ok(())
+    }
+
     // Log debug io port codes.
     fn log_debug_ioport(&self, code: u8) {
         let ts = self.vm_ts.elapsed();
@@ -460,6 +630,8 @@ struct VcpuState {
     removing: bool,
     handle: Option<thread::JoinHandle<()>>,
     kill: Arc<AtomicBool>,
+    vcpu: Option<Arc<Mutex<Vcpu>>>,
+    counters: Option<Arc<VcpuCounters>>,
 }
 
 impl VcpuState {
@@ -537,6 +709,115 @@ impl CpuManager {
         Ok(cpu_manager)
     }
 
+    fn create_vcpu(
+        &self,
+        cpu_id: u8,
+        entry_addr: Option<GuestAddress>,
+        creation_ts: std::time::Instant,
+    ) -> Result<Vcpu> {
+        let ioapic = if let Some(ioapic) = &self.ioapic {
+            Some(ioapic.clone())
+        } else {
+            None
+        };
+
+        let mut vcpu = Vcpu::new(
+            cpu_id,
+            &self.fd,
+            self.io_bus.clone(),
+            self.mmio_bus.clone(),
+            ioapic,
+            creation_ts,
+        )?;
+
+        vcpu.configure(entry_addr, &self.vm_memory, self.cpuid.clone())?;
+
+        Ok(vcpu)
+    }
+
+    fn start_vcpu(
+        &mut self,
+        vcpu: Vcpu,
+        vcpu_thread_barrier: Arc<Barrier>,
+        inserting: bool,
+    ) -> Result<()> {
+        let cpu_id = vcpu.id;
+        let counters = vcpu.counters.clone();
+        let vcpu = Arc::new(Mutex::new(vcpu));
+        let vcpu_clone = vcpu.clone();
+
+        let reset_evt = self.reset_evt.try_clone().unwrap();
+        let vcpu_kill_signalled = self.vcpus_kill_signalled.clone();
+        let vcpu_pause_signalled = self.vcpus_pause_signalled.clone();
+
+        let vcpu_kill = self.vcpu_states[usize::from(cpu_id)].kill.clone();
+
+        let handle = Some(
+            thread::Builder::new()
+                .name(format!("vcpu{}", cpu_id))
+                .spawn(move || {
+                    extern "C" fn handle_signal(_: i32, _: *mut siginfo_t, _: *mut c_void) {}
+                    // This uses an async signal safe handler to kill the vcpu handles.
+                    register_signal_handler(SIGRTMIN(), handle_signal)
+                        .expect("Failed to register vcpu signal handler");
+
+                    // Block until all CPUs are ready.
+                    vcpu_thread_barrier.wait();
+
+                    // Restored vCPUs are started paused, and wait for the
+                    // VM to be resumed before running.
+                    while vcpu_pause_signalled.load(Ordering::SeqCst) {
+                        thread::park();
+                    }
+
+                    loop {
+                        // vcpu.run() returns false on a KVM_EXIT_SHUTDOWN (triple-fault) so trigger a reset
+                        match vcpu.lock().unwrap().run() {
+                            Err(e) => {
+                                error!("VCPU generated error: {:?}", e);
+                                event!("vcpu", "error", "id", cpu_id.to_string());
+                                break;
+                            }
+                            Ok(true) => {}
+                            Ok(false) => {
+                                reset_evt.write(1).unwrap();
+                                break;
+                            }
+                        }
+
+                        // We've been told to terminate
+                        if vcpu_kill_signalled.load(Ordering::SeqCst)
+                            || vcpu_kill.load(Ordering::SeqCst)
+                        {
+                            break;
+                        }
+
+                        // If we are being told to pause, we park the thread
+                        // until the pause boolean is toggled.
+                        // The resume operation is responsible for toggling
+                        // the boolean and unpark the thread.
+                        // We enter a loop because park() could spuriously
+                        // return. We will then park() again unless the
+                        // pause boolean has been toggled.
+                        while vcpu_pause_signalled.load(Ordering::SeqCst) {
+                            thread::park();
+                        }
+                    }
+                })
+                .map_err(Error::VcpuSpawn)?,
+        );
+
+        // On hot plug calls into this function entry_addr is None. It is for
+        // those hotplug CPU additions that we need to set the inserting flag.
+        let state = &mut self.vcpu_states[usize::from(cpu_id)];
+        state.handle = handle;
+        state.inserting = inserting;
+        state.vcpu = Some(vcpu_clone);
+        state.counters = Some(counters);
+
+        Ok(())
+    }
+
     fn activate_vcpus(
         &mut self,
         desired_vcpus: u8,
@@ -552,86 +833,8 @@ impl CpuManager {
         ));
 
         for cpu_id in self.present_vcpus()..desired_vcpus {
-            let ioapic = if let Some(ioapic) = &self.ioapic {
-                Some(ioapic.clone())
-            } else {
-                None
-            };
-
-            let mut vcpu = Vcpu::new(
-                cpu_id,
-                &self.fd,
-                self.io_bus.clone(),
-                self.mmio_bus.clone(),
-                ioapic,
-                creation_ts,
-            )?;
-
-            let vcpu_thread_barrier = vcpu_thread_barrier.clone();
-
-            let reset_evt = self.reset_evt.try_clone().unwrap();
-            let vcpu_kill_signalled = self.vcpus_kill_signalled.clone();
-            let vcpu_pause_signalled = self.vcpus_pause_signalled.clone();
-
-            let vcpu_kill = self.vcpu_states[usize::from(cpu_id)].kill.clone();
-            let vm_memory = self.vm_memory.clone();
-            let cpuid = self.cpuid.clone();
-
-            let handle = Some(
-                thread::Builder::new()
-                    .name(format!("vcpu{}", vcpu.id))
-                    .spawn(move || {
-                        extern "C" fn handle_signal(_: i32, _: *mut siginfo_t, _: *mut c_void) {}
-                        // This uses an async signal safe handler to kill the vcpu handles.
-                        register_signal_handler(SIGRTMIN(), handle_signal)
-                            .expect("Failed to register vcpu signal handler");
-
-                        vcpu.configure(entry_addr, &vm_memory, cpuid)
-                            .expect("Failed to configure vCPU");
-
-                        // Block until all CPUs are ready.
-                        vcpu_thread_barrier.wait();
-
-                        loop {
-                            // vcpu.run() returns false on a KVM_EXIT_SHUTDOWN (triple-fault) so trigger a reset
-                            match vcpu.run() {
-                                Err(e) => {
-                                    error!("VCPU generated error: {:?}", e);
-                                    break;
-                                }
-                                Ok(true) => {}
-                                Ok(false) => {
-                                    reset_evt.write(1).unwrap();
-                                    break;
-                                }
-                            }
-
-                            // We've been told to terminate
-                            if vcpu_kill_signalled.load(Ordering::SeqCst)
-                                || vcpu_kill.load(Ordering::SeqCst)
-                            {
-                                break;
-                            }
-
-                            // If we are being told to pause, we park the thread
-                            // until the pause boolean is toggled.
-                            // The resume operation is responsible for toggling
-                            // the boolean and unpark the thread.
-                            // We enter a loop because park() could spuriously
-                            // return. We will then park() again unless the
-                            // pause boolean has been toggled.
-                            while vcpu_pause_signalled.load(Ordering::SeqCst) {
-                                thread::park();
-                            }
-                        }
-                    })
-                    .map_err(Error::VcpuSpawn)?,
-            );
-
-            // On hot plug calls into this function entry_addr is None. It is for
-            // those hotplug CPU additions that we need to set the inserting flag.
-            self.vcpu_states[usize::from(cpu_id)].handle = handle;
-            self.vcpu_states[usize::from(cpu_id)].inserting = entry_addr.is_none();
+            let vcpu = self.create_vcpu(cpu_id, entry_addr, creation_ts)?;
+            self.start_vcpu(vcpu, vcpu_thread_barrier.clone(), entry_addr.is_none())?;
         }
 
         // Unblock all CPU threads.
@@ -653,6 +856,8 @@ impl CpuManager {
         state.signal_thread();
         state.join_thread()?;
         state.handle = None;
+        state.vcpu = None;
+        state.counters = None;
         Ok(())
     }
 
@@ -696,6 +901,19 @@ impl CpuManager {
         self.max_vcpus
     }
 
+    /// Returns the exit counters of all the running vCPUs.
+    pub fn counters(&self) -> BTreeMap<String, BTreeMap<String, u64>> {
+        let mut counters = BTreeMap::new();
+
+        for (cpu_id, state) in self.vcpu_states.iter().enumerate() {
+            if let Some(vcpu_counters) = &state.counters {
+                counters.insert(format!("vcpu{}", cpu_id), vcpu_counters.values());
+            }
+        }
+
+        counters
+    }
+
     fn present_vcpus(&self) -> u8 {
         self.vcpu_states
             .iter()
@@ -1064,5 +1282,92 @@ impl Pausable for CpuManager {
     }
 }
 
-impl Snapshotable for CpuManager {}
+const CPU_MANAGER_SNAPSHOT_ID: &str = "cpu-manager";
+
+impl Snapshotable for Vcpu {
+    fn id(&self) -> String {
+        format!("vcpu{}", self.id)
+    }
+
+    fn snapshot(&self) -> std::result::Result<Snapshot, MigratableError> {
+        let state = self
+            .state()
+            .map_err(|e| MigratableError::Snapshot(anyhow!("Could not get vCPU state {:?}", e)))?;
+        let snapshot =
+            serde_json::to_vec(&state).map_err(|e| MigratableError::Snapshot(e.into()))?;
+
+        let mut vcpu_snapshot = Snapshot::new(&self.id());
+        vcpu_snapshot.add_data_section(SnapshotDataSection {
+            id: format!("{}-section", self.id()),
+            snapshot,
+        });
+
+        Ok(vcpu_snapshot)
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> std::result::Result<(), MigratableError> {
+        if let Some(vcpu_section) = snapshot
+            .snapshot_data
+            .get(&format!("{}-section", self.id()))
+        {
+            let state: VcpuKvmState = serde_json::from_slice(&vcpu_section.snapshot)
+                .map_err(|e| MigratableError::Restore(e.into()))?;
+
+            self.set_state(&state)
+                .map_err(|e| MigratableError::Restore(anyhow!("Could not set vCPU state {:?}", e)))
+        } else {
+            Err(MigratableError::Restore(anyhow!(
+                "Could not find vCPU snapshot section"
+            )))
+        }
+    }
+}
+
+impl Snapshotable for CpuManager {
+    fn id(&self) -> String {
+        CPU_MANAGER_SNAPSHOT_ID.to_string()
+    }
+
+    fn snapshot(&self) -> std::result::Result<Snapshot, MigratableError> {
+        let mut cpu_manager_snapshot = Snapshot::new(&self.id());
+
+        // The vCPU threads are parked outside of KVM_RUN, so we can take
+        // each vCPU lock and save its state.
+        for state in self.vcpu_states.iter() {
+            if let Some(vcpu) = &state.vcpu {
+                cpu_manager_snapshot.add_snapshot(vcpu.lock().unwrap().snapshot()?);
+            }
+        }
+
+        Ok(cpu_manager_snapshot)
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> std::result::Result<(), MigratableError> {
+        // The restored vCPUs must not run until the VM is explicitly resumed.
+        self.vcpus_pause_signalled.store(true, Ordering::SeqCst);
+
+        let creation_ts = std::time::Instant::now();
+        let vcpu_thread_barrier = Arc::new(Barrier::new(snapshot.snapshots.len() + 1));
+
+        for cpu_id in 0..self.max_vcpus {
+            let vcpu_snapshot = match snapshot.snapshots.get(&format!("vcpu{}", cpu_id)) {
+                Some(vcpu_snapshot) => vcpu_snapshot,
+                None => continue,
+            };
+
+            let mut vcpu = self
+                .create_vcpu(cpu_id, None, creation_ts)
+                .map_err(|e| MigratableError::Restore(anyhow!("Could not create vCPU {:?}", e)))?;
+            vcpu.restore(*vcpu_snapshot.clone())?;
+
+            self.start_vcpu(vcpu, vcpu_thread_barrier.clone(), false)
+                .map_err(|e| MigratableError::Restore(anyhow!("Could not start vCPU {:?}", e)))?;
+        }
+
+        // Unblock all CPU threads.
+        vcpu_thread_barrier.wait();
+        Ok(())
+    }
+}
+
 impl Migratable for CpuManager {}
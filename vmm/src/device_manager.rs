@@ -14,13 +14,16 @@ extern crate vm_device;
 use crate::config::ConsoleOutputMode;
 #[cfg(feature = "pci_support")]
 use crate::config::DeviceConfig;
-use crate::config::{DiskConfig, NetConfig, VmConfig};
+use crate::config::{
+    DiskConfig, FsConfig, NetConfig, PmemConfig, PortForwardProtocol, VmConfig, VsockConfig,
+};
 use crate::interrupt::{
     KvmLegacyUserspaceInterruptManager, KvmMsiInterruptManager, KvmRoutingEntry,
 };
 use crate::memory_manager::{Error as MemoryManagerError, MemoryManager};
 #[cfg(feature = "acpi")]
 use acpi_tables::{aml, aml::Aml};
+use anyhow::anyhow;
 #[cfg(feature = "acpi")]
 use arch::layout;
 use arch::layout::{APIC_START, IOAPIC_SIZE, IOAPIC_START};
@@ -35,14 +38,16 @@ use pci::{
 use qcow::{self, ImageType, QcowFile};
 #[cfg(feature = "pci_support")]
 use std::any::Any;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs::{File, OpenOptions};
 use std::io::{self, sink, stdout};
+use std::net::SocketAddrV4;
 #[cfg(feature = "pci_support")]
 use std::num::Wrapping;
 use std::os::unix::fs::OpenOptionsExt;
 use std::path::PathBuf;
 use std::result;
+use std::sync::atomic::Ordering;
 use std::sync::{Arc, Mutex};
 use tempfile::NamedTempFile;
 #[cfg(feature = "pci_support")]
@@ -51,7 +56,7 @@ use vm_allocator::SystemAllocator;
 use vm_device::interrupt::{
     InterruptIndex, InterruptManager, LegacyIrqGroupConfig, MsiIrqGroupConfig,
 };
-use vm_device::{Migratable, MigratableError, Pausable, Snapshotable};
+use vm_device::{Migratable, MigratableError, Pausable, Snapshot, Snapshotable};
 use vm_memory::guest_memory::FileOffset;
 #[cfg(feature = "cmos")]
 use vm_memory::GuestAddressSpace;
@@ -62,7 +67,10 @@ use vm_virtio::transport::VirtioTransport;
 use vm_virtio::vhost_user::VhostUserConfig;
 #[cfg(feature = "pci_support")]
 use vm_virtio::{DmaRemapping, IommuMapping, VirtioIommuRemapping};
-use vm_virtio::{VirtioSharedMemory, VirtioSharedMemoryList};
+use vm_virtio::{
+    QueueCounters, RateLimiter, RateLimiterConfig, ResizableBlock, VirtioDeviceType,
+    VirtioSharedMemory, VirtioSharedMemoryList,
+};
 use vmm_sys_util::eventfd::EventFd;
 
 #[cfg(feature = "mmio_support")]
@@ -70,6 +78,16 @@ const MMIO_LEN: u64 = 0x1000;
 
 #[cfg(feature = "pci_support")]
 const VFIO_DEVICE_NAME_PREFIX: &str = "vfio";
+#[cfg(feature = "pci_support")]
+const DISK_DEVICE_NAME_PREFIX: &str = "disk";
+#[cfg(feature = "pci_support")]
+const NET_DEVICE_NAME_PREFIX: &str = "net";
+#[cfg(feature = "pci_support")]
+const FS_DEVICE_NAME_PREFIX: &str = "fs";
+#[cfg(feature = "pci_support")]
+const PMEM_DEVICE_NAME_PREFIX: &str = "pmem";
+#[cfg(feature = "pci_support")]
+const VSOCK_DEVICE_NAME_PREFIX: &str = "vsock";
 
 /// Errors associated with device manager
 #[derive(Debug)]
@@ -98,6 +116,9 @@ pub enum DeviceManagerError {
     /// Cannot create virtio-fs device
     CreateVirtioFs(vm_virtio::vhost_user::Error),
 
+    /// Failed converting Path to &str for the virtio-fs device.
+    CreateVirtioFsConvertPath,
+
     /// Cannot create vhost-user-blk device
     CreateVhostUserBlk(vm_virtio::vhost_user::Error),
 
@@ -122,6 +143,39 @@ pub enum DeviceManagerError {
     /// Cannot open qcow disk path
     QcowDeviceCreate(qcow::Error),
 
+    /// Failed to find a qcow2 disk with the given identifier.
+    UnknownQcowDiskId(String),
+
+    /// Cannot create, apply or delete a qcow2 disk snapshot
+    DiskSnapshot(qcow::Error),
+
+    /// The disk snapshot size differs from the size of the disk.
+    DiskSnapshotSizeMismatch(String),
+
+    /// Failed to find a rate limited disk with the given identifier.
+    UnknownRateLimitedDiskId(String),
+
+    /// Failed to find a rate limited network interface with the given identifier.
+    UnknownRateLimitedNetId(String),
+
+    /// Failed to find a resizable disk with the given identifier.
+    UnknownResizableDiskId(String),
+
+    /// Cannot resize a disk
+    DiskResize(io::Error),
+
+    /// Failed to find a virtio-net device with the given identifier.
+    UnknownVirtioNetId(String),
+
+    /// Cannot change the link state of a network interface
+    SetNetLink(io::Error),
+
+    /// The disk image is already in use, by another VM or another disk.
+    DiskImageLocked(PathBuf),
+
+    /// Cannot lock the disk image
+    LockDiskImage(io::Error),
+
     /// Cannot open tap interface
     OpenTap(net_util::TapError),
 
@@ -224,9 +278,9 @@ pub enum DeviceManagerError {
     /// Missing PCI bus.
     NoPciBus,
 
-    /// Could not find an available VFIO device name.
+    /// Could not find an available device name.
     #[cfg(feature = "pci_support")]
-    NoAvailableVfioDeviceName,
+    NoAvailableDeviceName,
 
     /// Missing PCI device.
     MissingPciDevice,
@@ -241,9 +295,17 @@ pub enum DeviceManagerError {
     /// Failed removing a bus device from the MMIO bus.
     RemoveDeviceFromMmioBus(devices::BusError),
 
-    /// Failed to find VFIO device corresponding to the given identifier.
+    /// Failed to find the device corresponding to the given identifier.
+    #[cfg(feature = "pci_support")]
+    UnknownDeviceId(String),
+
+    /// Devices attached to the virtual IOMMU cannot be hot plugged.
+    #[cfg(feature = "pci_support")]
+    InvalidIommuHotplug,
+
+    /// Failed to unregister an ioeventfd.
     #[cfg(feature = "pci_support")]
-    UnknownVfioDeviceId(String),
+    UnregisterIoevent(kvm_ioctls::Error),
 
     /// Failed to find an available PCI device ID.
     #[cfg(feature = "pci_support")]
@@ -277,6 +339,56 @@ pub fn get_win_size() -> (u16, u16) {
     (ws.cols, ws.rows)
 }
 
+// Formats the limits as parameters of the vhost-user-net backend, such as
+// ",rx_bw=<bytes_per_second>,rx_bw_burst=<bytes>".
+fn net_backend_rate_limiter_params(
+    direction: &str,
+    rate_limiter_config: &Option<RateLimiterConfig>,
+) -> String {
+    let mut params = String::new();
+    if let Some(rate_limiter_config) = rate_limiter_config {
+        let buckets = [
+            ("bw", rate_limiter_config.bandwidth),
+            ("pps", rate_limiter_config.ops),
+        ];
+        for (name, bucket) in buckets.iter() {
+            if let Some(bucket) = bucket {
+                params.push_str(&format!(
+                    ",{}_{}={},{}_{}_burst={}",
+                    direction, name, bucket.rate, direction, name, bucket.burst
+                ));
+            }
+        }
+    }
+
+    params
+}
+
+// A disk image opened with lock=on, along with the name of its disk.
+struct DiskLock {
+    id: Option<String>,
+    path: PathBuf,
+    readonly: bool,
+    image: File,
+}
+
+impl DiskLock {
+    fn acquire(&self) -> DeviceManagerResult<()> {
+        vm_virtio::lock_disk_image(&self.image, self.readonly).map_err(|e| match e.raw_os_error() {
+            Some(libc::EAGAIN) | Some(libc::EACCES) => {
+                DeviceManagerError::DiskImageLocked(self.path.clone())
+            }
+            _ => DeviceManagerError::LockDiskImage(e),
+        })
+    }
+
+    fn release(&self) {
+        if let Err(e) = vm_virtio::unlock_disk_image(&self.image) {
+            warn!("Failed to release disk image lock: {}", e);
+        }
+    }
+}
+
 #[derive(Default)]
 pub struct Console {
     // Serial port on 0x3f8
@@ -440,6 +552,15 @@ impl Drop for ActivatedBackend {
     }
 }
 
+// Guest mapping of a device memory region (virtio-fs DAX window,
+// virtio-pmem), released when the device is removed.
+#[cfg(feature = "pci_support")]
+struct DeviceMapping {
+    slot: u32,
+    guest_addr: GuestAddress,
+    mmap_region: MmapRegion,
+}
+
 pub struct DeviceManager {
     // Manage address space related to devices
     address_manager: Arc<AddressManager>,
@@ -466,14 +587,20 @@ pub struct DeviceManager {
     // VM configuration
     config: Arc<Mutex<VmConfig>>,
 
-    // Migratable devices
-    migratable_devices: Vec<Arc<Mutex<dyn Migratable>>>,
+    // Migratable devices, along with their identifier in the VM snapshot
+    migratable_devices: Vec<(String, Arc<Mutex<dyn Migratable>>)>,
+
+    // Number of migratable devices registered without an identifier
+    unnamed_migratable_devices: usize,
 
     // Memory Manager
     memory_manager: Arc<Mutex<MemoryManager>>,
 
     // The virtio devices on the system
-    virtio_devices: Vec<(VirtioDeviceArc, bool)>,
+    virtio_devices: Vec<(VirtioDeviceArc, bool, Option<String>)>,
+
+    // Named virtio devices along with their queues counters
+    virtio_counters: Vec<(String, VirtioDeviceArc, Vec<Arc<QueueCounters>>)>,
 
     // List of bus devices
     // Let the DeviceManager keep strong references to the BusDevice devices.
@@ -522,6 +649,34 @@ pub struct DeviceManager {
     // Hashmap of PCI b/d/f to their corresponding Arc<Mutex<dyn PciDevice>>.
     #[cfg(feature = "pci_support")]
     pci_devices: HashMap<u32, Arc<dyn Any + Send + Sync>>,
+
+    // Hashmap of device's name to the guest mapping of its memory region.
+    #[cfg(feature = "pci_support")]
+    device_mappings: HashMap<String, DeviceMapping>,
+
+    // Hashmap of disk's name to their qcow2 image, for internal snapshots.
+    qcow_disks: HashMap<String, Arc<Mutex<QcowFile>>>,
+
+    // Hashmap of disk's name to their I/O rate limiter.
+    disk_rate_limiters: HashMap<String, Arc<Mutex<RateLimiter>>>,
+
+    // Hashmap of disk's name to their virtio-block device, for runtime resizing.
+    resizable_disks: HashMap<String, Arc<Mutex<dyn ResizableBlock>>>,
+
+    // Hashmap of network interface's name to their rx and tx rate limiters.
+    net_rate_limiters: HashMap<String, (Arc<Mutex<RateLimiter>>, Arc<Mutex<RateLimiter>>)>,
+
+    // Hashmap of network interface's name to their virtio-net device, for
+    // runtime link state changes.
+    virtio_net_devices: HashMap<String, Arc<Mutex<vm_virtio::Net>>>,
+
+    // Disk images opened with lock=on. The locks are released explicitly, as
+    // the devices may outlive the VM for a while.
+    disk_locks: Vec<DiskLock>,
+
+    // Whether the disk image locks are held. A VM received through a
+    // migration only takes them once the source VMM has released them.
+    disk_locks_held: bool,
 }
 
 impl DeviceManager {
@@ -534,9 +689,10 @@ impl DeviceManager {
         _exit_evt: &EventFd,
         reset_evt: &EventFd,
         vmm_path: PathBuf,
+        lock_disks: bool,
     ) -> DeviceManagerResult<Arc<Mutex<Self>>> {
-        let mut virtio_devices: Vec<(Arc<Mutex<dyn vm_virtio::VirtioDevice>>, bool)> = Vec::new();
-        let migratable_devices: Vec<Arc<Mutex<dyn Migratable>>> = Vec::new();
+        let mut virtio_devices: Vec<(VirtioDeviceArc, bool, Option<String>)> = Vec::new();
+        let migratable_devices: Vec<(String, Arc<Mutex<dyn Migratable>>)> = Vec::new();
         let mut bus_devices: Vec<Arc<Mutex<dyn BusDevice>>> = Vec::new();
         let mut _mmap_regions = Vec::new();
 
@@ -597,15 +753,17 @@ impl DeviceManager {
         let mut device_manager = DeviceManager {
             address_manager: Arc::clone(&address_manager),
             console: Arc::new(Console::default()),
-            ioapic: Some(ioapic),
+            ioapic: Some(Arc::clone(&ioapic)),
             _mmap_regions,
             cmdline_additions,
             #[cfg(feature = "acpi")]
             ged_notification_device: None,
             config,
             migratable_devices,
+            unnamed_migratable_devices: 0,
             memory_manager,
             virtio_devices: Vec::new(),
+            virtio_counters: Vec::new(),
             bus_devices,
             vmm_path,
             vhost_user_backends: Vec::new(),
@@ -627,8 +785,19 @@ impl DeviceManager {
             device_id_cnt: Wrapping(0),
             #[cfg(feature = "pci_support")]
             pci_devices: HashMap::new(),
+            #[cfg(feature = "pci_support")]
+            device_mappings: HashMap::new(),
+            qcow_disks: HashMap::new(),
+            disk_rate_limiters: HashMap::new(),
+            resizable_disks: HashMap::new(),
+            net_rate_limiters: HashMap::new(),
+            virtio_net_devices: HashMap::new(),
+            disk_locks: Vec::new(),
+            disk_locks_held: lock_disks,
         };
 
+        device_manager.add_migratable_device(None, ioapic);
+
         device_manager
             .add_legacy_devices(reset_evt.try_clone().map_err(DeviceManagerError::EventFd)?)?;
 
@@ -681,7 +850,7 @@ impl DeviceManager {
     #[allow(unused_variables)]
     fn add_pci_devices(
         &mut self,
-        virtio_devices: Vec<(Arc<Mutex<dyn vm_virtio::VirtioDevice>>, bool)>,
+        virtio_devices: Vec<(VirtioDeviceArc, bool, Option<String>)>,
     ) -> DeviceManagerResult<()> {
         #[cfg(feature = "pci_support")]
         {
@@ -705,17 +874,22 @@ impl DeviceManager {
 
             let mut iommu_attached_devices = Vec::new();
 
-            for (device, iommu_attached) in virtio_devices {
+            for (device, iommu_attached, id) in virtio_devices {
                 let mapping: &Option<Arc<IommuMapping>> = if iommu_attached {
                     &iommu_mapping
                 } else {
                     &None
                 };
 
-                let virtio_iommu_attach_dev =
-                    self.add_virtio_pci_device(device, &mut pci_bus, mapping, &interrupt_manager)?;
+                let dev_id = self.add_virtio_pci_device(
+                    device,
+                    &mut pci_bus,
+                    mapping,
+                    &interrupt_manager,
+                    id,
+                )?;
 
-                if let Some(dev_id) = virtio_iommu_attach_dev {
+                if mapping.is_some() {
                     iommu_attached_devices.push(dev_id);
                 }
             }
@@ -734,7 +908,13 @@ impl DeviceManager {
                 // Because we determined the virtio-iommu b/d/f, we have to
                 // add the device to the PCI topology now. Otherwise, the
                 // b/d/f won't match the virtio-iommu device as expected.
-                self.add_virtio_pci_device(iommu_device, &mut pci_bus, &None, &interrupt_manager)?;
+                self.add_virtio_pci_device(
+                    iommu_device,
+                    &mut pci_bus,
+                    &None,
+                    &interrupt_manager,
+                    None,
+                )?;
             }
 
             let pci_bus = Arc::new(Mutex::new(pci_bus));
@@ -766,12 +946,12 @@ impl DeviceManager {
     #[allow(unused_variables, unused_mut)]
     fn add_mmio_devices(
         &mut self,
-        virtio_devices: Vec<(Arc<Mutex<dyn vm_virtio::VirtioDevice>>, bool)>,
+        virtio_devices: Vec<(VirtioDeviceArc, bool, Option<String>)>,
         interrupt_manager: &Arc<dyn InterruptManager<GroupConfig = LegacyIrqGroupConfig>>,
     ) -> DeviceManagerResult<()> {
         #[cfg(feature = "mmio_support")]
         {
-            for (device, _) in virtio_devices {
+            for (device, _, id) in virtio_devices {
                 let mmio_addr = self
                     .address_manager
                     .allocator
@@ -779,7 +959,7 @@ impl DeviceManager {
                     .unwrap()
                     .allocate_mmio_addresses(None, MMIO_LEN, Some(MMIO_LEN));
                 if let Some(addr) = mmio_addr {
-                    self.add_virtio_mmio_device(device, interrupt_manager, addr)?;
+                    self.add_virtio_mmio_device(device, interrupt_manager, addr, id)?;
                 } else {
                     error!("Unable to allocate MMIO address!");
                 }
@@ -875,11 +1055,33 @@ impl DeviceManager {
 
         self.bus_devices
             .push(Arc::clone(&i8042) as Arc<Mutex<dyn BusDevice>>);
+        self.add_migratable_device(None, Arc::clone(&i8042) as Arc<Mutex<dyn Migratable>>);
 
         self.address_manager
             .io_bus
             .insert(i8042, 0x61, 0x4)
             .map_err(DeviceManagerError::BusError)?;
+        #[cfg(feature = "acpi")]
+        {
+            // Add a pvpanic device, for the guest to report its panics.
+            // It is discovered through ACPI only.
+            let pvpanic = Arc::new(Mutex::new(devices::legacy::PvPanicDevice::new()));
+
+            self.bus_devices
+                .push(Arc::clone(&pvpanic) as Arc<Mutex<dyn BusDevice>>);
+
+            self.address_manager
+                .allocator
+                .lock()
+                .unwrap()
+                .allocate_io_addresses(Some(GuestAddress(0x505)), 0x1, None)
+                .ok_or(DeviceManagerError::AllocateIOPort)?;
+
+            self.address_manager
+                .io_bus
+                .insert(pvpanic, 0x505, 0x1)
+                .map_err(DeviceManagerError::BusError)?;
+        }
         #[cfg(feature = "cmos")]
         {
             // Add a CMOS emulated device
@@ -903,6 +1105,7 @@ impl DeviceManager {
 
             self.bus_devices
                 .push(Arc::clone(&cmos) as Arc<Mutex<dyn BusDevice>>);
+            self.add_migratable_device(None, Arc::clone(&cmos) as Arc<Mutex<dyn Migratable>>);
 
             self.address_manager
                 .io_bus
@@ -916,7 +1119,7 @@ impl DeviceManager {
     fn add_console_device(
         &mut self,
         interrupt_manager: &Arc<dyn InterruptManager<GroupConfig = LegacyIrqGroupConfig>>,
-        virtio_devices: &mut Vec<(Arc<Mutex<dyn vm_virtio::VirtioDevice>>, bool)>,
+        virtio_devices: &mut Vec<(VirtioDeviceArc, bool, Option<String>)>,
     ) -> DeviceManagerResult<Arc<Console>> {
         let serial_config = self.config.lock().unwrap().serial.clone();
         let serial_writer: Option<Box<dyn io::Write + Send>> = match serial_config.mode {
@@ -944,6 +1147,7 @@ impl DeviceManager {
 
             self.bus_devices
                 .push(Arc::clone(&serial) as Arc<Mutex<dyn BusDevice>>);
+            self.add_migratable_device(None, Arc::clone(&serial) as Arc<Mutex<dyn Migratable>>);
 
             self.address_manager
                 .allocator
@@ -978,10 +1182,15 @@ impl DeviceManager {
             let (virtio_console_device, console_input) =
                 vm_virtio::Console::new(writer, col, row, console_config.iommu)
                     .map_err(DeviceManagerError::CreateVirtioConsole)?;
+            let virtio_console_device = Arc::new(Mutex::new(virtio_console_device));
+            self.add_migratable_device(
+                None,
+                Arc::clone(&virtio_console_device) as Arc<Mutex<dyn Migratable>>,
+            );
             virtio_devices.push((
-                Arc::new(Mutex::new(virtio_console_device))
-                    as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
+                virtio_console_device as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
                 false,
+                None,
             ));
             Some(console_input)
         } else {
@@ -996,8 +1205,10 @@ impl DeviceManager {
         }))
     }
 
-    fn make_virtio_devices(&mut self) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool)>> {
-        let mut devices: Vec<(Arc<Mutex<dyn vm_virtio::VirtioDevice>>, bool)> = Vec::new();
+    fn make_virtio_devices(
+        &mut self,
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, Option<String>)>> {
+        let mut devices: Vec<(VirtioDeviceArc, bool, Option<String>)> = Vec::new();
 
         // Create "standard" virtio devices (net/block/rng)
         devices.append(&mut self.make_virtio_block_devices()?);
@@ -1018,6 +1229,10 @@ impl DeviceManager {
 
     /// Launch block backend
     fn start_block_backend(&mut self, disk_cfg: &DiskConfig) -> DeviceManagerResult<String> {
+        // A backend spawned for a migrated VM can't take the lock over from
+        // the backend of the source VMM, so it doesn't lock the image at all.
+        let lock = disk_cfg.lock && self.disk_locks_held;
+
         let _socket_file = NamedTempFile::new().map_err(DeviceManagerError::CreateSocketFile)?;
         let sock = _socket_file.path().to_str().unwrap().to_owned();
 
@@ -1025,11 +1240,13 @@ impl DeviceManager {
             .args(&[
                 "--block-backend",
                 &format!(
-                    "image={},sock={},num_queues={},queue_size={}",
+                    "image={},sock={},num_queues={},queue_size={},readonly={},lock={}",
                     disk_cfg.path.to_str().unwrap(),
                     &sock,
                     disk_cfg.num_queues,
-                    disk_cfg.queue_size
+                    disk_cfg.queue_size,
+                    disk_cfg.readonly,
+                    lock
                 ),
             ])
             .spawn()
@@ -1044,99 +1261,190 @@ impl DeviceManager {
         Ok(sock)
     }
 
-    fn make_virtio_block_devices(&mut self) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool)>> {
-        let mut devices = Vec::new();
+    fn make_virtio_block_device(
+        &mut self,
+        disk_cfg: &mut DiskConfig,
+    ) -> DeviceManagerResult<(VirtioDeviceArc, bool, Option<String>)> {
+        #[cfg(feature = "pci_support")]
+        {
+            if disk_cfg.id.is_none() {
+                disk_cfg.id = Some(self.next_device_name(DISK_DEVICE_NAME_PREFIX)?);
+            }
+        }
+
+        if disk_cfg.vhost_user {
+            let sock = if let Some(sock) = disk_cfg.vhost_socket.clone() {
+                sock
+            } else {
+                self.start_block_backend(disk_cfg)?
+            };
+            let vu_cfg = VhostUserConfig {
+                sock,
+                num_queues: disk_cfg.num_queues,
+                queue_size: disk_cfg.queue_size,
+            };
+            let vhost_user_block_device = Arc::new(Mutex::new(
+                vm_virtio::vhost_user::Blk::new(disk_cfg.wce, vu_cfg)
+                    .map_err(DeviceManagerError::CreateVhostUserBlk)?,
+            ));
+
+            self.add_migratable_device(
+                disk_cfg.id.clone(),
+                Arc::clone(&vhost_user_block_device) as Arc<Mutex<dyn Migratable>>,
+            );
+
+            Ok((
+                Arc::clone(&vhost_user_block_device) as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
+                false,
+                disk_cfg.id.clone(),
+            ))
+        } else {
+            let mut options = OpenOptions::new();
+            options.read(true);
+            options.write(!disk_cfg.readonly);
+            if disk_cfg.direct {
+                options.custom_flags(libc::O_DIRECT);
+            }
+            // Open block device path
+            let image: File = options
+                .open(&disk_cfg.path)
+                .map_err(DeviceManagerError::Disk)?;
+
+            if disk_cfg.lock {
+                let lock = DiskLock {
+                    id: disk_cfg.id.clone(),
+                    path: disk_cfg.path.clone(),
+                    readonly: disk_cfg.readonly,
+                    image: image.try_clone().map_err(DeviceManagerError::Disk)?,
+                };
+                if self.disk_locks_held {
+                    lock.acquire()?;
+                }
+                self.disk_locks.push(lock);
+            }
 
-        let block_devices = self.config.lock().unwrap().disks.clone();
-        if let Some(disk_list_cfg) = &block_devices {
-            for disk_cfg in disk_list_cfg.iter() {
-                if disk_cfg.vhost_user {
-                    let sock = if let Some(sock) = disk_cfg.vhost_socket.clone() {
-                        sock
+            let mut raw_img = vm_virtio::RawFile::new(image, disk_cfg.direct);
+
+            let image_type = qcow::detect_image_type(&mut raw_img)
+                .map_err(DeviceManagerError::DetectImageType)?;
+            match image_type {
+                ImageType::Raw => {
+                    // Raw images are accessed asynchronously through io_uring
+                    // when the host kernel supports it. O_DIRECT images keep
+                    // using the synchronous path, which takes care of the
+                    // alignment constraints.
+                    let dev = if !disk_cfg.direct && vm_virtio::block_io_uring_is_supported() {
+                        vm_virtio::Block::new_io_uring(
+                            raw_img,
+                            disk_cfg.path.clone(),
+                            disk_cfg.readonly,
+                            disk_cfg.iommu,
+                            disk_cfg.num_queues,
+                            disk_cfg.queue_size,
+                        )
                     } else {
-                        self.start_block_backend(disk_cfg)?
-                    };
-                    let vu_cfg = VhostUserConfig {
-                        sock,
-                        num_queues: disk_cfg.num_queues,
-                        queue_size: disk_cfg.queue_size,
-                    };
-                    let vhost_user_block_device = Arc::new(Mutex::new(
-                        vm_virtio::vhost_user::Blk::new(disk_cfg.wce, vu_cfg)
-                            .map_err(DeviceManagerError::CreateVhostUserBlk)?,
-                    ));
-
-                    devices.push((
-                        Arc::clone(&vhost_user_block_device)
-                            as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
-                        false,
-                    ));
-
-                    self.migratable_devices
-                        .push(Arc::clone(&vhost_user_block_device) as Arc<Mutex<dyn Migratable>>);
-                } else {
-                    let mut options = OpenOptions::new();
-                    options.read(true);
-                    options.write(!disk_cfg.readonly);
-                    if disk_cfg.direct {
-                        options.custom_flags(libc::O_DIRECT);
+                        vm_virtio::Block::new(
+                            raw_img,
+                            disk_cfg.path.clone(),
+                            disk_cfg.readonly,
+                            disk_cfg.iommu,
+                            disk_cfg.num_queues,
+                            disk_cfg.queue_size,
+                        )
                     }
-                    // Open block device path
-                    let image: File = options
-                        .open(&disk_cfg.path)
-                        .map_err(DeviceManagerError::Disk)?;
-
-                    let mut raw_img = vm_virtio::RawFile::new(image, disk_cfg.direct);
-
-                    let image_type = qcow::detect_image_type(&mut raw_img)
-                        .map_err(DeviceManagerError::DetectImageType)?;
-                    match image_type {
-                        ImageType::Raw => {
-                            let dev = vm_virtio::Block::new(
-                                raw_img,
-                                disk_cfg.path.clone(),
-                                disk_cfg.readonly,
-                                disk_cfg.iommu,
-                                disk_cfg.num_queues,
-                                disk_cfg.queue_size,
-                            )
-                            .map_err(DeviceManagerError::CreateVirtioBlock)?;
+                    .map_err(DeviceManagerError::CreateVirtioBlock)?;
 
-                            let block = Arc::new(Mutex::new(dev));
+                    self.add_disk_rate_limiter(disk_cfg, dev.rate_limiter());
 
-                            devices.push((
-                                Arc::clone(&block) as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
-                                disk_cfg.iommu,
-                            ));
-                            self.migratable_devices
-                                .push(Arc::clone(&block) as Arc<Mutex<dyn Migratable>>);
-                        }
-                        ImageType::Qcow2 => {
-                            let qcow_img = QcowFile::from(raw_img)
-                                .map_err(DeviceManagerError::QcowDeviceCreate)?;
-                            let dev = vm_virtio::Block::new(
-                                qcow_img,
-                                disk_cfg.path.clone(),
-                                disk_cfg.readonly,
-                                disk_cfg.iommu,
-                                disk_cfg.num_queues,
-                                disk_cfg.queue_size,
-                            )
-                            .map_err(DeviceManagerError::CreateVirtioBlock)?;
+                    let block = Arc::new(Mutex::new(dev));
 
-                            let block = Arc::new(Mutex::new(dev));
+                    if let Some(id) = &disk_cfg.id {
+                        self.resizable_disks.insert(
+                            id.clone(),
+                            Arc::clone(&block) as Arc<Mutex<dyn ResizableBlock>>,
+                        );
+                    }
+                    self.add_migratable_device(
+                        disk_cfg.id.clone(),
+                        Arc::clone(&block) as Arc<Mutex<dyn Migratable>>,
+                    );
+
+                    Ok((
+                        Arc::clone(&block) as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
+                        disk_cfg.iommu,
+                        disk_cfg.id.clone(),
+                    ))
+                }
+                ImageType::Qcow2 => {
+                    let qcow_img =
+                        QcowFile::from(raw_img).map_err(DeviceManagerError::QcowDeviceCreate)?;
+                    let dev = vm_virtio::Block::new(
+                        qcow_img,
+                        disk_cfg.path.clone(),
+                        disk_cfg.readonly,
+                        disk_cfg.iommu,
+                        disk_cfg.num_queues,
+                        disk_cfg.queue_size,
+                    )
+                    .map_err(DeviceManagerError::CreateVirtioBlock)?;
 
-                            devices.push((
-                                Arc::clone(&block) as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
-                                disk_cfg.iommu,
-                            ));
-                            self.migratable_devices
-                                .push(Arc::clone(&block) as Arc<Mutex<dyn Migratable>>);
-                        }
-                    };
+                    if let Some(id) = &disk_cfg.id {
+                        self.qcow_disks.insert(id.clone(), dev.disk_image());
+                    }
+                    self.add_disk_rate_limiter(disk_cfg, dev.rate_limiter());
+
+                    let block = Arc::new(Mutex::new(dev));
+
+                    if let Some(id) = &disk_cfg.id {
+                        self.resizable_disks.insert(
+                            id.clone(),
+                            Arc::clone(&block) as Arc<Mutex<dyn ResizableBlock>>,
+                        );
+                    }
+                    self.add_migratable_device(
+                        disk_cfg.id.clone(),
+                        Arc::clone(&block) as Arc<Mutex<dyn Migratable>>,
+                    );
+
+                    Ok((
+                        Arc::clone(&block) as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
+                        disk_cfg.iommu,
+                        disk_cfg.id.clone(),
+                    ))
                 }
             }
         }
+    }
+
+    // Applies the configured limits to the disk, and keeps track of its rate
+    // limiter so that they can be updated at runtime.
+    fn add_disk_rate_limiter(
+        &mut self,
+        disk_cfg: &DiskConfig,
+        rate_limiter: Arc<Mutex<RateLimiter>>,
+    ) {
+        if let Some(rate_limiter_config) = &disk_cfg.rate_limiter_config {
+            rate_limiter.lock().unwrap().update(rate_limiter_config);
+        }
+        if let Some(id) = &disk_cfg.id {
+            self.disk_rate_limiters.insert(id.clone(), rate_limiter);
+        }
+    }
+
+    fn make_virtio_block_devices(
+        &mut self,
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, Option<String>)>> {
+        let mut devices = Vec::new();
+
+        let mut block_devices = self.config.lock().unwrap().disks.clone();
+        if let Some(disk_list_cfg) = &mut block_devices {
+            for disk_cfg in disk_list_cfg.iter_mut() {
+                devices.push(self.make_virtio_block_device(disk_cfg)?);
+            }
+        }
+
+        // Update the list of disks with the generated identifiers
+        self.config.lock().unwrap().disks = block_devices;
 
         Ok(devices)
     }
@@ -1150,8 +1458,14 @@ impl DeviceManager {
             .args(&[
                 "--net-backend",
                 &format!(
-                    "ip={},mask={},sock={},num_queues={},queue_size={}",
-                    net_cfg.ip, net_cfg.mask, &sock, net_cfg.num_queues, net_cfg.queue_size
+                    "ip={},mask={},sock={},num_queues={},queue_size={}{}{}",
+                    net_cfg.ip,
+                    net_cfg.mask,
+                    &sock,
+                    net_cfg.num_queues,
+                    net_cfg.queue_size,
+                    net_backend_rate_limiter_params("rx", &net_cfg.rx_rate_limiter_config),
+                    net_backend_rate_limiter_params("tx", &net_cfg.tx_rate_limiter_config),
                 ),
             ])
             .spawn()
@@ -1166,76 +1480,166 @@ impl DeviceManager {
         Ok(sock)
     }
 
-    /// Add virto-net and vhost-user-net devices
-    fn make_virtio_net_devices(&mut self) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool)>> {
-        let mut devices = Vec::new();
-        let net_devices = self.config.lock().unwrap().net.clone();
-        if let Some(net_list_cfg) = &net_devices {
-            for net_cfg in net_list_cfg.iter() {
-                if net_cfg.vhost_user {
-                    let sock = if let Some(sock) = net_cfg.vhost_socket.clone() {
-                        sock
-                    } else {
-                        self.start_net_backend(net_cfg)?
-                    };
-                    let vu_cfg = VhostUserConfig {
-                        sock,
-                        num_queues: net_cfg.num_queues,
-                        queue_size: net_cfg.queue_size,
-                    };
-                    let vhost_user_net_device = Arc::new(Mutex::new(
-                        vm_virtio::vhost_user::Net::new(net_cfg.mac, vu_cfg)
-                            .map_err(DeviceManagerError::CreateVhostUserNet)?,
-                    ));
-                    devices.push((
-                        Arc::clone(&vhost_user_net_device)
-                            as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
+    /// Add virto-net and vhost-user-net device
+    fn make_virtio_net_device(
+        &mut self,
+        net_cfg: &mut NetConfig,
+    ) -> DeviceManagerResult<(VirtioDeviceArc, bool, Option<String>)> {
+        #[cfg(feature = "pci_support")]
+        {
+            if net_cfg.id.is_none() {
+                net_cfg.id = Some(self.next_device_name(NET_DEVICE_NAME_PREFIX)?);
+            }
+        }
+
+        if net_cfg.vhost_user {
+            let sock = if let Some(sock) = net_cfg.vhost_socket.clone() {
+                sock
+            } else {
+                self.start_net_backend(net_cfg)?
+            };
+            let vu_cfg = VhostUserConfig {
+                sock,
+                num_queues: net_cfg.num_queues,
+                queue_size: net_cfg.queue_size,
+            };
+            let vhost_user_net_device = Arc::new(Mutex::new(
+                vm_virtio::vhost_user::Net::new(net_cfg.mac, vu_cfg)
+                    .map_err(DeviceManagerError::CreateVhostUserNet)?,
+            ));
+            self.add_migratable_device(
+                net_cfg.id.clone(),
+                Arc::clone(&vhost_user_net_device) as Arc<Mutex<dyn Migratable>>,
+            );
+
+            Ok((
+                Arc::clone(&vhost_user_net_device) as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
+                net_cfg.iommu,
+                net_cfg.id.clone(),
+            ))
+        } else {
+            let virtio_net_device = if net_cfg.user {
+                let port_forwards = net_cfg
+                    .port_forwards
+                    .iter()
+                    .map(|port_forward| net_util::PortForward {
+                        protocol: match port_forward.protocol {
+                            PortForwardProtocol::Tcp => net_util::PortForwardProtocol::Tcp,
+                            PortForwardProtocol::Udp => net_util::PortForwardProtocol::Udp,
+                        },
+                        host_addr: SocketAddrV4::new(port_forward.host_ip, port_forward.host_port),
+                        guest_port: port_forward.guest_port,
+                    })
+                    .collect();
+                let user_net_cfg = net_util::UserNetConfig {
+                    gateway: net_cfg.ip,
+                    netmask: net_cfg.mask,
+                    guest_mac: net_cfg.mac,
+                    port_forwards,
+                    host_loopback: net_cfg.host_loopback,
+                };
+                Arc::new(Mutex::new(
+                    vm_virtio::Net::new_user(user_net_cfg, net_cfg.iommu, net_cfg.queue_size)
+                        .map_err(DeviceManagerError::CreateVirtioNet)?,
+                ))
+            } else if let Some(ref tap_if_name) = net_cfg.tap {
+                Arc::new(Mutex::new(
+                    vm_virtio::Net::new(
+                        Some(tap_if_name),
+                        None,
+                        None,
+                        Some(net_cfg.mac),
                         net_cfg.iommu,
-                    ));
-                    self.migratable_devices
-                        .push(Arc::clone(&vhost_user_net_device) as Arc<Mutex<dyn Migratable>>);
-                } else {
-                    let virtio_net_device = if let Some(ref tap_if_name) = net_cfg.tap {
-                        Arc::new(Mutex::new(
-                            vm_virtio::Net::new(
-                                Some(tap_if_name),
-                                None,
-                                None,
-                                Some(net_cfg.mac),
-                                net_cfg.iommu,
-                                net_cfg.num_queues,
-                                net_cfg.queue_size,
-                            )
-                            .map_err(DeviceManagerError::CreateVirtioNet)?,
-                        ))
-                    } else {
-                        Arc::new(Mutex::new(
-                            vm_virtio::Net::new(
-                                None,
-                                Some(net_cfg.ip),
-                                Some(net_cfg.mask),
-                                Some(net_cfg.mac),
-                                net_cfg.iommu,
-                                net_cfg.num_queues,
-                                net_cfg.queue_size,
-                            )
-                            .map_err(DeviceManagerError::CreateVirtioNet)?,
-                        ))
-                    };
-                    devices.push((
-                        Arc::clone(&virtio_net_device) as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
+                        net_cfg.num_queues,
+                        net_cfg.queue_size,
+                    )
+                    .map_err(DeviceManagerError::CreateVirtioNet)?,
+                ))
+            } else {
+                Arc::new(Mutex::new(
+                    vm_virtio::Net::new(
+                        None,
+                        Some(net_cfg.ip),
+                        Some(net_cfg.mask),
+                        Some(net_cfg.mac),
                         net_cfg.iommu,
-                    ));
-                    self.migratable_devices
-                        .push(Arc::clone(&virtio_net_device) as Arc<Mutex<dyn Migratable>>);
-                }
+                        net_cfg.num_queues,
+                        net_cfg.queue_size,
+                    )
+                    .map_err(DeviceManagerError::CreateVirtioNet)?,
+                ))
+            };
+
+            let (rx_rate_limiter, tx_rate_limiter) = {
+                let net = virtio_net_device.lock().unwrap();
+                (net.rx_rate_limiter(), net.tx_rate_limiter())
+            };
+            self.add_net_rate_limiters(net_cfg, rx_rate_limiter, tx_rate_limiter);
+
+            if let Some(id) = &net_cfg.id {
+                self.virtio_net_devices
+                    .insert(id.clone(), Arc::clone(&virtio_net_device));
+            }
+            self.add_migratable_device(
+                net_cfg.id.clone(),
+                Arc::clone(&virtio_net_device) as Arc<Mutex<dyn Migratable>>,
+            );
+
+            Ok((
+                Arc::clone(&virtio_net_device) as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
+                net_cfg.iommu,
+                net_cfg.id.clone(),
+            ))
+        }
+    }
+
+    // Applies the configured limits to the network interface, and keeps track
+    // of its rate limiters so that they can be updated at runtime.
+    fn add_net_rate_limiters(
+        &mut self,
+        net_cfg: &NetConfig,
+        rx_rate_limiter: Arc<Mutex<RateLimiter>>,
+        tx_rate_limiter: Arc<Mutex<RateLimiter>>,
+    ) {
+        if let Some(rx_rate_limiter_config) = &net_cfg.rx_rate_limiter_config {
+            rx_rate_limiter
+                .lock()
+                .unwrap()
+                .update(rx_rate_limiter_config);
+        }
+        if let Some(tx_rate_limiter_config) = &net_cfg.tx_rate_limiter_config {
+            tx_rate_limiter
+                .lock()
+                .unwrap()
+                .update(tx_rate_limiter_config);
+        }
+        if let Some(id) = &net_cfg.id {
+            self.net_rate_limiters
+                .insert(id.clone(), (rx_rate_limiter, tx_rate_limiter));
+        }
+    }
+
+    /// Add virto-net and vhost-user-net devices
+    fn make_virtio_net_devices(
+        &mut self,
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, Option<String>)>> {
+        let mut devices = Vec::new();
+        let mut net_devices = self.config.lock().unwrap().net.clone();
+        if let Some(net_list_cfg) = &mut net_devices {
+            for net_cfg in net_list_cfg.iter_mut() {
+                devices.push(self.make_virtio_net_device(net_cfg)?);
             }
         }
 
+        // Update the list of network devices with the generated identifiers
+        self.config.lock().unwrap().net = net_devices;
+
         Ok(devices)
     }
 
-    fn make_virtio_rng_devices(&mut self) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool)>> {
+    fn make_virtio_rng_devices(
+        &mut self,
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, Option<String>)>> {
         let mut devices = Vec::new();
 
         // Add virtio-rng if required
@@ -1248,207 +1652,301 @@ impl DeviceManager {
             devices.push((
                 Arc::clone(&virtio_rng_device) as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
                 false,
+                None,
             ));
 
-            self.migratable_devices
-                .push(Arc::clone(&virtio_rng_device) as Arc<Mutex<dyn Migratable>>);
+            self.add_migratable_device(
+                None,
+                Arc::clone(&virtio_rng_device) as Arc<Mutex<dyn Migratable>>,
+            );
         }
 
         Ok(devices)
     }
 
-    fn make_virtio_fs_devices(&mut self) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool)>> {
-        let mut devices = Vec::new();
-        // Add virtio-fs if required
-        if let Some(fs_list_cfg) = &self.config.lock().unwrap().fs {
-            for fs_cfg in fs_list_cfg.iter() {
-                if let Some(fs_sock) = fs_cfg.sock.to_str() {
-                    let cache: Option<(VirtioSharedMemoryList, u64)> = if fs_cfg.dax {
-                        let fs_cache = fs_cfg.cache_size;
-                        // The memory needs to be 2MiB aligned in order to support
-                        // hugepages.
-                        let fs_guest_addr = self
-                            .address_manager
-                            .allocator
-                            .lock()
-                            .unwrap()
-                            .allocate_mmio_addresses(
-                                None,
-                                fs_cache as GuestUsize,
-                                Some(0x0020_0000),
-                            )
-                            .ok_or(DeviceManagerError::FsRangeAllocation)?;
-
-                        let mmap_region = MmapRegion::build(
-                            None,
-                            fs_cache as usize,
-                            libc::PROT_NONE,
-                            libc::MAP_ANONYMOUS | libc::MAP_PRIVATE,
-                        )
-                        .map_err(DeviceManagerError::NewMmapRegion)?;
-                        let addr: u64 = mmap_region.as_ptr() as u64;
-
-                        self._mmap_regions.push(mmap_region);
-
-                        self.memory_manager
-                            .lock()
-                            .unwrap()
-                            .create_userspace_mapping(
-                                fs_guest_addr.raw_value(),
-                                fs_cache,
-                                addr,
-                                false,
-                            )
-                            .map_err(DeviceManagerError::MemoryManager)?;
-
-                        let mut region_list = Vec::new();
-                        region_list.push(VirtioSharedMemory {
-                            offset: 0,
-                            len: fs_cache,
-                        });
-
-                        Some((
-                            VirtioSharedMemoryList {
-                                addr: fs_guest_addr,
-                                len: fs_cache as GuestUsize,
-                                region_list,
-                            },
-                            addr,
-                        ))
-                    } else {
-                        None
-                    };
-
-                    let virtio_fs_device = Arc::new(Mutex::new(
-                        vm_virtio::vhost_user::Fs::new(
-                            fs_sock,
-                            &fs_cfg.tag,
-                            fs_cfg.num_queues,
-                            fs_cfg.queue_size,
-                            cache,
-                        )
-                        .map_err(DeviceManagerError::CreateVirtioFs)?,
-                    ));
-
-                    devices.push((
-                        Arc::clone(&virtio_fs_device) as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
-                        false,
-                    ));
+    #[allow(unused_variables)]
+    fn add_device_mapping(
+        &mut self,
+        id: &Option<String>,
+        guest_addr: GuestAddress,
+        mmap_region: MmapRegion,
+        mergeable: bool,
+    ) -> DeviceManagerResult<()> {
+        let slot = self
+            .memory_manager
+            .lock()
+            .unwrap()
+            .create_userspace_mapping(
+                guest_addr.raw_value(),
+                mmap_region.size() as u64,
+                mmap_region.as_ptr() as u64,
+                mergeable,
+            )
+            .map_err(DeviceManagerError::MemoryManager)?;
 
-                    self.migratable_devices
-                        .push(Arc::clone(&virtio_fs_device) as Arc<Mutex<dyn Migratable>>);
-                }
+        // Keep track of the mappings belonging to devices that can be
+        // removed, so that they can be released along with the device.
+        #[cfg(feature = "pci_support")]
+        {
+            if let Some(id) = id {
+                self.device_mappings.insert(
+                    id.clone(),
+                    DeviceMapping {
+                        slot,
+                        guest_addr,
+                        mmap_region,
+                    },
+                );
+                return Ok(());
             }
         }
 
-        Ok(devices)
-    }
+        self._mmap_regions.push(mmap_region);
 
-    fn make_virtio_pmem_devices(&mut self) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool)>> {
-        let mut devices = Vec::new();
-        // Add virtio-pmem if required
-        if let Some(pmem_list_cfg) = &self.config.lock().unwrap().pmem {
-            for pmem_cfg in pmem_list_cfg.iter() {
-                let size = pmem_cfg.size;
+        Ok(())
+    }
 
-                // The memory needs to be 2MiB aligned in order to support
-                // hugepages.
-                let pmem_guest_addr = self
-                    .address_manager
-                    .allocator
-                    .lock()
-                    .unwrap()
-                    .allocate_mmio_addresses(None, size as GuestUsize, Some(0x0020_0000))
-                    .ok_or(DeviceManagerError::PmemRangeAllocation)?;
+    fn make_virtio_fs_device(
+        &mut self,
+        fs_cfg: &mut FsConfig,
+    ) -> DeviceManagerResult<(VirtioDeviceArc, bool, Option<String>)> {
+        #[cfg(feature = "pci_support")]
+        {
+            if fs_cfg.id.is_none() {
+                fs_cfg.id = Some(self.next_device_name(FS_DEVICE_NAME_PREFIX)?);
+            }
+        }
 
-                let (custom_flags, set_len) = if pmem_cfg.file.is_dir() {
-                    (O_TMPFILE, true)
-                } else {
-                    (0, false)
-                };
+        let fs_sock = fs_cfg
+            .sock
+            .to_str()
+            .ok_or(DeviceManagerError::CreateVirtioFsConvertPath)?;
+
+        let cache: Option<(VirtioSharedMemoryList, u64)> = if fs_cfg.dax {
+            let fs_cache = fs_cfg.cache_size;
+            // The memory needs to be 2MiB aligned in order to support
+            // hugepages.
+            let fs_guest_addr = self
+                .address_manager
+                .allocator
+                .lock()
+                .unwrap()
+                .allocate_mmio_addresses(None, fs_cache as GuestUsize, Some(0x0020_0000))
+                .ok_or(DeviceManagerError::FsRangeAllocation)?;
+
+            let mmap_region = MmapRegion::build(
+                None,
+                fs_cache as usize,
+                libc::PROT_NONE,
+                libc::MAP_ANONYMOUS | libc::MAP_PRIVATE,
+            )
+            .map_err(DeviceManagerError::NewMmapRegion)?;
+            let addr: u64 = mmap_region.as_ptr() as u64;
+
+            self.add_device_mapping(&fs_cfg.id, fs_guest_addr, mmap_region, false)?;
+
+            let mut region_list = Vec::new();
+            region_list.push(VirtioSharedMemory {
+                offset: 0,
+                len: fs_cache,
+            });
+
+            Some((
+                VirtioSharedMemoryList {
+                    addr: fs_guest_addr,
+                    len: fs_cache as GuestUsize,
+                    region_list,
+                },
+                addr,
+            ))
+        } else {
+            None
+        };
 
-                let file = OpenOptions::new()
-                    .read(true)
-                    .write(true)
-                    .custom_flags(custom_flags)
-                    .open(&pmem_cfg.file)
-                    .map_err(DeviceManagerError::PmemFileOpen)?;
+        let virtio_fs_device = Arc::new(Mutex::new(
+            vm_virtio::vhost_user::Fs::new(
+                fs_sock,
+                &fs_cfg.tag,
+                fs_cfg.num_queues,
+                fs_cfg.queue_size,
+                cache,
+            )
+            .map_err(DeviceManagerError::CreateVirtioFs)?,
+        ));
 
-                if set_len {
-                    file.set_len(size)
-                        .map_err(DeviceManagerError::PmemFileSetLen)?;
-                }
+        self.add_migratable_device(
+            fs_cfg.id.clone(),
+            Arc::clone(&virtio_fs_device) as Arc<Mutex<dyn Migratable>>,
+        );
 
-                let cloned_file = file.try_clone().map_err(DeviceManagerError::CloneFile)?;
-                let mmap_region =
-                    MmapRegion::from_file(FileOffset::new(cloned_file, 0), size as usize)
-                        .map_err(DeviceManagerError::NewMmapRegion)?;
-                let addr: u64 = mmap_region.as_ptr() as u64;
+        Ok((
+            Arc::clone(&virtio_fs_device) as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
+            false,
+            fs_cfg.id.clone(),
+        ))
+    }
 
-                self._mmap_regions.push(mmap_region);
+    fn make_virtio_fs_devices(
+        &mut self,
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, Option<String>)>> {
+        let mut devices = Vec::new();
 
-                self.memory_manager
-                    .lock()
-                    .unwrap()
-                    .create_userspace_mapping(
-                        pmem_guest_addr.raw_value(),
-                        size,
-                        addr,
-                        pmem_cfg.mergeable,
-                    )
-                    .map_err(DeviceManagerError::MemoryManager)?;
+        let mut fs_devices = self.config.lock().unwrap().fs.clone();
+        if let Some(fs_list_cfg) = &mut fs_devices {
+            for fs_cfg in fs_list_cfg.iter_mut() {
+                devices.push(self.make_virtio_fs_device(fs_cfg)?);
+            }
+        }
 
-                let virtio_pmem_device = Arc::new(Mutex::new(
-                    vm_virtio::Pmem::new(file, pmem_guest_addr, size as GuestUsize, pmem_cfg.iommu)
-                        .map_err(DeviceManagerError::CreateVirtioPmem)?,
-                ));
+        // Update the list of virtio-fs devices with the generated identifiers
+        self.config.lock().unwrap().fs = fs_devices;
 
-                devices.push((
-                    Arc::clone(&virtio_pmem_device) as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
-                    false,
-                ));
+        Ok(devices)
+    }
 
-                self.migratable_devices
-                    .push(Arc::clone(&virtio_pmem_device) as Arc<Mutex<dyn Migratable>>);
+    fn make_virtio_pmem_device(
+        &mut self,
+        pmem_cfg: &mut PmemConfig,
+    ) -> DeviceManagerResult<(VirtioDeviceArc, bool, Option<String>)> {
+        #[cfg(feature = "pci_support")]
+        {
+            if pmem_cfg.id.is_none() {
+                pmem_cfg.id = Some(self.next_device_name(PMEM_DEVICE_NAME_PREFIX)?);
             }
         }
 
-        Ok(devices)
-    }
+        let size = pmem_cfg.size;
 
-    fn make_virtio_vsock_devices(&mut self) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool)>> {
-        let mut devices = Vec::new();
-        // Add vsock if required
-        if let Some(vsock_list_cfg) = &self.config.lock().unwrap().vsock {
-            for vsock_cfg in vsock_list_cfg.iter() {
-                let socket_path = vsock_cfg
-                    .sock
-                    .to_str()
-                    .ok_or(DeviceManagerError::CreateVsockConvertPath)?;
-                let backend =
-                    vm_virtio::vsock::VsockUnixBackend::new(vsock_cfg.cid, socket_path.to_string())
-                        .map_err(DeviceManagerError::CreateVsockBackend)?;
-
-                let vsock_device = Arc::new(Mutex::new(
-                    vm_virtio::Vsock::new(vsock_cfg.cid, backend, vsock_cfg.iommu)
-                        .map_err(DeviceManagerError::CreateVirtioVsock)?,
-                ));
+        // The memory needs to be 2MiB aligned in order to support
+        // hugepages.
+        let pmem_guest_addr = self
+            .address_manager
+            .allocator
+            .lock()
+            .unwrap()
+            .allocate_mmio_addresses(None, size as GuestUsize, Some(0x0020_0000))
+            .ok_or(DeviceManagerError::PmemRangeAllocation)?;
 
-                devices.push((
-                    Arc::clone(&vsock_device) as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
-                    false,
-                ));
+        let (custom_flags, set_len) = if pmem_cfg.file.is_dir() {
+            (O_TMPFILE, true)
+        } else {
+            (0, false)
+        };
 
-                self.migratable_devices
-                    .push(Arc::clone(&vsock_device) as Arc<Mutex<dyn Migratable>>);
-            }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(custom_flags)
+            .open(&pmem_cfg.file)
+            .map_err(DeviceManagerError::PmemFileOpen)?;
+
+        if set_len {
+            file.set_len(size)
+                .map_err(DeviceManagerError::PmemFileSetLen)?;
         }
 
-        Ok(devices)
-    }
+        let cloned_file = file.try_clone().map_err(DeviceManagerError::CloneFile)?;
+        let mmap_region = MmapRegion::from_file(FileOffset::new(cloned_file, 0), size as usize)
+            .map_err(DeviceManagerError::NewMmapRegion)?;
 
-    #[cfg(feature = "pci_support")]
-    fn create_kvm_device(vm: &Arc<VmFd>) -> DeviceManagerResult<DeviceFd> {
+        self.add_device_mapping(
+            &pmem_cfg.id,
+            pmem_guest_addr,
+            mmap_region,
+            pmem_cfg.mergeable,
+        )?;
+
+        let virtio_pmem_device = Arc::new(Mutex::new(
+            vm_virtio::Pmem::new(file, pmem_guest_addr, size as GuestUsize, pmem_cfg.iommu)
+                .map_err(DeviceManagerError::CreateVirtioPmem)?,
+        ));
+
+        self.add_migratable_device(
+            pmem_cfg.id.clone(),
+            Arc::clone(&virtio_pmem_device) as Arc<Mutex<dyn Migratable>>,
+        );
+
+        Ok((
+            Arc::clone(&virtio_pmem_device) as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
+            false,
+            pmem_cfg.id.clone(),
+        ))
+    }
+
+    fn make_virtio_pmem_devices(
+        &mut self,
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, Option<String>)>> {
+        let mut devices = Vec::new();
+
+        let mut pmem_devices = self.config.lock().unwrap().pmem.clone();
+        if let Some(pmem_list_cfg) = &mut pmem_devices {
+            for pmem_cfg in pmem_list_cfg.iter_mut() {
+                devices.push(self.make_virtio_pmem_device(pmem_cfg)?);
+            }
+        }
+
+        // Update the list of virtio-pmem devices with the generated identifiers
+        self.config.lock().unwrap().pmem = pmem_devices;
+
+        Ok(devices)
+    }
+
+    fn make_virtio_vsock_device(
+        &mut self,
+        vsock_cfg: &mut VsockConfig,
+    ) -> DeviceManagerResult<(VirtioDeviceArc, bool, Option<String>)> {
+        #[cfg(feature = "pci_support")]
+        {
+            if vsock_cfg.id.is_none() {
+                vsock_cfg.id = Some(self.next_device_name(VSOCK_DEVICE_NAME_PREFIX)?);
+            }
+        }
+
+        let socket_path = vsock_cfg
+            .sock
+            .to_str()
+            .ok_or(DeviceManagerError::CreateVsockConvertPath)?;
+        let backend =
+            vm_virtio::vsock::VsockUnixBackend::new(vsock_cfg.cid, socket_path.to_string())
+                .map_err(DeviceManagerError::CreateVsockBackend)?;
+
+        let vsock_device = Arc::new(Mutex::new(
+            vm_virtio::Vsock::new(vsock_cfg.cid, backend, vsock_cfg.iommu)
+                .map_err(DeviceManagerError::CreateVirtioVsock)?,
+        ));
+
+        self.add_migratable_device(
+            vsock_cfg.id.clone(),
+            Arc::clone(&vsock_device) as Arc<Mutex<dyn Migratable>>,
+        );
+
+        Ok((
+            Arc::clone(&vsock_device) as Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
+            false,
+            vsock_cfg.id.clone(),
+        ))
+    }
+
+    fn make_virtio_vsock_devices(
+        &mut self,
+    ) -> DeviceManagerResult<Vec<(VirtioDeviceArc, bool, Option<String>)>> {
+        let mut devices = Vec::new();
+
+        let mut vsock_devices = self.config.lock().unwrap().vsock.clone();
+        if let Some(vsock_list_cfg) = &mut vsock_devices {
+            for vsock_cfg in vsock_list_cfg.iter_mut() {
+                devices.push(self.make_virtio_vsock_device(vsock_cfg)?);
+            }
+        }
+
+        // Update the list of vsock devices with the generated identifiers
+        self.config.lock().unwrap().vsock = vsock_devices;
+
+        Ok(devices)
+    }
+
+    #[cfg(feature = "pci_support")]
+    fn create_kvm_device(vm: &Arc<VmFd>) -> DeviceManagerResult<DeviceFd> {
         let mut vfio_dev = kvm_bindings::kvm_create_device {
             type_: kvm_bindings::kvm_device_type_KVM_DEV_TYPE_VFIO,
             fd: 0,
@@ -1478,7 +1976,7 @@ impl DeviceManager {
                 break;
             }
         }
-        Err(DeviceManagerError::NoAvailableVfioDeviceName)
+        Err(DeviceManagerError::NoAvailableDeviceName)
     }
 
     #[cfg(feature = "pci_support")]
@@ -1610,7 +2108,14 @@ impl DeviceManager {
         pci: &mut PciBus,
         iommu_mapping: &Option<Arc<IommuMapping>>,
         interrupt_manager: &Arc<dyn InterruptManager<GroupConfig = MsiIrqGroupConfig>>,
-    ) -> DeviceManagerResult<Option<u32>> {
+        id: Option<String>,
+    ) -> DeviceManagerResult<u32> {
+        if let Some(id) = &id {
+            if self.pci_id_list.contains_key(id) {
+                return Err(DeviceManagerError::DeviceIdAlreadyInUse);
+            }
+        }
+
         // Allows support for one MSI-X vector per queue. It also adds 1
         // as we need to take into account the dedicated vector to notify
         // about a virtio config change.
@@ -1647,18 +2152,19 @@ impl DeviceManager {
             };
 
         let memory = self.memory_manager.lock().unwrap().guest_memory();
+        let dirty_log = self.memory_manager.lock().unwrap().vmm_dirty_log();
         let mut virtio_pci_device = VirtioPciDevice::new(
             memory,
-            virtio_device,
+            virtio_device.clone(),
             msix_num,
             iommu_mapping_cb,
+            Some(dirty_log),
             interrupt_manager,
         )
         .map_err(DeviceManagerError::VirtioDevice)?;
 
-        let mut allocator = self.address_manager.allocator.lock().unwrap();
         let bars = virtio_pci_device
-            .allocate_bars(&mut allocator)
+            .allocate_bars(&mut self.address_manager.allocator.lock().unwrap())
             .map_err(DeviceManagerError::AllocateBars)?;
 
         let bar_addr = virtio_pci_device.config_bar_addr();
@@ -1670,14 +2176,12 @@ impl DeviceManager {
                 .map_err(DeviceManagerError::RegisterIoevent)?;
         }
 
+        let queue_counters = virtio_pci_device.queue_counters();
         let virtio_pci_device = Arc::new(Mutex::new(virtio_pci_device));
 
         pci.add_device(virtio_pci_device.clone())
             .map_err(DeviceManagerError::AddPciDevice)?;
 
-        self.bus_devices
-            .push(Arc::clone(&virtio_pci_device) as Arc<Mutex<dyn BusDevice>>);
-
         pci.register_mapping(
             virtio_pci_device.clone(),
             self.address_manager.io_bus.as_ref(),
@@ -1686,16 +2190,27 @@ impl DeviceManager {
         )
         .map_err(DeviceManagerError::AddPciDevice)?;
 
-        self.migratable_devices
-            .push(Arc::clone(&virtio_pci_device) as Arc<Mutex<dyn Migratable>>);
+        // The device is only tracked once it has been attached, so that a
+        // failure above doesn't leave stale entries behind.
+        self.add_virtio_counters(&virtio_device, queue_counters);
+        self.bus_devices
+            .push(Arc::clone(&virtio_pci_device) as Arc<Mutex<dyn BusDevice>>);
+
+        self.add_migratable_device(
+            id.clone(),
+            Arc::clone(&virtio_pci_device) as Arc<Mutex<dyn Migratable>>,
+        );
 
-        let ret = if iommu_mapping.is_some() {
-            Some(dev_id)
-        } else {
-            None
-        };
+        // Only the devices with an identifier can be removed.
+        if let Some(id) = id {
+            self.pci_devices.insert(
+                dev_id,
+                Arc::clone(&virtio_pci_device) as Arc<dyn Any + Send + Sync>,
+            );
+            self.pci_id_list.insert(id, dev_id);
+        }
 
-        Ok(ret)
+        Ok(dev_id)
     }
 
     #[cfg(feature = "mmio_support")]
@@ -1704,10 +2219,15 @@ impl DeviceManager {
         virtio_device: Arc<Mutex<dyn vm_virtio::VirtioDevice>>,
         interrupt_manager: &Arc<dyn InterruptManager<GroupConfig = LegacyIrqGroupConfig>>,
         mmio_base: GuestAddress,
+        id: Option<String>,
     ) -> DeviceManagerResult<()> {
         let memory = self.memory_manager.lock().unwrap().guest_memory();
-        let mut mmio_device = vm_virtio::transport::MmioDevice::new(memory, virtio_device)
-            .map_err(DeviceManagerError::VirtioDevice)?;
+        let dirty_log = self.memory_manager.lock().unwrap().vmm_dirty_log();
+        let mut mmio_device =
+            vm_virtio::transport::MmioDevice::new(memory, virtio_device.clone(), Some(dirty_log))
+                .map_err(DeviceManagerError::VirtioDevice)?;
+
+        self.add_virtio_counters(&virtio_device, mmio_device.queue_counters());
 
         for (i, (event, addr)) in mmio_device.ioeventfds(mmio_base.0).iter().enumerate() {
             let io_addr = IoEventAddress::Mmio(*addr);
@@ -1748,12 +2268,209 @@ impl DeviceManager {
             irq_num
         ));
 
-        self.migratable_devices
-            .push(Arc::clone(&mmio_device_arc) as Arc<Mutex<dyn Migratable>>);
+        self.add_migratable_device(
+            id,
+            Arc::clone(&mmio_device_arc) as Arc<Mutex<dyn Migratable>>,
+        );
+
+        Ok(())
+    }
+
+    // Registers a device to be paused, resumed and snapshotted along with
+    // the VM. Its identifier in the VM snapshot is derived from the device
+    // identifier, so that its state is restored into the same device even
+    // after other devices have been hotplugged or unplugged. Devices without
+    // an identifier can't be unplugged, and are all created from the VM
+    // configuration in the same order, so their rank among such devices
+    // identifies them as well.
+    fn add_migratable_device(&mut self, id: Option<String>, device: Arc<Mutex<dyn Migratable>>) {
+        let device_id = device.lock().unwrap().id();
+        let snapshot_id = if let Some(id) = id {
+            format!("{}-{}", id, device_id)
+        } else {
+            self.unnamed_migratable_devices += 1;
+            format!("{}-{}", device_id, self.unnamed_migratable_devices - 1)
+        };
+
+        self.migratable_devices.push((snapshot_id, device));
+    }
+
+    fn add_virtio_counters(
+        &mut self,
+        virtio_device: &VirtioDeviceArc,
+        queue_counters: Vec<Arc<QueueCounters>>,
+    ) {
+        let device_type = VirtioDeviceType::from(virtio_device.lock().unwrap().device_type());
+        let mut index = 0;
+        let name = loop {
+            let name = format!("{}{}", device_type, index);
+            if !self.virtio_counters.iter().any(|(n, _, _)| *n == name) {
+                break name;
+            }
+            index += 1;
+        };
+
+        self.virtio_counters
+            .push((name, Arc::clone(virtio_device), queue_counters));
+    }
+
+    /// Returns the counters of all the virtio devices, indexed by device name.
+    pub fn counters(&self) -> BTreeMap<String, BTreeMap<String, u64>> {
+        let mut counters = BTreeMap::new();
+
+        for (name, virtio_device, queue_counters) in self.virtio_counters.iter() {
+            let mut device_counters = BTreeMap::new();
+
+            if let Some(values) = virtio_device.lock().unwrap().counters() {
+                for (key, value) in values {
+                    device_counters.insert(key.to_string(), value);
+                }
+            }
+
+            for (i, queue) in queue_counters.iter().enumerate() {
+                device_counters.insert(
+                    format!("queue{}_notifications", i),
+                    queue.notifications.load(Ordering::Relaxed),
+                );
+                device_counters.insert(
+                    format!("queue{}_interrupts", i),
+                    queue.interrupts.load(Ordering::Relaxed),
+                );
+            }
+
+            counters.insert(name.clone(), device_counters);
+        }
+
+        counters
+    }
+
+    /// Creates an internal snapshot of the qcow2 disk `id`.
+    pub fn create_disk_snapshot(&self, id: &str, name: &str) -> DeviceManagerResult<()> {
+        self.qcow_disk(id)?
+            .lock()
+            .unwrap()
+            .create_snapshot(name)
+            .map_err(DeviceManagerError::DiskSnapshot)
+    }
+
+    /// Reverts the qcow2 disk `id` to one of its internal snapshots.
+    pub fn apply_disk_snapshot(&self, id: &str, name: &str) -> DeviceManagerResult<()> {
+        let disk = self.qcow_disk(id)?;
+        let mut disk = disk.lock().unwrap();
+
+        // The capacity exposed to the guest can't change.
+        if let Some(snapshot) = disk.snapshot(name) {
+            if snapshot.disk_size != disk.header().size {
+                return Err(DeviceManagerError::DiskSnapshotSizeMismatch(
+                    name.to_string(),
+                ));
+            }
+        }
+
+        disk.apply_snapshot(name)
+            .map_err(DeviceManagerError::DiskSnapshot)
+    }
+
+    /// Deletes one of the internal snapshots of the qcow2 disk `id`.
+    pub fn delete_disk_snapshot(&self, id: &str, name: &str) -> DeviceManagerResult<()> {
+        self.qcow_disk(id)?
+            .lock()
+            .unwrap()
+            .delete_snapshot(name)
+            .map_err(DeviceManagerError::DiskSnapshot)
+    }
+
+    fn qcow_disk(&self, id: &str) -> DeviceManagerResult<Arc<Mutex<QcowFile>>> {
+        self.qcow_disks
+            .get(id)
+            .cloned()
+            .ok_or_else(|| DeviceManagerError::UnknownQcowDiskId(id.to_string()))
+    }
+
+    /// Replaces the bandwidth and IOPS limits of the disk `id`.
+    pub fn set_disk_rate_limit(
+        &self,
+        id: &str,
+        rate_limiter_config: &RateLimiterConfig,
+    ) -> DeviceManagerResult<()> {
+        self.disk_rate_limiters
+            .get(id)
+            .ok_or_else(|| DeviceManagerError::UnknownRateLimitedDiskId(id.to_string()))?
+            .lock()
+            .unwrap()
+            .update(rate_limiter_config);
+        Ok(())
+    }
+
+    /// Grows the disk `id` to `size` bytes, updating the capacity seen by the guest.
+    pub fn resize_disk(&self, id: &str, size: u64) -> DeviceManagerResult<()> {
+        self.resizable_disks
+            .get(id)
+            .ok_or_else(|| DeviceManagerError::UnknownResizableDiskId(id.to_string()))?
+            .lock()
+            .unwrap()
+            .resize(size)
+            .map_err(DeviceManagerError::DiskResize)
+    }
+
+    /// Replaces the limits of the traffic received and transmitted by the
+    /// network interface `id`.
+    pub fn set_net_rate_limit(
+        &self,
+        id: &str,
+        rx_rate_limiter_config: &RateLimiterConfig,
+        tx_rate_limiter_config: &RateLimiterConfig,
+    ) -> DeviceManagerResult<()> {
+        let (rx_rate_limiter, tx_rate_limiter) = self
+            .net_rate_limiters
+            .get(id)
+            .ok_or_else(|| DeviceManagerError::UnknownRateLimitedNetId(id.to_string()))?;
+        rx_rate_limiter
+            .lock()
+            .unwrap()
+            .update(rx_rate_limiter_config);
+        tx_rate_limiter
+            .lock()
+            .unwrap()
+            .update(tx_rate_limiter_config);
+        Ok(())
+    }
+
+    /// Sets the link state of the network interface `id` reported to the
+    /// guest.
+    pub fn set_net_link(&self, id: &str, up: bool) -> DeviceManagerResult<()> {
+        self.virtio_net_devices
+            .get(id)
+            .ok_or_else(|| DeviceManagerError::UnknownVirtioNetId(id.to_string()))?
+            .lock()
+            .unwrap()
+            .set_link_up(up)
+            .map_err(DeviceManagerError::SetNetLink)
+    }
+
+    /// Lock all the disk images opened with lock=on. Either all the locks are
+    /// taken, or none of them.
+    pub fn acquire_disk_locks(&mut self) -> DeviceManagerResult<()> {
+        for (i, lock) in self.disk_locks.iter().enumerate() {
+            if let Err(e) = lock.acquire() {
+                for lock in self.disk_locks[..i].iter() {
+                    lock.release();
+                }
+                return Err(e);
+            }
+        }
+        self.disk_locks_held = true;
 
         Ok(())
     }
 
+    pub fn release_disk_locks(&mut self) {
+        for lock in self.disk_locks.iter() {
+            lock.release();
+        }
+        self.disk_locks_held = false;
+    }
+
     pub fn io_bus(&self) -> &Arc<devices::Bus> {
         &self.address_manager.io_bus
     }
@@ -1827,9 +2544,212 @@ impl DeviceManager {
         // Update the PCIU bitmap
         self.pci_devices_up |= 1 << (device_id >> 3);
 
+        if let Some(id) = &device_cfg.id {
+            event!("vm", "device-added", "id", id.as_str());
+        }
+
         Ok(())
     }
 
+    #[cfg(feature = "pci_support")]
+    fn validate_hotplug_device(&self, iommu: bool, id: &Option<String>) -> DeviceManagerResult<()> {
+        // The virtual IOMMU topology is built at boot time, and can't be
+        // updated for a hot plugged virtio device.
+        if iommu {
+            return Err(DeviceManagerError::InvalidIommuHotplug);
+        }
+
+        if let Some(id) = id {
+            if self.pci_id_list.contains_key(id) {
+                return Err(DeviceManagerError::DeviceIdAlreadyInUse);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "pci_support")]
+    fn hotplug_virtio_pci_device(
+        &mut self,
+        device: VirtioDeviceArc,
+        id: Option<String>,
+    ) -> DeviceManagerResult<()> {
+        let pci = if let Some(pci_bus) = &self.pci_bus {
+            Arc::clone(&pci_bus)
+        } else {
+            return Err(DeviceManagerError::NoPciBus);
+        };
+
+        let interrupt_manager = Arc::clone(&self.msi_interrupt_manager);
+
+        let device_id = self.add_virtio_pci_device(
+            Arc::clone(&device),
+            &mut pci.lock().unwrap(),
+            &None,
+            &interrupt_manager,
+            id.clone(),
+        )?;
+
+        // Update the PCIU bitmap
+        self.pci_devices_up |= 1 << (device_id >> 3);
+
+        if let Some(id) = &id {
+            event!("vm", "device-added", "id", id.as_str());
+        }
+
+        self.virtio_devices.push((device, false, id));
+
+        Ok(())
+    }
+
+    // Drops the entries the virtio devices identified by ids were registered
+    // with when they were created, releasing their disk image locks and the
+    // guest memory regions backing them.
+    #[cfg(feature = "pci_support")]
+    fn release_virtio_device_entries(&mut self, ids: &[String]) -> DeviceManagerResult<()> {
+        for id in ids.iter() {
+            self.qcow_disks.remove(id);
+            self.disk_rate_limiters.remove(id);
+            self.resizable_disks.remove(id);
+            self.net_rate_limiters.remove(id);
+            self.virtio_net_devices.remove(id);
+        }
+        self.disk_locks.retain(|lock| {
+            if lock.id.as_ref().map_or(false, |id| ids.contains(id)) {
+                lock.release();
+                false
+            } else {
+                true
+            }
+        });
+
+        for id in ids.iter() {
+            if let Some(mapping) = self.device_mappings.remove(id) {
+                self.memory_manager
+                    .lock()
+                    .unwrap()
+                    .remove_userspace_mapping(
+                        mapping.guest_addr.raw_value(),
+                        mapping.mmap_region.as_ptr() as u64,
+                        mapping.slot,
+                    )
+                    .map_err(DeviceManagerError::MemoryManager)?;
+                self.address_manager
+                    .allocator
+                    .lock()
+                    .unwrap()
+                    .free_mmio_addresses(mapping.guest_addr, mapping.mmap_region.size() as u64);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Undoes a failed hotplug, so that nothing is left behind from the
+    // device being created or attached, and the same device can be added
+    // again. A leaked disk image lock would otherwise make every retry fail.
+    #[cfg(feature = "pci_support")]
+    fn rollback_virtio_pci_device(&mut self, id: &Option<String>, migratable_devices: usize) {
+        self.migratable_devices.truncate(migratable_devices);
+
+        if let Some(id) = id {
+            if let Err(e) = self.release_virtio_device_entries(&[id.clone()]) {
+                warn!("Failed to release the entries for device {}: {:?}", id, e);
+            }
+        }
+    }
+
+    #[cfg(feature = "pci_support")]
+    pub fn add_disk(&mut self, disk_cfg: &mut DiskConfig) -> DeviceManagerResult<()> {
+        self.validate_hotplug_device(disk_cfg.iommu, &disk_cfg.id)?;
+
+        let migratable_devices = self.migratable_devices.len();
+        let result = self
+            .make_virtio_block_device(disk_cfg)
+            .and_then(|(device, _, id)| self.hotplug_virtio_pci_device(device, id));
+        if result.is_err() {
+            self.rollback_virtio_pci_device(&disk_cfg.id, migratable_devices);
+        }
+
+        result
+    }
+
+    #[cfg(feature = "pci_support")]
+    pub fn add_net(&mut self, net_cfg: &mut NetConfig) -> DeviceManagerResult<()> {
+        self.validate_hotplug_device(net_cfg.iommu, &net_cfg.id)?;
+
+        let migratable_devices = self.migratable_devices.len();
+        let result = self
+            .make_virtio_net_device(net_cfg)
+            .and_then(|(device, _, id)| self.hotplug_virtio_pci_device(device, id));
+        if result.is_err() {
+            self.rollback_virtio_pci_device(&net_cfg.id, migratable_devices);
+        }
+
+        result
+    }
+
+    #[cfg(feature = "pci_support")]
+    pub fn add_fs(&mut self, fs_cfg: &mut FsConfig) -> DeviceManagerResult<()> {
+        self.validate_hotplug_device(false, &fs_cfg.id)?;
+
+        let migratable_devices = self.migratable_devices.len();
+        let result = self
+            .make_virtio_fs_device(fs_cfg)
+            .and_then(|(device, _, id)| self.hotplug_virtio_pci_device(device, id));
+        if result.is_err() {
+            self.rollback_virtio_pci_device(&fs_cfg.id, migratable_devices);
+        }
+
+        result
+    }
+
+    #[cfg(feature = "pci_support")]
+    pub fn add_pmem(&mut self, pmem_cfg: &mut PmemConfig) -> DeviceManagerResult<()> {
+        self.validate_hotplug_device(pmem_cfg.iommu, &pmem_cfg.id)?;
+
+        let migratable_devices = self.migratable_devices.len();
+        let result = self
+            .make_virtio_pmem_device(pmem_cfg)
+            .and_then(|(device, _, id)| self.hotplug_virtio_pci_device(device, id));
+        if result.is_err() {
+            self.rollback_virtio_pci_device(&pmem_cfg.id, migratable_devices);
+        }
+
+        result
+    }
+
+    #[cfg(feature = "pci_support")]
+    pub fn add_vsock(&mut self, vsock_cfg: &mut VsockConfig) -> DeviceManagerResult<()> {
+        self.validate_hotplug_device(vsock_cfg.iommu, &vsock_cfg.id)?;
+
+        let migratable_devices = self.migratable_devices.len();
+        let result = self
+            .make_virtio_vsock_device(vsock_cfg)
+            .and_then(|(device, _, id)| self.hotplug_virtio_pci_device(device, id));
+        if result.is_err() {
+            self.rollback_virtio_pci_device(&vsock_cfg.id, migratable_devices);
+        }
+
+        result
+    }
+
+    #[cfg(feature = "pci_support")]
+    pub fn remove_net(&mut self, id: String) -> DeviceManagerResult<()> {
+        let is_net = self
+            .config
+            .lock()
+            .unwrap()
+            .net
+            .as_ref()
+            .map_or(false, |net| net.iter().any(|n| n.id.as_ref() == Some(&id)));
+        if !is_net {
+            return Err(DeviceManagerError::UnknownDeviceId(id));
+        }
+
+        self.remove_device(id)
+    }
+
     #[cfg(feature = "pci_support")]
     pub fn remove_device(&mut self, id: String) -> DeviceManagerResult<()> {
         if let Some(pci_device_bdf) = self.pci_id_list.get(&id) {
@@ -1838,10 +2758,57 @@ impl DeviceManager {
 
             Ok(())
         } else {
-            Err(DeviceManagerError::UnknownVfioDeviceId(id))
+            Err(DeviceManagerError::UnknownDeviceId(id))
         }
     }
 
+    #[cfg(feature = "pci_support")]
+    fn remove_virtio_pci_device(
+        &mut self,
+        virtio_pci_device: &Arc<Mutex<VirtioPciDevice>>,
+        ids: &[String],
+    ) -> DeviceManagerResult<()> {
+        // Stop the guest notifications from reaching the device.
+        let virtio_pci_device = virtio_pci_device.lock().unwrap();
+        let bar_addr = virtio_pci_device.config_bar_addr();
+        for (event, addr) in virtio_pci_device.ioeventfds(bar_addr) {
+            let io_addr = IoEventAddress::Mmio(addr);
+            self.address_manager
+                .vm_fd
+                .unregister_ioevent(event, &io_addr)
+                .map_err(DeviceManagerError::UnregisterIoevent)?;
+        }
+
+        // Drop all the references the DeviceManager holds on the virtio
+        // device, so that it gets dropped along with its transport.
+        let mut removed_devices = Vec::new();
+        self.virtio_devices.retain(|(device, _, id)| {
+            if id.as_ref().map_or(false, |id| ids.contains(id)) {
+                removed_devices.push(Arc::clone(device));
+                false
+            } else {
+                true
+            }
+        });
+
+        self.release_virtio_device_entries(ids)?;
+
+        for device in removed_devices.iter() {
+            device.lock().unwrap().shutdown();
+
+            // The virtio device and its Migratable counterpart are the same
+            // object, behind different trait objects.
+            let device_ptr = &**device as *const Mutex<dyn vm_virtio::VirtioDevice> as *const u8;
+            self.migratable_devices.retain(|(_, dev)| {
+                &**dev as *const Mutex<dyn Migratable> as *const u8 != device_ptr
+            });
+            self.virtio_counters
+                .retain(|(_, dev, _)| !Arc::ptr_eq(dev, device));
+        }
+
+        Ok(())
+    }
+
     #[cfg(feature = "pci_support")]
     pub fn eject_device(&mut self, device_id: u8) -> DeviceManagerResult<()> {
         // Retrieve the PCI bus.
@@ -1856,7 +2823,18 @@ impl DeviceManager {
 
         // Find the device name corresponding to the PCI b/d/f while removing
         // the device entry.
-        self.pci_id_list.retain(|_, bdf| *bdf != pci_device_bdf);
+        let mut removed_ids = Vec::new();
+        self.pci_id_list.retain(|id, bdf| {
+            if *bdf == pci_device_bdf {
+                removed_ids.push(id.clone());
+                false
+            } else {
+                true
+            }
+        });
+        for id in removed_ids.iter() {
+            event!("vm", "device-removed", "id", id.as_str());
+        }
 
         // Give the PCI device ID back to the PCI bus.
         pci.lock()
@@ -1866,14 +2844,24 @@ impl DeviceManager {
 
         if let Some(any_device) = self.pci_devices.remove(&pci_device_bdf) {
             let (pci_device, bus_device, migratable_device) =
-                if let Ok(vfio_pci_device) = any_device.downcast::<Mutex<VfioPciDevice>>() {
-                    (
+                match any_device.downcast::<Mutex<VfioPciDevice>>() {
+                    Ok(vfio_pci_device) => (
                         Arc::clone(&vfio_pci_device) as Arc<Mutex<dyn PciDevice>>,
                         Arc::clone(&vfio_pci_device) as Arc<Mutex<dyn BusDevice>>,
                         None as Option<Arc<Mutex<dyn Migratable>>>,
-                    )
-                } else {
-                    return Ok(());
+                    ),
+                    Err(any_device) => match any_device.downcast::<Mutex<VirtioPciDevice>>() {
+                        Ok(virtio_pci_device) => {
+                            self.remove_virtio_pci_device(&virtio_pci_device, &removed_ids)?;
+
+                            (
+                                Arc::clone(&virtio_pci_device) as Arc<Mutex<dyn PciDevice>>,
+                                Arc::clone(&virtio_pci_device) as Arc<Mutex<dyn BusDevice>>,
+                                Some(Arc::clone(&virtio_pci_device) as Arc<Mutex<dyn Migratable>>),
+                            )
+                        }
+                        Err(_) => return Ok(()),
+                    },
                 };
 
             // Free the allocated BARs
@@ -1907,7 +2895,7 @@ impl DeviceManager {
             // Remove the device from the list of Migratable devices.
             if let Some(migratable_device) = &migratable_device {
                 self.migratable_devices
-                    .retain(|dev| !Arc::ptr_eq(dev, &migratable_device));
+                    .retain(|(_, dev)| !Arc::ptr_eq(dev, &migratable_device));
             }
 
             // At this point, the device has been removed from all the list and
@@ -2153,6 +3141,19 @@ impl Aml for DeviceManager {
         )
         .to_aml_bytes();
 
+        let pvpanic_dsdt_data = aml::Device::new(
+            "_SB_.PEVT".into(),
+            vec![
+                &aml::Name::new("_HID".into(), &"QEMU0001"),
+                &aml::Name::new("_UID".into(), &aml::ZERO),
+                &aml::Name::new(
+                    "_CRS".into(),
+                    &aml::ResourceTemplate::new(vec![&aml::IO::new(0x505, 0x505, 1, 0x1)]),
+                ),
+            ],
+        )
+        .to_aml_bytes();
+
         let s5_sleep_data =
             aml::Name::new("_S5_".into(), &aml::Package::new(vec![&5u8])).to_aml_bytes();
 
@@ -2169,6 +3170,7 @@ impl Aml for DeviceManager {
         if self.config.lock().unwrap().serial.mode != ConsoleOutputMode::Off {
             bytes.extend_from_slice(com1_dsdt_data.as_slice());
         }
+        bytes.extend_from_slice(pvpanic_dsdt_data.as_slice());
         bytes.extend_from_slice(s5_sleep_data.as_slice());
         bytes.extend_from_slice(ged_data.as_slice());
         bytes
@@ -2177,7 +3179,7 @@ impl Aml for DeviceManager {
 
 impl Pausable for DeviceManager {
     fn pause(&mut self) -> result::Result<(), MigratableError> {
-        for dev in &self.migratable_devices {
+        for (_, dev) in &self.migratable_devices {
             dev.lock().unwrap().pause()?;
         }
 
@@ -2185,7 +3187,7 @@ impl Pausable for DeviceManager {
     }
 
     fn resume(&mut self) -> result::Result<(), MigratableError> {
-        for dev in &self.migratable_devices {
+        for (_, dev) in &self.migratable_devices {
             dev.lock().unwrap().resume()?;
         }
 
@@ -2193,7 +3195,39 @@ impl Pausable for DeviceManager {
     }
 }
 
-impl Snapshotable for DeviceManager {}
+pub const DEVICE_MANAGER_SNAPSHOT_ID: &str = "device-manager";
+
+impl Snapshotable for DeviceManager {
+    fn id(&self) -> String {
+        DEVICE_MANAGER_SNAPSHOT_ID.to_string()
+    }
+
+    fn snapshot(&self) -> result::Result<Snapshot, MigratableError> {
+        let mut snapshot = Snapshot::new(DEVICE_MANAGER_SNAPSHOT_ID);
+
+        for (id, dev) in &self.migratable_devices {
+            let mut device_snapshot = dev.lock().unwrap().snapshot()?;
+            device_snapshot.id = id.clone();
+            snapshot.add_snapshot(device_snapshot);
+        }
+
+        Ok(snapshot)
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> result::Result<(), MigratableError> {
+        for (id, dev) in &self.migratable_devices {
+            let mut dev = dev.lock().unwrap();
+            let device_snapshot = snapshot.snapshots.get(id).ok_or_else(|| {
+                MigratableError::Restore(anyhow!("Missing device snapshot {}", id))
+            })?;
+
+            dev.restore(*device_snapshot.clone())?;
+        }
+
+        Ok(())
+    }
+}
+
 impl Migratable for DeviceManager {}
 
 #[cfg(feature = "pci_support")]
@@ -2271,7 +3305,7 @@ impl BusDevice for DeviceManager {
 
 impl Drop for DeviceManager {
     fn drop(&mut self) {
-        for (device, _) in self.virtio_devices.drain(..) {
+        for (device, _, _) in self.virtio_devices.drain(..) {
             device.lock().unwrap().shutdown();
         }
     }
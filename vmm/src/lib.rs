@@ -5,6 +5,8 @@
 
 extern crate arc_swap;
 #[macro_use]
+extern crate event_monitor;
+#[macro_use]
 extern crate lazy_static;
 #[macro_use]
 extern crate log;
@@ -15,8 +17,13 @@ extern crate serde_json;
 extern crate tempfile;
 extern crate vmm_sys_util;
 
-use crate::api::{ApiError, ApiRequest, ApiResponse, ApiResponsePayload, VmInfo, VmmPingResponse};
-use crate::config::{DeviceConfig, VmConfig};
+use crate::api::{
+    ApiError, ApiRequest, ApiResponse, ApiResponsePayload, DiskSnapshotAction, VmCounters, VmInfo,
+    VmmPingResponse,
+};
+use crate::config::{
+    DeviceConfig, DiskConfig, FsConfig, NetConfig, PmemConfig, RestoreConfig, VmConfig, VsockConfig,
+};
 use crate::vm::{Error as VmError, Vm, VmState};
 use libc::EFD_NONBLOCK;
 use std::io;
@@ -25,7 +32,8 @@ use std::path::PathBuf;
 use std::sync::mpsc::{Receiver, RecvError, SendError, Sender};
 use std::sync::{Arc, Mutex};
 use std::{result, thread};
-use vm_device::Pausable;
+use vm_device::{Pausable, Snapshotable};
+use vm_virtio::RateLimiterConfig;
 use vmm_sys_util::eventfd::EventFd;
 
 pub mod api;
@@ -34,6 +42,7 @@ pub mod cpu;
 pub mod device_manager;
 pub mod interrupt;
 pub mod memory_manager;
+pub mod migration;
 pub mod vm;
 
 #[cfg(feature = "acpi")]
@@ -231,6 +240,8 @@ impl Vmm {
     }
 
     fn vm_boot(&mut self) -> result::Result<(), VmError> {
+        event!("vm", "booting");
+
         // Create a new VM is we don't have one yet.
         if self.vm.is_none() {
             let exit_evt = self.exit_evt.try_clone().map_err(VmError::EventFdClone)?;
@@ -249,15 +260,21 @@ impl Vmm {
 
         // Now we can boot the VM.
         if let Some(ref mut vm) = self.vm {
-            vm.boot()
+            vm.boot()?;
         } else {
-            Err(VmError::VmNotCreated)
+            return Err(VmError::VmNotCreated);
         }
+
+        event!("vm", "booted");
+        Ok(())
     }
 
     fn vm_pause(&mut self) -> result::Result<(), VmError> {
         if let Some(ref mut vm) = self.vm {
-            vm.pause().map_err(VmError::Pause)
+            event!("vm", "pausing");
+            vm.pause().map_err(VmError::Pause)?;
+            event!("vm", "paused");
+            Ok(())
         } else {
             Err(VmError::VmNotRunning)
         }
@@ -265,21 +282,84 @@ impl Vmm {
 
     fn vm_resume(&mut self) -> result::Result<(), VmError> {
         if let Some(ref mut vm) = self.vm {
-            vm.resume().map_err(VmError::Resume)
+            event!("vm", "resuming");
+            vm.resume().map_err(VmError::Resume)?;
+            event!("vm", "resumed");
+            Ok(())
         } else {
             Err(VmError::VmNotRunning)
         }
     }
 
+    fn vm_snapshot(&mut self, destination_url: &str) -> result::Result<(), VmError> {
+        if let Some(ref vm) = self.vm {
+            event!("vm", "snapshotting");
+            let snapshot = vm.snapshot().map_err(VmError::Snapshot)?;
+            vm.send_snapshot(&snapshot, destination_url)?;
+            event!("vm", "snapshotted");
+            Ok(())
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_restore(&mut self, restore_cfg: RestoreConfig) -> result::Result<(), VmError> {
+        let exit_evt = self.exit_evt.try_clone().map_err(VmError::EventFdClone)?;
+        let reset_evt = self.reset_evt.try_clone().map_err(VmError::EventFdClone)?;
+
+        event!("vm", "restoring");
+        let vm = Vm::new_from_snapshot(
+            &restore_cfg.source_url,
+            exit_evt,
+            reset_evt,
+            self.vmm_path.clone(),
+        )?;
+        self.vm_config = Some(vm.get_config());
+        self.vm = Some(vm);
+        event!("vm", "restored");
+
+        Ok(())
+    }
+
+    fn vm_send_migration(&mut self, destination_url: &str) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            event!("vm", "migrating");
+            vm.send_migration(destination_url)?;
+            event!("vm", "migrated");
+        } else {
+            return Err(VmError::VmNotRunning);
+        }
+
+        // The VM now runs in the destination VMM, we can get rid of it.
+        self.vm_delete()
+    }
+
+    fn vm_receive_migration(&mut self, receiver_url: &str) -> result::Result<(), VmError> {
+        let exit_evt = self.exit_evt.try_clone().map_err(VmError::EventFdClone)?;
+        let reset_evt = self.reset_evt.try_clone().map_err(VmError::EventFdClone)?;
+
+        event!("vm", "receiving-migration");
+        let vm = Vm::new_from_migration(receiver_url, exit_evt, reset_evt, self.vmm_path.clone())?;
+        self.vm_config = Some(vm.get_config());
+        self.vm = Some(vm);
+        event!("vm", "migration-received");
+
+        Ok(())
+    }
+
     fn vm_shutdown(&mut self) -> result::Result<(), VmError> {
         if let Some(ref mut vm) = self.vm.take() {
-            vm.shutdown()
+            vm.shutdown()?;
+            event!("vm", "shutdown");
+            Ok(())
         } else {
             Err(VmError::VmNotRunning)
         }
     }
 
     fn vm_reboot(&mut self) -> result::Result<(), VmError> {
+        event!("vm", "rebooting");
+
         // Without ACPI, a reset is equivalent to a shutdown
         #[cfg(not(feature = "acpi"))]
         {
@@ -312,6 +392,7 @@ impl Vmm {
             return Err(VmError::VmNotCreated);
         }
 
+        event!("vm", "rebooted");
         Ok(())
     }
 
@@ -332,6 +413,14 @@ impl Vmm {
         }
     }
 
+    fn vm_counters(&self) -> result::Result<VmCounters, VmError> {
+        if let Some(ref vm) = self.vm {
+            Ok(vm.counters())
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
     fn vmm_ping(&self) -> result::Result<VmmPingResponse, ApiError> {
         Ok(VmmPingResponse {
             version: self.version.clone(),
@@ -348,11 +437,14 @@ impl Vmm {
 
         self.vm_config = None;
 
+        event!("vm", "deleted");
         Ok(())
     }
 
     fn vmm_shutdown(&mut self) -> result::Result<(), VmError> {
-        self.vm_delete()
+        self.vm_delete()?;
+        event!("vmm", "shutdown");
+        Ok(())
     }
 
     fn vm_resize(
@@ -361,10 +453,12 @@ impl Vmm {
         desired_ram: Option<u64>,
     ) -> result::Result<(), VmError> {
         if let Some(ref mut vm) = self.vm {
+            event!("vm", "resizing");
             if let Err(e) = vm.resize(desired_vcpus, desired_ram) {
                 error!("Error when resizing VM: {:?}", e);
                 Err(e)
             } else {
+                event!("vm", "resized");
                 Ok(())
             }
         } else {
@@ -385,6 +479,87 @@ impl Vmm {
         }
     }
 
+    fn vm_add_disk(&mut self, disk_cfg: DiskConfig) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            if let Err(e) = vm.add_disk(disk_cfg) {
+                error!("Error when adding new disk to the VM: {:?}", e);
+                Err(e)
+            } else {
+                Ok(())
+            }
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_add_net(&mut self, net_cfg: NetConfig) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            if let Err(e) = vm.add_net(net_cfg) {
+                error!("Error when adding new network device to the VM: {:?}", e);
+                Err(e)
+            } else {
+                Ok(())
+            }
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_add_fs(&mut self, fs_cfg: FsConfig) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            if let Err(e) = vm.add_fs(fs_cfg) {
+                error!("Error when adding new virtio-fs device to the VM: {:?}", e);
+                Err(e)
+            } else {
+                Ok(())
+            }
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_add_pmem(&mut self, pmem_cfg: PmemConfig) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            if let Err(e) = vm.add_pmem(pmem_cfg) {
+                error!(
+                    "Error when adding new virtio-pmem device to the VM: {:?}",
+                    e
+                );
+                Err(e)
+            } else {
+                Ok(())
+            }
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_add_vsock(&mut self, vsock_cfg: VsockConfig) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            if let Err(e) = vm.add_vsock(vsock_cfg) {
+                error!("Error when adding new vsock device to the VM: {:?}", e);
+                Err(e)
+            } else {
+                Ok(())
+            }
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_remove_net(&mut self, id: String) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            if let Err(e) = vm.remove_net(id) {
+                error!("Error when removing network device from the VM: {:?}", e);
+                Err(e)
+            } else {
+                Ok(())
+            }
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
     fn vm_remove_device(&mut self, id: String) -> result::Result<(), VmError> {
         if let Some(ref mut vm) = self.vm {
             if let Err(e) = vm.remove_device(id) {
@@ -398,6 +573,92 @@ impl Vmm {
         }
     }
 
+    fn vm_disk_snapshot(
+        &mut self,
+        action: DiskSnapshotAction,
+        id: &str,
+        name: &str,
+    ) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            let result = match action {
+                DiskSnapshotAction::Create => vm.create_disk_snapshot(id, name),
+                DiskSnapshotAction::Apply => vm.apply_disk_snapshot(id, name),
+                DiskSnapshotAction::Delete => vm.delete_disk_snapshot(id, name),
+            };
+            if let Err(e) = result {
+                error!("Error when operating on disk snapshot: {:?}", e);
+                Err(e)
+            } else {
+                Ok(())
+            }
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_set_disk_rate_limit(
+        &mut self,
+        id: &str,
+        rate_limiter_config: RateLimiterConfig,
+    ) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            if let Err(e) = vm.set_disk_rate_limit(id, rate_limiter_config) {
+                error!("Error when updating disk rate limits: {:?}", e);
+                Err(e)
+            } else {
+                Ok(())
+            }
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_resize_disk(&mut self, id: &str, desired_size: u64) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            if let Err(e) = vm.resize_disk(id, desired_size) {
+                error!("Error when resizing disk: {:?}", e);
+                Err(e)
+            } else {
+                Ok(())
+            }
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_set_net_rate_limit(
+        &mut self,
+        id: &str,
+        rx_rate_limiter_config: RateLimiterConfig,
+        tx_rate_limiter_config: RateLimiterConfig,
+    ) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            if let Err(e) =
+                vm.set_net_rate_limit(id, rx_rate_limiter_config, tx_rate_limiter_config)
+            {
+                error!("Error when updating network rate limits: {:?}", e);
+                Err(e)
+            } else {
+                Ok(())
+            }
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
+    fn vm_set_net_link(&mut self, id: &str, up: bool) -> result::Result<(), VmError> {
+        if let Some(ref mut vm) = self.vm {
+            if let Err(e) = vm.set_net_link(id, up) {
+                error!("Error when setting the network link state: {:?}", e);
+                Err(e)
+            } else {
+                Ok(())
+            }
+        } else {
+            Err(VmError::VmNotRunning)
+        }
+    }
+
     fn control_loop(&mut self, api_receiver: Arc<Receiver<ApiRequest>>) -> Result<()> {
         const EPOLL_EVENTS_LEN: usize = 100;
 
@@ -512,6 +773,14 @@ impl Vmm {
 
                                     sender.send(response).map_err(Error::ApiResponseSend)?;
                                 }
+                                ApiRequest::VmCounters(sender) => {
+                                    let response = self
+                                        .vm_counters()
+                                        .map_err(ApiError::VmCounters)
+                                        .map(ApiResponsePayload::VmCounters);
+
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
                                 ApiRequest::VmmPing(sender) => {
                                     let response = self.vmm_ping().map(ApiResponsePayload::VmmPing);
 
@@ -560,6 +829,48 @@ impl Vmm {
                                         .map(|_| ApiResponsePayload::Empty);
                                     sender.send(response).map_err(Error::ApiResponseSend)?;
                                 }
+                                ApiRequest::VmAddDisk(add_disk_data, sender) => {
+                                    let response = self
+                                        .vm_add_disk(add_disk_data.as_ref().clone())
+                                        .map_err(ApiError::VmAddDisk)
+                                        .map(|_| ApiResponsePayload::Empty);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmAddNet(add_net_data, sender) => {
+                                    let response = self
+                                        .vm_add_net(add_net_data.as_ref().clone())
+                                        .map_err(ApiError::VmAddNet)
+                                        .map(|_| ApiResponsePayload::Empty);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmAddFs(add_fs_data, sender) => {
+                                    let response = self
+                                        .vm_add_fs(add_fs_data.as_ref().clone())
+                                        .map_err(ApiError::VmAddFs)
+                                        .map(|_| ApiResponsePayload::Empty);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmAddPmem(add_pmem_data, sender) => {
+                                    let response = self
+                                        .vm_add_pmem(add_pmem_data.as_ref().clone())
+                                        .map_err(ApiError::VmAddPmem)
+                                        .map(|_| ApiResponsePayload::Empty);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmAddVsock(add_vsock_data, sender) => {
+                                    let response = self
+                                        .vm_add_vsock(add_vsock_data.as_ref().clone())
+                                        .map_err(ApiError::VmAddVsock)
+                                        .map(|_| ApiResponsePayload::Empty);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmRemoveNet(remove_net_data, sender) => {
+                                    let response = self
+                                        .vm_remove_net(remove_net_data.id.clone())
+                                        .map_err(ApiError::VmRemoveNet)
+                                        .map(|_| ApiResponsePayload::Empty);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
                                 ApiRequest::VmRemoveDevice(remove_device_data, sender) => {
                                     let response = self
                                         .vm_remove_device(remove_device_data.id.clone())
@@ -567,6 +878,97 @@ impl Vmm {
                                         .map(|_| ApiResponsePayload::Empty);
                                     sender.send(response).map_err(Error::ApiResponseSend)?;
                                 }
+                                ApiRequest::VmSnapshot(snapshot_data, sender) => {
+                                    let response = self
+                                        .vm_snapshot(&snapshot_data.destination_url)
+                                        .map_err(ApiError::VmSnapshot)
+                                        .map(|_| ApiResponsePayload::Empty);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmDiskSnapshot(action, disk_snapshot_data, sender) => {
+                                    let response = self
+                                        .vm_disk_snapshot(
+                                            action,
+                                            &disk_snapshot_data.id,
+                                            &disk_snapshot_data.name,
+                                        )
+                                        .map_err(ApiError::VmDiskSnapshot)
+                                        .map(|_| ApiResponsePayload::Empty);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmSetDiskRateLimit(disk_rate_limit_data, sender) => {
+                                    let response = self
+                                        .vm_set_disk_rate_limit(
+                                            &disk_rate_limit_data.id,
+                                            disk_rate_limit_data.rate_limiter_config,
+                                        )
+                                        .map_err(ApiError::VmSetDiskRateLimit)
+                                        .map(|_| ApiResponsePayload::Empty);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmSetNetRateLimit(net_rate_limit_data, sender) => {
+                                    let response = self
+                                        .vm_set_net_rate_limit(
+                                            &net_rate_limit_data.id,
+                                            net_rate_limit_data.rx_rate_limiter_config,
+                                            net_rate_limit_data.tx_rate_limiter_config,
+                                        )
+                                        .map_err(ApiError::VmSetNetRateLimit)
+                                        .map(|_| ApiResponsePayload::Empty);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmSetNetLink(net_link_data, sender) => {
+                                    let response = self
+                                        .vm_set_net_link(&net_link_data.id, net_link_data.up)
+                                        .map_err(ApiError::VmSetNetLink)
+                                        .map(|_| ApiResponsePayload::Empty);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmResizeDisk(resize_disk_data, sender) => {
+                                    let response = self
+                                        .vm_resize_disk(
+                                            &resize_disk_data.id,
+                                            resize_disk_data.desired_size,
+                                        )
+                                        .map_err(ApiError::VmResizeDisk)
+                                        .map(|_| ApiResponsePayload::Empty);
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmRestore(restore_data, sender) => {
+                                    // We can only restore into a VMM without any VM.
+                                    let response = if self.vm_config.is_none() {
+                                        self.vm_restore(restore_data.as_ref().clone())
+                                            .map_err(ApiError::VmRestore)
+                                            .map(|_| ApiResponsePayload::Empty)
+                                    } else {
+                                        Err(ApiError::VmAlreadyCreated)
+                                    };
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
+                                ApiRequest::VmSendMigration(migration_data, sender) => {
+                                    let response = self
+                                        .vm_send_migration(&migration_data.destination_url)
+                                        .map_err(ApiError::VmSendMigration)
+                                        .map(|_| ApiResponsePayload::Empty);
+                                    let migrated = response.is_ok();
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+
+                                    // The VM lives on in the destination VMM.
+                                    if migrated {
+                                        break 'outer;
+                                    }
+                                }
+                                ApiRequest::VmReceiveMigration(migration_data, sender) => {
+                                    // We can only receive a VM into a VMM without any VM.
+                                    let response = if self.vm_config.is_none() {
+                                        self.vm_receive_migration(&migration_data.receiver_url)
+                                            .map_err(ApiError::VmReceiveMigration)
+                                            .map(|_| ApiResponsePayload::Empty)
+                                    } else {
+                                        Err(ApiError::VmAlreadyCreated)
+                                    };
+                                    sender.send(response).map_err(Error::ApiResponseSend)?;
+                                }
                             }
                         }
                     }
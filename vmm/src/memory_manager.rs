@@ -5,27 +5,33 @@
 
 #[cfg(feature = "acpi")]
 use acpi_tables::{aml, aml::Aml};
+use anyhow::anyhow;
 use arch::RegionType;
 use devices::BusDevice;
-use kvm_bindings::kvm_userspace_memory_region;
+use kvm_bindings::{kvm_userspace_memory_region, KVM_MEM_LOG_DIRTY_PAGES};
 use kvm_ioctls::*;
+use std::cmp::min;
 use std::convert::TryInto;
 use std::fs::{File, OpenOptions};
-use std::io;
+use std::io::{self, Read, Write};
 use std::os::unix::io::FromRawFd;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use vm_allocator::SystemAllocator;
+use vm_device::{MigratableError, Snapshot, SnapshotDataSection, Snapshotable};
 use vm_memory::guest_memory::FileOffset;
 use vm_memory::{
-    mmap::MmapRegionError, Address, Error as MmapError, GuestAddress, GuestAddressSpace,
+    mmap::MmapRegionError, Address, Bytes, Error as MmapError, GuestAddress, GuestAddressSpace,
     GuestMemory, GuestMemoryAtomic, GuestMemoryMmap, GuestMemoryRegion, GuestRegionMmap,
     GuestUsize, MmapRegion,
 };
+use vm_virtio::{DirtyLog, DIRTY_LOG_PAGE_SIZE};
 
 const HOTPLUG_COUNT: usize = 8;
 
-#[derive(Default)]
+pub const MEMORY_MANAGER_SNAPSHOT_ID: &str = "memory-manager";
+
+#[derive(Clone, Default, Deserialize, Serialize)]
 struct HotPlugState {
     base: u64,
     length: u64,
@@ -34,6 +40,22 @@ struct HotPlugState {
     removing: bool,
 }
 
+// A guest RAM region mapped into the guest through a KVM memory slot.
+#[derive(Clone, Copy)]
+struct GuestRamMapping {
+    slot: u32,
+    gpa: u64,
+    size: u64,
+    userspace_addr: u64,
+}
+
+/// The dirty pages of a guest RAM region, one bit per page.
+pub struct DirtyRegion {
+    pub start: GuestAddress,
+    pub size: u64,
+    pub bitmap: Vec<u64>,
+}
+
 pub struct MemoryManager {
     guest_memory: GuestMemoryAtomic<GuestMemoryMmap>,
     next_kvm_memory_slot: u32,
@@ -45,8 +67,22 @@ pub struct MemoryManager {
     backing_file: Option<PathBuf>,
     mergeable: bool,
     allocator: Arc<Mutex<SystemAllocator>>,
+    boot_ram: u64,
     current_ram: u64,
     next_hotplug_slot: usize,
+    guest_ram_mappings: Vec<GuestRamMapping>,
+    dirty_log: Arc<DirtyLog>,
+}
+
+// The memory manager state saved in a snapshot. The guest RAM content is
+// not part of it, and is saved separately through write_memory().
+#[derive(Deserialize, Serialize)]
+struct MemoryManagerState {
+    boot_ram: u64,
+    current_ram: u64,
+    hotplug_slots: Vec<HotPlugState>,
+    next_hotplug_slot: usize,
+    regions: Vec<(u64, u64)>,
 }
 
 #[derive(Debug)]
@@ -80,6 +116,9 @@ pub enum Error {
 
     /// Failed to set the user memory region.
     SetUserMemoryRegion(kvm_ioctls::Error),
+
+    /// Failed to get the dirty log of a memory region.
+    GetDirtyLog(kvm_ioctls::Error),
 }
 
 pub fn get_host_cpu_phys_bits() -> u8 {
@@ -248,18 +287,19 @@ impl MemoryManager {
             backing_file: backing_file.clone(),
             mergeable,
             allocator: allocator.clone(),
+            boot_ram,
             current_ram: boot_ram,
             next_hotplug_slot: 0,
+            guest_ram_mappings: Vec::new(),
+            dirty_log: Arc::new(DirtyLog::new()),
         }));
 
         guest_memory.memory().with_regions(|_, region| {
-            let _ = memory_manager.lock().unwrap().create_userspace_mapping(
+            memory_manager.lock().unwrap().create_ram_mapping(
                 region.start_addr().raw_value(),
                 region.len() as u64,
                 region.as_ptr() as u64,
-                mergeable,
-            )?;
-            Ok(())
+            )
         })?;
 
         // Allocate RAM and Reserved address ranges.
@@ -274,6 +314,62 @@ impl MemoryManager {
         Ok(memory_manager)
     }
 
+    /// Create a memory manager from a snapshot.
+    ///
+    /// The guest RAM layout is rebuilt by replaying the memory hotplug
+    /// operations recorded in the snapshot, so that all regions end up at
+    /// the same guest addresses. The guest RAM content must then be filled
+    /// through read_memory().
+    pub fn new_from_snapshot(
+        snapshot: &Snapshot,
+        allocator: Arc<Mutex<SystemAllocator>>,
+        fd: Arc<VmFd>,
+        hotplug_size: Option<u64>,
+        backing_file: &Option<PathBuf>,
+        mergeable: bool,
+    ) -> Result<Arc<Mutex<MemoryManager>>, MigratableError> {
+        let section = snapshot
+            .snapshot_data
+            .get(&format!("{}-section", MEMORY_MANAGER_SNAPSHOT_ID))
+            .ok_or_else(|| {
+                MigratableError::Restore(anyhow!("Could not find memory manager snapshot section"))
+            })?;
+        let state: MemoryManagerState = serde_json::from_slice(&section.snapshot)
+            .map_err(|e| MigratableError::Restore(e.into()))?;
+
+        let memory_manager = MemoryManager::new(
+            allocator,
+            fd,
+            state.boot_ram,
+            hotplug_size,
+            backing_file,
+            mergeable,
+        )
+        .map_err(|e| {
+            MigratableError::Restore(anyhow!("Could not create memory manager {:?}", e))
+        })?;
+
+        {
+            let mut mm = memory_manager.lock().unwrap();
+            for slot in state.hotplug_slots.iter().take(state.next_hotplug_slot) {
+                mm.hotplug_ram_region(slot.length as usize).map_err(|e| {
+                    MigratableError::Restore(anyhow!("Could not restore RAM region {:?}", e))
+                })?;
+            }
+
+            if mm.regions() != state.regions {
+                return Err(MigratableError::Restore(anyhow!(
+                    "Guest RAM layout does not match the snapshot"
+                )));
+            }
+
+            mm.hotplug_slots = state.hotplug_slots;
+            mm.current_ram = state.current_ram;
+        }
+
+        Ok(memory_manager)
+    }
+
     fn create_ram_region(
         backing_file: &Option<PathBuf>,
         start_addr: GuestAddress,
@@ -345,11 +441,10 @@ impl MemoryManager {
         let region = MemoryManager::create_ram_region(&self.backing_file, start_addr, size)?;
 
         // Map it into the guest
-        self.create_userspace_mapping(
+        self.create_ram_mapping(
             region.start_addr().0,
             region.len() as u64,
             region.as_ptr() as u64,
-            self.mergeable,
         )?;
 
         // Tell the allocator
@@ -450,6 +545,193 @@ impl MemoryManager {
         Ok(slot)
     }
 
+    pub fn remove_userspace_mapping(
+        &mut self,
+        guest_phys_addr: u64,
+        userspace_addr: u64,
+        slot: u32,
+    ) -> Result<(), Error> {
+        // A zero sized region tells KVM to delete the memory slot.
+        let mem_region = kvm_userspace_memory_region {
+            slot,
+            guest_phys_addr,
+            memory_size: 0,
+            userspace_addr,
+            flags: 0,
+        };
+
+        // Safe because the slot was created through create_userspace_mapping()
+        // and the caller keeps the userspace mapping alive until it's removed.
+        unsafe { self.fd.set_user_memory_region(mem_region) }
+            .map_err(Error::SetUserMemoryRegion)?;
+
+        info!(
+            "Removed userspace mapping: {:x} -> {:x}",
+            guest_phys_addr, userspace_addr
+        );
+
+        Ok(())
+    }
+
+    /// The guest RAM regions, as (guest address, size) pairs.
+    pub fn regions(&self) -> Vec<(u64, u64)> {
+        self.guest_memory.memory().map_and_fold(
+            Vec::new(),
+            |(_, region)| (region.start_addr().raw_value(), region.len() as u64),
+            |mut regions, region| {
+                regions.push(region);
+                regions
+            },
+        )
+    }
+
+    // Map a guest RAM region, keeping track of it so that its dirty pages
+    // can be logged.
+    fn create_ram_mapping(
+        &mut self,
+        gpa: u64,
+        size: u64,
+        userspace_addr: u64,
+    ) -> Result<(), Error> {
+        let slot = self.create_userspace_mapping(gpa, size, userspace_addr, self.mergeable)?;
+        let mapping = GuestRamMapping {
+            slot,
+            gpa,
+            size,
+            userspace_addr,
+        };
+
+        self.dirty_log.add_region(GuestAddress(gpa), size);
+        // Memory hotplugged while logging must be logged as well.
+        if self.dirty_log.is_enabled() {
+            self.set_ram_mapping_flags(&mapping, KVM_MEM_LOG_DIRTY_PAGES)?;
+        }
+        self.guest_ram_mappings.push(mapping);
+
+        Ok(())
+    }
+
+    fn set_ram_mapping_flags(&self, mapping: &GuestRamMapping, flags: u32) -> Result<(), Error> {
+        let mem_region = kvm_userspace_memory_region {
+            slot: mapping.slot,
+            guest_phys_addr: mapping.gpa,
+            memory_size: mapping.size,
+            userspace_addr: mapping.userspace_addr,
+            flags,
+        };
+
+        // Safe because the region is already mapped, only its flags change.
+        unsafe { self.fd.set_user_memory_region(mem_region) }.map_err(Error::SetUserMemoryRegion)
+    }
+
+    /// The log of the guest pages written by the VMM, to be shared with the
+    /// virtio devices queues.
+    pub fn vmm_dirty_log(&self) -> Arc<DirtyLog> {
+        self.dirty_log.clone()
+    }
+
+    /// Start logging the guest RAM pages being written, either by the vCPUs
+    /// or by the VMM on behalf of the emulated devices.
+    pub fn start_dirty_log(&mut self) -> Result<(), Error> {
+        for mapping in self.guest_ram_mappings.iter() {
+            self.set_ram_mapping_flags(mapping, KVM_MEM_LOG_DIRTY_PAGES)?;
+        }
+        self.dirty_log.start();
+
+        Ok(())
+    }
+
+    /// Stop logging the guest RAM pages being written.
+    pub fn stop_dirty_log(&mut self) -> Result<(), Error> {
+        self.dirty_log.stop();
+        for mapping in self.guest_ram_mappings.iter() {
+            self.set_ram_mapping_flags(mapping, 0)?;
+        }
+
+        Ok(())
+    }
+
+    /// Get the guest RAM pages written since logging started, or since the
+    /// previous call. The returned bitmaps follow the KVM dirty log layout,
+    /// with one bit per 4KiB page.
+    pub fn get_dirty_log(&self) -> Result<Vec<DirtyRegion>, Error> {
+        let mut regions = Vec::new();
+        for mapping in self.guest_ram_mappings.iter() {
+            let mut bitmap = self
+                .fd
+                .get_dirty_log(mapping.slot, mapping.size as usize)
+                .map_err(Error::GetDirtyLog)?;
+            let vmm_bitmap = self
+                .dirty_log
+                .take_bitmap(GuestAddress(mapping.gpa), mapping.size);
+            for (word, vmm_word) in bitmap.iter_mut().zip(vmm_bitmap.iter()) {
+                *word |= vmm_word;
+            }
+
+            regions.push(DirtyRegion {
+                start: GuestAddress(mapping.gpa),
+                size: mapping.size,
+                bitmap,
+            });
+        }
+
+        Ok(regions)
+    }
+
+    /// Get the guest RAM ranges written since logging started, or since the
+    /// previous call, as (guest address, length) pairs. Adjacent dirty pages
+    /// are merged into a single range.
+    pub fn dirty_ranges(&self) -> Result<Vec<(u64, u64)>, Error> {
+        let mut ranges: Vec<(u64, u64)> = Vec::new();
+        for region in self.get_dirty_log()? {
+            let pages = (region.size + DIRTY_LOG_PAGE_SIZE - 1) / DIRTY_LOG_PAGE_SIZE;
+            for page in 0..pages {
+                if region.bitmap[(page / 64) as usize] & (1 << (page % 64)) == 0 {
+                    continue;
+                }
+
+                let offset = page * DIRTY_LOG_PAGE_SIZE;
+                let gpa = region.start.raw_value() + offset;
+                let length = min(DIRTY_LOG_PAGE_SIZE, region.size - offset);
+                match ranges.last_mut() {
+                    Some((start, len)) if *start + *len == gpa => *len += length,
+                    _ => ranges.push((gpa, length)),
+                }
+            }
+        }
+
+        Ok(ranges)
+    }
+
+    /// Size of the guest RAM content written by write_memory().
+    pub fn memory_size(&self) -> u64 {
+        self.regions().iter().map(|(_, size)| size).sum()
+    }
+
+    /// Write the whole guest RAM content, one region after the other.
+    pub fn write_memory(&self, writer: &mut dyn Write) -> Result<(), MigratableError> {
+        let guest_memory = self.guest_memory.memory();
+        for (start, size) in self.regions() {
+            guest_memory
+                .write_all_to(GuestAddress(start), writer, size as usize)
+                .map_err(|e| MigratableError::Snapshot(e.into()))?;
+        }
+
+        Ok(())
+    }
+
+    /// Fill the guest RAM from the content written by write_memory().
+    pub fn read_memory(&self, reader: &mut dyn Read) -> Result<(), MigratableError> {
+        let guest_memory = self.guest_memory.memory();
+        for (start, size) in self.regions() {
+            guest_memory
+                .read_exact_from(GuestAddress(start), reader, size as usize)
+                .map_err(|e| MigratableError::Restore(e.into()))?;
+        }
+
+        Ok(())
+    }
+
     pub fn resize(&mut self, desired_ram: u64) -> Result<bool, Error> {
         if desired_ram > self.current_ram {
             self.hotplug_ram_region((desired_ram - self.current_ram) as usize)?;
@@ -794,3 +1076,28 @@ impl Aml for MemoryManager {
         bytes
     }
 }
+
+impl Snapshotable for MemoryManager {
+    fn id(&self) -> String {
+        MEMORY_MANAGER_SNAPSHOT_ID.to_string()
+    }
+
+    fn snapshot(&self) -> std::result::Result<Snapshot, MigratableError> {
+        let state = MemoryManagerState {
+            boot_ram: self.boot_ram,
+            current_ram: self.current_ram,
+            hotplug_slots: self.hotplug_slots.clone(),
+            next_hotplug_slot: self.next_hotplug_slot,
+            regions: self.regions(),
+        };
+
+        let mut memory_manager_snapshot = Snapshot::new(&self.id());
+        memory_manager_snapshot.add_data_section(SnapshotDataSection {
+            id: format!("{}-section", self.id()),
+            snapshot: serde_json::to_vec(&state)
+                .map_err(|e| MigratableError::Snapshot(e.into()))?,
+        });
+
+        Ok(memory_manager_snapshot)
+    }
+}
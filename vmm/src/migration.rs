@@ -0,0 +1,474 @@
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Live migration protocol.
+//!
+//! The source VMM connects to the UNIX socket the destination VMM listens on,
+//! and sends a sequence of requests, each of them being acknowledged by the
+//! destination through a response:
+//!
+//! * `Config`: the JSON serialized VM configuration.
+//! * `Layout`: the JSON serialized memory manager snapshot, describing the
+//!   guest RAM regions the destination creates the VM with.
+//! * `Memory`, any number of times: guest RAM ranges, each of them being a
+//!   64 bits guest address and a 64 bits length followed by the content. The
+//!   first one carries the whole guest RAM, and the next ones the pages the
+//!   guest wrote in the meantime.
+//! * `State`: the JSON serialized VM snapshot, taken once the VM is paused.
+//! * `Complete`: the destination resumes the VM. Once this is sent, the
+//!   source only resumes the VM if the destination reports an error, as
+//!   anything else may mean the VM runs on the destination.
+//!
+//! A request is made of a 32 bits command and a 64 bits payload length,
+//! followed by the payload itself. A response is a 32 bits status. All
+//! integers are little endian.
+
+use anyhow::anyhow;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::result;
+use std::time::{Duration, Instant};
+use vm_device::MigratableError;
+
+// Largest JSON payload accepted from the source. The configuration and the
+// device states are well below this, which only prevents a broken or hostile
+// source from making the destination allocate an arbitrary amount of memory.
+const MAX_JSON_PAYLOAD_LENGTH: u64 = 64 << 20;
+
+// How long the destination waits for the source to connect, and then for
+// each read from the source. Receiving a migration runs on the VMM control
+// loop, which must not be blocked forever by a source that never shows up
+// or stops sending.
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Errors associated with the live migration protocol.
+#[derive(Debug)]
+pub enum Error {
+    /// Invalid migration URL, only unix: URLs are supported
+    InvalidUrl(String),
+
+    /// Cannot connect to the migration socket
+    Connect(io::Error),
+
+    /// Cannot bind to the migration socket
+    Bind(io::Error),
+
+    /// The migration socket path exists and is not a socket
+    NotASocket(PathBuf),
+
+    /// Cannot accept a connection on the migration socket
+    Accept(io::Error),
+
+    /// Cannot read from or write to the migration socket
+    Socket(io::Error),
+
+    /// Cannot serialize or deserialize the migration payload
+    Serde(serde_json::Error),
+
+    /// Unknown command received
+    InvalidCommand(u32),
+
+    /// Unknown response status received
+    InvalidStatus(u32),
+
+    /// Received a command different from the expected one
+    UnexpectedCommand(Command, Command),
+
+    /// The payload length does not match the expected one
+    InvalidLength(u64, u64),
+
+    /// The source abandoned the migration
+    Abandoned,
+
+    /// The destination reported an error
+    Refused,
+
+    /// The received payload cannot be used to restore the VM
+    InvalidPayload(MigratableError),
+}
+pub type Result<T> = result::Result<T, Error>;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Command {
+    Config = 1,
+    State = 2,
+    Memory = 3,
+    Complete = 4,
+    Abandon = 5,
+    Layout = 6,
+}
+
+impl Command {
+    fn from_raw(raw: u32) -> Result<Self> {
+        match raw {
+            1 => Ok(Command::Config),
+            2 => Ok(Command::State),
+            3 => Ok(Command::Memory),
+            4 => Ok(Command::Complete),
+            5 => Ok(Command::Abandon),
+            6 => Ok(Command::Layout),
+            _ => Err(Error::InvalidCommand(raw)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Request {
+    command: Command,
+    length: u64,
+}
+
+impl Request {
+    pub fn new(command: Command, length: u64) -> Self {
+        Request { command, length }
+    }
+
+    pub fn command(&self) -> Command {
+        self.command
+    }
+
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+
+    pub fn write_to(&self, writer: &mut dyn Write) -> Result<()> {
+        let mut buf = [0u8; 12];
+        buf[0..4].copy_from_slice(&(self.command as u32).to_le_bytes());
+        buf[4..12].copy_from_slice(&self.length.to_le_bytes());
+        writer.write_all(&buf).map_err(Error::Socket)
+    }
+
+    pub fn read_from(reader: &mut dyn Read) -> Result<Self> {
+        let mut command = [0u8; 4];
+        let mut length = [0u8; 8];
+        reader.read_exact(&mut command).map_err(Error::Socket)?;
+        reader.read_exact(&mut length).map_err(Error::Socket)?;
+
+        Ok(Request {
+            command: Command::from_raw(u32::from_le_bytes(command))?,
+            length: u64::from_le_bytes(length),
+        })
+    }
+}
+
+/// Header of a guest RAM range carried by a `Memory` request.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MemoryRange {
+    gpa: u64,
+    length: u64,
+}
+
+impl MemoryRange {
+    /// Size of the header preceding the range content.
+    pub const SIZE: u64 = 16;
+
+    pub fn new(gpa: u64, length: u64) -> Self {
+        MemoryRange { gpa, length }
+    }
+
+    pub fn gpa(&self) -> u64 {
+        self.gpa
+    }
+
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+
+    pub fn write_to(&self, writer: &mut dyn Write) -> Result<()> {
+        let mut buf = [0u8; 16];
+        buf[0..8].copy_from_slice(&self.gpa.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.length.to_le_bytes());
+        writer.write_all(&buf).map_err(Error::Socket)
+    }
+
+    pub fn read_from(reader: &mut dyn Read) -> Result<Self> {
+        let mut gpa = [0u8; 8];
+        let mut length = [0u8; 8];
+        reader.read_exact(&mut gpa).map_err(Error::Socket)?;
+        reader.read_exact(&mut length).map_err(Error::Socket)?;
+
+        Ok(MemoryRange {
+            gpa: u64::from_le_bytes(gpa),
+            length: u64::from_le_bytes(length),
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Response {
+    Ok = 1,
+    Error = 2,
+}
+
+impl Response {
+    pub fn write_to(self, writer: &mut dyn Write) -> Result<()> {
+        writer
+            .write_all(&(self as u32).to_le_bytes())
+            .map_err(Error::Socket)
+    }
+
+    pub fn read_from(reader: &mut dyn Read) -> Result<Self> {
+        let mut status = [0u8; 4];
+        reader.read_exact(&mut status).map_err(Error::Socket)?;
+
+        match u32::from_le_bytes(status) {
+            1 => Ok(Response::Ok),
+            2 => Ok(Response::Error),
+            s => Err(Error::InvalidStatus(s)),
+        }
+    }
+
+    /// Turn an error response into an Error::Refused.
+    pub fn ok_or_refused(self) -> Result<()> {
+        match self {
+            Response::Ok => Ok(()),
+            Response::Error => Err(Error::Refused),
+        }
+    }
+}
+
+/// Get the socket path from a "unix:<path>" migration URL.
+pub fn unix_url_to_path(url: &str) -> Result<PathBuf> {
+    if url.starts_with("unix:") {
+        Ok(PathBuf::from(&url[5..]))
+    } else {
+        Err(Error::InvalidUrl(url.to_string()))
+    }
+}
+
+/// Connect to the destination VMM.
+pub fn connect(path: &Path) -> Result<UnixStream> {
+    UnixStream::connect(path).map_err(Error::Connect)
+}
+
+// Remove the socket file at `path`, if any. Anything else found there is
+// left alone, as the path comes from the API caller and may point to a file
+// which has nothing to do with the migration.
+fn remove_socket(path: &Path) -> Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            fs::remove_file(path).map_err(Error::Bind)
+        }
+        Ok(_) => Err(Error::NotASocket(path.to_path_buf())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(Error::Bind(e)),
+    }
+}
+
+// Wait up to `timeout` for a connection on the listener.
+fn accept_timeout(listener: &UnixListener, timeout: Duration) -> io::Result<UnixStream> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining == Duration::from_secs(0) {
+            return Err(io::Error::from(io::ErrorKind::TimedOut));
+        }
+
+        let mut pollfd = libc::pollfd {
+            fd: listener.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // Safe because we give a valid pollfd array of a single entry, and
+        // check the return value.
+        let ret = unsafe { libc::poll(&mut pollfd, 1, remaining.as_millis() as libc::c_int) };
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+
+        if ret > 0 {
+            return listener.accept().map(|(socket, _)| socket);
+        }
+    }
+}
+
+/// Wait for the source VMM to connect, for at most a minute. The socket file
+/// is removed once the connection is established, as it is only used for a
+/// single migration. Reads from the source time out as well, so that a
+/// stalled source can't hang the destination.
+pub fn accept(path: &Path) -> Result<UnixStream> {
+    // Remove any stale socket left behind by a previous migration.
+    remove_socket(path)?;
+    let listener = UnixListener::bind(path).map_err(Error::Bind)?;
+    let socket = accept_timeout(&listener, RECEIVE_TIMEOUT);
+    remove_socket(path).ok();
+
+    let socket = socket.map_err(Error::Accept)?;
+    socket
+        .set_read_timeout(Some(RECEIVE_TIMEOUT))
+        .map_err(Error::Socket)?;
+
+    Ok(socket)
+}
+
+/// Send a request and its payload, then wait for the destination answer.
+pub fn send_request(socket: &mut UnixStream, command: Command, payload: &[u8]) -> Result<()> {
+    Request::new(command, payload.len() as u64).write_to(socket)?;
+    socket.write_all(payload).map_err(Error::Socket)?;
+    Response::read_from(socket)?.ok_or_refused()
+}
+
+/// Send a JSON serialized request payload.
+pub fn send_json<T: Serialize>(socket: &mut UnixStream, command: Command, data: &T) -> Result<()> {
+    let payload = serde_json::to_vec(data).map_err(Error::Serde)?;
+    send_request(socket, command, &payload)
+}
+
+/// Wait for the given command, returning its payload length.
+pub fn expect_request(socket: &mut UnixStream, command: Command) -> Result<u64> {
+    let request = Request::read_from(socket)?;
+    match request.command() {
+        c if c == command => Ok(request.length()),
+        Command::Abandon => Err(Error::Abandoned),
+        c => Err(Error::UnexpectedCommand(command, c)),
+    }
+}
+
+/// Wait for the given command and deserialize its JSON payload. The request
+/// is acknowledged once the payload has been successfully deserialized.
+pub fn receive_json<T: DeserializeOwned>(socket: &mut UnixStream, command: Command) -> Result<T> {
+    let length = expect_request(socket, command)?;
+    receive_json_payload(socket, command, length)
+}
+
+/// Deserialize the JSON payload of a request which has already been read,
+/// and acknowledge it.
+pub fn receive_json_payload<T: DeserializeOwned>(
+    socket: &mut UnixStream,
+    command: Command,
+    length: u64,
+) -> Result<T> {
+    if length > MAX_JSON_PAYLOAD_LENGTH {
+        return Err(Error::InvalidPayload(MigratableError::Restore(anyhow!(
+            "{:?} payload of {} bytes exceeds the {} bytes limit",
+            command,
+            length,
+            MAX_JSON_PAYLOAD_LENGTH
+        ))));
+    }
+    let mut payload = vec![0u8; length as usize];
+    socket.read_exact(&mut payload).map_err(Error::Socket)?;
+    let data = serde_json::from_slice(&payload).map_err(Error::Serde)?;
+    Response::Ok.write_to(socket)?;
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_roundtrip() {
+        let mut buf = Vec::new();
+        Request::new(Command::Memory, 0x1_0000_0000)
+            .write_to(&mut buf)
+            .unwrap();
+        assert_eq!(buf.len(), 12);
+
+        let request = Request::read_from(&mut buf.as_slice()).unwrap();
+        assert_eq!(request.command(), Command::Memory);
+        assert_eq!(request.length(), 0x1_0000_0000);
+
+        let mut invalid = Vec::new();
+        invalid.extend_from_slice(&42u32.to_le_bytes());
+        invalid.extend_from_slice(&0u64.to_le_bytes());
+        assert!(Request::read_from(&mut invalid.as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_memory_range_roundtrip() {
+        let mut buf = Vec::new();
+        MemoryRange::new(0x1_0000_0000, 0x2000)
+            .write_to(&mut buf)
+            .unwrap();
+        assert_eq!(buf.len() as u64, MemoryRange::SIZE);
+
+        let range = MemoryRange::read_from(&mut buf.as_slice()).unwrap();
+        assert_eq!(range.gpa(), 0x1_0000_0000);
+        assert_eq!(range.length(), 0x2000);
+        assert!(MemoryRange::read_from(&mut &buf[..8]).is_err());
+    }
+
+    #[test]
+    fn test_receive_json_length_limit() {
+        let (mut source, mut destination) = UnixStream::pair().unwrap();
+
+        Request::new(Command::Config, MAX_JSON_PAYLOAD_LENGTH + 1)
+            .write_to(&mut source)
+            .unwrap();
+        match receive_json::<serde_json::Value>(&mut destination, Command::Config) {
+            Err(Error::InvalidPayload(MigratableError::Restore(_))) => {}
+            _ => panic!("Oversized payload not rejected"),
+        }
+
+        let payload = br#"{"id":42}"#;
+        Request::new(Command::State, payload.len() as u64)
+            .write_to(&mut source)
+            .unwrap();
+        source.write_all(payload).unwrap();
+        let data: serde_json::Value = receive_json(&mut destination, Command::State).unwrap();
+        assert_eq!(data["id"], 42);
+    }
+
+    #[test]
+    fn test_accept() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("migration.sock");
+
+        // A stale socket is replaced, and removed once connected.
+        drop(UnixListener::bind(&path).unwrap());
+        let connect_path = path.clone();
+        let source = std::thread::spawn(move || {
+            // Wait for the destination to listen.
+            loop {
+                if let Ok(socket) = connect(&connect_path) {
+                    return socket;
+                }
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        });
+        let mut destination = accept(&path).unwrap();
+        let mut source = source.join().unwrap();
+        assert!(!path.exists());
+        Response::Ok.write_to(&mut source).unwrap();
+        assert_eq!(Response::read_from(&mut destination).unwrap(), Response::Ok);
+
+        // Any other kind of file is left alone.
+        fs::write(&path, b"data").unwrap();
+        match accept(&path) {
+            Err(Error::NotASocket(p)) => assert_eq!(p, path),
+            _ => panic!("Regular file not rejected"),
+        }
+        assert_eq!(fs::read(&path).unwrap(), b"data");
+    }
+
+    #[test]
+    fn test_accept_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let listener = UnixListener::bind(dir.path().join("migration.sock")).unwrap();
+        let e = accept_timeout(&listener, Duration::from_millis(10)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn test_unix_url_to_path() {
+        assert_eq!(
+            unix_url_to_path("unix:/tmp/migration.sock").unwrap(),
+            PathBuf::from("/tmp/migration.sock")
+        );
+        assert!(unix_url_to_path("file:///tmp/migration.sock").is_err());
+    }
+}
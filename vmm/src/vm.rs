@@ -24,10 +24,17 @@ extern crate vm_allocator;
 extern crate vm_memory;
 extern crate vm_virtio;
 
-use crate::config::{DeviceConfig, VmConfig};
+use crate::config::{
+    DeviceConfig, DiskConfig, FsConfig, NetConfig, PmemConfig, VmConfig, VsockConfig,
+};
 use crate::cpu;
+#[cfg(feature = "pci_support")]
+use crate::device_manager::DeviceManagerResult;
 use crate::device_manager::{get_win_size, Console, DeviceManager, DeviceManagerError};
-use crate::memory_manager::{get_host_cpu_phys_bits, Error as MemoryManagerError, MemoryManager};
+use crate::memory_manager::{
+    get_host_cpu_phys_bits, Error as MemoryManagerError, MemoryManager, MEMORY_MANAGER_SNAPSHOT_ID,
+};
+use crate::migration;
 use anyhow::anyhow;
 use arch::layout;
 use devices::{ioapic, HotPlugNotificationFlags};
@@ -36,19 +43,23 @@ use kvm_ioctls::*;
 use linux_loader::cmdline::Cmdline;
 use linux_loader::loader::KernelLoader;
 use signal_hook::{iterator::Signals, SIGINT, SIGTERM, SIGWINCH};
+use std::cmp;
+use std::collections::BTreeMap;
 use std::ffi::CString;
-use std::fs::File;
-use std::io;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read};
 use std::ops::Deref;
-use std::path::PathBuf;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex, RwLock};
 use std::{result, str, thread};
 use vm_allocator::{GsiApic, SystemAllocator};
-use vm_device::{Migratable, MigratableError, Pausable, Snapshotable};
+use vm_device::{Migratable, MigratableError, Pausable, Snapshot, Snapshotable};
 use vm_memory::{
     Address, Bytes, GuestAddress, GuestAddressSpace, GuestMemory, GuestMemoryMmap,
     GuestMemoryRegion, GuestUsize,
 };
+use vm_virtio::RateLimiterConfig;
 use vmm_sys_util::eventfd::EventFd;
 use vmm_sys_util::terminal::Terminal;
 
@@ -61,6 +72,13 @@ const HYPERVISOR_ECX_BIT: u8 = 31; // Hypervisor ecx bit.
 // 64 bit direct boot entry offset for bzImage
 const KERNEL_64BIT_ENTRY_OFFSET: u64 = 0x200;
 
+const VM_SNAPSHOT_ID: &str = "vm";
+
+// Files making up a VM snapshot directory
+const SNAPSHOT_CONFIG_FILE: &str = "config.json";
+const SNAPSHOT_STATE_FILE: &str = "state.json";
+const SNAPSHOT_MEMORY_FILE: &str = "memory";
+
 /// Errors associated with VM management
 #[derive(Debug)]
 pub enum Error {
@@ -132,6 +150,9 @@ pub enum Error {
     /// VM is not running
     VmNotRunning,
 
+    /// VM is not paused
+    VmNotPaused,
+
     /// Cannot clone EventFd.
     EventFdClone(io::Error),
 
@@ -167,9 +188,39 @@ pub enum Error {
 
     /// No PCI support
     NoPciSupport,
+
+    /// Cannot snapshot VM
+    Snapshot(MigratableError),
+
+    /// Cannot restore VM
+    Restore(MigratableError),
+
+    /// Invalid snapshot URL, only file:// URLs are supported
+    InvalidSnapshotUrl(String),
+
+    /// Cannot access the snapshot files
+    SnapshotIo(io::Error),
+
+    /// Cannot serialize or deserialize the snapshot
+    SnapshotSerde(serde_json::Error),
+
+    /// Live migration failed
+    Migration(migration::Error),
+
+    /// Live migration failed after the destination was asked to resume the
+    /// VM, which is kept paused as it may be running on the destination
+    MigrationOutcomeUnknown(migration::Error),
 }
 pub type Result<T> = result::Result<T, Error>;
 
+// Maximum number of passes sending the guest RAM while the VM runs. The VM
+// is paused after the last one, however much RAM the guest keeps writing.
+const MIGRATION_MAX_PRECOPY_PASSES: u32 = 5;
+
+// The VM is paused as soon as the guest RAM written during a pass is small
+// enough to be sent while paused without a noticeable downtime.
+const MIGRATION_MAX_PAUSED_RAM: u64 = 8 << 20;
+
 #[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
 pub enum VmState {
     Created,
@@ -224,12 +275,229 @@ pub struct Vm {
     memory_manager: Arc<Mutex<MemoryManager>>,
 }
 
+fn snapshot_url_to_path(url: &str) -> Result<PathBuf> {
+    if url.starts_with("file://") {
+        Ok(PathBuf::from(&url[7..]))
+    } else {
+        Err(Error::InvalidSnapshotUrl(url.to_string()))
+    }
+}
+
 impl Vm {
     pub fn new(
         config: Arc<Mutex<VmConfig>>,
         exit_evt: EventFd,
         reset_evt: EventFd,
         vmm_path: PathBuf,
+    ) -> Result<Self> {
+        Vm::new_internal(config, exit_evt, reset_evt, vmm_path, None, true)
+    }
+
+    /// Create a paused VM from a snapshot directory, as written by
+    /// send_snapshot(). The VM configuration is read from the snapshot.
+    pub fn new_from_snapshot(
+        source_url: &str,
+        exit_evt: EventFd,
+        reset_evt: EventFd,
+        vmm_path: PathBuf,
+    ) -> Result<Self> {
+        let source = snapshot_url_to_path(source_url)?;
+
+        let config_file =
+            File::open(source.join(SNAPSHOT_CONFIG_FILE)).map_err(Error::SnapshotIo)?;
+        let config: VmConfig =
+            serde_json::from_reader(BufReader::new(config_file)).map_err(Error::SnapshotSerde)?;
+
+        let state_file = File::open(source.join(SNAPSHOT_STATE_FILE)).map_err(Error::SnapshotIo)?;
+        let snapshot: Snapshot =
+            serde_json::from_reader(BufReader::new(state_file)).map_err(Error::SnapshotSerde)?;
+
+        let mut memory_file =
+            File::open(source.join(SNAPSHOT_MEMORY_FILE)).map_err(Error::SnapshotIo)?;
+
+        Vm::new_restored(
+            config,
+            snapshot,
+            &mut memory_file,
+            exit_evt,
+            reset_evt,
+            vmm_path,
+            true,
+        )
+    }
+
+    /// Receive a VM migrated from another VMM process, listening on the
+    /// UNIX socket pointed by receiver_url. The VM is resumed once the
+    /// migration is complete.
+    pub fn new_from_migration(
+        receiver_url: &str,
+        exit_evt: EventFd,
+        reset_evt: EventFd,
+        vmm_path: PathBuf,
+    ) -> Result<Self> {
+        let path = migration::unix_url_to_path(receiver_url).map_err(Error::Migration)?;
+        let mut socket = migration::accept(&path).map_err(Error::Migration)?;
+
+        let vm = Vm::receive_migration(&mut socket, exit_evt, reset_evt, vmm_path);
+        if vm.is_err() {
+            // Let the source know, so that it can resume the VM on its side.
+            migration::Response::Error.write_to(&mut socket).ok();
+        }
+
+        vm
+    }
+
+    fn receive_migration(
+        socket: &mut UnixStream,
+        exit_evt: EventFd,
+        reset_evt: EventFd,
+        vmm_path: PathBuf,
+    ) -> Result<Self> {
+        let config: VmConfig = migration::receive_json(socket, migration::Command::Config)
+            .map_err(Error::Migration)?;
+        let layout: Snapshot = migration::receive_json(socket, migration::Command::Layout)
+            .map_err(Error::Migration)?;
+
+        // The disk images are still locked by the source VMM, which only
+        // releases them right before completing the migration.
+        let mut vm = Vm::new_internal(
+            Arc::new(Mutex::new(config)),
+            exit_evt,
+            reset_evt,
+            vmm_path,
+            Some(&layout),
+            false,
+        )?;
+
+        // The guest RAM is received as many times as the source sends it,
+        // until the VM state is sent along with the VM being paused.
+        let snapshot: Snapshot = loop {
+            let request = migration::Request::read_from(socket).map_err(Error::Migration)?;
+            match request.command() {
+                migration::Command::Memory => {
+                    vm.receive_memory(socket, request.length())?;
+                    migration::Response::Ok
+                        .write_to(socket)
+                        .map_err(Error::Migration)?;
+                }
+                migration::Command::State => {
+                    break migration::receive_json_payload(
+                        socket,
+                        migration::Command::State,
+                        request.length(),
+                    )
+                    .map_err(Error::Migration)?;
+                }
+                migration::Command::Abandon => {
+                    return Err(Error::Migration(migration::Error::Abandoned));
+                }
+                c => {
+                    return Err(Error::Migration(migration::Error::UnexpectedCommand(
+                        migration::Command::State,
+                        c,
+                    )));
+                }
+            }
+        };
+        vm.restore(snapshot).map_err(Error::Restore)?;
+        vm.setup_console_input()?;
+
+        migration::expect_request(socket, migration::Command::Complete)
+            .map_err(Error::Migration)?;
+        vm.device_manager
+            .lock()
+            .unwrap()
+            .acquire_disk_locks()
+            .map_err(Error::DeviceManager)?;
+        if let Err(e) = vm.resume() {
+            vm.device_manager.lock().unwrap().release_disk_locks();
+            return Err(Error::Resume(e));
+        }
+
+        // The VM now runs here, whether the source gets the confirmation or
+        // not. Without it, the source keeps its copy paused.
+        if let Err(e) = migration::Response::Ok.write_to(socket) {
+            warn!("Could not confirm the migration completion: {:?}", e);
+        }
+
+        Ok(vm)
+    }
+
+    // Fill the guest RAM ranges carried by a Memory request.
+    fn receive_memory(&self, socket: &mut UnixStream, length: u64) -> Result<()> {
+        let guest_memory = self.memory_manager.lock().unwrap().guest_memory();
+        let mem = guest_memory.memory();
+
+        let mut remaining = length;
+        while remaining > 0 {
+            if remaining < migration::MemoryRange::SIZE {
+                return Err(Error::Migration(migration::Error::InvalidLength(
+                    length,
+                    length - remaining,
+                )));
+            }
+            let range = migration::MemoryRange::read_from(socket).map_err(Error::Migration)?;
+            remaining -= migration::MemoryRange::SIZE;
+            if range.length() > remaining {
+                return Err(Error::Migration(migration::Error::InvalidLength(
+                    length,
+                    length - remaining + range.length(),
+                )));
+            }
+
+            mem.read_exact_from(GuestAddress(range.gpa()), socket, range.length() as usize)
+                .map_err(|e| Error::Restore(MigratableError::Restore(e.into())))?;
+            remaining -= range.length();
+        }
+
+        Ok(())
+    }
+
+    fn new_restored(
+        config: VmConfig,
+        snapshot: Snapshot,
+        memory: &mut dyn Read,
+        exit_evt: EventFd,
+        reset_evt: EventFd,
+        vmm_path: PathBuf,
+        lock_disks: bool,
+    ) -> Result<Self> {
+        let memory_manager_snapshot = snapshot
+            .snapshots
+            .get(MEMORY_MANAGER_SNAPSHOT_ID)
+            .ok_or_else(|| {
+                Error::Restore(MigratableError::Restore(anyhow!(
+                    "Missing memory manager snapshot"
+                )))
+            })?;
+        let mut vm = Vm::new_internal(
+            Arc::new(Mutex::new(config)),
+            exit_evt,
+            reset_evt,
+            vmm_path,
+            Some(memory_manager_snapshot),
+            lock_disks,
+        )?;
+
+        vm.memory_manager
+            .lock()
+            .unwrap()
+            .read_memory(memory)
+            .map_err(Error::Restore)?;
+
+        vm.restore(snapshot).map_err(Error::Restore)?;
+        vm.setup_console_input()?;
+
+        Ok(vm)
+    }
+
+    fn new_internal(
+        config: Arc<Mutex<VmConfig>>,
+        exit_evt: EventFd,
+        reset_evt: EventFd,
+        vmm_path: PathBuf,
+        memory_manager_snapshot: Option<&Snapshot>,
+        lock_disks: bool,
     ) -> Result<Self> {
         let kvm = Kvm::new().map_err(Error::KvmNew)?;
 
@@ -331,15 +599,27 @@ impl Vm {
 
         let memory_config = config.lock().unwrap().memory.clone();
 
-        let memory_manager = MemoryManager::new(
-            allocator.clone(),
-            fd.clone(),
-            memory_config.size,
-            memory_config.hotplug_size,
-            &memory_config.file,
-            memory_config.mergeable,
-        )
-        .map_err(Error::MemoryManager)?;
+        let memory_manager = if let Some(memory_manager_snapshot) = memory_manager_snapshot {
+            MemoryManager::new_from_snapshot(
+                memory_manager_snapshot,
+                allocator.clone(),
+                fd.clone(),
+                memory_config.hotplug_size,
+                &memory_config.file,
+                memory_config.mergeable,
+            )
+            .map_err(Error::Restore)?
+        } else {
+            MemoryManager::new(
+                allocator.clone(),
+                fd.clone(),
+                memory_config.size,
+                memory_config.hotplug_size,
+                &memory_config.file,
+                memory_config.mergeable,
+            )
+            .map_err(Error::MemoryManager)?
+        };
 
         let guest_memory = memory_manager.lock().unwrap().guest_memory();
 
@@ -351,6 +631,7 @@ impl Vm {
             &exit_evt,
             &reset_evt,
             vmm_path,
+            lock_disks,
         )
         .map_err(Error::DeviceManager)?;
 
@@ -501,6 +782,10 @@ impl Vm {
         for thread in self.threads.drain(..) {
             thread.join().map_err(Error::ThreadCleanup)?
         }
+
+        // Let the disk images be used again, by a rebooted VM in particular.
+        self.device_manager.lock().unwrap().release_disk_locks();
+
         *state = new_state;
 
         Ok(())
@@ -543,76 +828,280 @@ impl Vm {
         Ok(())
     }
 
-    pub fn add_device(&mut self, mut _device_cfg: DeviceConfig) -> Result<()> {
+    /// Returns the virtio devices and vCPUs counters, indexed by name.
+    pub fn counters(&self) -> BTreeMap<String, BTreeMap<String, u64>> {
+        let mut counters = self.device_manager.lock().unwrap().counters();
+        counters.append(&mut self.cpu_manager.lock().unwrap().counters());
+
+        counters
+    }
+
+    // Adds a device through the DeviceManager, then updates VmConfig with
+    // the device configuration. This is important to ensure the device would
+    // be created in case of a reboot.
+    #[cfg(feature = "pci_support")]
+    fn hotplug_pci_device<T, F, C>(
+        &mut self,
+        mut device_cfg: T,
+        add: F,
+        config_list: C,
+    ) -> Result<()>
+    where
+        F: FnOnce(&mut DeviceManager, &mut T) -> DeviceManagerResult<()>,
+        C: FnOnce(&mut VmConfig) -> &mut Option<Vec<T>>,
+    {
+        add(&mut *self.device_manager.lock().unwrap(), &mut device_cfg)
+            .map_err(Error::DeviceManager)?;
+
+        {
+            let mut config = self.config.lock().unwrap();
+            let devices = config_list(&mut *config);
+            if let Some(devices) = devices.as_mut() {
+                devices.push(device_cfg);
+            } else {
+                *devices = Some(vec![device_cfg]);
+            }
+        }
+
+        self.device_manager
+            .lock()
+            .unwrap()
+            .notify_hotplug(HotPlugNotificationFlags::PCI_DEVICES_CHANGED)
+            .map_err(Error::DeviceManager)
+    }
+
+    // Removes a device through the DeviceManager, then removes it from
+    // VmConfig. This is important to ensure the device would not be created
+    // in case of a reboot.
+    #[cfg(feature = "pci_support")]
+    fn unplug_pci_device<F>(&mut self, id: String, remove: F) -> Result<()>
+    where
+        F: FnOnce(&mut DeviceManager, String) -> DeviceManagerResult<()>,
+    {
+        remove(&mut *self.device_manager.lock().unwrap(), id.clone())
+            .map_err(Error::DeviceManager)?;
+
+        {
+            let mut config = self.config.lock().unwrap();
+            if let Some(devices) = config.devices.as_mut() {
+                devices.retain(|dev| dev.id.as_ref() != Some(&id));
+            }
+            if let Some(disks) = config.disks.as_mut() {
+                disks.retain(|disk| disk.id.as_ref() != Some(&id));
+            }
+            if let Some(net) = config.net.as_mut() {
+                net.retain(|net| net.id.as_ref() != Some(&id));
+            }
+            if let Some(fs) = config.fs.as_mut() {
+                fs.retain(|fs| fs.id.as_ref() != Some(&id));
+            }
+            if let Some(pmem) = config.pmem.as_mut() {
+                pmem.retain(|pmem| pmem.id.as_ref() != Some(&id));
+            }
+            if let Some(vsock) = config.vsock.as_mut() {
+                vsock.retain(|vsock| vsock.id.as_ref() != Some(&id));
+            }
+        }
+
+        self.device_manager
+            .lock()
+            .unwrap()
+            .notify_hotplug(HotPlugNotificationFlags::PCI_DEVICES_CHANGED)
+            .map_err(Error::DeviceManager)
+    }
+
+    pub fn add_device(&mut self, _device_cfg: DeviceConfig) -> Result<()> {
         if cfg!(feature = "pci_support") {
             #[cfg(feature = "pci_support")]
-            {
-                self.device_manager
-                    .lock()
-                    .unwrap()
-                    .add_device(&mut _device_cfg)
-                    .map_err(Error::DeviceManager)?;
+            self.hotplug_pci_device(_device_cfg, DeviceManager::add_device, |config| {
+                &mut config.devices
+            })?;
+            Ok(())
+        } else {
+            Err(Error::NoPciSupport)
+        }
+    }
 
-                // Update VmConfig by adding the new device. This is important to
-                // ensure the device would be created in case of a reboot.
-                {
-                    let mut config = self.config.lock().unwrap();
-                    if let Some(devices) = config.devices.as_mut() {
-                        devices.push(_device_cfg);
-                    } else {
-                        config.devices = Some(vec![_device_cfg]);
-                    }
-                }
+    pub fn add_disk(&mut self, _disk_cfg: DiskConfig) -> Result<()> {
+        if cfg!(feature = "pci_support") {
+            #[cfg(feature = "pci_support")]
+            self.hotplug_pci_device(_disk_cfg, DeviceManager::add_disk, |config| {
+                &mut config.disks
+            })?;
+            Ok(())
+        } else {
+            Err(Error::NoPciSupport)
+        }
+    }
 
-                self.device_manager
-                    .lock()
-                    .unwrap()
-                    .notify_hotplug(HotPlugNotificationFlags::PCI_DEVICES_CHANGED)
-                    .map_err(Error::DeviceManager)?;
-            }
+    pub fn add_net(&mut self, _net_cfg: NetConfig) -> Result<()> {
+        if cfg!(feature = "pci_support") {
+            #[cfg(feature = "pci_support")]
+            self.hotplug_pci_device(_net_cfg, DeviceManager::add_net, |config| &mut config.net)?;
             Ok(())
         } else {
             Err(Error::NoPciSupport)
         }
     }
 
-    pub fn remove_device(&mut self, _id: String) -> Result<()> {
+    pub fn add_fs(&mut self, _fs_cfg: FsConfig) -> Result<()> {
         if cfg!(feature = "pci_support") {
             #[cfg(feature = "pci_support")]
-            {
-                self.device_manager
-                    .lock()
-                    .unwrap()
-                    .remove_device(_id.clone())
-                    .map_err(Error::DeviceManager)?;
+            self.hotplug_pci_device(_fs_cfg, DeviceManager::add_fs, |config| &mut config.fs)?;
+            Ok(())
+        } else {
+            Err(Error::NoPciSupport)
+        }
+    }
 
-                // Update VmConfig by removing the device. This is important to
-                // ensure the device would not be created in case of a reboot.
-                {
-                    let mut config = self.config.lock().unwrap();
-                    if let Some(devices) = config.devices.as_mut() {
-                        devices.retain(|dev| {
-                            if let Some(dev_id) = &dev.id {
-                                *dev_id != _id
-                            } else {
-                                true
-                            }
-                        });
-                    }
-                }
+    pub fn add_pmem(&mut self, _pmem_cfg: PmemConfig) -> Result<()> {
+        if cfg!(feature = "pci_support") {
+            #[cfg(feature = "pci_support")]
+            self.hotplug_pci_device(_pmem_cfg, DeviceManager::add_pmem, |config| {
+                &mut config.pmem
+            })?;
+            Ok(())
+        } else {
+            Err(Error::NoPciSupport)
+        }
+    }
 
-                self.device_manager
-                    .lock()
-                    .unwrap()
-                    .notify_hotplug(HotPlugNotificationFlags::PCI_DEVICES_CHANGED)
-                    .map_err(Error::DeviceManager)?;
-            }
+    pub fn add_vsock(&mut self, _vsock_cfg: VsockConfig) -> Result<()> {
+        if cfg!(feature = "pci_support") {
+            #[cfg(feature = "pci_support")]
+            self.hotplug_pci_device(_vsock_cfg, DeviceManager::add_vsock, |config| {
+                &mut config.vsock
+            })?;
+            Ok(())
+        } else {
+            Err(Error::NoPciSupport)
+        }
+    }
+
+    pub fn remove_net(&mut self, _id: String) -> Result<()> {
+        if cfg!(feature = "pci_support") {
+            #[cfg(feature = "pci_support")]
+            self.unplug_pci_device(_id, DeviceManager::remove_net)?;
             Ok(())
         } else {
             Err(Error::NoPciSupport)
         }
     }
 
+    pub fn remove_device(&mut self, _id: String) -> Result<()> {
+        if cfg!(feature = "pci_support") {
+            #[cfg(feature = "pci_support")]
+            self.unplug_pci_device(_id, DeviceManager::remove_device)?;
+            Ok(())
+        } else {
+            Err(Error::NoPciSupport)
+        }
+    }
+
+    pub fn create_disk_snapshot(&mut self, id: &str, name: &str) -> Result<()> {
+        self.check_paused()?;
+        self.device_manager
+            .lock()
+            .unwrap()
+            .create_disk_snapshot(id, name)
+            .map_err(Error::DeviceManager)
+    }
+
+    pub fn apply_disk_snapshot(&mut self, id: &str, name: &str) -> Result<()> {
+        self.check_paused()?;
+        self.device_manager
+            .lock()
+            .unwrap()
+            .apply_disk_snapshot(id, name)
+            .map_err(Error::DeviceManager)
+    }
+
+    pub fn delete_disk_snapshot(&mut self, id: &str, name: &str) -> Result<()> {
+        self.check_paused()?;
+        self.device_manager
+            .lock()
+            .unwrap()
+            .delete_disk_snapshot(id, name)
+            .map_err(Error::DeviceManager)
+    }
+
+    pub fn set_net_rate_limit(
+        &mut self,
+        id: &str,
+        rx_rate_limiter_config: RateLimiterConfig,
+        tx_rate_limiter_config: RateLimiterConfig,
+    ) -> Result<()> {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .set_net_rate_limit(id, &rx_rate_limiter_config, &tx_rate_limiter_config)
+            .map_err(Error::DeviceManager)?;
+
+        // Update VmConfig with the new limits. This is important to ensure
+        // they would still apply in case of a reboot.
+        if let Some(net) = self.config.lock().unwrap().net.as_mut() {
+            for net_cfg in net.iter_mut() {
+                if net_cfg.id.as_deref() == Some(id) {
+                    net_cfg.rx_rate_limiter_config = Some(rx_rate_limiter_config);
+                    net_cfg.tx_rate_limiter_config = Some(tx_rate_limiter_config);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn set_net_link(&mut self, id: &str, up: bool) -> Result<()> {
+        // A rebooted VM starts with the link up, like on a fresh boot.
+        self.device_manager
+            .lock()
+            .unwrap()
+            .set_net_link(id, up)
+            .map_err(Error::DeviceManager)
+    }
+
+    pub fn set_disk_rate_limit(
+        &mut self,
+        id: &str,
+        rate_limiter_config: RateLimiterConfig,
+    ) -> Result<()> {
+        self.device_manager
+            .lock()
+            .unwrap()
+            .set_disk_rate_limit(id, &rate_limiter_config)
+            .map_err(Error::DeviceManager)?;
+
+        // Update VmConfig with the new limits. This is important to ensure
+        // they would still apply in case of a reboot.
+        if let Some(disks) = self.config.lock().unwrap().disks.as_mut() {
+            for disk in disks.iter_mut() {
+                if disk.id.as_deref() == Some(id) {
+                    disk.rate_limiter_config = Some(rate_limiter_config);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn resize_disk(&mut self, id: &str, desired_size: u64) -> Result<()> {
+        // The disk image itself is resized, there is nothing to update in
+        // VmConfig for the new size to survive a reboot.
+        self.device_manager
+            .lock()
+            .unwrap()
+            .resize_disk(id, desired_size)
+            .map_err(Error::DeviceManager)
+    }
+
+    // Disk snapshots can't race with the guest I/O, so they require a paused VM.
+    fn check_paused(&self) -> Result<()> {
+        if self.get_state()? != VmState::Paused {
+            return Err(Error::VmNotPaused);
+        }
+        Ok(())
+    }
+
     fn os_signal_handler(signals: Signals, console_input_clone: Arc<Console>, on_tty: bool) {
         for signal in signals.forever() {
             match signal {
@@ -651,6 +1140,15 @@ impl Vm {
             .start_boot_vcpus(entry_addr)
             .map_err(Error::CpuManager)?;
 
+        self.setup_console_input()?;
+
+        let mut state = self.state.try_write().map_err(|_| Error::PoisonedState)?;
+        *state = new_state;
+
+        Ok(())
+    }
+
+    fn setup_console_input(&mut self) -> Result<()> {
         if self
             .device_manager
             .lock()
@@ -683,9 +1181,6 @@ impl Vm {
             }
         }
 
-        let mut state = self.state.try_write().map_err(|_| Error::PoisonedState)?;
-        *state = new_state;
-
         Ok(())
     }
 
@@ -719,6 +1214,190 @@ impl Vm {
         Arc::clone(&self.config)
     }
 
+    /// Write a snapshot of the VM to a snapshot directory.
+    /// The directory contains the VM configuration, the snapshot itself and
+    /// the guest RAM content.
+    pub fn send_snapshot(&self, snapshot: &Snapshot, destination_url: &str) -> Result<()> {
+        let destination = snapshot_url_to_path(destination_url)?;
+        fs::create_dir_all(&destination).map_err(Error::SnapshotIo)?;
+
+        let config_file =
+            File::create(destination.join(SNAPSHOT_CONFIG_FILE)).map_err(Error::SnapshotIo)?;
+        serde_json::to_writer(BufWriter::new(config_file), &*self.config.lock().unwrap())
+            .map_err(Error::SnapshotSerde)?;
+
+        let state_file =
+            File::create(destination.join(SNAPSHOT_STATE_FILE)).map_err(Error::SnapshotIo)?;
+        serde_json::to_writer(BufWriter::new(state_file), snapshot)
+            .map_err(Error::SnapshotSerde)?;
+
+        self.write_memory_to(&destination.join(SNAPSHOT_MEMORY_FILE))
+    }
+
+    fn write_memory_to(&self, path: &Path) -> Result<()> {
+        let mut memory_file = File::create(path).map_err(Error::SnapshotIo)?;
+        self.memory_manager
+            .lock()
+            .unwrap()
+            .write_memory(&mut memory_file)
+            .map_err(Error::Snapshot)
+    }
+
+    /// Migrate the VM to the VMM listening on the UNIX socket pointed by
+    /// destination_url. The guest RAM is sent while the VM runs, the VM only
+    /// being paused to send the RAM written in the meantime and its state.
+    /// It is resumed if the migration fails, unless the destination may have
+    /// resumed it already.
+    pub fn send_migration(&mut self, destination_url: &str) -> Result<()> {
+        let path = migration::unix_url_to_path(destination_url).map_err(Error::Migration)?;
+        let mut socket = migration::connect(&path).map_err(Error::Migration)?;
+
+        let was_running = self.get_state()? == VmState::Running;
+        let mut completing = false;
+        let result = self.send_migration_data(&mut socket, &mut completing);
+        if let Err(e) = self.memory_manager.lock().unwrap().stop_dirty_log() {
+            warn!("Could not stop logging the dirty pages: {:?}", e);
+        }
+
+        match result {
+            Ok(()) => Ok(()),
+            // Only an explicit error from the destination guarantees the VM
+            // didn't resume there. Resuming it here otherwise could leave it
+            // running twice, writing to the same disk images.
+            Err(Error::Migration(e)) if completing && !matches!(e, migration::Error::Refused) => {
+                error!(
+                    "Keeping the VM paused, the migration outcome is unknown: {:?}",
+                    e
+                );
+                Err(Error::MigrationOutcomeUnknown(e))
+            }
+            Err(e) => {
+                if !completing {
+                    migration::Request::new(migration::Command::Abandon, 0)
+                        .write_to(&mut socket)
+                        .ok();
+                }
+                // The disk image locks may have been handed over already.
+                self.device_manager
+                    .lock()
+                    .unwrap()
+                    .acquire_disk_locks()
+                    .map_err(Error::DeviceManager)?;
+                if was_running && self.get_state()? == VmState::Paused {
+                    self.resume().map_err(Error::Resume)?;
+                }
+
+                Err(e)
+            }
+        }
+    }
+
+    fn send_migration_data(
+        &mut self,
+        socket: &mut UnixStream,
+        completing: &mut bool,
+    ) -> Result<()> {
+        migration::send_json(
+            socket,
+            migration::Command::Config,
+            &*self.config.lock().unwrap(),
+        )
+        .map_err(Error::Migration)?;
+        let layout = self
+            .memory_manager
+            .lock()
+            .unwrap()
+            .snapshot()
+            .map_err(Error::Snapshot)?;
+        migration::send_json(socket, migration::Command::Layout, &layout)
+            .map_err(Error::Migration)?;
+
+        let mut ranges = self.memory_manager.lock().unwrap().regions();
+        if self.get_state()? == VmState::Running && self.dirty_log_is_complete() {
+            // Send the guest RAM while the VM runs, then what the guest
+            // wrote in the meantime, until it is small enough to be sent
+            // with the VM paused.
+            self.memory_manager
+                .lock()
+                .unwrap()
+                .start_dirty_log()
+                .map_err(Error::MemoryManager)?;
+            for _ in 0..MIGRATION_MAX_PRECOPY_PASSES {
+                self.send_memory(socket, &ranges)?;
+                ranges = self
+                    .memory_manager
+                    .lock()
+                    .unwrap()
+                    .dirty_ranges()
+                    .map_err(Error::MemoryManager)?;
+                if ranges.iter().map(|(_, len)| len).sum::<u64>() <= MIGRATION_MAX_PAUSED_RAM {
+                    break;
+                }
+            }
+
+            self.pause().map_err(Error::Pause)?;
+            let dirty = self
+                .memory_manager
+                .lock()
+                .unwrap()
+                .dirty_ranges()
+                .map_err(Error::MemoryManager)?;
+            ranges = merge_ranges(ranges, dirty);
+        } else if self.get_state()? == VmState::Running {
+            self.pause().map_err(Error::Pause)?;
+        }
+        self.send_memory(socket, &ranges)?;
+
+        let snapshot = self.snapshot().map_err(Error::Snapshot)?;
+        migration::send_json(socket, migration::Command::State, &snapshot)
+            .map_err(Error::Migration)?;
+
+        // Hand the disk image locks over to the destination VMM, which takes
+        // them before resuming the VM.
+        self.device_manager.lock().unwrap().release_disk_locks();
+        *completing = true;
+        migration::send_request(socket, migration::Command::Complete, &[]).map_err(Error::Migration)
+    }
+
+    // Send the given guest RAM ranges through a Memory request.
+    fn send_memory(&self, socket: &mut UnixStream, ranges: &[(u64, u64)]) -> Result<()> {
+        let length = ranges
+            .iter()
+            .map(|(_, len)| migration::MemoryRange::SIZE + len)
+            .sum();
+        migration::Request::new(migration::Command::Memory, length)
+            .write_to(socket)
+            .map_err(Error::Migration)?;
+
+        let guest_memory = self.memory_manager.lock().unwrap().guest_memory();
+        let mem = guest_memory.memory();
+        for (gpa, len) in ranges.iter() {
+            migration::MemoryRange::new(*gpa, *len)
+                .write_to(socket)
+                .map_err(Error::Migration)?;
+            mem.write_all_to(GuestAddress(*gpa), socket, *len as usize)
+                .map_err(|e| Error::Snapshot(MigratableError::Snapshot(e.into())))?;
+        }
+
+        migration::Response::read_from(socket)
+            .and_then(|r| r.ok_or_refused())
+            .map_err(Error::Migration)
+    }
+
+    // Whether every guest RAM write can be logged while the VM runs. The
+    // vhost-user backends and the VFIO devices write to the guest RAM behind
+    // the VMM back, which leaves stopping the VM before sending the guest
+    // RAM as the only option.
+    fn dirty_log_is_complete(&self) -> bool {
+        let config = self.config.lock().unwrap();
+        let vhost_user_disk = config.disks.iter().flatten().any(|disk| disk.vhost_user);
+        let vhost_user_net = config.net.iter().flatten().any(|net| net.vhost_user);
+        let fs = config.fs.iter().flatten().next().is_some();
+        let vfio = config.devices.iter().flatten().next().is_some();
+
+        !(vhost_user_disk || vhost_user_net || fs || vfio)
+    }
+
     /// Get the VM state. Returns an error if the state is poisoned.
     pub fn get_state(&self) -> Result<VmState> {
         self.state
@@ -728,6 +1407,26 @@ impl Vm {
     }
 }
 
+// Merge two lists of sorted (guest address, length) ranges into a sorted
+// list of ranges, without any overlap.
+fn merge_ranges(a: Vec<(u64, u64)>, b: Vec<(u64, u64)>) -> Vec<(u64, u64)> {
+    let mut ranges = a;
+    ranges.extend(b);
+    ranges.sort_unstable();
+
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (gpa, len) in ranges {
+        match merged.last_mut() {
+            Some((start, length)) if gpa <= *start + *length => {
+                *length = cmp::max(*length, gpa + len - *start);
+            }
+            _ => merged.push((gpa, len)),
+        }
+    }
+
+    merged
+}
+
 impl Pausable for Vm {
     fn pause(&mut self) -> std::result::Result<(), MigratableError> {
         let mut state = self
@@ -769,7 +1468,72 @@ impl Pausable for Vm {
     }
 }
 
-impl Snapshotable for Vm {}
+impl Snapshotable for Vm {
+    fn id(&self) -> String {
+        VM_SNAPSHOT_ID.to_string()
+    }
+
+    fn snapshot(&self) -> std::result::Result<Snapshot, MigratableError> {
+        let current_state = self
+            .get_state()
+            .map_err(|e| MigratableError::Snapshot(anyhow!("Could not get VM state: {:?}", e)))?;
+        if current_state != VmState::Paused {
+            return Err(MigratableError::Snapshot(anyhow!(
+                "Trying to snapshot while VM is {:?}",
+                current_state
+            )));
+        }
+
+        let mut vm_snapshot = Snapshot::new(VM_SNAPSHOT_ID);
+        vm_snapshot.add_snapshot(self.cpu_manager.lock().unwrap().snapshot()?);
+        vm_snapshot.add_snapshot(self.memory_manager.lock().unwrap().snapshot()?);
+        vm_snapshot.add_snapshot(self.device_manager.lock().unwrap().snapshot()?);
+
+        Ok(vm_snapshot)
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) -> std::result::Result<(), MigratableError> {
+        let mut state = self
+            .state
+            .try_write()
+            .map_err(|e| MigratableError::Restore(anyhow!("Could not get VM state: {}", e)))?;
+        if *state != VmState::Created {
+            return Err(MigratableError::Restore(anyhow!(
+                "Trying to restore a VM that is {:?}",
+                *state
+            )));
+        }
+
+        // The guest memory has been restored when creating the memory
+        // manager, we now restore the devices before the vCPUs.
+        let device_manager_id = self.device_manager.lock().unwrap().id();
+        let device_manager_snapshot = snapshot
+            .snapshots
+            .get(&device_manager_id)
+            .ok_or_else(|| MigratableError::Restore(anyhow!("Missing device manager snapshot")))?;
+        self.device_manager
+            .lock()
+            .unwrap()
+            .restore(*device_manager_snapshot.clone())?;
+
+        let cpu_manager_id = self.cpu_manager.lock().unwrap().id();
+        let cpu_manager_snapshot = snapshot
+            .snapshots
+            .get(&cpu_manager_id)
+            .ok_or_else(|| MigratableError::Restore(anyhow!("Missing CPU manager snapshot")))?;
+        self.cpu_manager
+            .lock()
+            .unwrap()
+            .restore(*cpu_manager_snapshot.clone())?;
+
+        // A restored VM is paused, waiting for being resumed. This is not a
+        // regular state transition as the VM never ran in this process.
+        *state = VmState::Paused;
+
+        Ok(())
+    }
+}
+
 impl Migratable for Vm {}
 
 #[cfg(test)]
@@ -809,6 +1573,27 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_merge_ranges() {
+        assert_eq!(merge_ranges(Vec::new(), Vec::new()), Vec::new());
+        assert_eq!(
+            merge_ranges(
+                vec![(0x1000, 0x1000), (0x5000, 0x2000), (0x10000, 0x1000)],
+                vec![(0x2000, 0x1000), (0x6000, 0x1000), (0x8000, 0x1000)]
+            ),
+            vec![
+                (0x1000, 0x2000),
+                (0x5000, 0x2000),
+                (0x8000, 0x1000),
+                (0x10000, 0x1000)
+            ]
+        );
+        assert_eq!(
+            merge_ranges(vec![(0x1000, 0x4000)], vec![(0, 0x2000), (0x4000, 0x2000)]),
+            vec![(0, 0x6000)]
+        );
+    }
+
     #[test]
     fn test_vm_created_transitions() {
         test_vm_state_transitions(VmState::Created);